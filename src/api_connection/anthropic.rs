@@ -0,0 +1,170 @@
+//! Translation between the OpenAI-style chat completion types and the Anthropic Messages API.
+use serde::Deserialize;
+use serde_json::json;
+
+use super::endpoints::{
+    ChatCompletionChoice, ChatCompletionRequest, ChatCompletionResponse,
+    ChatCompletionResponseMessage, ChatCompletionUsage, ResponseFormat,
+};
+
+pub const ANTHROPIC_API_VERSION: &str = "2023-06-01";
+// The Messages API requires `max_tokens`; used when the request leaves it unset.
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+#[derive(Debug, Deserialize)]
+struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicResponse {
+    id: String,
+    model: String,
+    content: Vec<AnthropicContentBlock>,
+    stop_reason: Option<String>,
+    usage: Option<AnthropicUsage>,
+}
+
+/// Builds a Messages API payload. System messages move to the top-level `system` field and, since
+/// Anthropic has no `response_format`, a requested JSON schema is appended to the system prompt.
+pub fn to_anthropic_payload(request: &ChatCompletionRequest, model: &str) -> serde_json::Value {
+    let mut system_parts: Vec<String> = request.messages.iter()
+        .filter(|message| message.role == "system")
+        .map(|message| message.content.clone())
+        .collect();
+    if let Some(schema) = request.response_format.as_ref().and_then(ResponseFormat::schema) {
+        system_parts.push(format!(
+            "Respond ONLY with a JSON object matching this JSON schema:\n{}",
+            serde_json::to_string(&schema.schema).unwrap_or_default()
+        ));
+    }
+    let messages: Vec<serde_json::Value> = request.messages.iter()
+        .filter(|message| message.role != "system")
+        .map(|message| json!({ "role": message.role, "content": message.content }))
+        .collect();
+
+    let mut payload = json!({
+        "model": model,
+        "max_tokens": request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        "messages": messages,
+    });
+    if !system_parts.is_empty() {
+        payload["system"] = json!(system_parts.join("\n\n"));
+    }
+    if let Some(temperature) = request.temperature {
+        payload["temperature"] = json!(temperature);
+    }
+    payload
+}
+
+/// Maps stop reasons onto the OpenAI `finish_reason` vocabulary.
+fn finish_reason_of(stop_reason: &str) -> String {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => "stop",
+        "max_tokens" => "length",
+        other => other,
+    }
+    .to_string()
+}
+
+impl From<AnthropicResponse> for ChatCompletionResponse {
+    fn from(response: AnthropicResponse) -> Self {
+        let content = response.content.iter()
+            .filter(|block| block.block_type == "text")
+            .map(|block| block.text.as_str())
+            .collect::<Vec<_>>()
+            .join("");
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        ChatCompletionResponse {
+            id: response.id,
+            object: Some("chat.completion".to_string()),
+            created,
+            model: response.model,
+            choices: vec![ChatCompletionChoice {
+                message: ChatCompletionResponseMessage {
+                    role: "assistant".to_string(),
+                    content,
+                },
+                finish_reason: response.stop_reason.as_deref().map(finish_reason_of),
+                index: 0,
+            }],
+            usage: response.usage.map(|usage| ChatCompletionUsage {
+                prompt_tokens: usage.input_tokens,
+                completion_tokens: Some(usage.output_tokens),
+                total_tokens: usage.input_tokens + usage.output_tokens,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::endpoints::{ChatMessage, JsonSchema, JsonSchemaDefinition, ResponseFormat, ResponseFormatMode};
+
+    #[test]
+    fn test_to_anthropic_payload_moves_system_and_schema() {
+        let request = |mode: ResponseFormatMode| ChatCompletionRequest {
+            model: "qwen/qwen3-32b".to_string(),
+            messages: vec![
+                ChatMessage { role: "system".to_string(), content: "Output JSON.".to_string() },
+                ChatMessage { role: "user".to_string(), content: "Convert 1 cup of flour.".to_string() },
+            ],
+            response_format: Some(ResponseFormat::for_schema(
+                JsonSchemaDefinition {
+                    name: "gram_conversion_schema".to_string(),
+                    strict: Some(true),
+                    schema: JsonSchema {
+                        schema_type: "object".to_string(),
+                        properties: None,
+                        required: None,
+                        additional_properties: Some(false),
+                    },
+                },
+                mode,
+            )),
+            temperature: Some(0.0),
+            max_tokens: None,
+        };
+
+        let payload = to_anthropic_payload(&request(ResponseFormatMode::JsonSchema), "claude-3-5-haiku-latest");
+        assert_eq!(payload["model"], "claude-3-5-haiku-latest");
+        assert_eq!(payload["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(payload["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(payload["messages"][0]["role"], "user");
+        let system = payload["system"].as_str().unwrap();
+        assert!(system.starts_with("Output JSON."));
+        assert!(system.contains("\"additionalProperties\":false"));
+        // `json_object` mode leaves the schema out of `response_format`, not out of the prompt.
+        let payload = to_anthropic_payload(&request(ResponseFormatMode::JsonObject), "claude-3-5-haiku-latest");
+        assert!(payload["system"].as_str().unwrap().contains("\"additionalProperties\":false"));
+    }
+
+    #[test]
+    fn test_anthropic_response_conversion() {
+        let raw = r#"{
+            "id": "msg_1", "type": "message", "role": "assistant", "model": "claude-3-5-haiku-latest",
+            "content": [{"type": "text", "text": "{\"grams\": "}, {"type": "text", "text": "120}"}],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 30, "output_tokens": 5}
+        }"#;
+        let response: ChatCompletionResponse = serde_json::from_str::<AnthropicResponse>(raw).unwrap().into();
+        assert_eq!(response.choices[0].message.content, "{\"grams\": 120}");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("length"));
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (30, Some(5), 35));
+    }
+}
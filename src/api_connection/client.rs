@@ -0,0 +1,259 @@
+//! The chat completion seam used by the recipe pipeline, so it can run against canned responses.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+
+use super::connection::ApiConnectionError;
+use super::endpoints::{
+    ChatCompletionChoice, ChatCompletionRequest, ChatCompletionResponse,
+    ChatCompletionResponseMessage, ChatCompletionUsage, Provider,
+};
+use crate::log_warning;
+
+/// Times a request cut off at its token limit is resent with a doubled `max_tokens`.
+pub const TRUNCATION_RETRIES: u32 = 1;
+/// Default of `--max-concurrent-requests`.
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: u32 = 4;
+
+/// Anything that can answer a chat completion request.
+pub trait ChatClient: Sync {
+    fn complete(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> impl Future<Output = Result<ChatCompletionResponse, ApiConnectionError>> + Send;
+}
+
+impl ChatClient for Provider {
+    fn complete(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> impl Future<Output = Result<ChatCompletionResponse, ApiConnectionError>> + Send {
+        self.call_chat_completion(request)
+    }
+}
+
+/// A client letting at most `max_concurrent` requests be in flight at once, across every task
+/// sharing it (clones share the limit). Keeps parallel stages under the provider's rate limits.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimited<C> {
+    inner: C,
+    permits: Arc<Semaphore>,
+}
+
+impl<C> ConcurrencyLimited<C> {
+    /// `max_concurrent` is raised to 1 if given as 0.
+    pub fn new(inner: C, max_concurrent: u32) -> Self {
+        Self { inner, permits: Arc::new(Semaphore::new(max_concurrent.max(1) as usize)) }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: ChatClient> ChatClient for ConcurrencyLimited<C> {
+    async fn complete(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse, ApiConnectionError> {
+        let _permit = self.permits.acquire().await.expect("the request semaphore is never closed");
+        self.inner.complete(request).await
+    }
+}
+
+/// A client naming `model` in every request it forwards. Providers configured with a model of
+/// their own (OpenAI, Anthropic) send that one regardless.
+#[derive(Debug, Clone, Copy)]
+pub struct WithModel<'a, C> {
+    inner: &'a C,
+    model: &'a str,
+}
+
+impl<'a, C> WithModel<'a, C> {
+    pub fn new(inner: &'a C, model: &'a str) -> Self {
+        Self { inner, model }
+    }
+}
+
+impl<C: ChatClient> ChatClient for WithModel<'_, C> {
+    async fn complete(&self, mut request: ChatCompletionRequest) -> Result<ChatCompletionResponse, ApiConnectionError> {
+        request.model = self.model.to_string();
+        self.inner.complete(request).await
+    }
+}
+
+/// Sends `request`, resending it up to `retries` times with a doubled `max_tokens` while the
+/// answer is truncated. An answer still truncated after that is an `ApiConnectionError::Truncated`.
+/// The returned `usage` covers every attempt.
+pub async fn complete_untruncated(
+    client: &impl ChatClient,
+    mut request: ChatCompletionRequest,
+    retries: u32,
+) -> Result<ChatCompletionResponse, ApiConnectionError> {
+    let mut retries_left = retries;
+    let mut spent: Option<ChatCompletionUsage> = None;
+    loop {
+        let max_tokens = request.max_tokens;
+        let mut response = client.complete(request.clone()).await?;
+        spent = match (spent, response.usage) {
+            (Some(earlier), Some(usage)) => Some(earlier.combined(usage)),
+            (earlier, usage) => earlier.or(usage),
+        };
+        if !response.choices.first().is_some_and(ChatCompletionChoice::is_truncated) {
+            response.usage = spent;
+            return Ok(response);
+        }
+        match max_tokens {
+            Some(limit) if retries_left > 0 => {
+                retries_left -= 1;
+                let raised = limit.saturating_mul(2);
+                log_warning!("[WARNING] Response truncated at {} max_tokens; retrying with {}.", limit, raised);
+                request.max_tokens = Some(raised);
+            }
+            _ => return Err(ApiConnectionError::Truncated { max_tokens }),
+        }
+    }
+}
+
+/// Replies with canned message contents in order and records every request it receives.
+/// Once the responses run out, each further call fails with an `ApiError`.
+#[derive(Debug, Default)]
+pub struct MockChatClient {
+    responses: Mutex<VecDeque<(String, String)>>, // (content, finish_reason)
+    requests: Mutex<Vec<ChatCompletionRequest>>,
+}
+
+impl MockChatClient {
+    pub fn new<I, S>(responses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::with_finish_reasons(responses.into_iter().map(|content| (content, "stop")))
+    }
+
+    /// Like `new`, with the `finish_reason` of each response, e.g. "length" for a truncated one.
+    pub fn with_finish_reasons<I, S>(responses: I) -> Self
+    where
+        I: IntoIterator<Item = (S, &'static str)>,
+        S: Into<String>,
+    {
+        Self {
+            responses: Mutex::new(
+                responses.into_iter()
+                    .map(|(content, finish_reason)| (content.into(), finish_reason.to_string()))
+                    .collect(),
+            ),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<ChatCompletionRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn respond(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse, ApiConnectionError> {
+        let model = request.model.clone();
+        self.requests.lock().unwrap().push(request);
+        let (content, finish_reason) = self.responses.lock().unwrap().pop_front().ok_or_else(|| {
+            ApiConnectionError::api_error(reqwest::StatusCode::SERVICE_UNAVAILABLE, "MockChatClient has no canned response left")
+        })?;
+
+        Ok(ChatCompletionResponse {
+            id: "mock".to_string(),
+            object: Some("chat.completion".to_string()),
+            created: 0,
+            model,
+            choices: vec![ChatCompletionChoice {
+                message: ChatCompletionResponseMessage {
+                    role: "assistant".to_string(),
+                    content,
+                },
+                finish_reason: Some(finish_reason),
+                index: 0,
+            }],
+            usage: None,
+        })
+    }
+}
+
+impl ChatClient for MockChatClient {
+    fn complete(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> impl Future<Output = Result<ChatCompletionResponse, ApiConnectionError>> + Send {
+        std::future::ready(self.respond(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::endpoints::ChatMessage;
+
+    fn request(max_tokens: Option<u32>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: "test".to_string(),
+            messages: vec![ChatMessage { role: "user".to_string(), content: "hi".to_string() }],
+            response_format: None,
+            temperature: Some(0.0),
+            max_tokens,
+        }
+    }
+
+    /// Counts how many of its requests are in flight, yielding once mid-request.
+    #[derive(Default)]
+    struct InFlightCounter {
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+        replies: MockChatClient,
+    }
+
+    impl ChatClient for InFlightCounter {
+        async fn complete(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse, ApiConnectionError> {
+            use std::sync::atomic::Ordering;
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.replies.complete(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limited_caps_in_flight_requests() {
+        for (limit, expected_max) in [(1, 1), (2, 2)] {
+            let counter = InFlightCounter { replies: MockChatClient::new(["a", "b", "c", "d"]), ..Default::default() };
+            let client = ConcurrencyLimited::new(counter, limit);
+            let (a, b, c, d) = tokio::join!(
+                client.complete(request(None)),
+                client.complete(request(None)),
+                client.complete(request(None)),
+                client.complete(request(None)),
+            );
+            assert!(a.is_ok() && b.is_ok() && c.is_ok() && d.is_ok());
+            assert_eq!(client.inner().max_in_flight.load(std::sync::atomic::Ordering::SeqCst), expected_max);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_complete_untruncated_raises_the_token_budget() {
+        let client = MockChatClient::with_finish_reasons([("{\"a\": ", "length"), ("{\"a\": 1}", "stop")]);
+        let response = complete_untruncated(&client, request(Some(100)), 1).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "{\"a\": 1}");
+        let max_tokens: Vec<Option<u32>> = client.requests().iter().map(|request| request.max_tokens).collect();
+        assert_eq!(max_tokens, vec![Some(100), Some(200)]);
+
+        let client = MockChatClient::with_finish_reasons([("{", "length"), ("{\"a\"", "length")]);
+        let error = complete_untruncated(&client, request(Some(100)), 1).await.unwrap_err();
+        assert!(matches!(error, ApiConnectionError::Truncated { max_tokens: Some(200) }));
+        assert!(error.to_string().contains("max-tokens"));
+    }
+
+    #[tokio::test]
+    async fn test_with_model_names_the_model() {
+        let client = MockChatClient::new(["a"]);
+        WithModel::new(&client, "mistral/small").complete(request(None)).await.unwrap();
+        assert_eq!(client.requests()[0].model, "mistral/small");
+    }
+}
@@ -1,14 +1,50 @@
 use dotenv::dotenv;
 use reqwest::Client;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::env;
 use std::error::Error;
 use std::fmt;
 
+use super::anthropic::{to_anthropic_payload, AnthropicResponse, ANTHROPIC_API_VERSION};
 use super::endpoints::{
-    ChatCompletionRequest, ChatCompletionResponse, OpenRouterAvailableModel, Provider,
-    OPENROUTER_MODELS,
+    ApiKeyValue, ChatCompletionRequest, ChatCompletionResponse, ChatMessage, OpenRouterAvailableModel, Provider,
+    ProviderConfig, ProviderKind, ANTHROPIC_BASE_URL, ANTHROPIC_DEFAULT_MODEL, OPENAI_BASE_URL,
+    OPENAI_DEFAULT_MODEL, OPENROUTER_BASE_URL, OPENROUTER_MODELS,
 };
+use super::streaming::ChatCompletionStream;
+use super::usage::record_usage;
+
+/// Fields of a JSON error body, such as OpenRouter's `{"error": {"code": 402, "message": "..."}}`
+/// or Anthropic's `{"type": "error", "error": {"type": "rate_limit_error", "message": "..."}}`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApiErrorDetails {
+    pub code: Option<String>,
+    pub message: Option<String>,
+    pub error_type: Option<String>,
+}
+
+impl ApiErrorDetails {
+    /// Reads the details from `error_body`, wrapped in an `error` object or not. `None` when the
+    /// body isn't JSON or has none of the fields.
+    pub fn parse(error_body: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(error_body).ok()?;
+        let error = value.get("error").filter(|error| error.is_object()).unwrap_or(&value);
+        let field = |name: &str| match error.get(name)? {
+            Value::String(text) => Some(text.clone()),
+            Value::Null => None,
+            other => Some(other.to_string()),
+        };
+        let details = ApiErrorDetails { code: field("code"), message: field("message"), error_type: field("type") };
+        (details != ApiErrorDetails::default()).then_some(details)
+    }
+
+    /// True when the code, type or message contains `needle` (lowercase).
+    fn mentions(&self, needle: &str) -> bool {
+        [&self.code, &self.error_type, &self.message].into_iter()
+            .flatten()
+            .any(|text| text.to_lowercase().contains(needle))
+    }
+}
 
 #[derive(Debug)]
 pub enum ApiConnectionError {
@@ -18,8 +54,14 @@ pub enum ApiConnectionError {
     ApiError {
         status: reqwest::StatusCode,
         error_body: String,
+        /// `error_body` parsed, when it is a JSON error.
+        details: Option<ApiErrorDetails>,
     },
     UnsupportedProvider(String),
+    /// The answer was cut off at the request's `max_tokens` (`finish_reason` "length").
+    Truncated { max_tokens: Option<u32> },
+    /// The answer is not the expected JSON; only reported as such in `JsonMode::Strict`.
+    InvalidJson { error: String, content: String },
 }
 
 impl fmt::Display for ApiConnectionError {
@@ -32,12 +74,59 @@ impl fmt::Display for ApiConnectionError {
             ApiConnectionError::SerializationError(err) => {
                 write!(f, "Serialization error: {}", err)
             }
-            ApiConnectionError::ApiError { status, error_body } => {
+            ApiConnectionError::ApiError { status, details: Some(details @ ApiErrorDetails { message: Some(message), .. }), .. } => {
+                write!(f, "API error {}: {}", status, message)?;
+                match (&details.error_type, &details.code) {
+                    (Some(error_type), Some(code)) => write!(f, " ({}, code {})", error_type, code),
+                    (Some(error_type), None) => write!(f, " ({})", error_type),
+                    (None, Some(code)) => write!(f, " (code {})", code),
+                    (None, None) => Ok(()),
+                }
+            }
+            ApiConnectionError::ApiError { status, error_body, .. } => {
                 write!(f, "API error {}: {}", status, error_body)
             }
             ApiConnectionError::UnsupportedProvider(provider_name) => {
                 write!(f, "Unsupported provider: {}", provider_name)
             }
+            ApiConnectionError::Truncated { max_tokens } => {
+                let limit = max_tokens.map_or_else(|| "the provider's".to_string(), |limit| limit.to_string());
+                write!(f, "Response truncated at {} max_tokens; raise the stage's --*-max-tokens option", limit)
+            }
+            ApiConnectionError::InvalidJson { error, content } => {
+                write!(f, "Response is not valid JSON ({}). Raw content:\n{}", error, content)
+            }
+        }
+    }
+}
+
+impl ApiConnectionError {
+    /// An `ApiError`, with `details` parsed from the body.
+    pub fn api_error(status: reqwest::StatusCode, error_body: impl Into<String>) -> Self {
+        let error_body = error_body.into();
+        let details = ApiErrorDetails::parse(&error_body);
+        ApiConnectionError::ApiError { status, error_body, details }
+    }
+
+    /// The provider refused the request because of its rate limits.
+    pub fn is_rate_limited(&self) -> bool {
+        match self {
+            ApiConnectionError::ApiError { status, details, .. } => {
+                *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || details.as_ref().is_some_and(|details| details.code.as_deref() == Some("429") || details.mentions("rate_limit") || details.mentions("rate limit"))
+            }
+            _ => false,
+        }
+    }
+
+    /// The account has run out of credits or quota.
+    pub fn is_insufficient_credits(&self) -> bool {
+        match self {
+            ApiConnectionError::ApiError { status, details, .. } => {
+                *status == reqwest::StatusCode::PAYMENT_REQUIRED
+                    || details.as_ref().is_some_and(|details| details.code.as_deref() == Some("402") || details.mentions("insufficient") || details.mentions("credits"))
+            }
+            _ => false,
         }
     }
 }
@@ -69,76 +158,381 @@ impl Provider {
         dotenv().ok();
         Self::OpenRouter {
             api_key: api_key_env_var_name.to_string(),
+            api_key_value: None,
             available_models: OPENROUTER_MODELS.to_vec(),
+            base_url: None,
+            model: None,
+        }
+    }
+
+    pub fn openai(api_key_env_var_name: &str) -> Self {
+        dotenv().ok();
+        Self::OpenAi {
+            api_key: api_key_env_var_name.to_string(),
+            api_key_value: None,
+            model: OPENAI_DEFAULT_MODEL.to_string(),
+            base_url: None,
+        }
+    }
+
+    pub fn anthropic(api_key_env_var_name: &str) -> Self {
+        dotenv().ok();
+        Self::Anthropic {
+            api_key: api_key_env_var_name.to_string(),
+            api_key_value: None,
+            model: ANTHROPIC_DEFAULT_MODEL.to_string(),
+            base_url: None,
+        }
+    }
+
+    /// Builds the provider for `kind`, reading its API key from the provider's usual variable.
+    pub fn for_kind(kind: ProviderKind) -> Self {
+        let api_key_env_var_name = kind.api_key_env_var();
+        match kind {
+            ProviderKind::OpenRouter => Self::openrouter(api_key_env_var_name),
+            ProviderKind::OpenAi => Self::openai(api_key_env_var_name),
+            ProviderKind::Anthropic => Self::anthropic(api_key_env_var_name),
+        }
+    }
+
+    /// Builds the provider described by a config file: its kind with the optional API key
+    /// variable, base URL and model overrides.
+    pub fn from_config(config: &ProviderConfig) -> Self {
+        let api_key_env_var_name = config.api_key_env.as_deref().unwrap_or(config.provider.api_key_env_var());
+        let mut provider = match config.provider {
+            ProviderKind::OpenRouter => Self::openrouter(api_key_env_var_name),
+            ProviderKind::OpenAi => Self::openai(api_key_env_var_name),
+            ProviderKind::Anthropic => Self::anthropic(api_key_env_var_name),
+        };
+        match &mut provider {
+            Provider::OpenRouter { base_url, .. }
+            | Provider::OpenAi { base_url, .. }
+            | Provider::Anthropic { base_url, .. } => base_url.clone_from(&config.base_url),
+        }
+        match &config.model {
+            Some(model) => provider.with_model(model),
+            None => provider,
+        }
+    }
+
+    /// Answers every request with `model`.
+    pub fn with_model(mut self, model: &str) -> Self {
+        match &mut self {
+            Provider::OpenRouter { model: configured, .. } => *configured = Some(model.to_string()),
+            Provider::OpenAi { model: configured, .. } | Provider::Anthropic { model: configured, .. } => {
+                *configured = model.to_string();
+            }
+        }
+        self
+    }
+
+    pub fn kind(&self) -> ProviderKind {
+        match self {
+            Provider::OpenRouter { .. } => ProviderKind::OpenRouter,
+            Provider::OpenAi { .. } => ProviderKind::OpenAi,
+            Provider::Anthropic { .. } => ProviderKind::Anthropic,
+        }
+    }
+
+    /// Name of the environment variable the API key is read from.
+    pub fn api_key_env_var(&self) -> &str {
+        match self {
+            Provider::OpenRouter { api_key, .. }
+            | Provider::OpenAi { api_key, .. }
+            | Provider::Anthropic { api_key, .. } => api_key,
+        }
+    }
+
+    /// Reads the API key from `env_var_name` instead of the provider's usual variable.
+    pub fn with_api_key_env(mut self, env_var_name: &str) -> Self {
+        match &mut self {
+            Provider::OpenRouter { api_key, .. }
+            | Provider::OpenAi { api_key, .. }
+            | Provider::Anthropic { api_key, .. } => *api_key = env_var_name.to_string(),
+        }
+        self
+    }
+
+    /// Uses `key` itself as the API key, e.g. one fetched from a secrets manager, instead of
+    /// reading it from the environment.
+    pub fn with_api_key(mut self, key: String) -> Self {
+        match &mut self {
+            Provider::OpenRouter { api_key_value, .. }
+            | Provider::OpenAi { api_key_value, .. }
+            | Provider::Anthropic { api_key_value, .. } => *api_key_value = Some(ApiKeyValue(key)),
+        }
+        self
+    }
+
+    fn api_key_value(&self) -> Option<&ApiKeyValue> {
+        match self {
+            Provider::OpenRouter { api_key_value, .. }
+            | Provider::OpenAi { api_key_value, .. }
+            | Provider::Anthropic { api_key_value, .. } => api_key_value.as_ref(),
         }
     }
 
+    /// Where the API key comes from, for messages: the environment variable, or a key given directly.
+    pub fn api_key_source(&self) -> String {
+        match self.api_key_value() {
+            Some(_) => "a key passed directly".to_string(),
+            None => self.api_key_env_var().to_string(),
+        }
+    }
+
+    /// Endpoint chat completions are posted to, under the configured or default base URL.
+    fn chat_endpoint(&self) -> String {
+        let (base_url, default_base_url, path) = match self {
+            Provider::OpenRouter { base_url, .. } => (base_url, OPENROUTER_BASE_URL, "chat/completions"),
+            Provider::OpenAi { base_url, .. } => (base_url, OPENAI_BASE_URL, "chat/completions"),
+            Provider::Anthropic { base_url, .. } => (base_url, ANTHROPIC_BASE_URL, "messages"),
+        };
+        format!("{}/{}", base_url.as_deref().unwrap_or(default_base_url).trim_end_matches('/'), path)
+    }
+
     pub fn get_available_models(&self) -> Vec<OpenRouterAvailableModel> {
         match self {
             Provider::OpenRouter {
                 available_models, ..
             } => available_models.clone(),
+            Provider::OpenAi { .. } | Provider::Anthropic { .. } => Vec::new(),
         }
     }
 
-    pub async fn call_chat_completion(
-        &self,
-        request: ChatCompletionRequest,
-    ) -> Result<ChatCompletionResponse, ApiConnectionError> {
+    /// Models requests are answered with: the OpenRouter model list, or the configured model.
+    pub fn model_names(&self) -> Vec<String> {
         match self {
-            Provider::OpenRouter {
-                api_key: api_key_env_var_name,
-                ..
-            } => {
-                dotenv().ok();
-                let actual_api_key = env::var(api_key_env_var_name)
-                    .map_err(|_| ApiConnectionError::MissingApiKey(api_key_env_var_name.clone()))?;
-
-                let client = Client::new();
-                let url = "https://openrouter.ai/api/v1/chat/completions";
-
-                let mut request_payload = serde_json::to_value(&request)
-                    .map_err(ApiConnectionError::SerializationError)?;
-
-                if let Some(obj) = request_payload.as_object_mut() {
-                    obj.insert(
-                        "provider".to_string(),
-                        json!({ "only": ["Cerebras"] }),
-                    );
-                } else {
-                    return Err(ApiConnectionError::SerializationError(
-                        serde_json::from_str::<serde_json::Value>(
-                            "Failed to create JSON object from request",
-                        )
-                        .unwrap_err(),
-                    ));
+            Provider::OpenRouter { model: Some(model), .. } => vec![model.clone()],
+            Provider::OpenRouter { available_models, .. } => available_models.iter()
+                .map(|model| format!("{} (via {})", model.model_name, model.model_source))
+                .collect(),
+            Provider::OpenAi { model, .. } | Provider::Anthropic { model, .. } => vec![model.clone()],
+        }
+    }
+
+    /// Sends a one-token completion, so a missing or rejected API key shows up before any real work.
+    pub async fn check_connection(&self) -> Result<(), ApiConnectionError> {
+        let request = ChatCompletionRequest {
+            model: OPENROUTER_MODELS[0].model_name.to_string(),
+            messages: vec![ChatMessage { role: "user".to_string(), content: "ping".to_string() }],
+            response_format: None,
+            temperature: Some(0.0),
+            max_tokens: Some(1),
+        };
+        self.call_chat_completion(request).await.map(|_| ())
+    }
+
+    fn api_key(&self) -> Result<String, ApiConnectionError> {
+        if let Some(ApiKeyValue(key)) = self.api_key_value() {
+            return Ok(key.clone());
+        }
+        let api_key_env_var_name = self.api_key_env_var();
+        dotenv().ok();
+        env::var(api_key_env_var_name)
+            .map_err(|_| ApiConnectionError::MissingApiKey(api_key_env_var_name.to_string()))
+    }
+
+    /// Builds the HTTP request for this provider. `stream` asks for server-sent events instead of a
+    /// single JSON body.
+    fn build_request(
+        &self,
+        client: &Client,
+        request: &ChatCompletionRequest,
+        stream: bool,
+    ) -> Result<reqwest::RequestBuilder, ApiConnectionError> {
+        let actual_api_key = self.api_key()?;
+
+        let mut request_payload = match self {
+            Provider::OpenRouter { model, .. } => {
+                let mut payload = serde_json::to_value(request)?;
+                if let Some(model) = model {
+                    payload["model"] = json!(model);
+                }
+                payload
+            }
+            Provider::OpenAi { model, .. } => {
+                let mut payload = serde_json::to_value(request)?;
+                payload["model"] = json!(model);
+                if stream {
+                    payload["stream_options"] = json!({ "include_usage": true });
                 }
-                
+                payload
+            }
+            Provider::Anthropic { model, .. } => to_anthropic_payload(request, model),
+        };
+
+        if let Some(obj) = request_payload.as_object_mut() {
+            if let Provider::OpenRouter { .. } = self {
+                obj.insert(
+                    "provider".to_string(),
+                    json!({ "only": ["Cerebras"] }),
+                );
+            }
+            if stream {
+                obj.insert("stream".to_string(), json!(true));
+            }
+        } else {
+            return Err(ApiConnectionError::SerializationError(
+                serde_json::from_str::<serde_json::Value>(
+                    "Failed to create JSON object from request",
+                )
+                .unwrap_err(),
+            ));
+        }
+
+        let endpoint = self.chat_endpoint();
+        let builder = match self {
+            Provider::OpenRouter { .. } => {
                 let site_url = env::var("SITE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
                 let app_name = env::var("APP_NAME").unwrap_or_else(|_| "RecipeOptim".to_string());
 
-                let response = client
-                    .post(url)
+                client
+                    .post(endpoint)
                     .bearer_auth(actual_api_key)
-                    .header("Content-Type", "application/json")
                     .header("HTTP-Referer", site_url) 
                     .header("X-Title", app_name)
-                    .json(&request_payload)
-                    .send()
-                    .await?;
-
-                if response.status().is_success() {
-                    let chat_response = response.json::<ChatCompletionResponse>().await?;
-                    Ok(chat_response)
-                } else {
-                    let status = response.status();
-                    let error_body = response
-                        .text()
-                        .await
-                        .unwrap_or_else(|_| "Failed to read error body".to_string());
-                    Err(ApiConnectionError::ApiError { status, error_body })
-                }
             }
+            Provider::OpenAi { .. } => client
+                .post(endpoint)
+                .bearer_auth(actual_api_key),
+            Provider::Anthropic { .. } => client
+                .post(endpoint)
+                .header("x-api-key", actual_api_key)
+                .header("anthropic-version", ANTHROPIC_API_VERSION),
+        };
+
+        Ok(builder
+            .header("Content-Type", "application/json")
+            .json(&request_payload))
+    }
+
+    /// Sends `request` as one non-streamed completion. The provider's token counts, if it reports
+    /// any, come back in `usage` for the caller and are added to the process-wide totals.
+    pub async fn call_chat_completion(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, ApiConnectionError> {
+        let client = Client::new();
+        let response = self.build_request(&client, &request, false)?.send().await?;
+        let response = check_status(response).await?;
+
+        let chat_response = match self {
+            Provider::OpenRouter { .. } | Provider::OpenAi { .. } => {
+                response.json::<ChatCompletionResponse>().await?
+            }
+            Provider::Anthropic { .. } => response.json::<AnthropicResponse>().await?.into(),
+        };
+
+        if let Some(usage) = &chat_response.usage {
+            record_usage(usage);
         }
+        Ok(chat_response)
+    }
+
+    /// Like `call_chat_completion`, but asks for a streamed response and yields content deltas as
+    /// they arrive. Use `ChatCompletionStream::collect_content` to get the full text.
+    pub async fn call_chat_completion_stream(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream, ApiConnectionError> {
+        let client = Client::new();
+        let response = self.build_request(&client, &request, true)?.send().await?;
+        Ok(ChatCompletionStream::new(check_status(response).await?))
+    }
+}
+
+/// Turns a non-success HTTP response into `ApiConnectionError::ApiError`.
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, ApiConnectionError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let status = response.status();
+        let error_body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Failed to read error body".to_string());
+        Err(ApiConnectionError::api_error(status, error_body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error_details() {
+        let credits = ApiConnectionError::api_error(
+            reqwest::StatusCode::PAYMENT_REQUIRED,
+            r#"{"error": {"code": 402, "message": "Insufficient credits"}}"#,
+        );
+        assert!(credits.is_insufficient_credits());
+        assert!(!credits.is_rate_limited());
+        assert_eq!(credits.to_string(), "API error 402 Payment Required: Insufficient credits (code 402)");
+
+        let anthropic = ApiConnectionError::api_error(
+            reqwest::StatusCode::OK,
+            r#"{"type": "error", "error": {"type": "rate_limit_error", "message": "Slow down"}}"#,
+        );
+        assert!(anthropic.is_rate_limited());
+        let ApiConnectionError::ApiError { details: Some(details), .. } = &anthropic else { panic!("expected details") };
+        assert_eq!(details.error_type.as_deref(), Some("rate_limit_error"));
+        assert_eq!(details.code, None);
+
+        // A bare error object, as streamed.
+        assert_eq!(ApiErrorDetails::parse(r#"{"message": "Overloaded", "code": "overloaded"}"#).unwrap().code.as_deref(), Some("overloaded"));
+
+        let raw = ApiConnectionError::api_error(reqwest::StatusCode::BAD_GATEWAY, "<html>Bad gateway</html>");
+        assert!(matches!(raw, ApiConnectionError::ApiError { details: None, .. }));
+        assert_eq!(raw.to_string(), "API error 502 Bad Gateway: <html>Bad gateway</html>");
+        assert!(ApiErrorDetails::parse(r#"{"id": 1}"#).is_none());
+    }
+
+    #[test]
+    fn test_api_key_passed_directly() {
+        let provider = Provider::for_kind(ProviderKind::OpenAi).with_api_key_env("RECIPE_OPTIM_TEST_UNSET_KEY");
+        assert_eq!(provider.api_key_source(), "RECIPE_OPTIM_TEST_UNSET_KEY");
+        assert!(matches!(provider.api_key(), Err(ApiConnectionError::MissingApiKey(name)) if name == "RECIPE_OPTIM_TEST_UNSET_KEY"));
+
+        let provider = provider.with_api_key("sk-secret".to_string());
+        assert_eq!(provider.api_key().unwrap(), "sk-secret");
+        assert_eq!(provider.api_key_source(), "a key passed directly");
+        // The key itself is neither logged nor saved.
+        assert!(!format!("{:?}", provider).contains("sk-secret"));
+        let json = serde_json::to_string(&provider).unwrap();
+        assert!(!json.contains("sk-secret"), "{}", json);
+        let reloaded: Provider = serde_json::from_str(&json).unwrap();
+        assert_eq!(reloaded.api_key_source(), "RECIPE_OPTIM_TEST_UNSET_KEY");
+    }
+
+    #[test]
+    fn test_provider_from_config() {
+        let config: ProviderConfig = serde_json::from_str(
+            r#"{ "provider": "openai", "api_key_env": "WORK_OPENAI_KEY", "base_url": "http://localhost:8080/v1/" }"#,
+        ).unwrap();
+        let provider = Provider::from_config(&config);
+        assert_eq!(provider.kind(), ProviderKind::OpenAi);
+        assert_eq!(provider.api_key_env_var(), "WORK_OPENAI_KEY");
+        assert_eq!(provider.chat_endpoint(), "http://localhost:8080/v1/chat/completions");
+
+        // An empty config is the default provider, as with no config at all.
+        let default = Provider::from_config(&serde_json::from_str("{}").unwrap());
+        assert_eq!(default.kind(), ProviderKind::OpenRouter);
+        assert_eq!(default.api_key_env_var(), "OPENROUTER_API_KEY");
+        assert_eq!(default.chat_endpoint(), "https://openrouter.ai/api/v1/chat/completions");
+        let claude = Provider::from_config(&serde_json::from_str(r#"{ "provider": "anthropic", "model": "claude-x" }"#).unwrap());
+        assert_eq!(claude.model_names(), vec!["claude-x"]);
+        assert!(serde_json::from_str::<ProviderConfig>(r#"{ "provider": "anthropic", "modle": "x" }"#).is_err());
+
+        // TOML files are read by their extension.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("provider.toml");
+        std::fs::write(&path, "provider = \"openrouter\"\nmodel = \"meta-llama/llama-3.3-70b-instruct\"\n").unwrap();
+        let openrouter = Provider::from_config(&ProviderConfig::load(&path).unwrap());
+        assert_eq!(openrouter.model_names(), vec!["meta-llama/llama-3.3-70b-instruct"]);
+
+        // Providers round-trip through serde.
+        let json = serde_json::to_string(&Provider::for_kind(ProviderKind::Anthropic)).unwrap();
+        let anthropic: Provider = serde_json::from_str(&json).unwrap();
+        assert_eq!(anthropic.chat_endpoint(), "https://api.anthropic.com/v1/messages");
     }
 }
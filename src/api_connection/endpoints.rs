@@ -1,24 +1,124 @@
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpenRouterAvailableModel {
-    pub model_name: &'static str,
-    pub model_source: &'static str,
+    pub model_name: Cow<'static, str>,
+    pub model_source: Cow<'static, str>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// An API key given as is rather than through an environment variable. It is never serialized
+/// and its `Debug` output is redacted, so it doesn't end up in logs or saved configs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKeyValue(pub String);
+
+impl std::fmt::Debug for ApiKeyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKeyValue(<redacted>)")
+    }
+}
+
+/// `api_key` is the name of the environment variable holding the key, not the key itself;
+/// `api_key_value`, when set with `Provider::with_api_key`, is used instead of it.
+/// `base_url` replaces the provider's API root (e.g. for a proxy) when set. An OpenRouter `model`,
+/// when set, replaces the model each request names.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Provider {
     OpenRouter {
         api_key: String,
+        #[serde(skip)]
+        api_key_value: Option<ApiKeyValue>,
         available_models: Vec<OpenRouterAvailableModel>,
+        #[serde(default)]
+        base_url: Option<String>,
+        #[serde(default)]
+        model: Option<String>,
+    },
+    OpenAi {
+        api_key: String,
+        #[serde(skip)]
+        api_key_value: Option<ApiKeyValue>,
+        model: String,
+        #[serde(default)]
+        base_url: Option<String>,
     },
+    Anthropic {
+        api_key: String,
+        #[serde(skip)]
+        api_key_value: Option<ApiKeyValue>,
+        model: String,
+        #[serde(default)]
+        base_url: Option<String>,
+    },
+}
+
+/// Provider settings as read from a `--provider-config` file, in TOML or JSON. Unset fields keep
+/// the provider's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    #[serde(default)]
+    pub provider: ProviderKind,
+    /// Environment variable holding the API key, instead of the provider's usual one.
+    pub api_key_env: Option<String>,
+    /// API root, e.g. "https://openrouter.ai/api/v1".
+    pub base_url: Option<String>,
+    /// Model answering every request, instead of the provider's default.
+    pub model: Option<String>,
 }
 
+impl ProviderConfig {
+    /// Reads a config file: TOML when its extension is `.toml`, JSON otherwise.
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read provider config {:?}", path))?;
+        let is_toml = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+        let config = if is_toml {
+            toml::from_str(&content).map_err(anyhow::Error::from)
+        } else {
+            serde_json::from_str(&content).map_err(anyhow::Error::from)
+        };
+        config.with_context(|| format!("Invalid provider config {:?}", path))
+    }
+}
+
+/// Which backend answers chat completions; selected with `--provider`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    #[value(name = "openrouter")]
+    OpenRouter,
+    #[value(name = "openai")]
+    OpenAi,
+    Anthropic,
+}
+
+impl ProviderKind {
+    /// Environment variable holding the API key for this provider.
+    pub fn api_key_env_var(self) -> &'static str {
+        match self {
+            ProviderKind::OpenRouter => "OPENROUTER_API_KEY",
+            ProviderKind::OpenAi => "OPENAI_API_KEY",
+            ProviderKind::Anthropic => "ANTHROPIC_API_KEY",
+        }
+    }
+}
+
+pub const OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1";
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+pub const OPENAI_DEFAULT_MODEL: &str = "gpt-4o-mini";
+pub const ANTHROPIC_DEFAULT_MODEL: &str = "claude-3-5-haiku-latest";
+
 pub const OPENROUTER_MODELS: &[OpenRouterAvailableModel] = &[
     OpenRouterAvailableModel {
-        model_name: "qwen/qwen3-32b",
-        model_source: "cerebras",
+        model_name: Cow::Borrowed("qwen/qwen3-32b"),
+        model_source: Cow::Borrowed("cerebras"),
     },
 ];
 
@@ -44,8 +144,9 @@ pub struct JsonSchemaProperty {
 pub struct JsonSchema {
     #[serde(rename = "type")]
     pub schema_type: String,
+    /// Kept sorted so identical schemas serialize to identical request bodies.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub properties: Option<HashMap<String, JsonSchemaProperty>>,
+    pub properties: Option<BTreeMap<String, JsonSchemaProperty>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub required: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -67,6 +168,44 @@ pub struct ResponseFormat {
     pub format_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub json_schema: Option<JsonSchemaDefinition>,
+    /// The schema of a `json_object` format: not sent, but kept for providers that describe the
+    /// expected answer in the prompt.
+    #[serde(skip)]
+    pub prompt_schema: Option<JsonSchemaDefinition>,
+}
+
+/// How requests expecting an answer of a given schema ask for it; selected with `--response-format`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResponseFormatMode {
+    /// `json_schema` with the schema, so the provider enforces it.
+    #[default]
+    JsonSchema,
+    /// Plain `json_object` without the schema, for models that reject strict schemas; the prompt
+    /// describes the expected fields.
+    JsonObject,
+}
+
+impl ResponseFormat {
+    /// The response format of a request whose answer must follow `schema`.
+    pub fn for_schema(schema: JsonSchemaDefinition, mode: ResponseFormatMode) -> Self {
+        match mode {
+            ResponseFormatMode::JsonSchema => ResponseFormat {
+                format_type: "json_schema".to_string(),
+                json_schema: Some(schema),
+                prompt_schema: None,
+            },
+            ResponseFormatMode::JsonObject => ResponseFormat {
+                format_type: "json_object".to_string(),
+                json_schema: None,
+                prompt_schema: Some(schema),
+            },
+        }
+    }
+
+    /// The schema the answer must follow, whether it is sent or only kept for the prompt.
+    pub fn schema(&self) -> Option<&JsonSchemaDefinition> {
+        self.json_schema.as_ref().or(self.prompt_schema.as_ref())
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -81,6 +220,49 @@ pub struct ChatCompletionRequest {
     pub max_tokens: Option<u32>,
 }
 
+/// Sampling settings of one pipeline stage's requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenParams {
+    pub temperature: f32,
+    pub max_tokens: u32,
+}
+
+impl GenParams {
+    pub const PARSER: GenParams = GenParams { temperature: 0.05, max_tokens: 2048 };
+    pub const CONVERSION: GenParams = GenParams { temperature: 0.0, max_tokens: 150 };
+    /// Per ingredient; batched requests get 10 more tokens for each ingredient in the batch.
+    pub const DISAMBIGUATION: GenParams = GenParams { temperature: 0.0, max_tokens: 50 };
+    pub const OPTIMIZER: GenParams = GenParams { temperature: 0.1, max_tokens: 1024 };
+
+    /// `self` with the given overrides applied.
+    pub fn with_overrides(self, temperature: Option<f32>, max_tokens: Option<u32>) -> Self {
+        GenParams {
+            temperature: temperature.unwrap_or(self.temperature),
+            max_tokens: max_tokens.unwrap_or(self.max_tokens),
+        }
+    }
+}
+
+/// `GenParams` of every LLM stage, defaulting to each stage's constant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StageGenParams {
+    pub parser: GenParams,
+    pub conversion: GenParams,
+    pub disambiguation: GenParams,
+    pub optimizer: GenParams,
+}
+
+impl Default for StageGenParams {
+    fn default() -> Self {
+        StageGenParams {
+            parser: GenParams::PARSER,
+            conversion: GenParams::CONVERSION,
+            disambiguation: GenParams::DISAMBIGUATION,
+            optimizer: GenParams::OPTIMIZER,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ChatCompletionResponseMessage {
     pub role: String,
@@ -95,13 +277,49 @@ pub struct ChatCompletionChoice {
     pub index: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl ChatCompletionChoice {
+    /// True when the answer stopped at the token limit, so its JSON is likely incomplete.
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+}
+
+/// Token counts of one response. Providers may leave out any count: missing ones read as 0
+/// (`None` for `completion_tokens`), and `total` falls back to the sum of the others.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
 pub struct ChatCompletionUsage {
+    #[serde(default)]
     pub prompt_tokens: u32,
+    #[serde(default)]
     pub completion_tokens: Option<u32>,
+    #[serde(default)]
     pub total_tokens: u32,
 }
 
+impl ChatCompletionUsage {
+    /// `total_tokens`, or prompt plus completion tokens when the provider didn't report it.
+    pub fn total(&self) -> u32 {
+        if self.total_tokens > 0 {
+            self.total_tokens
+        } else {
+            self.prompt_tokens + self.completion_tokens.unwrap_or(0)
+        }
+    }
+
+    /// The usage of two responses together, e.g. a truncated answer and its retry.
+    pub fn combined(self, other: ChatCompletionUsage) -> ChatCompletionUsage {
+        let completion_tokens = match (self.completion_tokens, other.completion_tokens) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        ChatCompletionUsage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens,
+            total_tokens: self.total() + other.total(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ChatCompletionResponse {
     pub id: String,
@@ -113,3 +331,55 @@ pub struct ChatCompletionResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<ChatCompletionUsage>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> JsonSchemaDefinition {
+        JsonSchemaDefinition {
+            name: "gram_conversion_schema".to_string(),
+            strict: Some(true),
+            schema: JsonSchema {
+                schema_type: "object".to_string(),
+                properties: None,
+                required: Some(vec!["grams".to_string()]),
+                additional_properties: Some(false),
+            },
+        }
+    }
+
+    #[test]
+    fn test_response_format_serialization() {
+        let enforced = serde_json::to_value(ResponseFormat::for_schema(schema(), ResponseFormatMode::JsonSchema)).unwrap();
+        assert_eq!(enforced, serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "gram_conversion_schema",
+                "strict": true,
+                "schema": {"type": "object", "required": ["grams"], "additionalProperties": false},
+            },
+        }));
+
+        let fallback = serde_json::to_value(ResponseFormat::for_schema(schema(), ResponseFormatMode::JsonObject)).unwrap();
+        assert_eq!(fallback, serde_json::json!({"type": "json_object"}));
+        let fallback = ResponseFormat::for_schema(schema(), ResponseFormatMode::JsonObject);
+        assert_eq!(fallback.schema().map(|schema| schema.name.as_str()), Some("gram_conversion_schema"));
+    }
+
+    #[test]
+    fn test_usage_tolerates_missing_counts() {
+        let response = |usage: &str| -> ChatCompletionResponse {
+            serde_json::from_str(&format!(r#"{{"id": "x", "created": 0, "model": "m", "choices": []{}}}"#, usage)).unwrap()
+        };
+        assert_eq!(response("").usage, None);
+        assert_eq!(response(r#", "usage": null"#).usage, None);
+        let partial = response(r#", "usage": {"prompt_tokens": 12}"#).usage.unwrap();
+        assert_eq!((partial.completion_tokens, partial.total()), (None, 12));
+        let full = response(r#", "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}"#).usage.unwrap();
+        assert_eq!(
+            partial.combined(full),
+            ChatCompletionUsage { prompt_tokens: 22, completion_tokens: Some(5), total_tokens: 27 }
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Extraction of the JSON payload from free-form model output.
+
+/// How much model output is cleaned up before it is parsed as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JsonMode {
+    /// Reasoning, prose and markdown fences around the object are dropped.
+    #[default]
+    Lenient,
+    /// The content must be the JSON itself; set with `--strict-json` to spot a model wrapping it.
+    Strict,
+}
+
+impl JsonMode {
+    /// The part of `content` to parse as JSON.
+    pub fn payload(self, content: &str) -> &str {
+        match self {
+            JsonMode::Lenient => extract_json_object(content).unwrap_or(content),
+            JsonMode::Strict => content,
+        }
+    }
+}
+
+/// Finds the first balanced `{...}` object in a model response.
+///
+/// Reasoning emitted in `<think>...</think>` blocks is skipped, as is any prose or markdown
+/// fencing around the object. Braces inside JSON strings are ignored when balancing.
+/// Returns `None` when the content holds no complete object.
+pub fn extract_json_object(content: &str) -> Option<&str> {
+    let after_reasoning = match content.rfind("</think>") {
+        Some(end) => &content[end + "</think>".len()..],
+        None => content,
+    };
+
+    let start = after_reasoning.find('{')?;
+    let candidate = &after_reasoning[start..];
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (offset, c) in candidate.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&candidate[..=offset]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_json_object_skips_prose_and_fences() {
+        let content = "Sure! Here is the result:\n```json\n{\"grams\": 120.0, \"notes\": \"ok\"}\n```\nHope this helps.";
+        assert_eq!(extract_json_object(content), Some("{\"grams\": 120.0, \"notes\": \"ok\"}"));
+    }
+
+    #[test]
+    fn test_extract_json_object_skips_think_blocks() {
+        let content = "<think>The user wants {grams}. Let me think...</think>\n{\"best_match_index\": 2}";
+        assert_eq!(extract_json_object(content), Some("{\"best_match_index\": 2}"));
+    }
+
+    #[test]
+    fn test_extract_json_object_handles_nested_braces_and_strings() {
+        let content = r#"{"modifications": [{"operation": "no_change", "reasoning": "keep } and { as is"}], "overall_reasoning": "a \"quoted\" }"} trailing"#;
+        assert_eq!(
+            extract_json_object(content),
+            Some(r#"{"modifications": [{"operation": "no_change", "reasoning": "keep } and { as is"}], "overall_reasoning": "a \"quoted\" }"}"#)
+        );
+    }
+
+    #[test]
+    fn test_extract_json_object_incomplete() {
+        assert_eq!(extract_json_object("no json here"), None);
+        assert_eq!(extract_json_object("{\"grams\": 12"), None);
+    }
+}
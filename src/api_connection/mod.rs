@@ -1,2 +1,7 @@
+pub mod anthropic;
+pub mod client;
 pub mod connection;
-pub mod endpoints;
\ No newline at end of file
+pub mod endpoints;
+pub mod json_extract;
+pub mod streaming;
+pub mod usage;
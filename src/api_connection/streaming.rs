@@ -0,0 +1,216 @@
+//! Server-sent event handling for streamed chat completions.
+use std::collections::VecDeque;
+
+use serde_json::Value;
+
+use super::connection::ApiConnectionError;
+use super::endpoints::ChatCompletionUsage;
+use super::usage::record_usage;
+
+const DATA_PREFIX: &str = "data:";
+const DONE_SENTINEL: &str = "[DONE]";
+
+/// What one `data:` line of a stream carried.
+#[derive(Debug, Default, PartialEq)]
+pub struct StreamChunk {
+    pub delta: Option<String>,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub done: bool,
+}
+
+/// Splits a byte stream into complete lines. Network chunks can end anywhere, including inside a JSON
+/// payload or a multi-byte character, so the unfinished tail is kept until the rest arrives.
+#[derive(Debug, Default)]
+pub struct SseLineBuffer {
+    pending: Vec<u8>,
+}
+
+impl SseLineBuffer {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(newline) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string());
+        }
+        lines
+    }
+
+    /// Returns whatever is left once the body has ended without a trailing newline.
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = String::from_utf8_lossy(&std::mem::take(&mut self.pending)).trim().to_string();
+        (!rest.is_empty()).then_some(rest)
+    }
+}
+
+/// Parses one SSE line. Returns `None` for lines without data (comments, `event:` names, keep-alives).
+/// Understands both the OpenAI/OpenRouter chunk shape and Anthropic's message events.
+pub fn parse_sse_line(line: &str) -> Result<Option<StreamChunk>, ApiConnectionError> {
+    let Some(data) = line.strip_prefix(DATA_PREFIX).map(str::trim) else {
+        return Ok(None);
+    };
+    if data.is_empty() {
+        return Ok(None);
+    }
+    if data == DONE_SENTINEL {
+        return Ok(Some(StreamChunk { done: true, ..StreamChunk::default() }));
+    }
+
+    let value: Value = serde_json::from_str(data)?;
+    if let Some(error) = value.get("error") {
+        return Err(ApiConnectionError::api_error(reqwest::StatusCode::OK, error.to_string()));
+    }
+
+    let as_u32 = |value: &Value| value.as_u64().map(|tokens| tokens as u32);
+    let delta = value.pointer("/choices/0/delta/content")
+        .or_else(|| value.pointer("/delta/text"))
+        .and_then(Value::as_str)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string);
+    let prompt_tokens = value.pointer("/usage/prompt_tokens")
+        .or_else(|| value.pointer("/message/usage/input_tokens"))
+        .and_then(as_u32);
+    let completion_tokens = value.pointer("/usage/completion_tokens")
+        .or_else(|| value.pointer("/usage/output_tokens"))
+        .and_then(as_u32);
+    let done = value.get("type").and_then(Value::as_str) == Some("message_stop");
+
+    Ok(Some(StreamChunk { delta, prompt_tokens, completion_tokens, done }))
+}
+
+/// Content deltas of a streamed chat completion, pulled with `next`.
+pub struct ChatCompletionStream {
+    response: reqwest::Response,
+    lines: SseLineBuffer,
+    deltas: VecDeque<String>,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    finished: bool,
+}
+
+impl ChatCompletionStream {
+    pub(crate) fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            lines: SseLineBuffer::default(),
+            deltas: VecDeque::new(),
+            prompt_tokens: None,
+            completion_tokens: None,
+            finished: false,
+        }
+    }
+
+    /// Next content delta, or `None` once the stream has ended.
+    pub async fn next(&mut self) -> Option<Result<String, ApiConnectionError>> {
+        loop {
+            if let Some(delta) = self.deltas.pop_front() {
+                return Some(Ok(delta));
+            }
+            if self.finished {
+                return None;
+            }
+
+            let lines = match self.response.chunk().await {
+                Ok(Some(bytes)) => self.lines.push(&bytes),
+                Ok(None) => {
+                    let rest = self.lines.finish();
+                    self.finish();
+                    rest.into_iter().collect()
+                }
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err.into()));
+                }
+            };
+            for line in lines {
+                match parse_sse_line(&line) {
+                    Ok(Some(chunk)) => self.apply(chunk),
+                    Ok(None) => {}
+                    Err(err) => {
+                        self.finished = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drains the stream and returns the concatenated content.
+    pub async fn collect_content(mut self) -> Result<String, ApiConnectionError> {
+        let mut content = String::new();
+        while let Some(delta) = self.next().await {
+            content.push_str(&delta?);
+        }
+        Ok(content)
+    }
+
+    fn apply(&mut self, chunk: StreamChunk) {
+        if self.finished {
+            return;
+        }
+        self.deltas.extend(chunk.delta);
+        self.prompt_tokens = chunk.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = chunk.completion_tokens.or(self.completion_tokens);
+        if chunk.done {
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if self.prompt_tokens.is_some() || self.completion_tokens.is_some() {
+            let prompt_tokens = self.prompt_tokens.unwrap_or(0);
+            record_usage(&ChatCompletionUsage {
+                prompt_tokens,
+                completion_tokens: self.completion_tokens,
+                total_tokens: prompt_tokens + self.completion_tokens.unwrap_or(0),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_buffer_joins_split_chunks() {
+        let mut buffer = SseLineBuffer::default();
+        assert!(buffer.push(b"data: {\"choices\":[{\"delta\":{\"con").is_empty());
+        let lines = buffer.push("tent\":\"cr\u{e8}me\"}}]}\r\n\ndata: [DO".as_bytes());
+        assert_eq!(lines, vec!["data: {\"choices\":[{\"delta\":{\"content\":\"cr\u{e8}me\"}}]}".to_string(), String::new()]);
+        assert_eq!(buffer.finish().as_deref(), Some("data: [DO"));
+
+        // A multi-byte character split across chunks survives.
+        let bytes = "data: \u{e9}\n".as_bytes();
+        assert!(buffer.push(&bytes[..7]).is_empty());
+        assert_eq!(buffer.push(&bytes[7..]), vec!["data: \u{e9}".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_sse_line() {
+        let chunk = parse_sse_line(r#"data: {"choices":[{"delta":{"content":"{\"grams\""}}]}"#).unwrap().unwrap();
+        assert_eq!(chunk.delta.as_deref(), Some("{\"grams\""));
+
+        let done = parse_sse_line("data: [DONE]").unwrap().unwrap();
+        assert!(done.done);
+        assert_eq!(parse_sse_line(": OPENROUTER PROCESSING").unwrap(), None);
+        assert_eq!(parse_sse_line("event: content_block_delta").unwrap(), None);
+
+        let usage = parse_sse_line(r#"data: {"choices":[{"delta":{"content":""}}],"usage":{"prompt_tokens":12,"completion_tokens":3,"total_tokens":15}}"#).unwrap().unwrap();
+        assert_eq!((usage.delta, usage.prompt_tokens, usage.completion_tokens), (None, Some(12), Some(3)));
+
+        let anthropic = parse_sse_line(r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"120"}}"#).unwrap().unwrap();
+        assert_eq!(anthropic.delta.as_deref(), Some("120"));
+        assert!(parse_sse_line(r#"data: {"type":"message_stop"}"#).unwrap().unwrap().done);
+
+        assert!(matches!(
+            parse_sse_line(r#"data: {"error":{"message":"rate limited"}}"#),
+            Err(ApiConnectionError::ApiError { .. })
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+//! Process-wide accounting of the tokens consumed by chat completion calls.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::endpoints::ChatCompletionUsage;
+use crate::log_verbose;
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static PROMPT_TOKENS: AtomicU64 = AtomicU64::new(0);
+static COMPLETION_TOKENS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_TOKENS: AtomicU64 = AtomicU64::new(0);
+
+/// Totals accumulated across all successful chat completion calls since the last reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Estimated cost given a flat price per 1000 tokens.
+    pub fn estimated_cost(&self, price_per_1k_tokens: f64) -> f64 {
+        self.total_tokens as f64 / 1000.0 * price_per_1k_tokens
+    }
+}
+
+/// Adds the usage reported by one response to the running totals.
+pub fn record_usage(usage: &ChatCompletionUsage) {
+    let completion_tokens = usage.completion_tokens.unwrap_or(0) as u64;
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    PROMPT_TOKENS.fetch_add(usage.prompt_tokens as u64, Ordering::Relaxed);
+    COMPLETION_TOKENS.fetch_add(completion_tokens, Ordering::Relaxed);
+    TOTAL_TOKENS.fetch_add(usage.total() as u64, Ordering::Relaxed);
+}
+
+/// One line describing the tokens a call spent, or that the provider didn't say.
+pub fn describe_usage(usage: Option<&ChatCompletionUsage>) -> String {
+    match usage {
+        Some(usage) => format!(
+            "{} prompt + {} completion = {} tokens",
+            usage.prompt_tokens,
+            usage.completion_tokens.map_or("?".to_string(), |tokens| tokens.to_string()),
+            usage.total()
+        ),
+        None => "usage not reported by the provider".to_string(),
+    }
+}
+
+/// Logs, in verbose mode, the tokens one call of `stage` spent, so spend can be told apart per stage.
+pub fn log_stage_usage(stage: &str, usage: Option<&ChatCompletionUsage>) {
+    log_verbose!("[USAGE] {}: {}", stage, describe_usage(usage));
+}
+
+pub fn total_usage() -> TokenUsage {
+    TokenUsage {
+        requests: REQUESTS.load(Ordering::Relaxed),
+        prompt_tokens: PROMPT_TOKENS.load(Ordering::Relaxed),
+        completion_tokens: COMPLETION_TOKENS.load(Ordering::Relaxed),
+        total_tokens: TOTAL_TOKENS.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset_usage() {
+    REQUESTS.store(0, Ordering::Relaxed);
+    PROMPT_TOKENS.store(0, Ordering::Relaxed);
+    COMPLETION_TOKENS.store(0, Ordering::Relaxed);
+    TOTAL_TOKENS.store(0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_usage_accumulates() {
+        reset_usage();
+        record_usage(&ChatCompletionUsage { prompt_tokens: 100, completion_tokens: Some(20), total_tokens: 120 });
+        record_usage(&ChatCompletionUsage { prompt_tokens: 50, completion_tokens: None, total_tokens: 50 });
+
+        let usage = total_usage();
+        assert_eq!(usage, TokenUsage { requests: 2, prompt_tokens: 150, completion_tokens: 20, total_tokens: 170 });
+        assert!((usage.estimated_cost(2.0) - 0.34).abs() < 1e-9);
+
+        let partial = ChatCompletionUsage { prompt_tokens: 40, completion_tokens: None, total_tokens: 0 };
+        assert_eq!(describe_usage(Some(&partial)), "40 prompt + ? completion = 40 tokens");
+        assert_eq!(describe_usage(None), "usage not reported by the provider");
+    }
+}
@@ -1,31 +1,70 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use std::str::FromStr;
 use std::collections::HashMap; // To store parsed optimization targets
+use std::path::{Path, PathBuf};
 
-// Define an enum for the nutrients we can target for percentage change
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum OptimizableNutrient {
-    Carb,
-    Fat,
-    Protein,
-    // Kcal is removed as a direct percentage target for --optimize.
-    // It will be an outcome of macronutrient changes.
-    // Add Sugars, Fiber etc. as needed in the future
-}
-
-impl FromStr for OptimizableNutrient {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "carb" | "carbohydrate" | "carbohydrates" => Ok(OptimizableNutrient::Carb),
-            "fat" | "fats" => Ok(OptimizableNutrient::Fat),
-            "protein" | "proteins" => Ok(OptimizableNutrient::Protein),
-            _ => Err(format!("Unknown nutrient for --optimize: '{}'. Supported: carb, fat, protein.", s)),
+use crate::api_connection::client::DEFAULT_MAX_CONCURRENT_REQUESTS;
+use crate::api_connection::endpoints::{GenParams, ProviderKind, ResponseFormatMode, StageGenParams};
+use crate::api_connection::json_extract::JsonMode;
+use crate::logging::Verbosity;
+use crate::nutritional_matcher::{AutoAccept, SubstitutionGoal};
+use crate::optim::nutri_eval::{MseMode, Tolerance, ToleranceBands};
+use crate::optim::optimizer::{Exploration, MassBand, DEFAULT_COOLING_RATE, DEFAULT_MASS_TOLERANCE_PCT};
+use crate::optim::targets::OptimizableNutrient;
+use crate::pipeline::{InputFormat, Scaling};
+use crate::recipe_aggregator::{IngredientOrder, DEFAULT_KCAL_TOLERANCE_PCT};
+use crate::recipe_converter::{ToTasteDefaults, DEFAULT_CONVERSION_PARSE_RETRIES};
+use crate::recipe_parser::{ParseLimits, DEFAULT_MAX_INGREDIENTS};
+use crate::search::ann_engine::DEFAULT_STORAGE_PATH;
+use crate::search::embedding_engine::DEFAULT_EMBEDDING_MODEL_ID;
+use crate::search::data_loader::{ColumnMapping, CIQUAL_COLUMNS, USDA_COLUMNS};
+
+/// How the resulting recipes are written, besides the JSON files kept as the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Only the enriched and optimized JSON files.
+    #[default]
+    Json,
+    /// Also the recipe as text to cook from, printed and saved next to each JSON file as `.txt`.
+    RecipeText,
+}
+
+/// Food composition database the nutritional index is built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DbFormat {
+    /// ANSES Ciqual table (`ciqual.csv`).
+    #[default]
+    Ciqual,
+    /// Flattened USDA FoodData Central export (`usda_fdc.csv`).
+    Usda,
+}
+
+impl DbFormat {
+    pub fn column_mapping(&self) -> &'static ColumnMapping {
+        match self {
+            DbFormat::Ciqual => &CIQUAL_COLUMNS,
+            DbFormat::Usda => &USDA_COLUMNS,
+        }
+    }
+
+    pub fn default_csv_path(&self) -> &'static str {
+        match self {
+            DbFormat::Ciqual => "ciqual.csv",
+            DbFormat::Usda => "usda_fdc.csv",
         }
     }
 }
 
+/// How progress events are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// Human-readable lines on stdout.
+    #[default]
+    Text,
+    /// One JSON object per event (NDJSON) on stderr.
+    Json,
+}
+
 // Custom parser for the <nutrient>:<percentage_change> format
 fn parse_optimization_target(s: &str) -> Result<(OptimizableNutrient, f32), String> {
     let parts: Vec<&str> = s.split(':').collect();
@@ -44,12 +83,158 @@ fn parse_optimization_target(s: &str) -> Result<(OptimizableNutrient, f32), Stri
     Ok((nutrient, percentage))
 }
 
+// Parser for the [<nutrient>:]<tolerance> format of --tolerance
+fn parse_tolerance(s: &str) -> Result<(Option<OptimizableNutrient>, Tolerance), String> {
+    match s.split_once(':') {
+        Some((nutrient, tolerance)) => Ok((Some(OptimizableNutrient::from_str(nutrient.trim())?), tolerance.parse()?)),
+        None => Ok((None, s.parse()?)),
+    }
+}
+
+/// A finite number above zero; `label` names the value in the errors.
+fn parse_positive_f32(s: &str, label: &str) -> Result<f32, String> {
+    let value = s.parse::<f32>().map_err(|e| format!("Invalid {} '{}': {}", label, s, e))?;
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("The {} must be positive, got {}", label, s))
+    }
+}
+
+/// A number from `min` to `max`, both included; `label` names the value in the errors.
+fn parse_f32_in_range(s: &str, label: &str, min: f32, max: f32) -> Result<f32, String> {
+    let value = s.parse::<f32>().map_err(|e| format!("Invalid {} '{}': {}", label, s, e))?;
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("The {} must be between {} and {}, got {}", label, min, max, s))
+    }
+}
+
+// Parser for the <keyword>=<grams> format of --to-taste-grams
+fn parse_to_taste_grams(s: &str) -> Result<(String, f32), String> {
+    let (keyword, grams) = s.split_once('=')
+        .ok_or_else(|| format!("Invalid format for to-taste default: '{}'. Expected <keyword>=<grams>", s))?;
+    if keyword.trim().is_empty() {
+        return Err(format!("Missing keyword in to-taste default '{}'", s));
+    }
+    let grams = grams.trim().parse::<f32>().map_err(|e| format!("Invalid grams value '{}': {}", grams, e))?;
+    if !grams.is_finite() {
+        return Err(format!("Grams must be a finite number, got {}", grams));
+    }
+    Ok((keyword.trim().to_string(), grams))
+}
+
+/// What to run instead of processing a recipe.
+#[cfg(feature = "server")]
+#[derive(clap::Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Serve the pipeline over HTTP for a web frontend: `POST /analyze` takes
+    /// `{"recipe": "...", "servings": 4, "optimize": {"protein": 20}}` and answers with the
+    /// enriched recipe JSON. The other options set the defaults of every request.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        addr: std::net::SocketAddr,
+    },
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// Path to the recipe text file
-    #[arg(short, long)]
-    pub recipe_file: String,
+    #[cfg(feature = "server")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the recipe text file. Use `-` to read the recipe from standard input.
+    /// Can be repeated with `--combine` to analyze several recipes as one meal.
+    #[arg(short, long, action = clap::ArgAction::Append,
+        required_unless_present_any = ["batch", "check", "url", "list_nutrients", "explain_match", "compare", "substitute"])]
+    pub recipe_file: Vec<String>,
+
+    /// Merge the recipes of every `--recipe-file` into one, each recipe's ingredients forming a
+    /// section named after it, and analyze and optimize the whole meal. Outputs are named after
+    /// all the files (`a+b_enriched.json`) unless `--output-name` is given.
+    #[arg(long)]
+    pub combine: bool,
+
+    /// Fetch the recipe from a web page instead of a file. Structured schema.org recipe data is used
+    /// directly when the page has it; otherwise the page text goes through the LLM parser.
+    #[arg(long, conflicts_with_all = ["recipe_file", "batch"])]
+    pub url: Option<String>,
+
+    /// Process every `.txt` recipe in this directory, reusing one nutritional index.
+    /// Outputs are written next to each recipe file.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["recipe_file", "output_name"])]
+    pub batch: Option<PathBuf>,
+
+    /// Check that the provider's API key is set and accepted, list the models it serves, and exit.
+    #[arg(long, conflicts_with_all = ["recipe_file", "batch", "url"])]
+    pub check: bool,
+
+    /// Print the nutrients `--optimize` accepts, with their aliases, and exit.
+    #[arg(long, conflicts_with_all = ["recipe_file", "batch", "url", "check"])]
+    pub list_nutrients: bool,
+
+    /// Match this one ingredient against the nutrition database and print every step: normalized
+    /// query, ANN candidates with scores, disambiguation prompt and raw LLM answer. Then exit.
+    #[arg(long, value_name = "INGREDIENT", conflicts_with_all = ["recipe_file", "batch", "url", "check", "list_nutrients"])]
+    pub explain_match: Option<String>,
+
+    /// Compare two enriched or optimized JSON files: per-nutrient changes of the aggregated and
+    /// per-100g profiles, and the ingredients added, removed or re-weighed. No LLM is needed.
+    #[arg(long, num_args = 2, value_names = ["BEFORE", "AFTER"],
+        conflicts_with_all = ["recipe_file", "batch", "url", "check", "list_nutrients", "explain_match"])]
+    pub compare: Option<Vec<PathBuf>>,
+
+    /// Suggest database items similar to this ingredient that serve the `--for` goal better,
+    /// ranked by similarity and improvement together. No LLM is needed. Then exit.
+    #[arg(long, value_name = "INGREDIENT", requires = "substitution_goal",
+        conflicts_with_all = ["recipe_file", "batch", "url", "check", "list_nutrients", "explain_match", "compare"])]
+    pub substitute: Option<String>,
+
+    /// What the substitutes of `--substitute` should improve.
+    #[arg(long = "for", value_name = "GOAL", value_enum, requires = "substitute",
+        conflicts_with_all = ["recipe_file", "batch", "url", "check", "list_nutrients", "explain_match", "compare"])]
+    pub substitution_goal: Option<SubstitutionGoal>,
+
+    /// Base name for the output files (`<name>_enriched.json`, `<name>_optimized.json`).
+    /// Defaults to the recipe file stem, or `recipe` when reading from standard input.
+    #[arg(long)]
+    pub output_name: Option<String>,
+
+    /// Also write the nutritional profile (total, per 100g, per serving) and the ingredient gram
+    /// breakdown as CSV to this file. Holds the optimized recipe when an optimization succeeded.
+    #[arg(long, value_name = "FILE", conflicts_with = "batch")]
+    pub csv_out: Option<PathBuf>,
+
+    /// `recipe-text` also renders each saved recipe as a plain recipe: title, ingredient lines with
+    /// their grams, then numbered instructions, printed and saved as `<name>_optimized.txt` (or
+    /// `_enriched.txt`, `_scaled.txt`).
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    pub output_format: OutputFormat,
+
+    /// Print a table of every ingredient's matched food item, similarity and how it was chosen
+    /// (override, auto-accepted, LLM, interactive, online fallback or unmatched), and save it as
+    /// `<name>_matches.json`, to check the matching of a whole recipe at once.
+    #[arg(long)]
+    pub match_report: bool,
+
+    /// Multiply every ingredient's quantity by FACTOR (2 doubles the recipe, 0.5 halves it) and
+    /// save the result as `<name>_scaled.json`. Per-100g nutrition is unchanged; the totals and,
+    /// unless the servings scale to a whole number, the per-serving values follow the quantities.
+    #[arg(long, value_name = "FACTOR", value_parser = |s: &str| parse_positive_f32(s, "scale factor"), conflicts_with_all = ["optimization_targets", "dry_run"])]
+    pub scale: Option<f32>,
+
+    /// Like `--scale`, with the factor that makes the recipe yield N servings. Needs the recipe's
+    /// servings, parsed or given with `--servings`.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+          conflicts_with_all = ["scale", "optimization_targets", "dry_run"])]
+    pub scale_to_servings: Option<u32>,
+
+    /// Format of the recipe input. Defaults to `json` for `.json` files and `text` otherwise.
+    #[arg(long, value_enum)]
+    pub input_format: Option<InputFormat>,
 
     /// Optimization targets for macronutrients (carb, fat, protein), can be specified multiple times.
     /// Format: <nutrient>:<percentage_change>
@@ -63,9 +248,461 @@ pub struct Cli {
     /// Maximum number of optimization iterations
     #[arg(long, default_value_t = 10)]
     pub max_iterations: u32,
+
+    /// How close an optimized nutrient must get to its target to count as met, as
+    /// <nutrient>:<tolerance> or, for every nutrient without its own, just <tolerance>. A tolerance
+    /// is a percentage of the target ("5%", the default) or an amount per 100 g ("2g").
+    /// Example: --tolerance protein:1.5g --tolerance 10%
+    #[arg(long, value_name = "[NUTRIENT:]TOLERANCE", value_parser = parse_tolerance, action = clap::ArgAction::Append)]
+    pub tolerance: Vec<(Option<OptimizableNutrient>, Tolerance)>,
+
+    /// Let the optimization accept worse candidates now and then, simulated-annealing style, to get
+    /// out of plateaus the greedy search stops on. TEMPERATURE is how much worsening is tolerated
+    /// at first: 0.1 accepts a candidate 10% worse than the current recipe with probability 1/e.
+    /// It cools down every iteration, and the best recipe seen is kept.
+    #[arg(long, value_name = "TEMPERATURE", value_parser = |s: &str| parse_positive_f32(s, "temperature"))]
+    pub explore: Option<f32>,
+
+    /// Seed of `--explore`'s random acceptances, to repeat a run. Random by default.
+    #[arg(long, value_name = "SEED", requires = "explore")]
+    pub explore_seed: Option<u64>,
+
+    /// End the optimization as soon as every targeted nutrient is within its `--tolerance`,
+    /// instead of running all iterations to lower the MSE further.
+    #[arg(long)]
+    pub stop_within_tolerance: bool,
+
+    /// Largest change an optimization step may make to an ingredient's quantity, in percent of
+    /// its grams in the original recipe. Larger adjustments are clamped.
+    #[arg(long, value_name = "PCT", value_parser = |s: &str| parse_positive_f32(s, "percentage"))]
+    pub max_quantity_change_pct: Option<f32>,
+
+    /// Ingredient the optimizer may change, by its name in the recipe; can be specified multiple
+    /// times. When given, suggestions touching any other ingredient are rejected. All by default.
+    /// Example: --optimizable "olive oil" --optimizable butter
+    #[arg(long, value_name = "NAME", action = clap::ArgAction::Append, requires = "optimization_targets")]
+    pub optimizable: Vec<String>,
+
+    /// Total recipe mass, in grams, that optimization must preserve: candidates whose mass leaves
+    /// the `--mass-tolerance-pct` range around it are rejected. For fixed-pan or fixed-portion recipes.
+    #[arg(long, value_name = "GRAMS", value_parser = |s: &str| parse_positive_f32(s, "grams value"))]
+    pub target_mass: Option<f32>,
+
+    /// How far, in percent of `--target-mass`, the optimized recipe's mass may drift.
+    #[arg(long, value_name = "PCT", default_value_t = DEFAULT_MASS_TOLERANCE_PCT, value_parser = |s: &str| parse_positive_f32(s, "percentage"), requires = "target_mass")]
+    pub mass_tolerance_pct: f32,
+
+    /// Warn when the recipe's kcal from the nutrition database and the Atwater estimate from its
+    /// macronutrients (4 kcal/g protein and carbohydrate, 9 kcal/g fat) differ by more than this percentage.
+    #[arg(long, value_name = "PCT", default_value_t = DEFAULT_KCAL_TOLERANCE_PCT, value_parser = |s: &str| parse_positive_f32(s, "percentage"))]
+    pub kcal_tolerance: f32,
+
+    /// When the kcal check fails, replace the database kcal with the Atwater estimate.
+    #[arg(long)]
+    pub reconcile_kcal: bool,
+
+    /// Only print the optimizer's suggested modifications, with their reasoning, without applying
+    /// or evaluating them. No optimized recipe is written.
+    #[arg(long, requires = "optimization_targets")]
+    pub suggest_only: bool,
+
+    /// Print the initial profile, the targets and the initial MSE, then stop before the optimization loop.
+    /// The recipe is still parsed and enriched (or loaded from cache) and the enriched file is written.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Food composition database to match ingredients against.
+    #[arg(long, value_enum, default_value_t = DbFormat::Ciqual)]
+    pub db_format: DbFormat,
+
+    /// Nutrition table to load, in the `--db-format` layout. Defaults to `ciqual.csv`
+    /// (`usda_fdc.csv` for `--db-format usda`) in the current directory.
+    #[arg(long, value_name = "FILE")]
+    pub ciqual_csv: Option<PathBuf>,
+
+    /// LLM provider. Reads its API key from `OPENROUTER_API_KEY`, `OPENAI_API_KEY` or `ANTHROPIC_API_KEY`.
+    #[arg(long, value_enum, default_value_t = ProviderKind::OpenRouter)]
+    pub provider: ProviderKind,
+
+    /// Most LLM requests in flight at once, whatever runs in parallel. Lower it if the
+    /// provider answers with HTTP 429 (rate limited).
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_CONCURRENT_REQUESTS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_requests: u32,
+
+    /// TOML (`.toml`) or JSON file with the provider settings, e.g.
+    /// `provider = "openai"`, `api_key_env = "MY_KEY"`, `base_url = "http://localhost:8080/v1"`
+    /// and `model = "gpt-4o"`. Cannot be combined with `--provider`.
+    #[arg(long, value_name = "FILE")]
+    pub provider_config: Option<PathBuf>,
+
+    /// Environment variable holding the API key, instead of the provider's usual one
+    /// (`OPENROUTER_API_KEY`, `OPENAI_API_KEY` or `ANTHROPIC_API_KEY`) or the config file's.
+    #[arg(long, value_name = "VAR")]
+    pub api_key_env: Option<String>,
+
+    /// The API key itself, e.g. fetched from a secrets manager. Discouraged: command lines end up
+    /// in the process list and shell history, so prefer `--api-key-env` where you can.
+    #[arg(long, value_name = "KEY", conflicts_with = "api_key_env")]
+    pub api_key: Option<String>,
+
+    /// model2vec embedding model (Hugging Face repo or local path) used to match ingredient names.
+    /// Set `HF_TOKEN` for gated models. Models of another dimension need their own `--index-path`.
+    #[arg(long, value_name = "MODEL", default_value = DEFAULT_EMBEDDING_MODEL_ID)]
+    pub embedding_model: String,
+
+    /// File backing the ingredient-name vector index. Point it at a cache directory to share it
+    /// between runs, or give concurrent runs separate files.
+    #[arg(long, value_name = "FILE", default_value = DEFAULT_STORAGE_PATH)]
+    pub index_path: PathBuf,
+
+    /// Delete and rebuild the index file when it was built for another embedding dimension
+    /// (e.g. after switching `--embedding-model`), instead of failing.
+    #[arg(long)]
+    pub rebuild_index: bool,
+
+    /// JSON file mapping ingredient names to exact food item names of the database.
+    /// Overridden ingredients skip embedding search and LLM disambiguation.
+    #[arg(long, value_name = "FILE")]
+    pub overrides: Option<PathBuf>,
+
+    /// Pick ambiguous ingredient matches yourself from the top candidates instead of letting the LLM decide.
+    #[arg(long)]
+    pub interactive: bool,
+
+    /// Don't keep ingredient-name embeddings in memory between lookups (saves memory, costs re-encoding).
+    #[arg(long)]
+    pub no_embedding_cache: bool,
+
+    /// Order of the ingredients in the optimized and scaled files, the CSV export and the recipe
+    /// text. Parsing, optimization and the enriched file, which later runs reuse, keep the recipe's order.
+    #[arg(long, value_enum, default_value_t = IngredientOrder::Original)]
+    pub sort_ingredients: IngredientOrder,
+
+    /// Accept the closest database item without asking the LLM when its similarity to the
+    /// ingredient is at least this (0-1) and it leads the runner-up by `--auto-accept-margin`.
+    #[arg(long, value_name = "SIMILARITY", value_parser = |s: &str| parse_f32_in_range(s, "similarity", 0.0, 1.0))]
+    pub auto_accept_similarity: Option<f32>,
+
+    /// Lead in similarity over the second-closest item needed to auto-accept a match [default: 0.05].
+    #[arg(long, value_name = "SIMILARITY", requires = "auto_accept_similarity",
+          value_parser = |s: &str| parse_f32_in_range(s, "margin", 0.0, 1.0))]
+    pub auto_accept_margin: Option<f32>,
+
+    /// Look ingredients without a database match up on Open Food Facts (needs network access).
+    /// Their nutrition is recorded with a source name like "OFF: <product>".
+    #[arg(long)]
+    pub online_fallback: bool,
+
+    /// JSON list of allergen rules (`{"allergen", "keywords", "exclusions"}`) extending the built-in ones;
+    /// a rule for an existing allergen replaces it.
+    #[arg(long, value_name = "FILE")]
+    pub allergens_config: Option<PathBuf>,
+
+    /// JSON list of container sizes (`{"container": "can", "ingredient": "tomato", "grams": 400}`)
+    /// extending the built-in table of cans, packages, sticks and eggs. An entry for an existing
+    /// container and ingredient replaces it, and 0 grams removes it; `ingredient` may be left out.
+    #[arg(long, value_name = "FILE")]
+    pub container_sizes: Option<PathBuf>,
+
+    /// CSV price table (`ingredient,price_per_kg`) used to estimate the recipe cost.
+    #[arg(long, value_name = "FILE")]
+    pub price_table: Option<PathBuf>,
+
+    /// Keep total recipe mass stable during optimization: a replacement without a sensible quantity
+    /// inherits the grams of the ingredient it replaces.
+    #[arg(long)]
+    pub preserve_mass: bool,
+
+    /// Penalize targeted nutrients the candidate profile has no value for, instead of ignoring them in the MSE,
+    /// so the optimizer can't improve its score by losing nutrition data.
+    #[arg(long)]
+    pub strict_mse: bool,
+
+    /// How nutrient errors are scaled in the MSE: `relative` to each target, or `absolute` grams
+    /// with kcal divided by 100 (the behavior of earlier versions).
+    #[arg(long, value_enum, default_value_t = MseMode::Relative)]
+    pub mse_mode: MseMode,
+
+    /// How many times to reprompt the model when a gram conversion response isn't valid JSON.
+    #[arg(long, default_value_t = DEFAULT_CONVERSION_PARSE_RETRIES)]
+    pub conversion_retries: u32,
+
+    /// Parse at most N ingredient lines per LLM call, for ingredient lists too long for one answer.
+    /// Needs an "Ingredients" heading in the recipe to find the list; the first call also reads the
+    /// title, instructions and metadata.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub parse_chunk_size: Option<u32>,
+
+    /// Fail when the recipe has more ingredients than this, instead of processing a recipe that is
+    /// likely a mis-scraped page.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_INGREDIENTS as u32)]
+    pub max_ingredients: u32,
+
+    /// Grams assumed for a seasoning measured "to taste", by a word of its name; can be specified
+    /// multiple times. Defaults: salt=1, pepper=0.5 and 0.5 for common spices. Use 0 to disable one.
+    /// Example: --to-taste-grams salt=2 --to-taste-grams paprika=0
+    #[arg(long, value_name = "KEYWORD=GRAMS", value_parser = parse_to_taste_grams, action = clap::ArgAction::Append)]
+    pub to_taste_grams: Vec<(String, f32)>,
+
+    /// Sampling temperature of the recipe parser, 0-2 (default 0.05).
+    #[arg(long, value_name = "TEMPERATURE", value_parser = |s: &str| parse_f32_in_range(s, "temperature", 0.0, 2.0))]
+    pub parser_temperature: Option<f32>,
+
+    /// Token limit of the recipe parser's answer (default 2048). Raise it if long recipes get truncated.
+    #[arg(long, value_name = "TOKENS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub parser_max_tokens: Option<u32>,
+
+    /// Sampling temperature of the gram conversions, 0-2 (default 0.0).
+    #[arg(long, value_name = "TEMPERATURE", value_parser = |s: &str| parse_f32_in_range(s, "temperature", 0.0, 2.0))]
+    pub conversion_temperature: Option<f32>,
+
+    /// Token limit of each gram conversion answer (default 150).
+    #[arg(long, value_name = "TOKENS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub conversion_max_tokens: Option<u32>,
+
+    /// Sampling temperature of the nutrition match disambiguation, 0-2 (default 0.0).
+    #[arg(long, value_name = "TEMPERATURE", value_parser = |s: &str| parse_f32_in_range(s, "temperature", 0.0, 2.0))]
+    pub disambiguation_temperature: Option<f32>,
+
+    /// Token limit of a single-ingredient disambiguation answer (default 50); batched requests
+    /// get 10 more per ingredient.
+    #[arg(long, value_name = "TOKENS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub disambiguation_max_tokens: Option<u32>,
+
+    /// Sampling temperature of the optimizer's modification suggestions, 0-2 (default 0.1).
+    #[arg(long, value_name = "TEMPERATURE", value_parser = |s: &str| parse_f32_in_range(s, "temperature", 0.0, 2.0))]
+    pub optimizer_temperature: Option<f32>,
+
+    /// Token limit of the optimizer's modification suggestions (default 1024).
+    #[arg(long, value_name = "TOKENS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub optimizer_max_tokens: Option<u32>,
+
+    /// Folder of prompt templates replacing the built-in prompts: parser_system.txt,
+    /// converter_system.txt, converter_user.txt, matcher_guidelines.txt, matcher_user.txt,
+    /// matcher_batch_user.txt, optimizer_system.txt and optimizer_user.txt. Missing files keep
+    /// the built-in prompt.
+    /// Templates use named placeholders such as {ingredient_name}, filled in by each stage.
+    #[arg(long, value_name = "DIR")]
+    pub prompt_dir: Option<PathBuf>,
+
+    /// Number of servings the recipe yields. Adds a per-serving nutrition view,
+    /// recomputed from the aggregated values (no reprocessing needed for cached recipes).
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub servings: Option<u32>,
+
+    /// Parse the recipe parser's and gram converter's answers as they are, without stripping
+    /// markdown fences or prose around the JSON, and report the raw answer when it isn't valid.
+    /// For debugging prompts and models.
+    #[arg(long)]
+    pub strict_json: bool,
+
+    /// How answers of a known shape are requested: `json-schema` has the provider enforce the
+    /// schema; `json-object` only asks for JSON, for models that reject strict schemas.
+    #[arg(long, value_enum, default_value_t = ResponseFormatMode::JsonSchema)]
+    pub response_format: ResponseFormatMode,
+
+    /// Ignore any cached `*_enriched.json` file and always reprocess the recipe from the raw text.
+    #[arg(long, visible_alias = "no-cache")]
+    pub force: bool,
+
+    /// Only print final results and errors.
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print everything, including LLM prompts, raw responses and index diagnostics; the
+    /// diagnostics go to stderr.
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Format of progress reporting. `json` streams NDJSON events to stderr for GUI wrappers.
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Text)]
+    pub progress_format: ProgressFormat,
+
+    /// Print text progress line by line instead of drawing a progress bar on the terminal.
+    #[arg(long)]
+    pub no_progress_bar: bool,
+
+    /// Price per 1000 tokens, used to print an estimated cost alongside the token usage summary.
+    #[arg(long = "price-per-1k")]
+    pub price_per_1k_tokens: Option<f64>,
 }
 
+/// File-name-safe last path segment of a URL, without query, fragment or extension.
+fn url_stem(url: &str) -> Option<String> {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme.split(['?', '#']).next()?;
+    let (_, path) = path.split_once('/')?;
+    let segment = path.split('/').rev().find(|segment| !segment.is_empty())?;
+    let stem = Path::new(segment).file_stem()?.to_string_lossy();
+    let stem: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    (!stem.is_empty()).then_some(stem)
+}
+
+/// Value of `--recipe-file` that selects standard input.
+pub const STDIN_RECIPE_FILE: &str = "-";
+const DEFAULT_STDIN_OUTPUT_NAME: &str = "recipe";
+
 impl Cli {
+    /// Per-stage sampling settings: the defaults with the `--<stage>-temperature` and
+    /// `--<stage>-max-tokens` overrides applied.
+    pub fn gen_params(&self) -> StageGenParams {
+        StageGenParams {
+            parser: GenParams::PARSER.with_overrides(self.parser_temperature, self.parser_max_tokens),
+            conversion: GenParams::CONVERSION.with_overrides(self.conversion_temperature, self.conversion_max_tokens),
+            disambiguation: GenParams::DISAMBIGUATION
+                .with_overrides(self.disambiguation_temperature, self.disambiguation_max_tokens),
+            optimizer: GenParams::OPTIMIZER.with_overrides(self.optimizer_temperature, self.optimizer_max_tokens),
+        }
+    }
+
+    /// The built-in to-taste defaults with the `--to-taste-grams` overrides applied.
+    pub fn to_taste_defaults(&self) -> ToTasteDefaults {
+        self.to_taste_grams.iter()
+            .fold(ToTasteDefaults::default(), |defaults, (keyword, grams)| defaults.with_override(keyword, *grams))
+    }
+
+    /// The `--tolerance` bands, later ones overriding earlier ones.
+    pub fn tolerance_bands(&self) -> ToleranceBands {
+        self.tolerance.iter().fold(ToleranceBands::default(), |bands, (nutrient, tolerance)| match nutrient {
+            Some(nutrient) => bands.with_tolerance(*nutrient, *tolerance),
+            None => ToleranceBands { default: *tolerance, ..bands },
+        })
+    }
+
+    pub fn scaling(&self) -> Option<Scaling> {
+        self.scale.map(Scaling::Factor)
+            .or(self.scale_to_servings.map(Scaling::Servings))
+    }
+
+    pub fn parse_limits(&self) -> ParseLimits {
+        ParseLimits {
+            chunk_size: self.parse_chunk_size.map(|size| size as usize),
+            max_ingredients: self.max_ingredients as usize,
+        }
+    }
+
+    pub fn json_mode(&self) -> JsonMode {
+        if self.strict_json { JsonMode::Strict } else { JsonMode::Lenient }
+    }
+
+    /// Auto-accept thresholds, when `--auto-accept-similarity` is given.
+    pub fn auto_accept(&self) -> Option<AutoAccept> {
+        self.auto_accept_similarity.map(|min_similarity| AutoAccept {
+            min_similarity,
+            min_margin: self.auto_accept_margin.unwrap_or(AutoAccept::DEFAULT_MARGIN),
+        })
+    }
+
+    /// Annealing settings for the optimization, when `--explore` is given.
+    pub fn exploration(&self) -> Option<Exploration> {
+        let initial_temperature = self.explore?;
+        let seed = self.explore_seed.unwrap_or_else(|| {
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+        Some(Exploration { initial_temperature, cooling_rate: DEFAULT_COOLING_RATE, seed })
+    }
+
+    /// Mass range for the optimization, when `--target-mass` is given.
+    pub fn mass_band(&self) -> Option<MassBand> {
+        self.target_mass.map(|target_g| MassBand { target_g, tolerance_pct: self.mass_tolerance_pct })
+    }
+
+    /// Nutrition table path: `--ciqual-csv`, or the database format's default file.
+    pub fn nutrition_csv_path(&self) -> PathBuf {
+        self.ciqual_csv.clone()
+            .unwrap_or_else(|| PathBuf::from(self.db_format.default_csv_path()))
+    }
+
+    /// The first `--recipe-file`, the only one unless combining.
+    pub fn recipe_file(&self) -> Option<&str> {
+        self.recipe_file.first().map(String::as_str)
+    }
+
+    /// True when the recipe text should be read from standard input.
+    pub fn reads_from_stdin(&self) -> bool {
+        self.recipe_file() == Some(STDIN_RECIPE_FILE)
+    }
+
+    /// Checks the `--recipe-file` combinations clap can't express: several files need
+    /// `--combine`, which needs recipe files and can't read from standard input.
+    pub fn validate_recipe_files(&self) -> Result<(), String> {
+        if self.combine && self.recipe_file.is_empty() {
+            return Err("--combine needs the recipes to combine, given with --recipe-file".to_string());
+        }
+        if self.recipe_file.len() > 1 && !self.combine {
+            return Err("--recipe-file can only be repeated with --combine".to_string());
+        }
+        if self.combine && self.recipe_file.iter().any(|file| file == STDIN_RECIPE_FILE) {
+            return Err("--combine reads recipe files and cannot read from stdin".to_string());
+        }
+        Ok(())
+    }
+
+    /// Output verbosity selected by `--quiet` / `--verbose`.
+    pub fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    /// True when text progress may be drawn as a progress bar: not disabled, and neither quiet
+    /// (nothing to show) nor verbose (diagnostics would break the bar). The terminal check is the caller's.
+    pub fn wants_progress_bar(&self) -> bool {
+        self.progress_format == ProgressFormat::Text && !self.no_progress_bar && self.verbosity() == Verbosity::Normal
+    }
+
+    /// Input format of `--recipe-file`, either given explicitly or inferred from the file extension.
+    pub fn resolved_input_format(&self) -> InputFormat {
+        self.input_format_for(Path::new(self.recipe_file().unwrap_or_default()))
+    }
+
+    /// Input format of the given recipe file, either given explicitly or inferred from its extension.
+    pub fn input_format_for(&self, recipe_path: &Path) -> InputFormat {
+        if let Some(format) = self.input_format {
+            return format;
+        }
+        let is_json_file = recipe_path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json_file { InputFormat::Json } else { InputFormat::Text }
+    }
+
+    /// Base name used to derive the output file names for `--recipe-file` or `--url`.
+    /// A URL gives the last segment of its path, e.g. `banana-bread` for `.../recipes/banana-bread/`.
+    pub fn output_stem(&self) -> String {
+        if self.output_name.is_none() {
+            if let Some(url) = &self.url {
+                return url_stem(url).unwrap_or_else(|| DEFAULT_STDIN_OUTPUT_NAME.to_string());
+            }
+        }
+        if self.reads_from_stdin() && self.output_name.is_none() {
+            return DEFAULT_STDIN_OUTPUT_NAME.to_string();
+        }
+        if self.combine && self.output_name.is_none() {
+            let stems: Vec<String> = self.recipe_file.iter().map(|file| self.output_stem_for(Path::new(file))).collect();
+            return stems.join("+");
+        }
+        self.output_stem_for(Path::new(self.recipe_file().unwrap_or_default()))
+    }
+
+    /// Base name used to derive the output file names for the given recipe file.
+    pub fn output_stem_for(&self, recipe_path: &Path) -> String {
+        if let Some(name) = &self.output_name {
+            return name.clone();
+        }
+        recipe_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+
     /// Helper to get optimization targets as a HashMap for easier lookup
     pub fn get_optimization_targets_map(&self) -> HashMap<OptimizableNutrient, f32> {
         self.optimization_targets.iter().cloned().collect()
@@ -73,5 +710,301 @@ impl Cli {
 }
 
 pub fn parse_args() -> Cli {
-    Cli::parse()
+    try_parse_args_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+/// `Cli::try_parse_from` plus the checks clap can't express: `--provider` has a default, so a
+/// `conflicts_with` on it would never trigger, and only an explicit one conflicts with
+/// `--provider-config`.
+pub fn try_parse_args_from<I, T>(args: I) -> Result<Cli, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let mut command = Cli::command();
+    let matches = command.try_get_matches_from_mut(args)?;
+    if matches.contains_id("provider_config")
+        && matches.value_source("provider") == Some(clap::parser::ValueSource::CommandLine)
+    {
+        return Err(command.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "the argument '--provider-config <FILE>' cannot be used with '--provider <PROVIDER>'",
+        ));
+    }
+    Cli::from_arg_matches(&matches).map_err(|e| e.format(&mut command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_stem_defaults() {
+        let from_file = Cli::try_parse_from(["recipe_optim", "--recipe-file", "dir/pancakes.txt"]).unwrap();
+        assert!(!from_file.reads_from_stdin());
+        assert_eq!(from_file.output_stem(), "pancakes");
+
+        let from_stdin = Cli::try_parse_from(["recipe_optim", "--recipe-file", "-"]).unwrap();
+        assert!(from_stdin.reads_from_stdin());
+        assert_eq!(from_stdin.output_stem(), "recipe");
+
+        let named = Cli::try_parse_from(["recipe_optim", "-r", "-", "--output-name", "soup"]).unwrap();
+        assert_eq!(named.output_stem(), "soup");
+    }
+
+    #[test]
+    fn test_verbosity_flags() {
+        let default = Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap();
+        assert_eq!(default.verbosity(), Verbosity::Normal);
+        let quiet = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--quiet"]).unwrap();
+        assert_eq!(quiet.verbosity(), Verbosity::Quiet);
+        let verbose = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "-v"]).unwrap();
+        assert_eq!(verbose.verbosity(), Verbosity::Verbose);
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn test_resolved_input_format() {
+        let text = Cli::try_parse_from(["recipe_optim", "-r", "pancakes.txt"]).unwrap();
+        assert_eq!(text.resolved_input_format(), InputFormat::Text);
+
+        let json = Cli::try_parse_from(["recipe_optim", "-r", "pancakes.JSON"]).unwrap();
+        assert_eq!(json.resolved_input_format(), InputFormat::Json);
+
+        let forced = Cli::try_parse_from(["recipe_optim", "-r", "-", "--input-format", "json"]).unwrap();
+        assert_eq!(forced.resolved_input_format(), InputFormat::Json);
+    }
+
+    #[test]
+    fn test_batch_flag() {
+        let batch = Cli::try_parse_from(["recipe_optim", "--batch", "cookbook"]).unwrap();
+        assert_eq!(batch.batch.as_deref(), Some(Path::new("cookbook")));
+        assert!(batch.recipe_file.is_empty());
+        assert_eq!(batch.output_stem_for(Path::new("cookbook/soup.txt")), "soup");
+
+        assert!(Cli::try_parse_from(["recipe_optim"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--batch", "cookbook", "-r", "a.txt"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--batch", "cookbook", "--output-name", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_provider_flag() {
+        let default = Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap();
+        assert_eq!(default.provider, ProviderKind::OpenRouter);
+
+        let anthropic = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--provider", "anthropic"]).unwrap();
+        assert_eq!(anthropic.provider, ProviderKind::Anthropic);
+        assert_eq!(anthropic.provider.api_key_env_var(), "ANTHROPIC_API_KEY");
+
+        let openai = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--provider", "openai"]).unwrap();
+        assert_eq!(openai.provider, ProviderKind::OpenAi);
+        assert_eq!(openai.api_key_env, None);
+        assert_eq!(openai.api_key, None);
+
+        let key = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--api-key", "sk-test"]).unwrap();
+        assert_eq!(key.api_key.as_deref(), Some("sk-test"));
+        let key_env = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--api-key-env", "WORK_KEY"]).unwrap();
+        assert_eq!(key_env.api_key_env.as_deref(), Some("WORK_KEY"));
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--api-key", "sk-test", "--api-key-env", "WORK_KEY"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--provider", "mistral"]).is_err());
+
+        let config = try_parse_args_from(["recipe_optim", "-r", "a.txt", "--provider-config", "p.toml"]).unwrap();
+        assert_eq!(config.provider_config.as_deref(), Some(Path::new("p.toml")));
+        let both = try_parse_args_from(["recipe_optim", "-r", "a.txt", "--provider-config", "p.toml", "--provider", "openai"]);
+        assert_eq!(both.unwrap_err().kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_nutrition_csv_path() {
+        let default = Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap();
+        assert_eq!(default.nutrition_csv_path(), Path::new("ciqual.csv"));
+        let usda = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--db-format", "usda"]).unwrap();
+        assert_eq!(usda.nutrition_csv_path(), Path::new("usda_fdc.csv"));
+        let custom = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--ciqual-csv", "/data/my_table.csv"]).unwrap();
+        assert_eq!(custom.nutrition_csv_path(), Path::new("/data/my_table.csv"));
+    }
+
+    #[test]
+    fn test_gen_params_overrides() {
+        let defaults = Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap();
+        assert_eq!(defaults.gen_params(), StageGenParams::default());
+
+        let cli = Cli::try_parse_from([
+            "recipe_optim", "-r", "a.txt", "--parser-max-tokens", "4096", "--optimizer-temperature", "0.4",
+        ]).unwrap();
+        let gen_params = cli.gen_params();
+        assert_eq!(gen_params.parser, GenParams { temperature: 0.05, max_tokens: 4096 });
+        assert_eq!(gen_params.optimizer, GenParams { temperature: 0.4, max_tokens: 1024 });
+        assert_eq!(gen_params.conversion, GenParams::CONVERSION);
+
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--conversion-temperature", "0"]).is_ok());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--parser-temperature=-0.5"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--optimizer-temperature", "NaN"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--disambiguation-max-tokens", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_url_flag() {
+        let url = Cli::try_parse_from(["recipe_optim", "--url", "https://example.com/recipes/banana-bread/?ref=home"]).unwrap();
+        assert!(url.recipe_file.is_empty());
+        assert!(!url.reads_from_stdin());
+        assert_eq!(url.output_stem(), "banana-bread");
+
+        let page = Cli::try_parse_from(["recipe_optim", "--url", "https://example.com/soupe%20pho.html"]).unwrap();
+        assert_eq!(page.output_stem(), "soupe_20pho");
+        let root = Cli::try_parse_from(["recipe_optim", "--url", "https://example.com"]).unwrap();
+        assert_eq!(root.output_stem(), "recipe");
+        let named = Cli::try_parse_from(["recipe_optim", "--url", "https://example.com/a", "--output-name", "x"]).unwrap();
+        assert_eq!(named.output_stem(), "x");
+
+        assert!(Cli::try_parse_from(["recipe_optim", "--url", "https://example.com/a", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_check_flag() {
+        let check = Cli::try_parse_from(["recipe_optim", "--check", "--provider", "openai"]).unwrap();
+        assert!(check.check);
+        assert!(check.recipe_file.is_empty());
+        assert!(Cli::try_parse_from(["recipe_optim", "--check", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_auto_accept_flags() {
+        let default = Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt"]).unwrap();
+        assert_eq!(default.auto_accept(), None);
+
+        let cli = Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--auto-accept-similarity", "0.9"]).unwrap();
+        assert_eq!(cli.auto_accept(), Some(AutoAccept { min_similarity: 0.9, min_margin: AutoAccept::DEFAULT_MARGIN }));
+
+        let cli = Cli::try_parse_from([
+            "recipe_optim", "--recipe-file", "r.txt", "--auto-accept-similarity", "0.9", "--auto-accept-margin", "0.2",
+        ]).unwrap();
+        assert_eq!(cli.auto_accept().unwrap().min_margin, 0.2);
+
+        assert!(Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--auto-accept-margin", "0.2"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--auto-accept-similarity", "1.5"]).is_err());
+        assert!(Cli::try_parse_from([
+            "recipe_optim", "--recipe-file", "r.txt", "--auto-accept-similarity", "0.9", "--auto-accept-margin=-0.1",
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_mass_band_flags() {
+        let default = Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt"]).unwrap();
+        assert_eq!(default.mass_band(), None);
+
+        let cli = Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--target-mass", "900"]).unwrap();
+        assert_eq!(cli.mass_band(), Some(MassBand { target_g: 900.0, tolerance_pct: DEFAULT_MASS_TOLERANCE_PCT }));
+
+        let cli = Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--target-mass", "900", "--mass-tolerance-pct", "5"]).unwrap();
+        assert_eq!(cli.mass_band().unwrap().tolerance_pct, 5.0);
+
+        assert!(Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--mass-tolerance-pct", "5"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--recipe-file", "r.txt", "--target-mass", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_nutrient_names_and_list_flag() {
+        for nutrient in OptimizableNutrient::ALL {
+            for name in nutrient.names() {
+                assert_eq!(OptimizableNutrient::from_str(&name.to_uppercase()), Ok(nutrient));
+            }
+        }
+        let error = OptimizableNutrient::from_str("sugar").unwrap_err();
+        assert!(error.contains("Supported: carb, fat, protein."));
+        assert!(error.contains("--list-nutrients"));
+
+        let cli = Cli::try_parse_from(["recipe_optim", "--list-nutrients"]).unwrap();
+        assert!(cli.list_nutrients);
+        assert!(Cli::try_parse_from(["recipe_optim", "--list-nutrients", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_explain_match_flag() {
+        let cli = Cli::try_parse_from(["recipe_optim", "--explain-match", "2% milk"]).unwrap();
+        assert_eq!(cli.explain_match.as_deref(), Some("2% milk"));
+        assert!(Cli::try_parse_from(["recipe_optim", "--explain-match", "milk", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_tolerance_flags() {
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "r.txt", "--tolerance", "protein:1.5g", "--tolerance", "10%", "--stop-within-tolerance"]).unwrap();
+        let bands = cli.tolerance_bands();
+        assert_eq!(bands.band(OptimizableNutrient::Protein), Tolerance::Absolute(1.5));
+        assert_eq!(bands.band(OptimizableNutrient::Fat), Tolerance::Percent(10.0));
+        assert!(cli.stop_within_tolerance);
+        assert_eq!(Cli::try_parse_from(["recipe_optim", "-r", "r.txt"]).unwrap().tolerance_bands(), ToleranceBands::default());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "r.txt", "--tolerance", "vitamins:5%"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "r.txt", "--tolerance", "-5%"]).is_err());
+    }
+
+    #[test]
+    fn test_substitute_flags() {
+        let cli = Cli::try_parse_from(["recipe_optim", "--substitute", "butter", "--for", "fat-reduction"]).unwrap();
+        assert_eq!(cli.substitute.as_deref(), Some("butter"));
+        assert_eq!(cli.substitution_goal, Some(SubstitutionGoal::FatReduction));
+        assert!(Cli::try_parse_from(["recipe_optim", "--substitute", "butter"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--for", "fat-reduction", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_explore_flags() {
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--explore", "0.2", "--explore-seed", "7"]).unwrap();
+        assert_eq!(cli.exploration(), Some(Exploration { initial_temperature: 0.2, cooling_rate: DEFAULT_COOLING_RATE, seed: 7 }));
+        assert_eq!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap().exploration(), None);
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--explore", "0"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--explore-seed", "7"]).is_err());
+    }
+
+    #[test]
+    fn test_scale_flags() {
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--scale", "1.5"]).unwrap();
+        assert_eq!(cli.scaling(), Some(Scaling::Factor(1.5)));
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--scale-to-servings", "6"]).unwrap();
+        assert_eq!(cli.scaling(), Some(Scaling::Servings(6)));
+        assert_eq!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap().scaling(), None);
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--scale", "0"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--scale", "2", "--scale-to-servings", "6"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--scale", "2", "--optimize", "fat:-10"]).is_err());
+    }
+
+    #[test]
+    fn test_output_format_flag() {
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "a.txt"]).unwrap();
+        assert_eq!(cli.output_format, OutputFormat::Json);
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--output-format", "recipe-text"]).unwrap();
+        assert_eq!(cli.output_format, OutputFormat::RecipeText);
+    }
+
+    #[test]
+    fn test_combine_flag() {
+        let cli = Cli::try_parse_from(["recipe_optim", "-r", "meals/steak.txt", "-r", "meals/salad.txt", "--combine"]).unwrap();
+        assert_eq!(cli.recipe_file, vec!["meals/steak.txt", "meals/salad.txt"]);
+        assert!(cli.validate_recipe_files().is_ok());
+        assert_eq!(cli.output_stem(), "steak+salad");
+
+        let uncombined = Cli::try_parse_from(["recipe_optim", "-r", "steak.txt", "-r", "salad.txt"]).unwrap();
+        assert!(uncombined.validate_recipe_files().is_err());
+        let stdin = Cli::try_parse_from(["recipe_optim", "-r", "steak.txt", "-r", "-", "--combine"]).unwrap();
+        assert!(stdin.validate_recipe_files().is_err());
+        let batch = Cli::try_parse_from(["recipe_optim", "--batch", "meals", "--combine"]).unwrap();
+        assert!(batch.validate_recipe_files().is_err());
+    }
+
+    #[test]
+    fn test_compare_flag() {
+        let cli = Cli::try_parse_from(["recipe_optim", "--compare", "a_enriched.json", "a_optimized.json"]).unwrap();
+        assert_eq!(cli.compare, Some(vec![PathBuf::from("a_enriched.json"), PathBuf::from("a_optimized.json")]));
+        assert!(Cli::try_parse_from(["recipe_optim", "--compare", "a_enriched.json"]).is_err());
+        assert!(Cli::try_parse_from(["recipe_optim", "--compare", "a.json", "b.json", "-r", "a.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_optimizable_flag() {
+        let cli = Cli::try_parse_from([
+            "recipe_optim", "-r", "a.txt", "--optimize", "fat:5", "--optimizable", "olive oil", "--optimizable", "butter",
+        ]).unwrap();
+        assert_eq!(cli.optimizable, vec!["olive oil".to_string(), "butter".to_string()]);
+        assert!(Cli::try_parse_from(["recipe_optim", "-r", "a.txt", "--optimizable", "butter"]).is_err());
+    }
 }
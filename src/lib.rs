@@ -2,7 +2,14 @@ pub mod api_connection;
 pub mod search;
 pub mod cli;
 pub mod recipe_parser;
+pub mod recipe_fetcher;
 pub mod recipe_converter;
 pub mod nutritional_matcher;
 pub mod recipe_aggregator;
 pub mod optim;
+pub mod pipeline;
+pub mod logging;
+pub mod progress;
+pub mod prompts;
+#[cfg(feature = "server")]
+pub mod server;
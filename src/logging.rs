@@ -0,0 +1,99 @@
+//! Process-wide output verbosity used by the CLI and the library's progress messages.
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only final results and errors.
+    Quiet = 0,
+    /// Progress messages, without prompts, raw LLM responses and other diagnostics.
+    Normal = 1,
+    /// Everything, including prompts and raw LLM responses.
+    Verbose = 2,
+}
+
+impl Verbosity {
+    /// True when messages of the given level are shown at this verbosity.
+    pub fn shows(self, level: Verbosity) -> bool {
+        level <= self
+    }
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// True when messages of the given level should be shown.
+pub fn enabled(level: Verbosity) -> bool {
+    verbosity().shows(level)
+}
+
+static BEFORE_PRINT: OnceLock<fn()> = OnceLock::new();
+
+/// Registers `hook` to run before the logging macros print, e.g. to erase a progress bar from the
+/// terminal. Only the first call has an effect.
+pub fn set_before_print(hook: fn()) {
+    let _ = BEFORE_PRINT.set(hook);
+}
+
+/// Runs the hook registered with `set_before_print`, if any.
+pub fn before_print() {
+    if let Some(hook) = BEFORE_PRINT.get() {
+        hook();
+    }
+}
+
+/// Prints a progress message unless running with `--quiet`.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Verbosity::Normal) {
+            $crate::logging::before_print();
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a diagnostic message on stderr only when running with `--verbose`, keeping stdout for results.
+#[macro_export]
+macro_rules! log_verbose {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Verbosity::Verbose) {
+            $crate::logging::before_print();
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a warning or error on stderr, even with `--quiet`.
+#[macro_export]
+macro_rules! log_warning {
+    ($($arg:tt)*) => {{
+        $crate::logging::before_print();
+        eprintln!($($arg)*);
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the levels without `set_verbosity`, which would change the output of tests running
+    // in parallel.
+    #[test]
+    fn test_verbosity_levels() {
+        assert!(!Verbosity::Quiet.shows(Verbosity::Normal));
+        assert!(Verbosity::Verbose.shows(Verbosity::Normal) && Verbosity::Verbose.shows(Verbosity::Verbose));
+        assert!(Verbosity::Normal.shows(Verbosity::Normal) && !Verbosity::Normal.shows(Verbosity::Verbose));
+    }
+}
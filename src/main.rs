@@ -1,231 +1,814 @@
-use anyhow::{Result, Context, bail, anyhow}; 
-use recipe_optim::cli::parse_args;
-use recipe_optim::recipe_parser::{parse_recipe_text, ParsedRecipe};
-use recipe_optim::recipe_converter::{convert_ingredients_to_grams, CleanedRecipe};
-use recipe_optim::nutritional_matcher::NutritionalIndex;
-use recipe_optim::recipe_aggregator::{calculate_nutritional_profile, EnrichedRecipeOutput, RecipeNutritionalProfile};
-use recipe_optim::optim::targets::{calculate_target_nutrition, TargetNutritionalValues}; 
-use recipe_optim::optim::optimizer::optimize_recipe; 
+use anyhow::{Result, Context, anyhow};
+use recipe_optim::api_connection::client::{ChatClient, ConcurrencyLimited};
+use recipe_optim::api_connection::connection::ApiConnectionError;
+use recipe_optim::api_connection::endpoints::{Provider, ProviderConfig};
+use recipe_optim::api_connection::usage::total_usage;
+use recipe_optim::cli::{parse_args, Cli, OutputFormat, ProgressFormat, STDIN_RECIPE_FILE};
+#[cfg(feature = "server")]
+use recipe_optim::cli::Command;
+#[cfg(feature = "server")]
+use recipe_optim::server::serve;
+use recipe_optim::{log_info, log_warning};
+use recipe_optim::logging::set_verbosity;
+use recipe_optim::progress::{clear_progress_bar, install_progress_bar, ProgressBar, ProgressEvent};
+use recipe_optim::prompts::{PromptKind, PromptTemplates};
+use recipe_optim::search::embedding_engine::HF_TOKEN_ENV_VAR;
+use recipe_optim::recipe_converter::{CleanedRecipe, ContainerSize, ContainerSizes};
+use recipe_optim::nutritional_matcher::{format_match_report, load_overrides, match_report, NutritionalIndex, SUBSTITUTION_COUNT};
+use recipe_optim::pipeline::{
+    build_output, combine_recipes, load_cached_recipe, optimize, parse_recipe, prepare_parsed_recipe, prepare_recipe, profile_recipe, scale_recipe,
+    validate_kcal, InputFormat, PipelineOptions, Scaling,
+};
+use recipe_optim::recipe_fetcher::{fetch_recipe, WebRecipe};
+use recipe_optim::recipe_aggregator::{
+    calculate_nutritional_profile, default_allergen_rules, load_price_table, merge_allergen_rules,
+    ensure_nutrition_computed, format_recipe_text, write_profile_csv, AllergenRule, EnrichedRecipeOutput, NutritionalSummary, RecipeNutritionalProfile,
+    write_file_atomically,
+};
+use recipe_optim::optim::nutri_eval::{calculate_mse_with_mode, calculate_nutrient_errors, calculate_rmse, TargetCheck};
+use recipe_optim::optim::targets::{calculate_target_nutrition, OptimizableNutrient, TargetNutritionalValues};
+use recipe_optim::optim::optimizer::{Checkpoint, OptimizerOptions};
+use recipe_optim::optim::recipe_diff::{diff_nutrition, diff_recipes, RecipeDiff};
 use tokio::fs;
+use std::io::{IsTerminal, Read};
 use std::path::{Path, PathBuf};
 
-// Define the environment variable name for the API key
-const API_KEY_ENV_VAR: &str = "OPENROUTER_API_KEY";
-const CIQUAL_CSV_PATH: &str = "ciqual.csv"; // Define path to ciqual.csv
-
-async fn enrich_with_nutritional_info(
-    cleaned_recipe: &mut CleanedRecipe, 
-    nutritional_index: &NutritionalIndex,
-    api_key_env_var: &str,
-    progress_updater: impl Fn(String) + Send + Sync + 'static,
-) -> Result<()> {
-    println!("\nEnriching recipe with nutritional information...");
-    let ingredients_count = cleaned_recipe.ingredients.len();
-    for (idx, ingredient) in cleaned_recipe.ingredients.iter_mut().enumerate() {
-        progress_updater(format!(
-            "Processing ingredient {}/{} for nutrition: {}",
-            idx + 1,
-            ingredients_count,
-            ingredient.ingredient_name
-        ));
-        
-        match nutritional_index.find_and_calculate_nutrition(ingredient, api_key_env_var, &progress_updater).await {
-            Ok(Some(nutritional_info)) => {
-                progress_updater(format!(
-                    "   -> Successfully calculated nutrition for '{}' from Ciqual item: '{}'",
-                    ingredient.ingredient_name, nutritional_info.source_ciqual_name
-                ));
-                ingredient.nutritional_info = Some(nutritional_info);
-            }
-            Ok(None) => {
-                progress_updater(format!(
-                    "   -> Could not find or calculate nutritional information for '{}'",
-                    ingredient.ingredient_name
-                ));
-            }
-            Err(e) => {
-                 progress_updater(format!(
-                    "   -> Error finding nutrition for '{}': {}",
-                    ingredient.ingredient_name, e
-                ));
+/// Builds the nutritional index on first use and returns the cached one afterwards.
+fn ensure_nutritional_index<'a>(cli_args: &Cli, slot: &'a mut Option<NutritionalIndex>) -> Result<&'a NutritionalIndex> {
+    if slot.is_none() {
+        log_info!("Initializing Nutritional Index (this may take a moment)...");
+        let db_format = cli_args.db_format;
+        let csv_path = cli_args.nutrition_csv_path();
+        let hf_token = std::env::var(HF_TOKEN_ENV_VAR).ok();
+        let mut index = NutritionalIndex::new(
+            &csv_path,
+            db_format.column_mapping(),
+            &cli_args.index_path,
+            Some(&cli_args.embedding_model),
+            hf_token.as_deref(),
+            cli_args.rebuild_index,
+        )
+        .with_context(|| format!("Failed to initialize Nutritional Index with data from {:?}", csv_path))?;
+        if let Some(overrides_path) = &cli_args.overrides {
+            let overrides = load_overrides(overrides_path)?;
+            log_info!("Loaded {} ingredient overrides from {:?}", overrides.len(), overrides_path);
+            index = index.with_overrides(&overrides)?;
+        }
+        index = index
+            .with_interactive(cli_args.interactive)
+            .with_embedding_cache(!cli_args.no_embedding_cache)
+            .with_disambiguation_params(cli_args.gen_params().disambiguation)
+            .with_response_format(cli_args.response_format)
+            .with_prompts(prompt_templates(cli_args)?)
+            .with_auto_accept(cli_args.auto_accept())
+            .with_online_fallback(cli_args.online_fallback)?;
+        log_info!("Nutritional Index initialized.");
+        *slot = Some(index);
+    }
+    slot.as_ref().ok_or_else(|| anyhow!("NutritionalIndex not initialized"))
+}
+
+/// Built-in allergen rules, merged with the ones from `--allergens-config` when given.
+fn allergen_rules(cli_args: &Cli) -> Result<Vec<AllergenRule>> {
+    let Some(config_path) = &cli_args.allergens_config else {
+        return Ok(default_allergen_rules());
+    };
+    let content = std::fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read allergens config {:?}", config_path))?;
+    let custom_rules: Vec<AllergenRule> = serde_json::from_str(&content)
+        .with_context(|| format!("Allergens config {:?} must be a JSON list of allergen rules", config_path))?;
+    Ok(merge_allergen_rules(default_allergen_rules(), custom_rules))
+}
+
+/// Built-in container sizes, with the ones from `--container-sizes` applied when given.
+fn container_sizes(cli_args: &Cli) -> Result<ContainerSizes> {
+    let Some(config_path) = &cli_args.container_sizes else {
+        return Ok(ContainerSizes::default());
+    };
+    let content = std::fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read container sizes {:?}", config_path))?;
+    let sizes: Vec<ContainerSize> = serde_json::from_str(&content)
+        .with_context(|| format!("Container sizes {:?} must be a JSON list of container sizes", config_path))?;
+    Ok(sizes.iter().fold(ContainerSizes::default(), ContainerSizes::with_size))
+}
+
+/// Built-in prompt templates, with the ones from `--prompt-dir` replacing them when given.
+fn prompt_templates(cli_args: &Cli) -> Result<PromptTemplates> {
+    cli_args.prompt_dir.as_deref().map_or_else(|| Ok(PromptTemplates::default()), PromptTemplates::load)
+}
+
+/// Pipeline settings derived from the command line.
+fn pipeline_options(cli_args: &Cli, input_format: InputFormat) -> Result<PipelineOptions> {
+    Ok(PipelineOptions {
+        input_format,
+        conversion_retries: cli_args.conversion_retries,
+        to_taste_defaults: cli_args.to_taste_defaults(),
+        container_sizes: container_sizes(cli_args)?,
+        servings: cli_args.servings,
+        optimization_targets: cli_args.get_optimization_targets_map(),
+        optimizer: OptimizerOptions {
+            max_iterations: cli_args.max_iterations,
+            preserve_mass: cli_args.preserve_mass,
+            strict_mse: cli_args.strict_mse,
+            mse_mode: cli_args.mse_mode,
+            max_quantity_change_pct: cli_args.max_quantity_change_pct,
+            mass_band: cli_args.mass_band(),
+            suggest_only: cli_args.suggest_only,
+            exploration: cli_args.exploration(),
+            optimizable: cli_args.optimizable.clone(),
+            // The checkpoint is set by `process_recipe` once the output paths are known; the
+            // settings shared with the other steps, such as the container sizes, `stop_within`
+            // and `reconcile_kcal_pct` by `optimize`.
+            ..OptimizerOptions::default()
+        },
+        allergen_rules: allergen_rules(cli_args)?,
+        price_table: cli_args.price_table.as_deref().map(load_price_table).transpose()?,
+        gen_params: cli_args.gen_params(),
+        ingredient_order: cli_args.sort_ingredients,
+        json_mode: cli_args.json_mode(),
+        response_format: cli_args.response_format,
+        prompts: prompt_templates(cli_args)?,
+        parse_limits: cli_args.parse_limits(),
+        kcal_tolerance_pct: cli_args.kcal_tolerance,
+        reconcile_kcal: cli_args.reconcile_kcal,
+        tolerances: cli_args.tolerance_bands(),
+        stop_within_tolerance: cli_args.stop_within_tolerance,
+        // The provider's model is used, and `process_recipe` handles the enriched file itself.
+        model: None,
+        cache_path: None,
+    })
+}
+
+/// Writes the `--csv-out` export of the recipe output, if one was requested.
+fn write_csv_export(cli_args: &Cli, output: &EnrichedRecipeOutput) -> Result<()> {
+    let Some(csv_path) = &cli_args.csv_out else {
+        return Ok(());
+    };
+    let file = std::fs::File::create(csv_path)
+        .with_context(|| format!("Failed to create CSV file {:?}", csv_path))?;
+    write_profile_csv(output, std::io::BufWriter::new(file))
+        .with_context(|| format!("Failed to write CSV file {:?}", csv_path))?;
+    println!("Nutritional profile CSV saved to '{}'", csv_path.display());
+    Ok(())
+}
+
+/// With `--output-format recipe-text`, prints the recipe as text and saves it next to `json_path`.
+fn write_recipe_text(cli_args: &Cli, output: &EnrichedRecipeOutput, json_path: &Path) -> Result<()> {
+    if cli_args.output_format != OutputFormat::RecipeText {
+        return Ok(());
+    }
+    let text = format_recipe_text(output);
+    let text_path = json_path.with_extension("txt");
+    println!("\n--- Recipe ---\n{}", text);
+    write_file_atomically(&text_path, &text)
+        .with_context(|| format!("Failed to write recipe text to {:?}", text_path))?;
+    println!("Recipe text saved to '{}'", text_path.display());
+    Ok(())
+}
+
+/// Prints the `--match-report` table of the recipe and writes it as JSON to `path`.
+fn write_match_report(recipe: &CleanedRecipe, path: &Path) -> Result<()> {
+    let report = match_report(recipe);
+    println!("\n--- Ingredient Matches ---");
+    print!("{}", format_match_report(&report));
+    let json = serde_json::to_string_pretty(&report).with_context(|| "Failed to serialize the match report")?;
+    write_file_atomically(path, &json).with_context(|| format!("Failed to write match report {:?}", path))?;
+    println!("Match report saved to '{}'", path.display());
+    Ok(())
+}
+
+/// `--scale` / `--scale-to-servings`: prints the resized recipe and saves it to `path`, with the
+/// quantities scaling left untouched in its notes. The recipe's servings are those of `profile`,
+/// so a `--servings` override counts.
+fn write_scaled_recipe(
+    recipe: &CleanedRecipe,
+    profile: &RecipeNutritionalProfile,
+    scaling: Scaling,
+    options: &PipelineOptions,
+    path: &Path,
+) -> Result<EnrichedRecipeOutput> {
+    let recipe = CleanedRecipe { servings: profile.servings, ..recipe.clone() };
+    let factor = scaling.factor(recipe.servings)?;
+    let scaled = scale_recipe(&recipe, factor);
+    let mut scaled_profile = profile_recipe(&scaled.recipe, None);
+    scaled_profile.refresh_energy_breakdown();
+    validate_kcal(&scaled.recipe, &mut scaled_profile, options);
+
+    println!("\n--- Scaled Recipe (x{}) ---", factor);
+    for ingredient in &scaled.recipe.ingredients {
+        let grams = ingredient.quantity_grams.map_or("?".to_string(), |grams| format!("{:.0}", grams));
+        println!("  {} {} {} ({} g)", ingredient.original_quantity, ingredient.original_unit, ingredient.ingredient_name, grams);
+    }
+    if let Some(servings) = scaled.recipe.servings {
+        println!("Servings: {}", servings);
+    }
+    for note in &scaled.notes {
+        println!("[NOTE] {}", note);
+    }
+    println!("Scaled Nutritional Profile (Aggregated): {:#?}", scaled_profile.aggregated);
+    if let Some(per_serving) = &scaled_profile.per_serving {
+        println!("Scaled Nutritional Profile (Per Serving): {:#?}", per_serving);
+    }
+    let output = build_output(&scaled.recipe, &scaled_profile, options)
+        .with_optimization_notes(scaled.notes)
+        .with_ingredient_order(options.ingredient_order);
+    output.save(path)
+        .with_context(|| format!("Failed to write scaled recipe to JSON file: {:?}", path))?;
+    println!("\nScaled recipe saved to '{}'", path.display());
+    Ok(output)
+}
+
+/// The provider from `--provider-config`, or `--provider` with its defaults, with the API key of
+/// `--api-key-env` or `--api-key` when given.
+fn load_provider(cli_args: &Cli) -> Result<Provider> {
+    let mut provider = match &cli_args.provider_config {
+        None => Provider::for_kind(cli_args.provider),
+        Some(config_path) => Provider::from_config(&ProviderConfig::load(config_path)?),
+    };
+    if let Some(env_var_name) = &cli_args.api_key_env {
+        provider = provider.with_api_key_env(env_var_name);
+    }
+    if let Some(key) = &cli_args.api_key {
+        provider = provider.with_api_key(key.clone());
+    }
+    Ok(provider)
+}
+
+/// `--list-nutrients`: the nutrients `--optimize` accepts, with their aliases.
+fn print_nutrient_list() {
+    println!("Nutrients accepted by --optimize <nutrient>:<percentage_change>:");
+    for nutrient in OptimizableNutrient::ALL {
+        let names = nutrient.names();
+        println!("  {:<10} {}", names[0], nutrient.description());
+        if names.len() > 1 {
+            println!("  {:<10} aliases: {}", "", names[1..].join(", "));
+        }
+    }
+}
+
+/// What the user can do about a provider error, for the errors that have a clear remedy.
+fn api_error_hint(error: &ApiConnectionError) -> Option<&'static str> {
+    if error.is_insufficient_credits() {
+        Some("The provider account is out of credits: top it up, or use another provider with --provider or --provider-config.")
+    } else if error.is_rate_limited() {
+        Some("The provider is rate limiting requests: lower --max-concurrent-requests or try again later.")
+    } else {
+        None
+    }
+}
+
+/// `--check`: verifies the provider's credentials up front. Fails (non-zero exit) when they don't work.
+async fn check_provider(client: &Provider) -> Result<()> {
+    println!("Provider: {:?} (API key from {})", client.kind(), client.api_key_source());
+    println!("Models:");
+    for model in client.model_names() {
+        println!("  {}", model);
+    }
+    match client.check_connection().await {
+        Ok(()) => {
+            println!("Authentication succeeded.");
+            Ok(())
+        }
+        Err(e) => {
+            println!("Authentication failed.");
+            if let Some(hint) = api_error_hint(&e) {
+                println!("{}", hint);
             }
+            Err(anyhow!("Provider check failed: {}", e))
+        }
+    }
+}
+
+/// Lists the `.txt` recipe files of a batch directory in a stable order.
+fn batch_recipe_files(batch_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut recipe_files = Vec::new();
+    for entry in std::fs::read_dir(batch_dir)
+        .with_context(|| format!("Failed to read batch directory {:?}", batch_dir))?
+    {
+        let path = entry?.path();
+        let is_txt_file = path.is_file()
+            && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("txt"));
+        if is_txt_file {
+            recipe_files.push(path);
+        }
+    }
+    recipe_files.sort();
+    Ok(recipe_files)
+}
+
+async fn process_batch<F>(
+    cli_args: &Cli,
+    batch_dir: &Path,
+    client: &impl ChatClient,
+    nutritional_index_slot: &mut Option<NutritionalIndex>,
+    progress_callback: F,
+) -> Result<()>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    let recipe_files = batch_recipe_files(batch_dir)?;
+    if recipe_files.is_empty() {
+        return Err(anyhow!("No .txt recipe files found in batch directory {:?}", batch_dir));
+    }
+    log_info!("Batch mode: {} recipe files found in {:?}", recipe_files.len(), batch_dir);
+
+    let mut failures: Vec<(PathBuf, anyhow::Error)> = Vec::new();
+    for (idx, recipe_path) in recipe_files.iter().enumerate() {
+        log_info!("\n=== Recipe {}/{}: {} ===", idx + 1, recipe_files.len(), recipe_path.display());
+        if let Err(e) = process_recipe(cli_args, Some(recipe_path), client, nutritional_index_slot, progress_callback).await {
+            log_warning!("\nFailed to process '{}': {:#}", recipe_path.display(), e);
+            failures.push((recipe_path.clone(), e));
+        }
+    }
+
+    println!(
+        "\n--- Batch Summary ---\n{} succeeded, {} failed (out of {}).",
+        recipe_files.len() - failures.len(),
+        failures.len(),
+        recipe_files.len()
+    );
+    for (recipe_path, e) in &failures {
+        println!("  FAILED {}: {:#}", recipe_path.display(), e);
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} of {} recipes failed", failures.len(), recipe_files.len()))
+    }
+}
+
+/// Prints one readable line per targeted nutrient, followed by the RMSE.
+fn print_nutrient_errors(heading: &str, per_100g: &NutritionalSummary, target_per_100g: &TargetNutritionalValues) {
+    println!("{}:", heading);
+    for error in calculate_nutrient_errors(per_100g, target_per_100g) {
+        println!("  {}", error);
+    }
+    println!("  RMSE: {:.2}", calculate_rmse(per_100g, target_per_100g));
+}
+
+/// Ingredient changes and the net mass change, one per line.
+fn print_recipe_diff(diff: &RecipeDiff) {
+    if diff.changes.is_empty() {
+        println!("  No ingredient changed.");
+    }
+    for change in &diff.changes {
+        println!("  {}", change);
+    }
+    let percent = diff.mass_delta_percent().map_or_else(String::new, |percent| format!(" ({:+.1}%)", percent));
+    println!(
+        "  Total mass: {:.1} g -> {:.1} g, {:+.1} g{}",
+        diff.mass_before_g, diff.mass_after_g, diff.mass_delta_g(), percent
+    );
+}
+
+/// `--compare`: what changed between two enriched or optimized files.
+fn compare_enriched_files(before_path: &Path, after_path: &Path) -> Result<()> {
+    let load = |path: &Path| -> Result<EnrichedRecipeOutput> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        EnrichedRecipeOutput::from_json(&content).with_context(|| format!("{:?} is not an enriched recipe file", path))
+    };
+    let (before, after) = (load(before_path)?, load(after_path)?);
+    println!("Comparing '{}' ({}) with '{}' ({})", before_path.display(), before.recipe_title, after_path.display(), after.recipe_title);
+
+    println!("\n--- Ingredient Changes ---");
+    print_recipe_diff(&diff_recipes(&before.to_cleaned_recipe(), &after.to_cleaned_recipe()));
+    for (heading, before_summary, after_summary) in [
+        ("Aggregated", &before.nutritional_profile.aggregated, &after.nutritional_profile.aggregated),
+        ("Per 100g", &before.nutritional_profile.per_100g, &after.nutritional_profile.per_100g),
+    ] {
+        println!("\n--- Nutrition Changes ({}) ---", heading);
+        let changes = diff_nutrition(before_summary, after_summary);
+        if changes.is_empty() {
+            println!("  No nutrition data in either file.");
+        }
+        for change in changes {
+            println!("  {}", change);
         }
     }
-    println!("Nutritional enrichment complete.");
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok(); // Load .env file for API keys
+/// What the optimization changed: ingredient changes, net mass change and the MSE before and after.
+fn print_optimization_diff(
+    cli_args: &Cli,
+    initial: (&CleanedRecipe, &RecipeNutritionalProfile),
+    optimized: (&CleanedRecipe, &RecipeNutritionalProfile),
+    targets: &TargetNutritionalValues,
+) {
+    println!("\n--- Changes From the Original Recipe ---");
+    print_recipe_diff(&diff_recipes(initial.0, optimized.0));
+    let mse = |profile: &RecipeNutritionalProfile| {
+        calculate_mse_with_mode(&profile.per_100g, targets, cli_args.mse_mode, cli_args.strict_mse)
+    };
+    println!("  MSE: {:.4} -> {:.4}", mse(initial.1), mse(optimized.1));
+}
 
-    let cli_args = parse_args();
-    println!("Input recipe file: {}", cli_args.recipe_file);
+/// Which targeted nutrients are within their `--tolerance`, one per line.
+fn print_target_checks(heading: &str, checks: &[TargetCheck]) {
+    if checks.is_empty() {
+        return;
+    }
+    let met = checks.iter().filter(|check| check.within).count();
+    println!("{} ({} of {} within tolerance):", heading, met, checks.len());
+    for check in checks {
+        println!("  {}", check);
+    }
+}
+
+fn print_energy_breakdown(profile: &RecipeNutritionalProfile) {
+    if let Some(breakdown) = &profile.energy_breakdown {
+        println!("Energy from macronutrients: {}", breakdown);
+    }
+}
+
+/// `--dry-run` output: what the optimizer would aim for, without running it.
+fn print_optimization_plan(cli_args: &Cli, profile: &RecipeNutritionalProfile) {
+    println!("\n--- Dry Run: Optimization Plan ---");
+    println!("Initial Nutritional Profile (Per 100g): {:#?}", profile.per_100g);
+    print_energy_breakdown(profile);
+    let goals_map = cli_args.get_optimization_targets_map();
+    if goals_map.is_empty() {
+        println!("No optimization targets given; nothing to optimize.");
+        return;
+    }
+    let target_nutrition_per_100g = calculate_target_nutrition(&profile.per_100g, &goals_map);
+    println!("Target Nutritional Values (per 100g): {:#?}", target_nutrition_per_100g);
+    let mse = calculate_mse_with_mode(&profile.per_100g, &target_nutrition_per_100g, cli_args.mse_mode, cli_args.strict_mse);
+    println!("Initial MSE: {:.4}", mse);
+    print_nutrient_errors("Initial values vs targets (per 100g)", &profile.per_100g, &target_nutrition_per_100g);
+    let tolerances = cli_args.tolerance_bands().for_targets(goals_map.keys().copied());
+    print_target_checks("Targeted nutrients", &tolerances.check(&profile.per_100g, &target_nutrition_per_100g));
+    println!("Optimization skipped (--dry-run); up to {} iterations would run.", cli_args.max_iterations);
+}
 
-    let input_path = PathBuf::from(&cli_args.recipe_file);
-    let file_stem = input_path.file_stem().unwrap_or_default().to_string_lossy();
-    let parent_dir = input_path.parent().unwrap_or_else(|| Path::new(""));
+/// Runs the full pipeline for one recipe. `recipe_path` is `None` when reading from `--url` or stdin.
+async fn process_recipe<F>(
+    cli_args: &Cli,
+    recipe_path: Option<&Path>,
+    client: &impl ChatClient,
+    nutritional_index_slot: &mut Option<NutritionalIndex>,
+    progress_callback: F,
+) -> Result<()>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    match (recipe_path, &cli_args.url) {
+        (Some(_), _) if cli_args.combine => log_info!("Input recipe files (combined): {}", cli_args.recipe_file.join(", ")),
+        (Some(path), _) => log_info!("Input recipe file: {}", path.display()),
+        (None, Some(url)) => log_info!("Input recipe URL: {}", url),
+        (None, None) => log_info!("Input recipe: <stdin>"),
+    }
+
+    let reads_from_stdin = recipe_path.is_none() && cli_args.url.is_none();
+    let (file_stem, input_format) = match recipe_path {
+        Some(path) if cli_args.combine => (cli_args.output_stem(), cli_args.input_format_for(path)),
+        Some(path) => (cli_args.output_stem_for(path), cli_args.input_format_for(path)),
+        None => (cli_args.output_stem(), cli_args.resolved_input_format()),
+    };
+    let mut options = pipeline_options(cli_args, input_format)?;
+    // Outputs go next to the input file; stdin and URL input write to the current directory.
+    let parent_dir = recipe_path
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
     
     let enriched_file_name = format!("{}_enriched.json", file_stem);
     let enriched_file_path = parent_dir.join(&enriched_file_name);
     let optimized_file_name = format!("{}_optimized.json", file_stem); 
     let optimized_file_path = parent_dir.join(&optimized_file_name);
+    let checkpoint = Checkpoint::new(optimized_file_path.clone());
+    if !cli_args.suggest_only {
+        options.optimizer.checkpoint = Some(checkpoint.clone());
+    }
 
     let mut initial_cleaned_recipe_opt: Option<CleanedRecipe> = None;
     let mut initial_nutritional_profile_opt: Option<RecipeNutritionalProfile> = None;
     
-    // Attempt to load existing enriched file first
-    if enriched_file_path.exists() { 
-        println!("Attempting to load existing enriched file: {:?}", enriched_file_path);
-        let enriched_content = fs::read_to_string(&enriched_file_path).await
-            .with_context(|| format!("Failed to read existing enriched file {:?}", enriched_file_path))?;
-        
-        match serde_json::from_str::<EnrichedRecipeOutput>(&enriched_content) {
-            Ok(loaded_data) => {
-                println!("Successfully loaded and parsed existing enriched data.");
-                initial_cleaned_recipe_opt = Some(CleanedRecipe {
-                    recipe_title: loaded_data.recipe_title.clone(),
-                    ingredients: loaded_data.ingredients.clone(),
-                    instructions: loaded_data.instructions.clone(),
-                });
-                initial_nutritional_profile_opt = Some(loaded_data.nutritional_profile.clone());
-            }
-            Err(e) => {
-                println!("Failed to parse existing enriched file ({}). Will re-process if needed.", e);
-            }
-        }
+    // Attempt to load existing enriched file first, unless a fresh run was requested
+    if cli_args.force {
+        log_info!("--force given: ignoring any cached enriched file and reprocessing from the raw recipe.");
+    } else if reads_from_stdin {
+        // The cached file can't be tied to piped content, so stdin input is always reprocessed.
+        log_info!("Reading from stdin: ignoring any cached enriched file.");
+    } else {
+        // Reprocessing would overwrite a file another version still reads, so only --force does that.
+        let (recipe, profile) = load_cached_recipe(&enriched_file_path)
+            .map_err(|e| anyhow!("{:#}. Use --force to reprocess the recipe and overwrite it.", e))?
+            .unzip();
+        initial_cleaned_recipe_opt = recipe;
+        initial_nutritional_profile_opt = profile;
     }
 
-    let mut nutritional_index_opt: Option<NutritionalIndex> = None;
     let needs_fresh_processing = initial_cleaned_recipe_opt.is_none();
-    let needs_optimization = !cli_args.optimization_targets.is_empty();
+    let needs_optimization = !cli_args.optimization_targets.is_empty() && !cli_args.dry_run;
 
     // Initialize NutritionalIndex if we need to process from scratch OR if optimization is requested.
-    if needs_fresh_processing || needs_optimization {
-        println!("Initializing Nutritional Index (this may take a moment)...");
-        nutritional_index_opt = Some(
-            NutritionalIndex::new(Path::new(CIQUAL_CSV_PATH), API_KEY_ENV_VAR)
-                .with_context(|| format!("Failed to initialize Nutritional Index with Ciqual data from '{}'", CIQUAL_CSV_PATH))?
-        );
-        println!("Nutritional Index initialized.");
-    }
-    
-    let progress_callback = |message: String| { println!("{}", message); };
+    // In batch mode the index built for an earlier recipe is reused.
+    let nutritional_index_opt = if needs_fresh_processing || needs_optimization {
+        Some(ensure_nutritional_index(cli_args, nutritional_index_slot)?)
+    } else {
+        None
+    };
 
     let (mut current_cleaned_recipe, mut current_nutritional_profile) = 
         if let (Some(recipe), Some(profile)) = (initial_cleaned_recipe_opt, initial_nutritional_profile_opt) {
             // This block is entered if initial_cleaned_recipe_opt and initial_nutritional_profile_opt are Some
-            println!("Using pre-loaded enriched recipe data as starting point.");
+            log_info!("Using pre-loaded enriched recipe data as starting point.");
             (recipe, profile)
         } else {
             // This block is entered if loading failed or file didn't exist
-            println!("Processing from raw recipe text...");
-            let index = nutritional_index_opt.as_ref()
+            log_info!("Processing from raw recipe text...");
+            let index = nutritional_index_opt
                 .ok_or_else(|| anyhow!("NutritionalIndex not initialized for raw processing but is required."))?;
 
-            let recipe_content = fs::read_to_string(&input_path)
-                .await
-                .with_context(|| format!("Failed to read recipe file '{}'", cli_args.recipe_file))?;
-            println!("\nRecipe content read successfully. Sending to parser...");
-
-            let parsed_recipe = parse_recipe_text(&recipe_content, API_KEY_ENV_VAR).await
-                .with_context(|| "Recipe parsing failed")?;
-            
-            println!("\nSuccessfully parsed recipe. Now converting ingredients to grams...");
-            
-            let mut temp_cleaned_recipe = convert_ingredients_to_grams(&parsed_recipe, API_KEY_ENV_VAR, progress_callback.clone()).await
-                .with_context(|| "Ingredient conversion to grams failed")?;
-            
-            println!("\nSuccessfully converted recipe ingredients to grams.");
-            
-            if let Err(e) = enrich_with_nutritional_info(&mut temp_cleaned_recipe, index, API_KEY_ENV_VAR, progress_callback.clone()).await {
-                eprintln!("\nError enriching recipe with nutritional info: {}", e);
-            }
-            let profile = calculate_nutritional_profile(&temp_cleaned_recipe);
-            (temp_cleaned_recipe, profile)
+            let recipe = match (recipe_path, &cli_args.url) {
+                (Some(_), _) if cli_args.combine => {
+                    let mut parsed_recipes = Vec::new();
+                    for file in &cli_args.recipe_file {
+                        let path = Path::new(file);
+                        let recipe_content = fs::read_to_string(path)
+                            .await
+                            .with_context(|| format!("Failed to read recipe file '{}'", path.display()))?;
+                        let parsed_recipe = parse_recipe(&recipe_content, cli_args.input_format_for(path), cli_args.gen_params().parser, cli_args.json_mode(), options.parse_limits, &options.prompts, client).await
+                            .with_context(|| format!("Failed to parse recipe file '{}'", path.display()))?;
+                        parsed_recipes.push(parsed_recipe);
+                    }
+                    let combined = combine_recipes(&parsed_recipes);
+                    log_info!("\nCombined {} recipes into '{}' ({} ingredients).", parsed_recipes.len(), combined.recipe_title, combined.ingredients.len());
+                    if combined.servings.is_none() && options.servings.is_none() {
+                        log_warning!("\n[WARNING] The combined recipes don't all state the same servings; pass --servings for per-serving values.");
+                    }
+                    prepare_parsed_recipe(&combined, &options, index, client, progress_callback).await?
+                }
+                (None, Some(url)) => {
+                    let parsed_recipe = match fetch_recipe(url).await? {
+                        WebRecipe::Structured(parsed_recipe) => {
+                            log_info!("\nFound structured recipe data on the page; skipping the LLM parse.");
+                            parsed_recipe
+                        }
+                        WebRecipe::Text(page_text) => parse_recipe(&page_text, InputFormat::Text, cli_args.gen_params().parser, cli_args.json_mode(), options.parse_limits, &options.prompts, client).await?,
+                    };
+                    if parsed_recipe.ingredients.is_empty() {
+                        return Err(anyhow!("No ingredients found at {}; the page doesn't look like a recipe", url));
+                    }
+                    prepare_parsed_recipe(&parsed_recipe, &options, index, client, progress_callback).await?
+                }
+                _ => {
+                    let recipe_content = match recipe_path {
+                        Some(path) => fs::read_to_string(path)
+                            .await
+                            .with_context(|| format!("Failed to read recipe file '{}'", path.display()))?,
+                        None => {
+                            let mut buffer = String::new();
+                            std::io::stdin().read_to_string(&mut buffer)
+                                .with_context(|| "Failed to read recipe from stdin")?;
+                            buffer
+                        }
+                    };
+                    prepare_recipe(&recipe_content, &options, index, client, progress_callback).await?
+                }
+            };
+            let profile = calculate_nutritional_profile(&recipe);
+            (recipe, profile)
         };
+    clear_progress_bar();
+
+    // Cheap step: the per-serving view only depends on the aggregated values, so it also
+    // applies to a cached enriched file without re-running the LLM pipeline.
+    // An explicit --servings overrides the yield parsed from the recipe.
+    if let Some(servings) = options.servings.or(current_cleaned_recipe.servings) {
+        log_info!("Computing per-serving nutrition for {} servings.", servings);
+        current_nutritional_profile.apply_servings(servings);
+    }
+    current_nutritional_profile.refresh_energy_breakdown();
+    validate_kcal(&current_cleaned_recipe, &mut current_nutritional_profile, &options);
+
+    if let Err(reason) = ensure_nutrition_computed(&current_cleaned_recipe, &current_nutritional_profile) {
+        if needs_optimization || cli_args.dry_run {
+            return Err(reason.context("Nothing to optimize"));
+        }
+        log_warning!("\n[WARNING] {}. The nutritional profile below is empty.", reason);
+    }
+
+    if cli_args.match_report {
+        write_match_report(&current_cleaned_recipe, &parent_dir.join(format!("{}_matches.json", file_stem)))?;
+    }
+
+    if cli_args.dry_run {
+        print_optimization_plan(cli_args, &current_nutritional_profile);
+    }
+
+    if let Some(scaling) = cli_args.scaling() {
+        let scaled_file_path = parent_dir.join(format!("{}_scaled.json", file_stem));
+        let scaled_output = write_scaled_recipe(&current_cleaned_recipe, &current_nutritional_profile, scaling, &options, &scaled_file_path)?;
+        write_recipe_text(cli_args, &scaled_output, &scaled_file_path)?;
+    }
 
     if needs_optimization {
-        println!("\n--- Starting Recipe Optimization ---");
-        let goals_map = cli_args.get_optimization_targets_map();
-        let target_nutrition_per_100g = calculate_target_nutrition(
-            &current_nutritional_profile.per_100g, 
-            &goals_map,
-        );
-        println!("Target Nutritional Values (per 100g): {:#?}", target_nutrition_per_100g);
-        
-        let index_for_optim = nutritional_index_opt.as_ref()
+        log_info!("\n--- Starting Recipe Optimization ---");
+        let index_for_optim = nutritional_index_opt
             .ok_or_else(|| anyhow!("NutritionalIndex not initialized for optimization but is required."))?;
 
-        match optimize_recipe(
+        // Each accepted iteration is checkpointed to the optimized file, so Ctrl-C keeps the best recipe so far.
+        let optimization = optimize(
             &current_cleaned_recipe,
             &current_nutritional_profile,
-            &target_nutrition_per_100g,
-            cli_args.max_iterations, 
+            &options,
             index_for_optim,
-            API_KEY_ENV_VAR,
-            progress_callback.clone(),
-        ).await {
-            Ok(optimized_recipe) => {
+            client,
+            progress_callback,
+        );
+        let optimization_result = tokio::select! {
+            result = optimization => result,
+            _ = tokio::signal::ctrl_c() => {
+                clear_progress_bar();
+                if checkpoint.writes() > 0 {
+                    println!("\nOptimization interrupted. The best recipe found so far is in '{}'.", optimized_file_path.display());
+                } else {
+                    println!("\nOptimization interrupted before any improvement was found; no optimized recipe was saved.");
+                }
+                return Err(anyhow!("Optimization interrupted"));
+            }
+        };
+        match optimization_result {
+            Ok(optimized) if cli_args.suggest_only => {
+                clear_progress_bar();
+                println!("\n--- Suggested Modifications ---");
+                if optimized.suggestions.is_empty() {
+                    println!("The optimizer had no modification to suggest.");
+                }
+                for (i, suggestion) in optimized.suggestions.iter().enumerate() {
+                    for modification in &suggestion.modifications {
+                        println!("{}. {}", i + 1, modification.describe());
+                    }
+                    for note in suggestion.reasoning_notes() {
+                        println!("     {}", note);
+                    }
+                }
+            }
+            Ok(optimized) => {
+                clear_progress_bar();
                 println!("\n--- Optimization Complete ---");
-                current_cleaned_recipe = optimized_recipe;
-                current_nutritional_profile = calculate_nutritional_profile(&current_cleaned_recipe);
+                print_optimization_diff(
+                    cli_args,
+                    (&current_cleaned_recipe, &current_nutritional_profile),
+                    (&optimized.recipe, &optimized.profile),
+                    &optimized.targets,
+                );
+                current_cleaned_recipe = optimized.recipe;
+                current_nutritional_profile = optimized.profile;
+                if !optimized.notes.is_empty() {
+                    println!("\nWhy the recipe changed:");
+                    for note in &optimized.notes {
+                        println!("  - {}", note);
+                    }
+                }
                 println!("Optimized Recipe Title: {}", current_cleaned_recipe.recipe_title);
                 println!("Optimized Nutritional Profile (Aggregated): {:#?}", current_nutritional_profile.aggregated); 
                 println!("Optimized Nutritional Profile (Per 100g): {:#?}", current_nutritional_profile.per_100g);
+                if let Some(per_serving) = &current_nutritional_profile.per_serving {
+                    println!("Optimized Nutritional Profile (Per Serving): {:#?}", per_serving);
+                }
+                print_energy_breakdown(&current_nutritional_profile);
+                print_nutrient_errors("Optimized values vs targets (per 100g)", &current_nutritional_profile.per_100g, &optimized.targets);
+                print_target_checks("Targeted nutrients", &optimized.target_checks);
                 
-                let optimized_output_data = EnrichedRecipeOutput {
-                    recipe_title: current_cleaned_recipe.recipe_title.clone(),
-                    ingredients: current_cleaned_recipe.ingredients.clone(),
-                    instructions: current_cleaned_recipe.instructions.clone(),
-                    nutritional_profile: current_nutritional_profile.clone(),
-                };
-                let optimized_json_output = serde_json::to_string_pretty(&optimized_output_data)
-                    .with_context(|| "Failed to serialize optimized recipe to JSON")?;
-                fs::write(&optimized_file_path, optimized_json_output)
-                    .await
+                let optimized_output_data = build_output(&current_cleaned_recipe, &current_nutritional_profile, &options)
+                    .with_optimization_notes(optimized.notes)
+                    .with_ingredient_order(options.ingredient_order);
+                optimized_output_data.save(&optimized_file_path)
                     .with_context(|| format!("Failed to write optimized recipe to JSON file: {:?}", optimized_file_path))?;
                 println!("\nOptimized recipe saved to '{}'", optimized_file_path.display());
+                write_csv_export(cli_args, &optimized_output_data)?;
+                write_recipe_text(cli_args, &optimized_output_data, &optimized_file_path)?;
 
             }
             Err(e) => {
+                clear_progress_bar();
                 eprintln!("\nRecipe optimization failed: {}", e);
                 println!("Proceeding with unoptimized recipe for final output (if it was processed).");
                 // If optimization failed, we still have current_cleaned_recipe and current_nutritional_profile
                 // which could be the initially loaded or processed one. We can save this to _enriched.json
                 // if it hasn't been saved yet (e.g. if optimization was the only goal).
                 if !enriched_file_path.exists() || needs_fresh_processing { // Save if it was freshly processed
-                    let output_data = EnrichedRecipeOutput {
-                        recipe_title: current_cleaned_recipe.recipe_title.clone(),
-                        ingredients: current_cleaned_recipe.ingredients.clone(),
-                        instructions: current_cleaned_recipe.instructions.clone(),
-                        nutritional_profile: current_nutritional_profile.clone(),
-                    };
-                    let json_output = serde_json::to_string_pretty(&output_data)
-                        .with_context(|| "Failed to serialize recipe to JSON after failed optimization")?;
-                    fs::write(&enriched_file_path, json_output)
-                        .await
+                    let output_data = build_output(&current_cleaned_recipe, &current_nutritional_profile, &options);
+                    output_data.save(&enriched_file_path)
                         .with_context(|| format!("Failed to write enriched recipe to JSON file after failed optimization: {:?}", enriched_file_path))?;
                     println!("\nUnoptimized (or initially processed) recipe saved to '{}'", enriched_file_path.display());
+                    let output_data = output_data.with_ingredient_order(options.ingredient_order);
+                    write_csv_export(cli_args, &output_data)?;
+                    write_recipe_text(cli_args, &output_data, &enriched_file_path)?;
                 }
             }
         }
     } else { // No optimization requested
-        let output_data = EnrichedRecipeOutput {
-            recipe_title: current_cleaned_recipe.recipe_title.clone(),
-            ingredients: current_cleaned_recipe.ingredients.clone(),
-            instructions: current_cleaned_recipe.instructions.clone(),
-            nutritional_profile: current_nutritional_profile.clone(),
-        };
-        let json_output = serde_json::to_string_pretty(&output_data)
-            .with_context(|| "Failed to serialize recipe to JSON")?;
-        fs::write(&enriched_file_path, json_output)
-            .await
+        print_energy_breakdown(&current_nutritional_profile);
+        // The enriched file is reused by later runs, so only what is shown is reordered.
+        let output_data = build_output(&current_cleaned_recipe, &current_nutritional_profile, &options);
+        output_data.save(&enriched_file_path)
             .with_context(|| format!("Failed to write enriched recipe to JSON file: {:?}", enriched_file_path))?;
         println!("\nEnriched recipe (unoptimized) saved to '{}'", enriched_file_path.display());
+        let output_data = output_data.with_ingredient_order(options.ingredient_order);
+        write_csv_export(cli_args, &output_data)?;
+        write_recipe_text(cli_args, &output_data, &enriched_file_path)?;
     }
-    
-    println!("\nSuccessfully processed recipe.");
 
+    println!("\nSuccessfully processed recipe.");
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok(); // Load .env file for API keys
+
+    let cli_args = parse_args();
+    set_verbosity(cli_args.verbosity());
+    if cli_args.list_nutrients {
+        print_nutrient_list();
+        return Ok(());
+    }
+    if let Some([before, after]) = cli_args.compare.as_deref() {
+        return compare_enriched_files(before, after);
+    }
+    cli_args.validate_recipe_files().map_err(|e| anyhow!(e))?;
+    if cli_args.interactive && cli_args.reads_from_stdin() {
+        return Err(anyhow!("--interactive needs stdin for match selection and cannot be combined with reading the recipe from stdin"));
+    }
+    if let Some(prompt_dir) = &cli_args.prompt_dir {
+        // Loaded here to report them and fail early; the stages get them through their options.
+        let overridden: Vec<&str> = prompt_templates(&cli_args)?.overridden().into_iter().map(PromptKind::file_name).collect();
+        if overridden.is_empty() {
+            log_warning!("[WARNING] No prompt template found in {:?}; using the built-in prompts.", prompt_dir);
+        } else {
+            log_info!("Using prompt templates from {:?}: {}", prompt_dir, overridden.join(", "));
+        }
+    }
+
+    let progress_format = cli_args.progress_format;
+    let progress_bar: Option<&'static ProgressBar> = (cli_args.wants_progress_bar()
+        && std::io::stdout().is_terminal()
+        && std::io::stderr().is_terminal())
+        .then(install_progress_bar);
+    let progress_callback = move |event: ProgressEvent| match (progress_format, progress_bar) {
+        (ProgressFormat::Text, Some(bar)) => bar.update(&event),
+        (ProgressFormat::Text, None) => { log_info!("{}", event); }
+        (ProgressFormat::Json, _) => eprintln!("{}", event.to_json_line()),
+    };
+
+    let client = ConcurrencyLimited::new(load_provider(&cli_args)?, cli_args.max_concurrent_requests);
+    if cli_args.check {
+        return check_provider(client.inner()).await;
+    }
+    // Built lazily, at most once per invocation.
+    let mut nutritional_index: Option<NutritionalIndex> = None;
+
+    #[cfg(feature = "server")]
+    if let Some(Command::Serve { addr }) = cli_args.command {
+        if cli_args.interactive {
+            return Err(anyhow!("--interactive needs stdin for match selection and cannot be combined with serve"));
+        }
+        ensure_nutritional_index(&cli_args, &mut nutritional_index)?;
+        let index = nutritional_index.take().expect("the nutritional index was just built");
+        let options = pipeline_options(&cli_args, cli_args.input_format.unwrap_or(InputFormat::Text))?;
+        return serve(addr, index, client, options).await;
+    }
+
+    if let Some(ingredient_name) = &cli_args.explain_match {
+        let index = ensure_nutritional_index(&cli_args, &mut nutritional_index)?;
+        print!("\n{}", index.explain_match(ingredient_name, &client).await?);
+        return Ok(());
+    }
+
+    if let (Some(ingredient_name), Some(goal)) = (&cli_args.substitute, cli_args.substitution_goal) {
+        let index = ensure_nutritional_index(&cli_args, &mut nutritional_index)?;
+        print!("\n{}", index.suggest_substitutions(ingredient_name, goal, SUBSTITUTION_COUNT)?);
+        return Ok(());
+    }
+
+    let result = if let Some(batch_dir) = &cli_args.batch {
+        process_batch(&cli_args, batch_dir, &client, &mut nutritional_index, progress_callback).await
+    } else {
+        let recipe_path = cli_args.recipe_file()
+            .filter(|file| *file != STDIN_RECIPE_FILE)
+            .map(Path::new);
+        process_recipe(&cli_args, recipe_path, &client, &mut nutritional_index, progress_callback).await
+    };
+    clear_progress_bar();
+    if let Some(hint) = result.as_ref().err()
+        .and_then(|e| e.chain().find_map(|cause| cause.downcast_ref::<ApiConnectionError>()))
+        .and_then(api_error_hint)
+    {
+        eprintln!("{}", hint);
+    }
+
+    let usage = total_usage();
+    println!(
+        "\nLLM usage: {} requests, {} prompt tokens, {} completion tokens, {} total tokens.",
+        usage.requests, usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+    );
+    if let Some(price) = cli_args.price_per_1k_tokens {
+        println!("Estimated cost: {:.4} (at {} per 1k tokens)", usage.estimated_cost(price), price);
+    }
+
+    result
+}
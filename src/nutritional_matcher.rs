@@ -1,16 +1,26 @@
-use anyhow::{Result, Context, anyhow};
+use anyhow::{Result, Context};
+use std::fmt;
+use std::io::{BufRead, Write};
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use serde::{Serialize, Deserialize}; // Added missing serde derives
 
-use crate::search::embedding_engine::{EmbeddingEngine, EMBEDDING_DIMENSION};
-use crate::search::ann_engine::AnnEngine;
-use crate::search::data_loader::load_ciqual_nutritional_data;
-use crate::recipe_converter::{CiqualFoodItem, CleanedIngredient, CalculatedNutritionalInfo};
+use crate::{log_info, log_verbose, log_warning};
+use crate::progress::{ProgressEvent, ProgressStage};
+use crate::prompts::{PromptKind, PromptTemplates};
+use crate::search::embedding_engine::EmbeddingEngine;
+use crate::search::ann_engine::{AnnEngine, AnnMatch};
+use crate::search::data_loader::{load_nutritional_data, ColumnMapping};
+use crate::search::open_food_facts::OpenFoodFacts;
+use crate::recipe_converter::{FoodItem, CleanedIngredient, CleanedRecipe, CalculatedNutritionalInfo, MatchMethod};
+use crate::recipe_parser::normalize_ingredient_name;
+use crate::api_connection::json_extract::extract_json_object;
 use crate::api_connection::endpoints::{
-    ChatCompletionRequest, ChatMessage, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
-    ResponseFormat, Provider,
+    ChatCompletionRequest, ChatMessage, GenParams, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
+    ResponseFormat, ResponseFormatMode,
 };
+use crate::api_connection::client::{complete_untruncated, ChatClient, TRUNCATION_RETRIES};
+use crate::api_connection::usage::log_stage_usage;
 // ApiConnectionError is not directly used, but might be relevant if we add more specific error handling
 // use crate::api_connection::connection::ApiConnectionError; 
 
@@ -21,7 +31,7 @@ struct DisambiguationResponse {
 }
 
 fn get_disambiguation_json_schema(candidate_count: usize) -> JsonSchemaDefinition {
-    let mut properties_map = HashMap::new();
+    let mut properties_map = BTreeMap::new();
     properties_map.insert(
         "best_match_index".to_string(),
         JsonSchemaProperty {
@@ -47,36 +57,611 @@ fn get_disambiguation_json_schema(candidate_count: usize) -> JsonSchemaDefinitio
     }
 }
 
+/// Batched disambiguation answer: one candidate index per ingredient, in prompt order.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct BatchDisambiguationResponse {
+    best_match_indices: Vec<i32>,
+}
+
+fn get_batch_disambiguation_json_schema(ingredient_count: usize) -> JsonSchemaDefinition {
+    let mut properties_map = BTreeMap::new();
+    properties_map.insert(
+        "best_match_indices".to_string(),
+        JsonSchemaProperty {
+            property_type: "array".to_string(),
+            description: Some(format!(
+                "Exactly {} integers, one per ingredient in the given order: the 1-based index of that ingredient's best matching candidate, or 0 if none is a good match.",
+                ingredient_count
+            )),
+            r#enum: None,
+            items: Some(Box::new(JsonSchema {
+                schema_type: "integer".to_string(),
+                properties: None,
+                required: None,
+                additional_properties: None,
+            })),
+        },
+    );
+
+    JsonSchemaDefinition {
+        name: "batch_disambiguation_schema".to_string(),
+        strict: Some(true),
+        schema: JsonSchema {
+            schema_type: "object".to_string(),
+            properties: Some(properties_map),
+            required: Some(vec!["best_match_indices".to_string()]),
+            additional_properties: Some(false),
+        },
+    }
+}
+
+/// ANN candidates offered to the disambiguation step for each ingredient.
+const CANDIDATE_COUNT: usize = 10;
+/// Ingredients disambiguated per LLM request by `find_and_calculate_nutrition_batch`.
+pub const DISAMBIGUATION_BATCH_SIZE: usize = 8;
+
+/// Built-in guidelines opening the disambiguation system prompts; see `PromptKind::MatcherGuidelines`.
+pub const DISAMBIGUATION_GUIDELINES: &str = "/no_thinking
+You are a food item matching assistant. Your task is to choose the best match for a given recipe ingredient from a list of candidate food items from a nutritional database.
+Consider the ingredient name and any preparation notes.
+**Crucially, pay close attention to the form of the user's ingredient (e.g., if it's a 'flour', a 'powder', a 'whole raw' item, a 'cooked' item, a 'liquid', 'puree', etc.) and strongly prefer CIQUAL candidates that match this specific form.**
+For example, if the user ingredient is 'wheat flour', prefer candidates like 'Wheat flour, type X' over 'Wheat, whole, raw'. If the user ingredient is 'apple puree', prefer 'Fruits puree, apple' over 'Apple, raw'.
+If the user ingredient mentions a specific state like 'cooked' or 'raw', try to match that state.";
+
+/// Built-in user prompt of single-ingredient disambiguation; see `PromptKind::MatcherUser`.
+pub const DISAMBIGUATION_USER_PROMPT: &str = "Recipe Ingredient: \"{ingredient_name}\"
+Preparation Notes: \"{preparation_notes}\"
+
+Candidate Nutritional Database Items:
+{candidates}
+Which candidate item (by number, 1 to {candidate_count}) is the best semantic and form-based match for the recipe ingredient?
+If none are a good match, respond with 0.";
+
+/// Built-in user prompt of batched disambiguation; see `PromptKind::MatcherBatchUser`.
+/// `{ingredients}` holds one numbered block per ingredient, each with its candidate list.
+pub const BATCH_DISAMBIGUATION_USER_PROMPT: &str = "{ingredients}For each of the {count} ingredients, which candidate item is the best semantic and form-based match? Use 0 where none is a good match.";
+
+/// A food item proposed by the ANN search, with its similarity score.
+type Candidate<'a> = (&'a FoodItem, f32);
+
+/// Puts candidates in the order they are numbered for the LLM: highest similarity first, equal
+/// similarities by database row. The LLM answers with a number, so the same ingredient must get
+/// the same numbering from the same index on every run, whatever order the vector store kept
+/// tied items in.
+fn order_candidates(candidates: &mut [Candidate]) {
+    candidates.sort_by(|(a, a_score), (b, b_score)| {
+        b_score.total_cmp(a_score)
+            .then_with(|| a.original_row_index.cmp(&b.original_row_index))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// The food item an ingredient is matched to, with how it was chosen and, for an ANN candidate,
+/// its similarity.
+#[derive(Debug, Clone, Copy)]
+struct ChosenItem<'a> {
+    item: &'a FoodItem,
+    method: MatchMethod,
+    similarity: Option<f32>,
+}
+
+impl<'a> ChosenItem<'a> {
+    /// `item`, one of `candidates`, chosen by `method`.
+    fn from_candidates(item: &'a FoodItem, candidates: &[Candidate<'a>], method: MatchMethod) -> Self {
+        let similarity = candidates.iter().find(|(candidate, _)| std::ptr::eq(*candidate, item)).map(|&(_, score)| score);
+        ChosenItem { item, method, similarity }
+    }
+
+    fn without_similarity(item: &'a FoodItem, method: MatchMethod) -> Self {
+        ChosenItem { item, method, similarity: None }
+    }
+}
+
+/// When the top ANN candidate is taken as the match without asking the LLM: its similarity must
+/// reach `min_similarity` and lead the runner-up's by at least `min_margin`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoAccept {
+    pub min_similarity: f32,
+    pub min_margin: f32,
+}
+
+impl AutoAccept {
+    pub const DEFAULT_MARGIN: f32 = 0.05;
+
+    /// The best candidate when it passes both thresholds. Candidates come sorted by similarity;
+    /// a lone candidate is compared against a runner-up of similarity 0.
+    fn accept<'a>(&self, candidates: &[Candidate<'a>]) -> Option<Candidate<'a>> {
+        let &(best, best_score) = candidates.first()?;
+        let runner_up_score = candidates.get(1).map_or(0.0, |&(_, score)| score);
+        (best_score >= self.min_similarity && best_score - runner_up_score >= self.min_margin)
+            .then_some((best, best_score - runner_up_score))
+    }
+}
+
+fn candidate_labels(candidates: &[Candidate]) -> Vec<String> {
+    candidates.iter()
+        .map(|(item, score)| format!("\"{}\" (similarity {:.2})", item.name, score))
+        .collect()
+}
+
+/// Numbered candidate lines, as shown to the LLM.
+fn candidate_prompt_list(candidates: &[Candidate]) -> String {
+    candidate_labels(candidates).iter().enumerate()
+        .map(|(i, label)| format!("{}. {}\n", i + 1, label))
+        .collect()
+}
+
+/// Candidate for a 1-based index; 0 and out-of-range indices mean no match.
+fn candidate_at<'a>(candidates: &[Candidate<'a>], index: i32) -> Option<&'a FoodItem> {
+    let index = usize::try_from(index).ok()?.checked_sub(1)?;
+    candidates.get(index).map(|(item, _)| *item)
+}
+
+/// Candidate indices of a batched disambiguation answer, or `None` when it doesn't parse or
+/// doesn't have exactly one index per ingredient.
+fn parse_batch_choices(content: &str, ingredient_count: usize) -> Option<Vec<i32>> {
+    let response: BatchDisambiguationResponse = serde_json::from_str(content).ok()?;
+    (response.best_match_indices.len() == ingredient_count).then_some(response.best_match_indices)
+}
+
+fn choose_interactively<'a>(ingredient: &CleanedIngredient, candidates: &[Candidate<'a>]) -> Result<Option<&'a FoodItem>> {
+    let labels = candidate_labels(candidates);
+    let candidate_names: Vec<&str> = labels.iter().map(String::as_str).collect();
+    let choice = read_candidate_choice(ingredient, &candidate_names, &mut std::io::stdin().lock(), &mut std::io::stdout())
+        .with_context(|| format!("Failed to read the match selection for '{}'", ingredient.ingredient_name))?;
+    Ok(choice.map(|idx| candidates[idx].0))
+}
+
+fn batch_disambiguation_request(
+    ingredients: &[CleanedIngredient],
+    batch: &[(usize, Vec<Candidate>)],
+    gen_params: GenParams,
+    response_format: ResponseFormatMode,
+    prompts: &PromptTemplates,
+) -> ChatCompletionRequest {
+    let system_prompt = format!("{}
+You will be given several recipe ingredients, each with its own numbered candidate list.
+
+Respond ONLY with a JSON object strictly adhering to the provided schema: {{ \"best_match_indices\": [number, ...] }}
+Give exactly one number per ingredient, in the order the ingredients are listed: the 1-based index of the chosen candidate in that ingredient's own list, or 0 if none of its candidates is a good match.",
+        prompts.render(PromptKind::MatcherGuidelines, &[])
+    );
+
+    let mut ingredient_blocks = String::new();
+    for (position, (idx, candidates)) in batch.iter().enumerate() {
+        let ingredient = &ingredients[*idx];
+        ingredient_blocks.push_str(&format!(
+            "Ingredient {}: \"{}\"\nPreparation Notes: \"{}\"\nCandidate Nutritional Database Items:\n{}\n",
+            position + 1,
+            ingredient.ingredient_name,
+            ingredient.preparation_notes,
+            candidate_prompt_list(candidates)
+        ));
+    }
+    let user_prompt = prompts.render(PromptKind::MatcherBatchUser, &[
+        ("ingredients", &ingredient_blocks),
+        ("count", &batch.len().to_string()),
+    ]);
+
+    ChatCompletionRequest {
+        model: "qwen/qwen3-32b".to_string(),
+        messages: vec![
+            ChatMessage { role: "system".to_string(), content: system_prompt },
+            ChatMessage { role: "user".to_string(), content: user_prompt },
+        ],
+        response_format: Some(ResponseFormat::for_schema(get_batch_disambiguation_json_schema(batch.len()), response_format)),
+        temperature: Some(gen_params.temperature),
+        max_tokens: Some(gen_params.max_tokens + 10 * batch.len() as u32),
+    }
+}
+
+/// Sends a disambiguation request and returns the answer as received, or the failure, which is
+/// also reported.
+async fn request_disambiguation(
+    request: ChatCompletionRequest,
+    client: &impl ChatClient,
+    progress_updater: &impl Fn(ProgressEvent),
+) -> Result<String, String> {
+    match complete_untruncated(client, request, TRUNCATION_RETRIES).await {
+        Ok(response) => {
+            log_stage_usage("matching", response.usage.as_ref());
+            if let Some(choice) = response.choices.first() {
+                Ok(choice.message.content.clone())
+            } else {
+                progress_updater("   -> LLM returned no choice for disambiguation.".into());
+                Err("The LLM returned no choice.".to_string())
+            }
+        }
+        Err(e) => {
+            progress_updater(ProgressEvent::Warning { text: format!("   -> API call for LLM disambiguation failed: {}", e) });
+            Err(format!("Disambiguation request failed: {}", e))
+        }
+    }
+}
+
+/// The JSON object of a disambiguation answer, without the prose or fences around it.
+fn json_part(raw_content: &str) -> &str {
+    let trimmed = raw_content.trim();
+    extract_json_object(trimmed).unwrap_or(trimmed)
+}
+
+/// Every step of the match of one ingredient, as printed by `--explain-match`.
+#[derive(Debug, Clone)]
+pub struct MatchExplanation {
+    pub ingredient_name: String,
+    /// The text actually embedded, see `normalize_ingredient_name`.
+    pub normalized_query: String,
+    /// Food item from the overrides file; when set, no other step runs.
+    pub override_item: Option<String>,
+    /// ANN candidates with their similarity, best first.
+    pub candidates: Vec<(String, f32)>,
+    pub auto_accepted: bool,
+    /// Disambiguation request sent to the LLM, if any.
+    pub request: Option<ChatCompletionRequest>,
+    /// The LLM's answer as received, before JSON extraction.
+    pub raw_response: Option<String>,
+    pub error: Option<String>,
+    pub chosen_item: Option<String>,
+}
+
+impl MatchExplanation {
+    /// An explanation with no step recorded yet.
+    pub fn new(ingredient_name: &str) -> Self {
+        MatchExplanation {
+            ingredient_name: ingredient_name.to_string(),
+            normalized_query: normalize_ingredient_name(ingredient_name),
+            override_item: None,
+            candidates: Vec::new(),
+            auto_accepted: false,
+            request: None,
+            raw_response: None,
+            error: None,
+            chosen_item: None,
+        }
+    }
+}
+
+impl fmt::Display for MatchExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Ingredient: \"{}\"", self.ingredient_name)?;
+        writeln!(f, "Normalized query: \"{}\"", self.normalized_query)?;
+        if let Some(item) = &self.override_item {
+            return writeln!(f, "Override: \"{}\" (no search or LLM involved)", item);
+        }
+        writeln!(f, "\nTop {} ANN candidates:", self.candidates.len())?;
+        for (i, (name, score)) in self.candidates.iter().enumerate() {
+            writeln!(f, "  {}. \"{}\" (similarity {:.4})", i + 1, name, score)?;
+        }
+        if self.auto_accepted {
+            writeln!(f, "\nAuto-accepted the top candidate; no LLM request.")?;
+        }
+        if let Some(request) = &self.request {
+            for message in &request.messages {
+                writeln!(f, "\n--- Disambiguation prompt ({}) ---\n{}", message.role, message.content.trim())?;
+            }
+        }
+        if let Some(raw_response) = &self.raw_response {
+            writeln!(f, "\n--- Raw LLM response ---\n{}", raw_response)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "\nError: {}", error)?;
+        }
+        match &self.chosen_item {
+            Some(item) => writeln!(f, "\nChosen: \"{}\"", item),
+            None => writeln!(f, "\nChosen: no match"),
+        }
+    }
+}
+
+/// One row of the match audit of a recipe: the food item each ingredient got, and how.
+/// An ingredient without `matched_item` is unmatched, or had no grams to scale nutrition by.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MatchReportEntry {
+    pub ingredient: String,
+    pub grams: Option<f32>,
+    pub matched_item: Option<String>,
+    pub similarity: Option<f32>,
+    /// Missing for unmatched ingredients and for files enriched before methods were recorded.
+    pub method: Option<MatchMethod>,
+}
+
+impl MatchReportEntry {
+    fn status(&self) -> String {
+        match (&self.matched_item, self.method) {
+            (Some(_), Some(method)) => method.to_string(),
+            (Some(_), None) => "unknown".to_string(),
+            (None, _) if self.grams.is_none() => "no grams".to_string(),
+            (None, _) => "unmatched".to_string(),
+        }
+    }
+}
+
+/// The match audit of every ingredient of `recipe`, in recipe order.
+pub fn match_report(recipe: &CleanedRecipe) -> Vec<MatchReportEntry> {
+    recipe.ingredients.iter()
+        .map(|ingredient| {
+            let info = ingredient.nutritional_info.as_ref();
+            MatchReportEntry {
+                ingredient: ingredient.ingredient_name.clone(),
+                grams: ingredient.quantity_grams,
+                matched_item: info.map(|info| info.source_ciqual_name.clone()),
+                similarity: info.and_then(|info| info.match_similarity),
+                method: info.and_then(|info| info.match_method),
+            }
+        })
+        .collect()
+}
+
+/// The match audit as an aligned table, one ingredient per line.
+pub fn format_match_report(entries: &[MatchReportEntry]) -> String {
+    let width = entries.iter().map(|entry| entry.ingredient.chars().count()).max().unwrap_or(0).max("Ingredient".len());
+    let mut table = format!("{:<width$}  {:<10}  {:<15}  Matched item\n", "Ingredient", "Similarity", "Method", width = width);
+    for entry in entries {
+        let similarity = entry.similarity.map_or("-".to_string(), |score| format!("{:.2}", score));
+        table.push_str(&format!(
+            "{:<width$}  {:<10}  {:<15}  {}\n",
+            entry.ingredient, similarity, entry.status(), entry.matched_item.as_deref().unwrap_or("-"), width = width
+        ));
+    }
+    table
+}
+
+/// ANN neighbours of an ingredient considered by `suggest_substitutions`.
+const SUBSTITUTION_CANDIDATE_COUNT: usize = 30;
+/// Substitutes listed by `--substitute`.
+pub const SUBSTITUTION_COUNT: usize = 5;
+/// Share of a substitute's score given to the nutrient improvement, the rest going to similarity.
+const SUBSTITUTION_IMPROVEMENT_WEIGHT: f32 = 0.5;
+
+/// What a substitute of `--substitute` should improve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SubstitutionGoal {
+    FatReduction,
+    SaturatedFatReduction,
+    SugarReduction,
+    SaltReduction,
+    CalorieReduction,
+    ProteinIncrease,
+    FiberIncrease,
+}
+
+impl SubstitutionGoal {
+    /// The nutrient the goal is about, with its unit per 100 g.
+    pub fn nutrient(self) -> (&'static str, &'static str) {
+        match self {
+            SubstitutionGoal::FatReduction => ("fat", "g"),
+            SubstitutionGoal::SaturatedFatReduction => ("saturated fat", "g"),
+            SubstitutionGoal::SugarReduction => ("sugars", "g"),
+            SubstitutionGoal::SaltReduction => ("salt", "g"),
+            SubstitutionGoal::CalorieReduction => ("energy", "kcal"),
+            SubstitutionGoal::ProteinIncrease => ("protein", "g"),
+            SubstitutionGoal::FiberIncrease => ("fiber", "g"),
+        }
+    }
+
+    fn lower_is_better(self) -> bool {
+        !matches!(self, SubstitutionGoal::ProteinIncrease | SubstitutionGoal::FiberIncrease)
+    }
+
+    fn value_per_100g(self, item: &FoodItem) -> Option<f32> {
+        match self {
+            SubstitutionGoal::FatReduction => item.fat_g_per_100g,
+            SubstitutionGoal::SaturatedFatReduction => item.fa_saturated_g_per_100g,
+            SubstitutionGoal::SugarReduction => item.sugars_g_per_100g,
+            SubstitutionGoal::SaltReduction => item.salt_g_per_100g,
+            SubstitutionGoal::CalorieReduction => item.kcal_per_100g,
+            SubstitutionGoal::ProteinIncrease => item.protein_g_per_100g,
+            SubstitutionGoal::FiberIncrease => item.fiber_g_per_100g,
+        }
+    }
+
+    /// Relative improvement of `value` over `reference`, in (0, 1]; `None` when it isn't better.
+    /// Increases count as a full improvement from doubling on, or from any amount over zero.
+    fn improvement(self, reference: f32, value: f32) -> Option<f32> {
+        let improvement = if self.lower_is_better() {
+            if reference <= 0.0 { 0.0 } else { (reference - value) / reference }
+        } else if reference <= 0.0 {
+            if value > 0.0 { 1.0 } else { 0.0 }
+        } else {
+            ((value - reference) / reference).min(1.0)
+        };
+        (improvement > 0.0).then_some(improvement)
+    }
+}
+
+/// A food item proposed in place of an ingredient by `suggest_substitutions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Substitution {
+    pub item_name: String,
+    /// Embedding similarity to the ingredient.
+    pub similarity: f32,
+    /// The goal's nutrient, per 100 g.
+    pub value_per_100g: f32,
+    /// Relative improvement over the ingredient's own item, in (0, 1].
+    pub improvement: f32,
+    /// Ranking score, combining similarity and improvement.
+    pub score: f32,
+}
+
+/// The substitutes of one ingredient for a goal, as printed by `--substitute`.
+#[derive(Debug, Clone)]
+pub struct SubstitutionSuggestions {
+    pub ingredient_name: String,
+    pub goal: SubstitutionGoal,
+    /// The food item the ingredient itself matches: its override, or else its nearest item.
+    pub reference_item: String,
+    /// The goal's nutrient in `reference_item`, per 100 g; without it nothing can be compared.
+    pub reference_value_per_100g: Option<f32>,
+    /// Best score first.
+    pub substitutions: Vec<Substitution>,
+}
+
+impl fmt::Display for SubstitutionSuggestions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (nutrient, unit) = self.goal.nutrient();
+        let direction = if self.goal.lower_is_better() { "less" } else { "more" };
+        let Some(reference_value) = self.reference_value_per_100g else {
+            return writeln!(f, "\"{}\" matches \"{}\", which has no {} value to compare substitutes with.", self.ingredient_name, self.reference_item, nutrient);
+        };
+        writeln!(
+            f,
+            "\"{}\" matches \"{}\" ({:.1} {} {} per 100 g). Similar items with {} {}:",
+            self.ingredient_name, self.reference_item, reference_value, unit, nutrient, direction, nutrient
+        )?;
+        if self.substitutions.is_empty() {
+            return writeln!(f, "  none found among its nearest items.");
+        }
+        for (i, substitution) in self.substitutions.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}. \"{}\": {:.1} {} ({:.0}% {}), similarity {:.2}, score {:.2}",
+                i + 1,
+                substitution.item_name,
+                substitution.value_per_100g,
+                unit,
+                substitution.improvement * 100.0,
+                if self.goal.lower_is_better() { "lower" } else { "higher" },
+                substitution.similarity,
+                substitution.score
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An ingredient known only by its name, for the lookups of a single name.
+fn bare_ingredient(ingredient_name: &str) -> CleanedIngredient {
+    CleanedIngredient {
+        raw_text: ingredient_name.to_string(),
+        ingredient_name: ingredient_name.to_string(),
+        original_quantity: String::new(),
+        original_unit: String::new(),
+        preparation_notes: String::new(),
+        quantity_grams: None,
+        conversion_source: String::new(),
+        conversion_notes: None,
+        nutritional_info: None,
+        section: None,
+        optional: false,
+    }
+}
+
+/// Reads an override file: a JSON object mapping ingredient names to exact food item names.
+pub fn load_overrides(path: &Path) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read overrides file {:?}", path))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Overrides file {:?} must be a JSON object of ingredient name -> food item name", path))
+}
+
+/// Maps each overridden ingredient (normalized) to the index of its food item.
+/// Every target must name an item of the database exactly, so typos fail loudly instead of silently falling back.
+fn resolve_overrides(overrides: &HashMap<String, String>, food_data: &[FoodItem]) -> Result<HashMap<String, usize>> {
+    overrides.iter()
+        .map(|(ingredient_name, item_name)| {
+            let item_idx = food_data.iter()
+                .position(|item| &item.name == item_name)
+                .ok_or_else(|| anyhow::anyhow!("Override for '{}' targets unknown food item '{}'", ingredient_name, item_name))?;
+            Ok((normalize_ingredient_name(ingredient_name), item_idx))
+        })
+        .collect()
+}
+
+/// Asks the user to pick one of `candidate_names` (1-based), or 0 to reject them all.
+/// Invalid answers are asked again; end of input counts as a rejection.
+fn read_candidate_choice(
+    ingredient: &CleanedIngredient,
+    candidate_names: &[&str],
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<Option<usize>> {
+    writeln!(output, "\nSelect the database item matching '{}' (preparation: \"{}\"):", ingredient.ingredient_name, ingredient.preparation_notes)?;
+    for (i, name) in candidate_names.iter().enumerate() {
+        writeln!(output, "  {}. {}", i + 1, name)?;
+    }
+    writeln!(output, "  0. None of these")?;
+
+    loop {
+        write!(output, "Choice [0-{}]: ", candidate_names.len())?;
+        output.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(0) => return Ok(None),
+            Ok(choice) if choice <= candidate_names.len() => return Ok(Some(choice - 1)),
+            _ => writeln!(output, "Please enter a number between 0 and {}.", candidate_names.len())?,
+        }
+    }
+}
+
+// Metadata stored with each vector, identifying the food item independently of its ANN ID.
+const FIELD_ORIGINAL_ROW_INDEX: &str = "original_row_index";
+const FIELD_NAME: &str = "name";
+
 pub struct NutritionalIndex {
     embedding_engine: EmbeddingEngine,
     ann_engine: AnnEngine,
-    ciqual_data: Vec<CiqualFoodItem>, // Stores all loaded Ciqual items
+    ciqual_data: Vec<FoodItem>, // Stores all loaded food items, whatever the source database
+    row_to_item: HashMap<usize, usize>, // original_row_index -> index into ciqual_data
+    overrides: HashMap<String, usize>, // Normalized ingredient name -> index into ciqual_data
+    interactive: bool, // Ask the user on stdin instead of the LLM to disambiguate candidates
+    online_fallback: Option<OpenFoodFacts>, // Looked up when the database has no match
+    disambiguation_params: GenParams,
+    response_format: ResponseFormatMode,
+    prompts: PromptTemplates,
+    auto_accept: Option<AutoAccept>, // Skips disambiguation for clear-cut ANN matches
 }
 
 impl NutritionalIndex {
-    pub fn new(ciqual_csv_path: &Path, _api_key_env_var: &str) -> Result<Self> {
-        println!("Initializing NutritionalIndex...");
-        println!(" > Loading Ciqual nutritional data from {:?}...", ciqual_csv_path);
-        let ciqual_data = load_ciqual_nutritional_data(ciqual_csv_path)
-            .with_context(|| format!("Failed to load Ciqual data from {:?}", ciqual_csv_path))?;
-        println!(" > Ciqual data loaded: {} items.", ciqual_data.len());
-
-        println!(" > Initializing embedding engine...");
-        let embedding_engine = EmbeddingEngine::new()
+    pub fn new(
+        csv_path: &Path,
+        mapping: &ColumnMapping,
+        index_path: &Path,
+        embedding_model: Option<&str>,
+        hf_token: Option<&str>,
+        rebuild_stale_index: bool,
+    ) -> Result<Self> {
+        log_info!("Initializing NutritionalIndex...");
+        log_verbose!(" > Loading {} nutritional data from {:?}...", mapping.database, csv_path);
+        let ciqual_data = load_nutritional_data(csv_path, mapping)
+            .with_context(|| format!("Failed to load {} data from {:?}", mapping.database, csv_path))?;
+        log_verbose!(" > {} data loaded: {} items.", mapping.database, ciqual_data.len());
+
+        log_verbose!(" > Initializing embedding engine...");
+        let embedding_engine = EmbeddingEngine::new(embedding_model, hf_token)
             .with_context(|| "Failed to initialize embedding engine")?;
-        
+        let dimension = embedding_engine.dimension();
+        log_verbose!(" > Embedding model '{}' dimension: {}", embedding_engine.model_id(), dimension);
+
+        log_verbose!(" > Initializing ANN engine with dimension {} at {:?}...", dimension, index_path);
+        let ann_engine = if rebuild_stale_index {
+            AnnEngine::new_rebuilding_stale(dimension, index_path)
+        } else {
+            AnnEngine::new(dimension, index_path)
+        };
+        let ann_engine = ann_engine.with_context(|| "Failed to initialize AnnEngine")?;
+        Self::from_food_items(ciqual_data, embedding_engine, ann_engine)
+    }
+
+    /// Builds the index from food items already in memory, embedding their names with
+    /// `embedding_engine` into `ann_engine`. With `EmbeddingEngine::from_embeddings` and
+    /// `AnnEngine::new_in_memory` this needs no model download, CSV or index file.
+    pub fn from_food_items(ciqual_data: Vec<FoodItem>, embedding_engine: EmbeddingEngine, mut ann_engine: AnnEngine) -> Result<Self> {
+        let dimension = embedding_engine.dimension();
         let food_names: Vec<String> = ciqual_data.iter().map(|item| item.name.clone()).collect();
-        println!(" > Generating embeddings for {} Ciqual food names...", food_names.len());
+        log_verbose!(" > Generating embeddings for {} Ciqual food names...", food_names.len());
         let embeddings = embedding_engine.embed(&food_names)
             .with_context(|| "Failed to generate embeddings for Ciqual food names")?;
-        println!(" > Embeddings generated. Count: {}", embeddings.len());
+        log_verbose!(" > Embeddings generated. Count: {}", embeddings.len());
 
         if embeddings.is_empty() {
             return Err(anyhow::anyhow!("No embeddings were generated for Ciqual food names."));
         }
-        println!(" > Inspecting generated embeddings (first few and overall checks)...");
+        log_verbose!(" > Inspecting generated embeddings (first few and overall checks)...");
         for (i, emb) in embeddings.iter().enumerate().take(3) { 
-            println!("   - Embedding {} (first 5 dims): {:?}", i, emb.iter().take(5).collect::<Vec<_>>());
+            log_verbose!("   - Embedding {} (first 5 dims): {:?}", i, emb.iter().take(5).collect::<Vec<_>>());
         }
 
         let mut found_nan_inf = false;
@@ -84,16 +669,16 @@ impl NutritionalIndex {
         let mut found_wrong_dimension = false;
 
         for (idx, emb) in embeddings.iter().enumerate() {
-            if emb.len() != EMBEDDING_DIMENSION {
-                eprintln!("[ERROR] Embedding at index {} has incorrect dimension: {}. Expected: {}", idx, emb.len(), EMBEDDING_DIMENSION);
+            if emb.len() != dimension {
+                log_warning!("[ERROR] Embedding at index {} has incorrect dimension: {}. Expected: {}", idx, emb.len(), dimension);
                 found_wrong_dimension = true;
             }
             if emb.iter().any(|val| val.is_nan() || val.is_infinite()) {
-                eprintln!("[ERROR] Embedding at index {} contains NaN or Infinity.", idx);
+                log_warning!("[ERROR] Embedding at index {} contains NaN or Infinity.", idx);
                 found_nan_inf = true;
             }
             if emb.iter().all(|&val| val == 0.0) {
-                eprintln!("[WARNING] Embedding at index {} is an all-zero vector.", idx);
+                log_warning!("[WARNING] Embedding at index {} is an all-zero vector.", idx);
                 found_zero_vector = true; 
             }
         }
@@ -105,7 +690,7 @@ impl NutritionalIndex {
             return Err(anyhow::anyhow!("One or more embeddings contained NaN or Infinity. Cannot proceed."));
         }
         if found_zero_vector {
-            println!("[INFO] Found one or more all-zero vectors. This might affect ANN performance or stability.");
+            log_info!("[INFO] Found one or more all-zero vectors. This might affect ANN performance or stability.");
         }
         
         let mut unique_embeddings = std::collections::HashSet::new();
@@ -117,162 +702,472 @@ impl NutritionalIndex {
             }
         }
         if duplicate_count > 0 {
-            println!("[WARNING] Found {} duplicate embeddings out of {}. This might impact HNSW construction.", duplicate_count, embeddings.len());
+            log_warning!("[WARNING] Found {} duplicate embeddings out of {}. This might impact HNSW construction.", duplicate_count, embeddings.len());
         }
-        println!(" > Embedding inspection complete.");
-
-        println!(" > Initializing ANN engine with dimension {}...", EMBEDDING_DIMENSION);
-        let mut ann_engine = AnnEngine::new(EMBEDDING_DIMENSION)
-            .with_context(|| "Failed to initialize AnnEngine")?; 
+        log_verbose!(" > Embedding inspection complete.");
         
-        let string_ann_ids: Vec<String> = (0..embeddings.len()).map(|i| i.to_string()).collect();
+        let string_ann_ids: Vec<String> = ciqual_data.iter().map(|item| item.original_row_index.to_string()).collect();
+        let item_fields: Vec<HashMap<String, serde_json::Value>> = ciqual_data.iter()
+            .map(|item| HashMap::from([
+                (FIELD_ORIGINAL_ROW_INDEX.to_string(), serde_json::json!(item.original_row_index)),
+                (FIELD_NAME.to_string(), serde_json::json!(item.name)),
+            ]))
+            .collect();
 
-        println!(" > Adding {} embeddings to ANN engine with sequential IDs (0 to {})...", embeddings.len(), embeddings.len().saturating_sub(1));
-        ann_engine.add_items_batch(&embeddings, &string_ann_ids)
-             .with_context(|| "Failed to add Ciqual embeddings to ANN engine")?;
+        log_verbose!(" > Adding {} embeddings to ANN engine keyed by source row...", embeddings.len());
+        ann_engine.add_items_batch_with_fields(&embeddings, &string_ann_ids, item_fields)
+             .with_context(|| "Failed to add food item embeddings to ANN engine")?;
+        let row_to_item = ciqual_data.iter().enumerate()
+            .map(|(idx, item)| (item.original_row_index, idx))
+            .collect();
         
-        println!(" > Building ANN index (no-op for NanoVectorDB)...");
+        log_verbose!(" > Building ANN index (no-op for NanoVectorDB)...");
         ann_engine.build_index().with_context(|| "Failed to build ANN index (should be no-op)")?;
-        println!(" > ANN items processed. Item count: {}", ann_engine.item_count());
+        log_verbose!(" > ANN items processed. Item count: {}", ann_engine.item_count());
 
-        println!("NutritionalIndex initialized successfully.");
+        log_info!("NutritionalIndex initialized successfully.");
         Ok(Self {
             embedding_engine,
             ann_engine, 
             ciqual_data,
+            row_to_item,
+            overrides: HashMap::new(),
+            interactive: false,
+            online_fallback: None,
+            disambiguation_params: GenParams::DISAMBIGUATION,
+            response_format: ResponseFormatMode::default(),
+            prompts: PromptTemplates::default(),
+            auto_accept: None,
         })
     }
 
+    /// Lets the user choose among the ANN candidates on stdin instead of calling the disambiguation LLM.
+    pub fn with_interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    /// Enables or disables caching of ingredient-name embeddings across lookups (enabled by default).
+    pub fn with_embedding_cache(mut self, enabled: bool) -> Self {
+        self.embedding_engine = self.embedding_engine.with_cache(enabled);
+        self
+    }
+
+    /// Looks ingredients the database can't match up on Open Food Facts (disabled by default).
+    pub fn with_online_fallback(mut self, enabled: bool) -> Result<Self> {
+        self.online_fallback = if enabled { Some(OpenFoodFacts::new()?) } else { None };
+        Ok(self)
+    }
+
+    /// Sampling settings of the disambiguation requests (`GenParams::DISAMBIGUATION` by default).
+    pub fn with_disambiguation_params(mut self, gen_params: GenParams) -> Self {
+        self.disambiguation_params = gen_params;
+        self
+    }
+
+    /// How the disambiguation requests ask for their answer's schema (`json_schema` by default).
+    pub fn with_response_format(mut self, response_format: ResponseFormatMode) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    /// Templates of the disambiguation prompts (the built-in ones by default).
+    pub fn with_prompts(mut self, prompts: PromptTemplates) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Takes a clear-cut top ANN candidate as the match without disambiguating it (disabled by
+    /// default). Interactive matching always asks.
+    pub fn with_auto_accept(mut self, auto_accept: Option<AutoAccept>) -> Self {
+        self.auto_accept = auto_accept;
+        self
+    }
+
+    /// Installs user-provided ingredient -> food item overrides, consulted before any matching.
+    pub fn with_overrides(mut self, overrides: &HashMap<String, String>) -> Result<Self> {
+        self.overrides = resolve_overrides(overrides, &self.ciqual_data)?;
+        Ok(self)
+    }
+
+    /// Matches the ingredient to a food item and scales the item's nutrition to its grams. Each
+    /// step of the match is recorded in `explanation` when one is given.
     pub async fn find_and_calculate_nutrition(
         &self,
         ingredient: &CleanedIngredient,
-        api_key_env_var: &str, 
-        progress_updater: &impl Fn(String),
+        client: &impl ChatClient, 
+        progress_updater: &impl Fn(ProgressEvent),
+        mut explanation: Option<&mut MatchExplanation>,
     ) -> Result<Option<CalculatedNutritionalInfo>> {
-        progress_updater(format!("   -> Matching ingredient: '{}'", ingredient.ingredient_name));
+        progress_updater(format!("   -> Matching ingredient: '{}'", ingredient.ingredient_name).into());
 
-        let query_embedding = self.embedding_engine.embed_one(&ingredient.ingredient_name)
+        if let Some(overridden_item) = self.override_for(ingredient, progress_updater) {
+            if let Some(explanation) = explanation {
+                explanation.override_item = Some(overridden_item.name.clone());
+                explanation.chosen_item = Some(overridden_item.name.clone());
+            }
+            return Ok(self.nutrition_for_match(ingredient, ChosenItem::without_similarity(overridden_item, MatchMethod::Override), progress_updater));
+        }
+
+        let query_embedding = self.embedding_engine.embed_one(&normalize_ingredient_name(&ingredient.ingredient_name))
             .with_context(|| format!("Failed to generate embedding for recipe ingredient: {}", ingredient.ingredient_name))?;
+        let Some(candidates) = self.candidates_for(ingredient, &query_embedding, progress_updater) else {
+            return Ok(self.finish_match(ingredient, None, progress_updater).await);
+        };
+        if let Some(explanation) = explanation.as_deref_mut() {
+            explanation.candidates = candidates.iter().map(|(item, score)| (item.name.clone(), *score)).collect();
+        }
 
-        let k = 10; 
-        let ann_search_results_str_ids: Vec<String> = self.ann_engine.search(&query_embedding, k);
-        
-        let candidate_vec_indices: Vec<usize> = ann_search_results_str_ids.iter()
-            .filter_map(|s_id| s_id.parse::<usize>().ok())
+        let chosen_item = if let Some(item) = self.auto_accepted(ingredient, &candidates, progress_updater) {
+            if let Some(explanation) = explanation.as_deref_mut() {
+                explanation.auto_accepted = true;
+            }
+            Some(ChosenItem::from_candidates(item, &candidates, MatchMethod::AutoAccepted))
+        } else if self.interactive {
+            choose_interactively(ingredient, &candidates)?
+                .map(|item| ChosenItem::from_candidates(item, &candidates, MatchMethod::Interactive))
+        } else {
+            self.disambiguate(ingredient, &candidates, client, progress_updater, explanation.as_deref_mut()).await
+                .map(|item| ChosenItem::from_candidates(item, &candidates, MatchMethod::Llm))
+        };
+        if let Some(explanation) = explanation {
+            explanation.chosen_item = chosen_item.as_ref().map(|chosen| chosen.item.name.clone());
+        }
+        Ok(self.finish_match(ingredient, chosen_item, progress_updater).await)
+    }
+
+    /// Batch version of `find_and_calculate_nutrition`, aligned with `ingredients`. All names are
+    /// embedded in one call, and the LLM disambiguates up to `DISAMBIGUATION_BATCH_SIZE` ingredients
+    /// per request. A batch whose answer can't be used is retried one ingredient at a time.
+    pub async fn find_and_calculate_nutrition_batch(
+        &self,
+        ingredients: &[CleanedIngredient],
+        client: &impl ChatClient,
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Result<Vec<Option<CalculatedNutritionalInfo>>> {
+        let mut results = vec![None; ingredients.len()];
+        let mut unmatched = Vec::new();
+        for (idx, ingredient) in ingredients.iter().enumerate() {
+            progress_updater(ProgressEvent::Step {
+                stage: ProgressStage::Matching,
+                index: idx as u32 + 1,
+                total: ingredients.len() as u32,
+                label: ingredient.ingredient_name.clone(),
+            });
+            match self.override_for(ingredient, progress_updater) {
+                Some(overridden_item) => {
+                    let chosen_item = ChosenItem::without_similarity(overridden_item, MatchMethod::Override);
+                    results[idx] = self.nutrition_for_match(ingredient, chosen_item, progress_updater);
+                }
+                None => unmatched.push(idx),
+            }
+        }
+
+        let names: Vec<String> = unmatched.iter().map(|&idx| normalize_ingredient_name(&ingredients[idx].ingredient_name)).collect();
+        let embeddings = self.embedding_engine.embed_many(&names)
+            .with_context(|| "Failed to generate embeddings for recipe ingredients")?;
+        let mut with_candidates: Vec<(usize, Vec<Candidate>)> = Vec::new();
+        for (&idx, embedding) in unmatched.iter().zip(&embeddings) {
+            match self.candidates_for(&ingredients[idx], embedding, progress_updater) {
+                Some(candidates) => match self.auto_accepted(&ingredients[idx], &candidates, progress_updater) {
+                    Some(item) => {
+                        let chosen_item = ChosenItem::from_candidates(item, &candidates, MatchMethod::AutoAccepted);
+                        results[idx] = self.nutrition_for_match(&ingredients[idx], chosen_item, progress_updater);
+                    }
+                    None => with_candidates.push((idx, candidates)),
+                },
+                None => results[idx] = self.finish_match(&ingredients[idx], None, progress_updater).await,
+            }
+        }
+
+        for batch in with_candidates.chunks(if self.interactive { 1 } else { DISAMBIGUATION_BATCH_SIZE }) {
+            let (chosen_items, method) = if self.interactive {
+                (vec![choose_interactively(&ingredients[batch[0].0], &batch[0].1)?], MatchMethod::Interactive)
+            } else {
+                (self.disambiguate_batch(ingredients, batch, client, progress_updater).await, MatchMethod::Llm)
+            };
+            for ((idx, candidates), chosen_item) in batch.iter().zip(chosen_items) {
+                let chosen_item = chosen_item.map(|item| ChosenItem::from_candidates(item, candidates, method));
+                results[*idx] = self.finish_match(&ingredients[*idx], chosen_item, progress_updater).await;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Matches a single ingredient name with `find_and_calculate_nutrition`, recording each step:
+    /// normalized query, ANN candidates, disambiguation prompt and raw answer. An online fallback
+    /// match isn't recorded.
+    pub async fn explain_match(&self, ingredient_name: &str, client: &impl ChatClient) -> Result<MatchExplanation> {
+        let mut explanation = MatchExplanation::new(ingredient_name);
+        self.find_and_calculate_nutrition(&bare_ingredient(ingredient_name), client, &|_| {}, Some(&mut explanation)).await?;
+        Ok(explanation)
+    }
+
+    /// Up to `limit` food items near `ingredient_name` in embedding space that do better than its
+    /// own item on `goal`, ranked by similarity and improvement together. No LLM is involved: the
+    /// ingredient's item is its override, or else its nearest item.
+    pub fn suggest_substitutions(&self, ingredient_name: &str, goal: SubstitutionGoal, limit: usize) -> Result<SubstitutionSuggestions> {
+        let query_embedding = self.embedding_engine.embed_one(&normalize_ingredient_name(ingredient_name))
+            .with_context(|| format!("Failed to generate embedding for ingredient: {}", ingredient_name))?;
+        let neighbours: Vec<Candidate> = self.ann_engine.search_with_fields(&query_embedding, SUBSTITUTION_CANDIDATE_COUNT)
+            .iter()
+            .filter_map(|ann_match| self.item_for_match(ann_match).map(|item| (item, ann_match.score)))
             .collect();
+        let reference = self.override_for(&bare_ingredient(ingredient_name), &|_| {})
+            .or_else(|| neighbours.first().map(|(item, _)| *item))
+            .ok_or_else(|| anyhow::anyhow!("No food item found near '{}'", ingredient_name))?;
+        let reference_value = goal.value_per_100g(reference);
 
-        if candidate_vec_indices.is_empty() {
-            progress_updater(format!("   -> No ANN candidates found for '{}'.", ingredient.ingredient_name));
-            return Ok(None);
+        let mut substitutions: Vec<Substitution> = neighbours.iter()
+            .filter(|(item, _)| !std::ptr::eq(*item, reference))
+            .filter_map(|&(item, similarity)| {
+                let value = goal.value_per_100g(item)?;
+                let improvement = goal.improvement(reference_value?, value)?;
+                Some(Substitution {
+                    item_name: item.name.clone(),
+                    similarity,
+                    value_per_100g: value,
+                    improvement,
+                    score: (1.0 - SUBSTITUTION_IMPROVEMENT_WEIGHT) * similarity + SUBSTITUTION_IMPROVEMENT_WEIGHT * improvement,
+                })
+            })
+            .collect();
+        substitutions.sort_by(|a, b| b.score.total_cmp(&a.score));
+        substitutions.truncate(limit);
+        Ok(SubstitutionSuggestions {
+            ingredient_name: ingredient_name.to_string(),
+            goal,
+            reference_item: reference.name.clone(),
+            reference_value_per_100g: reference_value,
+            substitutions,
+        })
+    }
+
+    /// The override's food item, if the ingredient has one.
+    fn override_for(&self, ingredient: &CleanedIngredient, progress_updater: &impl Fn(ProgressEvent)) -> Option<&FoodItem> {
+        let &item_idx = self.overrides.get(&normalize_ingredient_name(&ingredient.ingredient_name))?;
+        let overridden_item = &self.ciqual_data[item_idx];
+        progress_updater(format!("   -> Using override for '{}': '{}'", ingredient.ingredient_name, overridden_item.name).into());
+        Some(overridden_item)
+    }
+
+    /// ANN candidates for the ingredient, numbered as `order_candidates` sorts them, or `None`
+    /// (reported) when there are none.
+    fn candidates_for(
+        &self,
+        ingredient: &CleanedIngredient,
+        query_embedding: &[f32],
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Option<Vec<Candidate<'_>>> {
+        let ann_matches = self.ann_engine.search_with_fields(query_embedding, CANDIDATE_COUNT);
+        if ann_matches.is_empty() {
+            progress_updater(format!("   -> No ANN candidates found for '{}'.", ingredient.ingredient_name).into());
+            return None;
         }
 
-        let candidates: Vec<&CiqualFoodItem> = candidate_vec_indices.iter()
-            .filter_map(|&vec_idx| self.ciqual_data.get(vec_idx)) 
+        let mut candidates: Vec<Candidate> = ann_matches.iter()
+            .filter_map(|ann_match| self.item_for_match(ann_match).map(|item| (item, ann_match.score)))
             .collect();
-        
         if candidates.is_empty() {
-            progress_updater(format!("   -> ANN candidate indices did not map to Ciqual items for '{}'. Indices: {:?}", ingredient.ingredient_name, candidate_vec_indices));
-            return Ok(None);
+            let ids: Vec<&str> = ann_matches.iter().map(|ann_match| ann_match.id.as_str()).collect();
+            progress_updater(format!("   -> ANN candidates did not map to food items for '{}'. IDs: {:?}", ingredient.ingredient_name, ids).into());
+            return None;
         }
+        order_candidates(&mut candidates);
 
-        progress_updater(format!("   -> Top {} ANN candidates for '{}':", candidates.len(), ingredient.ingredient_name));
-        let mut candidate_prompt_list = String::new();
-        for (i, candidate_item) in candidates.iter().enumerate() {
-            let line = format!("{}. \"{}\"", i + 1, candidate_item.name);
-            progress_updater(format!("     {}", line));
-            candidate_prompt_list.push_str(&line);
-            candidate_prompt_list.push('\n');
+        log_verbose!("   -> Top {} ANN candidates for '{}':", candidates.len(), ingredient.ingredient_name);
+        for line in candidate_prompt_list(&candidates).lines() {
+            log_verbose!("     {}", line);
         }
+        Some(candidates)
+    }
 
-        let disambiguation_system_prompt = "/no_thinking
-You are a food item matching assistant. Your task is to choose the best match for a given recipe ingredient from a list of candidate food items from a nutritional database.
-Consider the ingredient name and any preparation notes.
-**Crucially, pay close attention to the form of the user's ingredient (e.g., if it's a 'flour', a 'powder', a 'whole raw' item, a 'cooked' item, a 'liquid', 'puree', etc.) and strongly prefer CIQUAL candidates that match this specific form.**
-For example, if the user ingredient is 'wheat flour', prefer candidates like 'Wheat flour, type X' over 'Wheat, whole, raw'. If the user ingredient is 'apple puree', prefer 'Fruits puree, apple' over 'Apple, raw'.
-If the user ingredient mentions a specific state like 'cooked' or 'raw', try to match that state.
+    /// The top candidate, when auto-accept is enabled, matching isn't interactive and it passes the thresholds.
+    fn auto_accepted<'a>(
+        &self,
+        ingredient: &CleanedIngredient,
+        candidates: &[Candidate<'a>],
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Option<&'a FoodItem> {
+        let auto_accept = self.auto_accept.filter(|_| !self.interactive)?;
+        let (item, margin) = auto_accept.accept(candidates)?;
+        progress_updater(format!(
+            "   -> Auto-accepted '{}' for '{}' (similarity {:.2}, margin {:.2}); skipping LLM disambiguation.",
+            item.name, ingredient.ingredient_name, candidates[0].1, margin
+        ).into());
+        Some(item)
+    }
 
-Respond ONLY with a JSON object strictly adhering to the provided schema: { \"best_match_index\": number }
-The number should be the 1-based index of the chosen candidate. 
-If none of the candidates are a good match, or if the best apparent match is still significantly different in form or type despite your best effort to match form, respond with 0.";
+    /// Asks the LLM which candidate matches the ingredient, recording the request, the answer
+    /// and any failure in `explanation`.
+    async fn disambiguate<'a>(
+        &self,
+        ingredient: &CleanedIngredient,
+        candidates: &[Candidate<'a>],
+        client: &impl ChatClient,
+        progress_updater: &impl Fn(ProgressEvent),
+        mut explanation: Option<&mut MatchExplanation>,
+    ) -> Option<&'a FoodItem> {
+        let request = self.disambiguation_request(ingredient, candidates);
+        if let Some(explanation) = explanation.as_deref_mut() {
+            explanation.request = Some(request.clone());
+        }
+        let raw_content = match request_disambiguation(request, client, progress_updater).await {
+            Ok(raw_content) => raw_content,
+            Err(error) => {
+                if let Some(explanation) = explanation {
+                    explanation.error = Some(error);
+                }
+                return None;
+            }
+        };
+        let llm_content = json_part(&raw_content);
+        let chosen = match serde_json::from_str::<DisambiguationResponse>(llm_content) {
+            Ok(disamb_response) => {
+                progress_updater(format!("   -> LLM chose index: {}", disamb_response.best_match_index).into());
+                let chosen = candidate_at(candidates, disamb_response.best_match_index);
+                if chosen.is_none() {
+                    progress_updater("   -> LLM indicated no good match or invalid index.".into());
+                }
+                chosen
+            }
+            Err(e) => {
+                progress_updater(ProgressEvent::Warning { text: format!("   -> Failed to parse LLM disambiguation response: {}. Raw: {}", e, llm_content) });
+                if let Some(explanation) = explanation.as_deref_mut() {
+                    explanation.error = Some(format!("Unparseable disambiguation response: {}", e));
+                }
+                None
+            }
+        };
+        if let Some(explanation) = explanation {
+            explanation.raw_response = Some(raw_content);
+        }
+        chosen
+    }
 
-        let disambiguation_user_prompt = format!(
-"Recipe Ingredient: \"{}\"
-Preparation Notes: \"{}\"
+    fn disambiguation_request(&self, ingredient: &CleanedIngredient, candidates: &[Candidate]) -> ChatCompletionRequest {
+        let disambiguation_system_prompt = format!("{}
 
-Candidate Nutritional Database Items:
-{}
-Which candidate item (by number, 1 to {}) is the best semantic and form-based match for the recipe ingredient?
-If none are a good match, respond with 0.",
-            ingredient.ingredient_name,
-            ingredient.preparation_notes,
-            candidate_prompt_list.trim(),
-            candidates.len()
+Respond ONLY with a JSON object strictly adhering to the provided schema: {{ \"best_match_index\": number }}
+The number should be the 1-based index of the chosen candidate. 
+If none of the candidates are a good match, or if the best apparent match is still significantly different in form or type despite your best effort to match form, respond with 0.",
+            self.prompts.render(PromptKind::MatcherGuidelines, &[])
         );
 
-        let provider = Provider::openrouter(api_key_env_var);
-        let request = ChatCompletionRequest {
+        let candidates_text = candidate_prompt_list(candidates);
+        let disambiguation_user_prompt = self.prompts.render(PromptKind::MatcherUser, &[
+            ("ingredient_name", &ingredient.ingredient_name),
+            ("preparation_notes", &ingredient.preparation_notes),
+            ("candidates", candidates_text.trim()),
+            ("candidate_count", &candidates.len().to_string()),
+        ]);
+
+        ChatCompletionRequest {
             model: "qwen/qwen3-32b".to_string(), 
             messages: vec![
-                ChatMessage { role: "system".to_string(), content: disambiguation_system_prompt.to_string() },
+                ChatMessage { role: "system".to_string(), content: disambiguation_system_prompt },
                 ChatMessage { role: "user".to_string(), content: disambiguation_user_prompt },
             ],
-            response_format: Some(ResponseFormat {
-                format_type: "json_schema".to_string(), // Corrected from "json_object" to "json_schema" if schema is provided
-                json_schema: Some(get_disambiguation_json_schema(candidates.len())),
-            }),
-            temperature: Some(0.0), // Changed from 0.1 to 0.0 for more deterministic output
-            max_tokens: Some(50),
-        };
+            response_format: Some(ResponseFormat::for_schema(get_disambiguation_json_schema(candidates.len()), self.response_format)),
+            temperature: Some(self.disambiguation_params.temperature),
+            max_tokens: Some(self.disambiguation_params.max_tokens),
+        }
+    }
 
-        let llm_response_content = match provider.call_chat_completion(request).await {
-            Ok(response) => {
-                if let Some(choice) = response.choices.first() {
-                    let mut content_str = choice.message.content.trim().to_string();
-                    // Handle potential markdown code block wrapping
-                    if content_str.starts_with("```json") && content_str.ends_with("```") {
-                        content_str = content_str.trim_start_matches("```json").trim_end_matches("```").trim().to_string();
-                    } else if content_str.starts_with("```") && content_str.ends_with("```") {
-                        content_str = content_str.trim_start_matches("```").trim_end_matches("```").trim().to_string();
+    /// Disambiguates several ingredients with one LLM request, aligned with `batch`. Single
+    /// ingredients, batches whose answer has the wrong shape, and custom `MatcherUser` templates
+    /// without a matching `MatcherBatchUser` one go through `disambiguate`.
+    async fn disambiguate_batch<'a>(
+        &self,
+        ingredients: &[CleanedIngredient],
+        batch: &[(usize, Vec<Candidate<'a>>)],
+        client: &impl ChatClient,
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Vec<Option<&'a FoodItem>> {
+        let single_prompt_only = self.prompts.is_overridden(PromptKind::MatcherUser)
+            && !self.prompts.is_overridden(PromptKind::MatcherBatchUser);
+        if batch.len() > 1 && !single_prompt_only {
+            let request = batch_disambiguation_request(ingredients, batch, self.disambiguation_params, self.response_format, &self.prompts);
+            if let Ok(raw_content) = request_disambiguation(request, client, progress_updater).await {
+                let llm_content = json_part(&raw_content);
+                match parse_batch_choices(llm_content, batch.len()) {
+                    Some(choices) => {
+                        progress_updater(format!("   -> LLM chose indices: {:?}", choices).into());
+                        return batch.iter().zip(choices)
+                            .map(|((_, candidates), choice)| candidate_at(candidates, choice))
+                            .collect();
                     }
-                    Some(content_str)
-                } else {
-                    progress_updater("   -> LLM returned no choice for disambiguation.".to_string());
-                    None
+                    None => progress_updater(format!(
+                        "   -> Unusable batched disambiguation response, matching ingredients one by one. Raw: {}", llm_content
+                    ).into()),
                 }
             }
-            Err(e) => {
-                progress_updater(format!("   -> API call for LLM disambiguation failed: {}", e));
-                None
-            }
-        };
+        }
 
-        if llm_response_content.is_none() {
-            return Ok(None);
+        let mut chosen_items = Vec::with_capacity(batch.len());
+        for (idx, candidates) in batch {
+            chosen_items.push(self.disambiguate(&ingredients[*idx], candidates, client, progress_updater, None).await);
         }
-        let llm_content = llm_response_content.unwrap();
+        chosen_items
+    }
 
-        let chosen_ciqual_item_option: Option<&CiqualFoodItem> = match serde_json::from_str::<DisambiguationResponse>(&llm_content) {
-            Ok(disamb_response) => {
-                progress_updater(format!("   -> LLM chose index: {}", disamb_response.best_match_index));
-                if disamb_response.best_match_index > 0 && (disamb_response.best_match_index as usize) <= candidates.len() {
-                    candidates.get((disamb_response.best_match_index - 1) as usize).copied()
-                } else {
-                    progress_updater("   -> LLM indicated no good match or invalid index.".to_string());
-                    None
-                }
+    /// Nutrition for the chosen item. Without one, the online fallback is tried when enabled, and
+    /// the ingredient is reported as unmatched if that finds nothing either.
+    async fn finish_match(
+        &self,
+        ingredient: &CleanedIngredient,
+        chosen_item: Option<ChosenItem<'_>>,
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Option<CalculatedNutritionalInfo> {
+        if let Some(chosen_item) = chosen_item {
+            return self.nutrition_for_match(ingredient, chosen_item, progress_updater);
+        }
+        if let Some(online_item) = self.online_match(ingredient, progress_updater).await {
+            let chosen_item = ChosenItem::without_similarity(&online_item, MatchMethod::OnlineFallback);
+            return self.nutrition_for_match(ingredient, chosen_item, progress_updater);
+        }
+        progress_updater(ProgressEvent::MatchNotFound { ingredient: ingredient.ingredient_name.clone() });
+        None
+    }
+
+    /// Open Food Facts product for the ingredient, when the online fallback is enabled.
+    async fn online_match(&self, ingredient: &CleanedIngredient, progress_updater: &impl Fn(ProgressEvent)) -> Option<FoodItem> {
+        let open_food_facts = self.online_fallback.as_ref()?;
+        progress_updater(format!("   -> No database match for '{}', searching Open Food Facts...", ingredient.ingredient_name).into());
+        match open_food_facts.lookup(&ingredient.ingredient_name).await {
+            Ok(Some(item)) => Some(item),
+            Ok(None) => {
+                progress_updater(format!("   -> Open Food Facts has no product with nutrition data for '{}'.", ingredient.ingredient_name).into());
+                None
             }
             Err(e) => {
-                progress_updater(format!("   -> Failed to parse LLM disambiguation response: {}. Raw: {}", e, llm_content));
+                progress_updater(ProgressEvent::Warning { text: format!("   -> Open Food Facts lookup failed for '{}': {:#}", ingredient.ingredient_name, e) });
                 None
             }
-        };
-        
-        if chosen_ciqual_item_option.is_none() {
-             progress_updater(format!("   -> No definitive match found for '{}' after LLM disambiguation.", ingredient.ingredient_name));
-            return Ok(None);
         }
-        let chosen_ciqual_item = chosen_ciqual_item_option.unwrap();
-        progress_updater(format!("   -> Matched '{}' to Ciqual item: '{}'", ingredient.ingredient_name, chosen_ciqual_item.name));
+    }
+
+    /// Food item referenced by an ANN hit's metadata. Entries left over from another database
+    /// (row missing or named differently) are ignored.
+    fn item_for_match(&self, ann_match: &AnnMatch) -> Option<&FoodItem> {
+        let row_index = ann_match.fields.get(FIELD_ORIGINAL_ROW_INDEX)?.as_u64()? as usize;
+        let item = self.ciqual_data.get(*self.row_to_item.get(&row_index)?)?;
+        let stored_name = ann_match.fields.get(FIELD_NAME).and_then(|name| name.as_str());
+        (stored_name == Some(item.name.as_str())).then_some(item)
+    }
+
+    /// Scales the matched item's per-100g values to the ingredient's quantity.
+    fn nutrition_for_match(
+        &self,
+        ingredient: &CleanedIngredient,
+        chosen_item: ChosenItem<'_>,
+        progress_updater: &impl Fn(ProgressEvent),
+    ) -> Option<CalculatedNutritionalInfo> {
+        let chosen_ciqual_item = chosen_item.item;
+        progress_updater(ProgressEvent::MatchFound {
+            ingredient: ingredient.ingredient_name.clone(),
+            ciqual_name: chosen_ciqual_item.name.clone(),
+        });
 
         if let Some(grams) = ingredient.quantity_grams {
             let scale = grams / 100.0;
@@ -286,11 +1181,15 @@ If none are a good match, respond with 0.",
                 sugars_g: chosen_ciqual_item.sugars_g_per_100g.map(|v| v * scale),
                 fa_saturated_g: chosen_ciqual_item.fa_saturated_g_per_100g.map(|v| v * scale),
                 salt_g: chosen_ciqual_item.salt_g_per_100g.map(|v| v * scale),
+                fiber_g: chosen_ciqual_item.fiber_g_per_100g.map(|v| v * scale),
+                cholesterol_mg: chosen_ciqual_item.cholesterol_mg_per_100g.map(|v| v * scale),
+                match_method: Some(chosen_item.method),
+                match_similarity: chosen_item.similarity,
             };
-            Ok(Some(calculated_info))
+            Some(calculated_info)
         } else {
-            progress_updater(format!("   -> Cannot calculate nutrition for '{}' as quantity_grams is missing.", ingredient.ingredient_name));
-            Ok(None)
+            progress_updater(format!("   -> Cannot calculate nutrition for '{}' as quantity_grams is missing.", ingredient.ingredient_name).into());
+            None
         }
     }
 }
@@ -299,3 +1198,369 @@ If none are a good match, respond with 0.",
 // No need to declare them again here.
 // use serde::{Serialize, Deserialize};
 // use std::collections::HashMap;
+
+/// Index fixtures shared by the tests of this module and of the modules built on it.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// A food item with every nutrient unknown.
+    pub(crate) fn food_item(name: &str, original_row_index: usize) -> FoodItem {
+        FoodItem {
+            name: name.to_string(),
+            original_row_index,
+            kcal_per_100g: None,
+            water_g_per_100g: None,
+            protein_g_per_100g: None,
+            carbohydrate_g_per_100g: None,
+            fat_g_per_100g: None,
+            sugars_g_per_100g: None,
+            fa_saturated_g_per_100g: None,
+            salt_g_per_100g: None,
+            fiber_g_per_100g: None,
+            cholesterol_mg_per_100g: None,
+        }
+    }
+
+    /// "<grams> g <name>", converted by the LLM and not yet matched.
+    pub(crate) fn cleaned_ingredient(name: &str, grams: f32) -> CleanedIngredient {
+        CleanedIngredient {
+            raw_text: format!("{} g {}", grams, name),
+            ingredient_name: name.to_string(),
+            original_quantity: grams.to_string(),
+            original_unit: "g".to_string(),
+            preparation_notes: String::new(),
+            quantity_grams: Some(grams),
+            conversion_source: "LLM".to_string(),
+            conversion_notes: None,
+            nutritional_info: None,
+            section: None,
+            optional: false,
+        }
+    }
+
+    /// An in-memory index of `items` with hand-made `embeddings`: no model, CSV or index file involved.
+    /// The ingredient names the test will look up need an embedding too.
+    pub(crate) fn index_of(items: Vec<FoodItem>, embeddings: HashMap<String, Vec<f32>>) -> Result<NutritionalIndex> {
+        let dimensions = embeddings.values().next().map_or(1, Vec::len);
+        NutritionalIndex::from_food_items(
+            items,
+            EmbeddingEngine::from_embeddings(embeddings)?,
+            AnnEngine::new_in_memory(dimensions),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::{cleaned_ingredient, food_item, index_of};
+    use crate::api_connection::client::MockChatClient;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    /// A three-item index with hand-made embeddings: no model, CSV or index file involved.
+    fn fixture_index() -> Result<NutritionalIndex> {
+        let butter = FoodItem { kcal_per_100g: Some(717.0), fat_g_per_100g: Some(81.0), ..food_item("Butter", 0) };
+        let leek = FoodItem { kcal_per_100g: Some(31.0), ..food_item("Leek, raw", 2) };
+        let embeddings = HashMap::from([
+            ("Butter".to_string(), vec![1.0, 0.0, 0.0]),
+            ("Salt".to_string(), vec![0.0, 1.0, 0.0]),
+            ("Leek, raw".to_string(), vec![0.0, 0.0, 1.0]),
+            ("butter".to_string(), vec![0.9, 0.1, 0.0]),
+            ("leek".to_string(), vec![0.0, 0.2, 0.9]),
+        ]);
+        index_of(vec![butter, food_item("Salt", 1), leek], embeddings)
+    }
+
+    #[tokio::test]
+    async fn test_find_and_calculate_nutrition_offline() -> Result<()> {
+        let index = fixture_index()?;
+        let client = MockChatClient::new([r#"{"best_match_index": 1}"#, r#"{"best_match_index": 0}"#]);
+
+        let nutrition = index.find_and_calculate_nutrition(&cleaned_ingredient("butter", 200.0), &client, &|_| {}, None).await?.unwrap();
+        assert_eq!(nutrition.source_ciqual_name, "Butter");
+        assert_eq!(nutrition.kcal, Some(1434.0));
+        assert_eq!(nutrition.fat_g, Some(162.0));
+        // Candidates are listed by similarity, so the closest item is number 1.
+        let prompt = &client.requests()[0].messages[1].content;
+        assert!(prompt.find("\"Butter\"").unwrap() < prompt.find("\"Salt\"").unwrap());
+
+        // The LLM rejecting every candidate leaves the ingredient unmatched.
+        assert!(index.find_and_calculate_nutrition(&cleaned_ingredient("leeks", 100.0), &client, &|_| {}, None).await?.is_none());
+        // Names without an embedding can't be matched.
+        assert!(index.find_and_calculate_nutrition(&cleaned_ingredient("saffron", 1.0), &client, &|_| {}, None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_and_calculate_nutrition_batch_offline() -> Result<()> {
+        let index = fixture_index()?
+            .with_overrides(&HashMap::from([("Salt".to_string(), "Salt".to_string())]))?;
+        let client = MockChatClient::new([r#"{"best_match_indices": [1, 1]}"#]);
+        let ingredients = [cleaned_ingredient("butter", 50.0), cleaned_ingredient("salt", 2.0), cleaned_ingredient("leeks", 300.0)];
+
+        let matches = index.find_and_calculate_nutrition_batch(&ingredients, &client, &|_| {}).await?;
+        let names: Vec<Option<&str>> = matches.iter()
+            .map(|nutrition| nutrition.as_ref().map(|nutrition| nutrition.source_ciqual_name.as_str()))
+            .collect();
+        assert_eq!(names, vec![Some("Butter"), Some("Salt"), Some("Leek, raw")]);
+        assert_eq!(matches[2].as_ref().unwrap().kcal, Some(93.0));
+        // The override needs no LLM; the two others share one request.
+        assert_eq!(client.requests().len(), 1);
+
+        // A custom single-ingredient prompt without a batched one is sent once per ingredient.
+        let index = fixture_index()?
+            .with_prompts(PromptTemplates::default().with_template(PromptKind::MatcherUser, "Which is {ingredient_name}?\n{candidates}".to_string())?);
+        let client = MockChatClient::new([r#"{"best_match_index": 1}"#, r#"{"best_match_index": 1}"#]);
+        index.find_and_calculate_nutrition_batch(&[ingredients[0].clone(), ingredients[2].clone()], &client, &|_| {}).await?;
+        let requests = client.requests();
+        let prompts: Vec<&str> = requests.iter().map(|request| request.messages[1].content.as_str()).collect();
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts.iter().all(|prompt| prompt.starts_with("Which is ")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_accept_skips_the_llm_on_clear_matches() -> Result<()> {
+        // "butter" is ~0.99 similar to "Butter" and far from the rest; "leeks" is ~0.98 similar
+        // to "Leek, raw" but a strict margin keeps it for the LLM.
+        let index = fixture_index()?.with_auto_accept(Some(AutoAccept { min_similarity: 0.95, min_margin: 0.8 }));
+        let client = MockChatClient::new([r#"{"best_match_index": 1}"#]);
+        let ingredients = [cleaned_ingredient("butter", 50.0), cleaned_ingredient("leeks", 300.0)];
+
+        let matches = index.find_and_calculate_nutrition_batch(&ingredients, &client, &|_| {}).await?;
+        assert_eq!(matches[0].as_ref().unwrap().source_ciqual_name, "Butter");
+        assert_eq!(matches[1].as_ref().unwrap().source_ciqual_name, "Leek, raw");
+        assert_eq!(client.requests().len(), 1);
+        assert!(!client.requests()[0].messages[1].content.contains("butter"));
+
+        let nutrition = index.find_and_calculate_nutrition(&cleaned_ingredient("butter", 100.0), &client, &|_| {}, None).await?;
+        assert_eq!(nutrition.unwrap().source_ciqual_name, "Butter");
+        assert_eq!(client.requests().len(), 1);
+
+        let (butter, salt) = (food_item("Butter", 0), food_item("Salt", 1));
+        let auto_accept = AutoAccept { min_similarity: 0.9, min_margin: AutoAccept::DEFAULT_MARGIN };
+        assert!(auto_accept.accept(&[(&butter, 0.95), (&salt, 0.93)]).is_none());
+        assert!(auto_accept.accept(&[(&butter, 0.85), (&salt, 0.1)]).is_none());
+        assert!(auto_accept.accept(&[(&butter, 0.92)]).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_matches_record_their_method_for_the_report() -> Result<()> {
+        let index = fixture_index()?
+            .with_overrides(&HashMap::from([("Salt".to_string(), "Salt".to_string())]))?
+            .with_auto_accept(Some(AutoAccept { min_similarity: 0.95, min_margin: 0.8 }));
+        let client = MockChatClient::new([r#"{"best_match_index": 1}"#]);
+        let mut ingredients = vec![cleaned_ingredient("butter", 50.0), cleaned_ingredient("salt", 2.0), cleaned_ingredient("leeks", 300.0)];
+
+        let matches = index.find_and_calculate_nutrition_batch(&ingredients, &client, &|_| {}).await?;
+        let methods: Vec<Option<MatchMethod>> = matches.iter().map(|nutrition| nutrition.as_ref().unwrap().match_method).collect();
+        assert_eq!(methods, vec![Some(MatchMethod::AutoAccepted), Some(MatchMethod::Override), Some(MatchMethod::Llm)]);
+        assert!(matches[0].as_ref().unwrap().match_similarity.unwrap() > 0.95);
+        assert!(matches[1].as_ref().unwrap().match_similarity.is_none());
+
+        for (ingredient, nutrition) in ingredients.iter_mut().zip(matches) {
+            ingredient.nutritional_info = nutrition;
+        }
+        ingredients.push(CleanedIngredient { quantity_grams: None, ..cleaned_ingredient("saffron", 1.0) });
+        let recipe = CleanedRecipe {
+            recipe_title: "Leek fondue".to_string(),
+            ingredients,
+            instructions: Vec::new(),
+            servings: None,
+            total_time_minutes: None,
+        };
+        let report = match_report(&recipe);
+        assert_eq!(report[2].matched_item.as_deref(), Some("Leek, raw"));
+        assert_eq!(report[3].matched_item, None);
+        let table = format_match_report(&report);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("Ingredient  Similarity  Method"));
+        assert!(lines[1].contains("auto-accepted") && lines[1].ends_with("Butter"));
+        assert!(lines[2].contains("-           override"));
+        assert!(lines[3].contains("LLM") && lines[3].ends_with("Leek, raw"));
+        assert!(lines[4].contains("no grams"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_name_variants_match_after_normalization() -> Result<()> {
+        let index = fixture_index()?.with_auto_accept(Some(AutoAccept { min_similarity: 0.95, min_margin: 0.5 }));
+        let client = MockChatClient::new(Vec::<String>::new());
+        let variants = ["Leeks (washed)", "LEEK", "leeks", "Butter [unsalted]", "Président® butter"];
+
+        // Before normalization only the spellings present in the table could be embedded at all.
+        let raw_embeddable = variants.iter().filter(|name| index.embedding_engine.embed_one(name).is_ok()).count();
+        assert_eq!(raw_embeddable, 0);
+
+        let ingredients: Vec<CleanedIngredient> = variants.iter().map(|name| cleaned_ingredient(name, 100.0)).collect();
+        let matches = index.find_and_calculate_nutrition_batch(&ingredients, &client, &|_| {}).await?;
+        let names: Vec<Option<&str>> = matches.iter()
+            .map(|nutrition| nutrition.as_ref().map(|nutrition| nutrition.source_ciqual_name.as_str()))
+            .collect();
+        assert_eq!(names, vec![Some("Leek, raw"), Some("Leek, raw"), Some("Leek, raw"), Some("Butter"), Some("Butter")]);
+        assert!(client.requests().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_explain_match() -> Result<()> {
+        let index = fixture_index()?;
+        let client = MockChatClient::new(["```json\n{\"best_match_index\": 1}\n```"]);
+
+        let explanation = index.explain_match("Leeks (washed)", &client).await?;
+        assert_eq!(explanation.normalized_query, "leek");
+        assert_eq!(explanation.candidates[0].0, "Leek, raw");
+        assert_eq!(explanation.candidates.len(), 3);
+        assert_eq!(explanation.raw_response.as_deref(), Some("```json\n{\"best_match_index\": 1}\n```"));
+        assert_eq!(explanation.chosen_item.as_deref(), Some("Leek, raw"));
+        let text = explanation.to_string();
+        assert!(text.contains("Recipe Ingredient: \"Leeks (washed)\""));
+        assert!(text.contains("--- Raw LLM response ---"));
+        assert!(text.ends_with("Chosen: \"Leek, raw\"\n"));
+
+        let index = index.with_overrides(&HashMap::from([("butter".to_string(), "Butter".to_string())]))?;
+        let overridden = index.explain_match("Butter", &client).await?;
+        assert_eq!(overridden.override_item.as_deref(), Some("Butter"));
+        assert!(overridden.request.is_none());
+        assert_eq!(client.requests().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suggest_substitutions_ranks_leaner_neighbours() -> Result<()> {
+        let fat = |name: &str, row: usize, fat: Option<f32>| FoodItem { fat_g_per_100g: fat, ..food_item(name, row) };
+        let embeddings = HashMap::from([
+            ("Butter".to_string(), vec![1.0, 0.0]),
+            ("Margarine, 60% fat".to_string(), vec![0.95, 0.3]),
+            ("Light spread, 25% fat".to_string(), vec![0.8, 0.6]),
+            ("Ghee".to_string(), vec![0.99, 0.1]),
+            ("Butter, unknown fat".to_string(), vec![0.9, 0.4]),
+            ("butter".to_string(), vec![1.0, 0.0]),
+        ]);
+        let index = index_of(
+            vec![
+                fat("Butter", 0, Some(81.0)),
+                fat("Margarine, 60% fat", 1, Some(60.0)),
+                fat("Light spread, 25% fat", 2, Some(25.0)),
+                fat("Ghee", 3, Some(99.5)),
+                fat("Butter, unknown fat", 4, None),
+            ],
+            embeddings,
+        )?;
+
+        let suggestions = index.suggest_substitutions("butter", SubstitutionGoal::FatReduction, SUBSTITUTION_COUNT)?;
+        assert_eq!(suggestions.reference_item, "Butter");
+        let names: Vec<&str> = suggestions.substitutions.iter().map(|substitution| substitution.item_name.as_str()).collect();
+        // Ghee has more fat and the last item no value; the light spread's larger cut outweighs its lower similarity.
+        assert_eq!(names, vec!["Light spread, 25% fat", "Margarine, 60% fat"]);
+        assert!((suggestions.substitutions[1].improvement - 21.0 / 81.0).abs() < 1e-4);
+        assert!(suggestions.to_string().contains("1. \"Light spread, 25% fat\": 25.0 g (69% lower)"));
+
+        // Butter has no protein value, so no item can be compared with it.
+        let protein = index.suggest_substitutions("butter", SubstitutionGoal::ProteinIncrease, SUBSTITUTION_COUNT)?;
+        assert_eq!(protein.reference_value_per_100g, None);
+        assert!(protein.substitutions.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_and_resolve_overrides() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, r#"{{ " Crème Fraîche ": "Cream, 30% fat", "stock cube": "Bouillon, dehydrated" }}"#)?;
+        file.flush()?;
+
+        let overrides = load_overrides(file.path())?;
+        let food_data = vec![food_item("Bouillon, dehydrated", 0), food_item("Cream, 30% fat", 1)];
+        let resolved = resolve_overrides(&overrides, &food_data)?;
+        assert_eq!(resolved.get("crème fraîche"), Some(&1));
+        assert_eq!(resolved.get("stock cube"), Some(&0));
+
+        let unknown = HashMap::from([("butter".to_string(), "Buter".to_string())]);
+        assert!(resolve_overrides(&unknown, &food_data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_batch_choices_and_candidate_at() {
+        assert_eq!(parse_batch_choices(r#"{"best_match_indices": [2, 0, 1]}"#, 3), Some(vec![2, 0, 1]));
+        // One index per ingredient, or the batch is retried one by one.
+        assert_eq!(parse_batch_choices(r#"{"best_match_indices": [2, 0]}"#, 3), None);
+        assert_eq!(parse_batch_choices("2, 0, 1", 3), None);
+
+        let (leek_raw, leek_cooked) = (food_item("Leek, raw", 0), food_item("Leek, cooked", 1));
+        let candidates = [(&leek_raw, 0.9), (&leek_cooked, 0.8)];
+        assert_eq!(candidate_at(&candidates, 2).map(|item| item.name.as_str()), Some("Leek, cooked"));
+        assert!(candidate_at(&candidates, 0).is_none());
+        assert!(candidate_at(&candidates, 3).is_none());
+        assert!(candidate_at(&candidates, -1).is_none());
+    }
+
+    #[test]
+    fn test_batch_disambiguation_request_lists_each_ingredient() {
+        let ingredients = [cleaned_ingredient("butter", 100.0), cleaned_ingredient("salt", 100.0), cleaned_ingredient("leeks", 100.0)];
+        let (butter, leek) = (food_item("Butter", 0), food_item("Leek, raw", 1));
+        let batch = vec![(0, vec![(&butter, 0.9)]), (2, vec![(&leek, 0.8)])];
+
+        let request = batch_disambiguation_request(&ingredients, &batch, GenParams::DISAMBIGUATION, ResponseFormatMode::JsonSchema, &PromptTemplates::default());
+        assert_eq!(request.max_tokens, Some(70));
+        let user_prompt = &request.messages[1].content;
+        assert!(user_prompt.contains("Ingredient 1: \"butter\""));
+        assert!(user_prompt.contains("Ingredient 2: \"leeks\""));
+        assert!(user_prompt.contains("1. \"Leek, raw\" (similarity 0.80)"));
+        assert!(!user_prompt.contains("salt"));
+        let request = batch_disambiguation_request(&ingredients, &batch, GenParams::DISAMBIGUATION, ResponseFormatMode::JsonObject, &PromptTemplates::default());
+        assert_eq!(request.response_format.unwrap().format_type, "json_object");
+
+        let prompts = PromptTemplates::default()
+            .with_template(PromptKind::MatcherBatchUser, "Pick one of each ({count}):\n{ingredients}".to_string())
+            .unwrap();
+        let request = batch_disambiguation_request(&ingredients, &batch, GenParams::DISAMBIGUATION, ResponseFormatMode::JsonSchema, &prompts);
+        assert!(request.messages[1].content.starts_with("Pick one of each (2):\nIngredient 1: \"butter\""));
+    }
+
+    #[test]
+    fn test_read_candidate_choice() -> Result<()> {
+        let ingredient = cleaned_ingredient("leeks", 200.0);
+        let candidates = ["Leek, raw", "Leek, cooked"];
+
+        let mut output = Vec::new();
+        let choice = read_candidate_choice(&ingredient, &candidates, &mut "abc\n7\n2\n".as_bytes(), &mut output)?;
+        assert_eq!(choice, Some(1));
+        assert!(String::from_utf8(output)?.contains("Please enter a number between 0 and 2."));
+
+        assert_eq!(read_candidate_choice(&ingredient, &candidates, &mut "0\n".as_bytes(), &mut Vec::new())?, None);
+        assert_eq!(read_candidate_choice(&ingredient, &candidates, &mut "".as_bytes(), &mut Vec::new())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tied_candidates_are_numbered_by_database_row() -> Result<()> {
+        // Two items as similar to "leek" as each other, stored in either order.
+        let items = vec![food_item("Leek, cooked", 7), food_item("Leek, raw", 3), food_item("Butter", 0)];
+        let embeddings = HashMap::from([
+            ("Leek, cooked".to_string(), vec![0.0, 1.0]),
+            ("Leek, raw".to_string(), vec![0.0, 1.0]),
+            ("Butter".to_string(), vec![1.0, 0.0]),
+            ("leek".to_string(), vec![0.2, 1.0]),
+        ]);
+        let build = |items: Vec<FoodItem>| index_of(items, embeddings.clone());
+        let index = build(items.clone())?;
+        let reversed = build(items.into_iter().rev().collect())?;
+
+        let ingredient = cleaned_ingredient("leek", 100.0);
+        let candidate_list = |index: &NutritionalIndex| -> Result<String> {
+            let query = index.embedding_engine.embed(std::slice::from_ref(&ingredient.ingredient_name))?.remove(0);
+            let candidates = index.candidates_for(&ingredient, &query, &|_| {}).unwrap();
+            Ok(candidate_prompt_list(&candidates))
+        };
+        let list = candidate_list(&index)?;
+        assert_eq!(list, candidate_list(&index)?);
+        assert_eq!(list, candidate_list(&reversed)?);
+        let lines: Vec<&str> = list.lines().collect();
+        assert!(lines[0].starts_with("1. \"Leek, raw\"") && lines[1].starts_with("2. \"Leek, cooked\""), "{}", list);
+        Ok(())
+    }
+}
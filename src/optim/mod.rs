@@ -1,3 +1,4 @@
 pub mod optimizer;
 pub mod targets;
 pub mod nutri_eval; // Added nutri_eval module
+pub mod recipe_diff;
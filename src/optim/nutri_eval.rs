@@ -1,11 +1,25 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
 use crate::recipe_aggregator::NutritionalSummary;
-use crate::optim::targets::TargetNutritionalValues;
+use crate::optim::targets::{OptimizableNutrient, TargetNutritionalValues};
+
+/// How each nutrient's error is scaled before the squared errors are averaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MseMode {
+    /// Squared fractional difference from the target, so every nutrient weighs the same whatever
+    /// its scale or the recipe's calorie density.
+    #[default]
+    Relative,
+    /// Squared difference in grams, with the kcal term divided by 100 (the original formulation).
+    Absolute,
+}
 
 /// Calculates the Mean Squared Error (MSE) between the nutritional profile of a recipe
 /// (per 100g) and the target nutritional values (per 100g).
 ///
-/// The MSE is calculated for key macronutrients: protein, carbohydrates, and fat.
-/// Kcal can also be included if desired, though it's derived.
+/// The MSE is calculated for key macronutrients: protein, carbohydrates, and fat, plus kcal.
+/// Each error is relative to its target (`MseMode::Relative`).
 /// Only fields present in both the profile and target are included in the MSE calculation.
 ///
 /// # Arguments
@@ -18,46 +32,267 @@ pub fn calculate_mse(
     current_profile_per_100g: &NutritionalSummary,
     target_values_per_100g: &TargetNutritionalValues,
 ) -> f32 {
+    calculate_mse_with_mode(current_profile_per_100g, target_values_per_100g, MseMode::Relative, false)
+}
+
+/// Squared-error term charged by `calculate_mse_strict` for a targeted nutrient the profile lacks:
+/// the square of a 100-fold miss in relative mode, or of a 100 g miss (the worst possible error for
+/// a per-100g value) in absolute mode.
+pub const MISSING_NUTRIENT_PENALTY: f32 = 10_000.0;
+
+/// Like `calculate_mse`, but a nutrient that has a target and no current value counts as a
+/// `MISSING_NUTRIENT_PENALTY` term instead of being skipped, so losing nutrition data never lowers the error.
+pub fn calculate_mse_strict(
+    current_profile_per_100g: &NutritionalSummary,
+    target_values_per_100g: &TargetNutritionalValues,
+) -> f32 {
+    calculate_mse_with_mode(current_profile_per_100g, target_values_per_100g, MseMode::Relative, true)
+}
+
+/// `calculate_mse` (or `calculate_mse_strict` when `strict`) with the given error scaling.
+pub fn calculate_mse_with_mode(
+    current_profile_per_100g: &NutritionalSummary,
+    target_values_per_100g: &TargetNutritionalValues,
+    mode: MseMode,
+    strict: bool,
+) -> f32 {
+    let (current, target) = (current_profile_per_100g, target_values_per_100g);
+    let missing_penalty = strict.then_some(MISSING_NUTRIENT_PENALTY);
+    // Add other nutrients (sugars, fiber, ...) here if they become primary targets.
+    let terms = [
+        (current.protein_g, target.protein_g, 1.0),
+        (current.carbohydrate_g, target.carbohydrate_g, 1.0),
+        (current.fat_g, target.fat_g, 1.0),
+        // Kcal values are much larger than gram values, so in absolute mode their squared
+        // error is scaled down to keep them from dominating.
+        (current.kcal, target.kcal, 100.0),
+    ];
+
     let mut squared_error_sum = 0.0;
     let mut count = 0;
+    for (current_value, target_value, absolute_divisor) in terms {
+        match (current_value, target_value, missing_penalty) {
+            (Some(current_value), Some(target_value), _) => {
+                squared_error_sum += match mode {
+                    // A zero target has no meaningful fraction; errors below 1 g (or kcal) per
+                    // 100 g are then measured against 1 instead.
+                    MseMode::Relative => ((current_value - target_value) / target_value.abs().max(1.0)).powi(2),
+                    MseMode::Absolute => (current_value - target_value).powi(2) / absolute_divisor,
+                };
+                count += 1;
+            }
+            (None, Some(_), Some(penalty)) => {
+                squared_error_sum += penalty;
+                count += 1;
+            }
+            _ => {}
+        }
+    }
 
-    // Protein
-    if let (Some(current_p), Some(target_p)) = (current_profile_per_100g.protein_g, target_values_per_100g.protein_g) {
-        squared_error_sum += (current_p - target_p).powi(2);
-        count += 1;
+    if count == 0 {
+        0.0 // No error if no targets are set for these fields.
+    } else {
+        squared_error_sum / count as f32
     }
+}
 
-    // Carbohydrates
-    if let (Some(current_c), Some(target_c)) = (current_profile_per_100g.carbohydrate_g, target_values_per_100g.carbohydrate_g) {
-        squared_error_sum += (current_c - target_c).powi(2);
-        count += 1;
+/// Root of the absolute-mode MSE, in the same (mostly gram) units as the nutrients it compares.
+pub fn calculate_rmse(
+    current_profile_per_100g: &NutritionalSummary,
+    target_values_per_100g: &TargetNutritionalValues,
+) -> f32 {
+    calculate_mse_with_mode(current_profile_per_100g, target_values_per_100g, MseMode::Absolute, false).sqrt()
+}
+
+/// How far one nutrient of a profile is from its target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NutrientError {
+    pub nutrient: &'static str,
+    /// The nutrient as an `--optimize` target, for those that can be one.
+    pub optimizable: Option<OptimizableNutrient>,
+    /// Unit suffix used when displaying values ("g", "mg" or " kcal").
+    pub unit: &'static str,
+    pub actual: f32,
+    pub target: f32,
+    /// `actual - target`.
+    pub absolute_error: f32,
+    /// `absolute_error` relative to the target, in percent. `None` when the target is zero.
+    pub percentage_error: Option<f32>,
+}
+
+impl std::fmt::Display for NutrientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:.1}{} vs target {:.1}{}", self.nutrient, self.actual, self.unit, self.target, self.unit)?;
+        match self.percentage_error {
+            Some(percentage) => write!(f, " ({:+.0}%)", percentage),
+            None => write!(f, " ({:+.1}{})", self.absolute_error, self.unit),
+        }
     }
+}
+
+/// Per-nutrient error for every nutrient that has both a current value and a target, unscaled,
+/// so results can be read directly ("protein: 18.0g vs target 20.0g (-10%)").
+pub fn calculate_nutrient_errors(
+    current_profile_per_100g: &NutritionalSummary,
+    target_values_per_100g: &TargetNutritionalValues,
+) -> Vec<NutrientError> {
+    let (current, target) = (current_profile_per_100g, target_values_per_100g);
+    let pairs = [
+        ("kcal", None, " kcal", current.kcal, target.kcal),
+        ("water", None, "g", current.water_g, target.water_g),
+        ("protein", Some(OptimizableNutrient::Protein), "g", current.protein_g, target.protein_g),
+        ("carbohydrate", Some(OptimizableNutrient::Carb), "g", current.carbohydrate_g, target.carbohydrate_g),
+        ("fat", Some(OptimizableNutrient::Fat), "g", current.fat_g, target.fat_g),
+        ("sugars", None, "g", current.sugars_g, target.sugars_g),
+        ("saturated fat", None, "g", current.fa_saturated_g, target.fa_saturated_g),
+        ("salt", None, "g", current.salt_g, target.salt_g),
+        ("fiber", None, "g", current.fiber_g, target.fiber_g),
+        ("cholesterol", None, "mg", current.cholesterol_mg, target.cholesterol_mg),
+    ];
+
+    pairs.into_iter()
+        .filter_map(|(nutrient, optimizable, unit, actual, target)| {
+            let (actual, target) = (actual?, target?);
+            let absolute_error = actual - target;
+            Some(NutrientError {
+                nutrient,
+                optimizable,
+                unit,
+                actual,
+                target,
+                absolute_error,
+                percentage_error: (target != 0.0).then(|| absolute_error / target * 100.0),
+            })
+        })
+        .collect()
+}
 
-    // Fat
-    if let (Some(current_f), Some(target_f)) = (current_profile_per_100g.fat_g, target_values_per_100g.fat_g) {
-        squared_error_sum += (current_f - target_f).powi(2);
-        count += 1;
+/// How far a nutrient may be from its target and still count as met.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tolerance {
+    /// In the nutrient's unit, per 100 g.
+    Absolute(f32),
+    /// In percent of the target.
+    Percent(f32),
+}
+
+/// Band of a targeted nutrient without a tolerance of its own.
+pub const DEFAULT_TOLERANCE: Tolerance = Tolerance::Percent(5.0);
+
+impl Tolerance {
+    pub fn allows(self, error: &NutrientError) -> bool {
+        match self {
+            Tolerance::Absolute(max) => error.absolute_error.abs() <= max,
+            // A zero target has no percentage error; only hitting it exactly is within a percentage.
+            Tolerance::Percent(max) => error.percentage_error.map_or(error.absolute_error == 0.0, |percentage| percentage.abs() <= max),
+        }
     }
+}
 
-    // Kcal (optional, as it's derived, but can be part of the target)
-    if let (Some(current_kcal), Some(target_kcal)) = (current_profile_per_100g.kcal, target_values_per_100g.kcal) {
-        // Kcal values can be much larger, so consider normalizing or weighting if it dominates MSE
-        // For now, direct MSE.
-        squared_error_sum += (current_kcal - target_kcal).powi(2) / 100.0; // Simple scaling for kcal
-        count += 1;
+impl std::fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tolerance::Absolute(max) => write!(f, "±{}", max),
+            Tolerance::Percent(max) => write!(f, "±{}%", max),
+        }
     }
-    
-    // Add other nutrients if they become primary targets, e.g., sugars, fiber, etc.
-    // if let (Some(current_s), Some(target_s)) = (current_profile_per_100g.sugars_g, target_values_per_100g.sugars_g) {
-    //     squared_error_sum += (current_s - target_s).powi(2);
-    //     count += 1;
-    // }
+}
 
-    if count == 0 {
-        0.0 // Or perhaps f32::MAX if no common targets could be evaluated, indicating a problem.
-            // For now, 0.0 means no error if no targets are set for these fields.
-    } else {
-        squared_error_sum / count as f32
+/// "5%" is a percentage of the target; "2" or "2g" an amount in the nutrient's unit.
+impl FromStr for Tolerance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, percent) = match s.strip_suffix('%') {
+            Some(number) => (number, true),
+            None => (s.strip_suffix('g').unwrap_or(s), false),
+        };
+        let value = number.trim().parse::<f32>().map_err(|e| format!("Invalid tolerance '{}': {}", s, e))?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!("Tolerance must be zero or positive, got {}", s));
+        }
+        Ok(if percent { Tolerance::Percent(value) } else { Tolerance::Absolute(value) })
+    }
+}
+
+/// Whether one targeted nutrient is within its tolerance band.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetCheck {
+    pub error: NutrientError,
+    pub tolerance: Tolerance,
+    pub within: bool,
+}
+
+impl std::fmt::Display for TargetCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verdict = if self.within { "within" } else { "OUT of" };
+        write!(f, "{}: {} {}", self.error, verdict, self.tolerance)?;
+        if let Tolerance::Absolute(_) = self.tolerance {
+            write!(f, "{}", self.error.unit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tolerance of each optimizable nutrient: its own, or the default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToleranceBands {
+    pub default: Tolerance,
+    pub by_nutrient: HashMap<OptimizableNutrient, Tolerance>,
+}
+
+impl Default for ToleranceBands {
+    fn default() -> Self {
+        ToleranceBands { default: DEFAULT_TOLERANCE, by_nutrient: HashMap::new() }
+    }
+}
+
+impl ToleranceBands {
+    pub fn with_tolerance(mut self, nutrient: OptimizableNutrient, tolerance: Tolerance) -> Self {
+        self.by_nutrient.insert(nutrient, tolerance);
+        self
+    }
+
+    pub fn band(&self, nutrient: OptimizableNutrient) -> Tolerance {
+        self.by_nutrient.get(&nutrient).copied().unwrap_or(self.default)
+    }
+
+    /// The bands of the `targeted` nutrients only.
+    pub fn for_targets(&self, targeted: impl IntoIterator<Item = OptimizableNutrient>) -> TargetTolerances {
+        let targeted: Vec<OptimizableNutrient> = targeted.into_iter().collect();
+        TargetTolerances {
+            bands: OptimizableNutrient::ALL.into_iter()
+                .filter(|nutrient| targeted.contains(nutrient))
+                .map(|nutrient| (nutrient, self.band(nutrient)))
+                .collect(),
+        }
+    }
+}
+
+/// The tolerance bands of the nutrients an optimization targets, which decide whether it met its targets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetTolerances {
+    bands: Vec<(OptimizableNutrient, Tolerance)>,
+}
+
+impl TargetTolerances {
+    /// One check per targeted nutrient having both a value and a target.
+    pub fn check(&self, current_profile_per_100g: &NutritionalSummary, target_values_per_100g: &TargetNutritionalValues) -> Vec<TargetCheck> {
+        let errors = calculate_nutrient_errors(current_profile_per_100g, target_values_per_100g);
+        self.bands.iter()
+            .filter_map(|&(nutrient, tolerance)| {
+                let error = errors.iter().find(|error| error.optimizable == Some(nutrient))?.clone();
+                let within = tolerance.allows(&error);
+                Some(TargetCheck { error, tolerance, within })
+            })
+            .collect()
+    }
+
+    /// True when every targeted nutrient has a value within its band; a missing value is not met.
+    pub fn all_met(&self, current_profile_per_100g: &NutritionalSummary, target_values_per_100g: &TargetNutritionalValues) -> bool {
+        let checks = self.check(current_profile_per_100g, target_values_per_100g);
+        checks.len() == self.bands.len() && checks.iter().all(|check| check.within)
     }
 }
 
@@ -102,10 +337,12 @@ mod tests {
             fat_g: Some(5.0),
             ..Default::default()
         };
-        // Sum of squared errors = 1 (kcal scaled) + 4 + 25 + 1 = 31
+        // Absolute: sum of squared errors = 1 (kcal scaled) + 4 + 25 + 1 = 31
         // Count = 4
         // MSE = 31 / 4 = 7.75
-        assert_eq!(calculate_mse(&profile, &target), 7.75);
+        assert_eq!(calculate_mse_with_mode(&profile, &target, MseMode::Absolute, false), 7.75);
+        // Relative: 0.05^2 (kcal) + 0.1^2 + (1/3)^2 + 0.2^2 = 0.163611, / 4 = 0.040903
+        assert!((calculate_mse(&profile, &target) - 0.163611 / 4.0).abs() < 1e-6);
     }
 
     #[test]
@@ -127,7 +364,9 @@ mod tests {
         // Sum of squared errors = 0 (protein) + 25 (carbs) = 25
         // Count = 2 (protein, carbs)
         // MSE = 25 / 2 = 12.5
-        assert_eq!(calculate_mse(&profile, &target), 12.5);
+        assert_eq!(calculate_mse_with_mode(&profile, &target, MseMode::Absolute, false), 12.5);
+        // Relative: carbs are 50% off, (0.5^2) / 2 = 0.125
+        assert_eq!(calculate_mse(&profile, &target), 0.125);
     }
 
     #[test]
@@ -149,7 +388,9 @@ mod tests {
         // Sum of squared errors = 0 (protein) + 4 (fat) = 4
         // Count = 2 (protein, fat)
         // MSE = 4 / 2 = 2.0
-        assert_eq!(calculate_mse(&profile, &target), 2.0);
+        assert_eq!(calculate_mse_with_mode(&profile, &target, MseMode::Absolute, false), 2.0);
+        // Relative: (2/7)^2 / 2
+        assert!((calculate_mse(&profile, &target) - (2.0f32 / 7.0).powi(2) / 2.0).abs() < 1e-6);
     }
 
     #[test]
@@ -165,4 +406,111 @@ mod tests {
         // No common fields for primary MSE calculation (kcal, P, C, F)
         assert_eq!(calculate_mse(&profile, &target), 0.0);
     }
+
+    #[test]
+    fn test_calculate_nutrient_errors_and_rmse() {
+        let profile = NutritionalSummary {
+            kcal: Some(210.0),
+            protein_g: Some(18.0),
+            carbohydrate_g: Some(20.0),
+            fat_g: Some(6.0),
+            salt_g: Some(0.3),
+            ..Default::default()
+        };
+        let target = TargetNutritionalValues {
+            kcal: Some(200.0),
+            protein_g: Some(20.0),
+            carbohydrate_g: Some(15.0),
+            fat_g: Some(5.0),
+            salt_g: Some(0.0),
+            fiber_g: Some(3.0), // No current value: not reported
+            ..Default::default()
+        };
+
+        let errors = calculate_nutrient_errors(&profile, &target);
+        let nutrients: Vec<&str> = errors.iter().map(|error| error.nutrient).collect();
+        assert_eq!(nutrients, vec!["kcal", "protein", "carbohydrate", "fat", "salt"]);
+
+        let protein = &errors[1];
+        assert_eq!(protein.absolute_error, -2.0);
+        assert_eq!(protein.percentage_error, Some(-10.0));
+        assert_eq!(protein.to_string(), "protein: 18.0g vs target 20.0g (-10%)");
+        assert_eq!(errors[0].to_string(), "kcal: 210.0 kcal vs target 200.0 kcal (+5%)");
+        assert_eq!(errors[4].percentage_error, None);
+        assert_eq!(errors[4].to_string(), "salt: 0.3g vs target 0.0g (+0.3g)");
+
+        // Absolute MSE of this profile is 7.75 (see test_calculate_mse_some_diff).
+        assert!((calculate_rmse(&profile, &target) - 7.75f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_relative_mse_is_scale_invariant() {
+        // Every nutrient 10% over target: kcal no longer dominates or vanishes, whatever the density.
+        for scale in [1.0, 4.0, 10.0] {
+            let target = TargetNutritionalValues {
+                kcal: Some(200.0 * scale),
+                protein_g: Some(20.0 * scale),
+                fat_g: Some(5.0 * scale),
+                ..Default::default()
+            };
+            let profile = NutritionalSummary {
+                kcal: Some(220.0 * scale),
+                protein_g: Some(22.0 * scale),
+                fat_g: Some(5.5 * scale),
+                ..Default::default()
+            };
+            assert!((calculate_mse(&profile, &target) - 0.01).abs() < 1e-6);
+        }
+
+        // A zero target is measured against 1 g.
+        let target = TargetNutritionalValues { fat_g: Some(0.0), ..Default::default() };
+        let profile = NutritionalSummary { fat_g: Some(0.5), ..Default::default() };
+        assert_eq!(calculate_mse(&profile, &target), 0.25);
+    }
+
+    #[test]
+    fn test_calculate_mse_strict_penalizes_missing_profile_fields() {
+        let target = TargetNutritionalValues {
+            protein_g: Some(20.0),
+            fat_g: Some(7.0),
+            ..Default::default()
+        };
+        let complete = NutritionalSummary { protein_g: Some(20.0), fat_g: Some(5.0), ..Default::default() };
+        let degraded = NutritionalSummary { protein_g: Some(20.0), ..Default::default() };
+
+        // Complete profiles score the same in both modes.
+        assert_eq!(calculate_mse_strict(&complete, &target), calculate_mse(&complete, &target));
+        // Losing the fat value "improves" the lenient MSE but not the strict one.
+        assert!(calculate_mse(&degraded, &target) < calculate_mse(&complete, &target));
+        assert_eq!(calculate_mse_strict(&degraded, &target), MISSING_NUTRIENT_PENALTY / 2.0);
+        assert!(calculate_mse_strict(&degraded, &target) > calculate_mse_strict(&complete, &target));
+    }
+
+    #[test]
+    fn test_tolerance_bands_check_targets() {
+        assert_eq!("5%".parse::<Tolerance>(), Ok(Tolerance::Percent(5.0)));
+        assert_eq!("2g".parse::<Tolerance>(), Ok(Tolerance::Absolute(2.0)));
+        assert_eq!(" 1.5 ".parse::<Tolerance>(), Ok(Tolerance::Absolute(1.5)));
+        assert!("-3%".parse::<Tolerance>().is_err());
+        assert!("lots".parse::<Tolerance>().is_err());
+
+        let profile = NutritionalSummary { protein_g: Some(18.0), fat_g: Some(6.0), ..Default::default() };
+        let target = TargetNutritionalValues { protein_g: Some(20.0), fat_g: Some(5.0), carbohydrate_g: Some(15.0), ..Default::default() };
+        let bands = ToleranceBands::default().with_tolerance(OptimizableNutrient::Fat, Tolerance::Absolute(1.0));
+        let tolerances = bands.for_targets([OptimizableNutrient::Fat, OptimizableNutrient::Protein]);
+
+        let checks = tolerances.check(&profile, &target);
+        assert_eq!(checks.len(), 2);
+        assert!(checks[0].within);
+        assert_eq!(checks[0].to_string(), "fat: 6.0g vs target 5.0g (+20%): within ±1g");
+        assert!(!checks[1].within, "protein is 10% under, outside the default 5%");
+        assert_eq!(checks[1].to_string(), "protein: 18.0g vs target 20.0g (-10%): OUT of ±5%");
+        assert_eq!(checks[1].error.optimizable, Some(OptimizableNutrient::Protein));
+        assert!(!tolerances.all_met(&profile, &target));
+
+        let fat_only = bands.for_targets([OptimizableNutrient::Fat]);
+        assert!(fat_only.all_met(&profile, &target));
+        // Carbohydrate has a target but no value: not met.
+        assert!(!bands.for_targets([OptimizableNutrient::Carb]).all_met(&profile, &target));
+    }
 }
@@ -1,14 +1,25 @@
-use anyhow::{Result, Context, anyhow};
+use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::recipe_converter::{CleanedRecipe, CleanedIngredient, convert_ingredients_to_grams, CalculatedNutritionalInfo};
-use crate::recipe_parser::{ParsedRecipe, ParsedIngredient}; 
-use crate::recipe_aggregator::{calculate_nutritional_profile, RecipeNutritionalProfile, NutritionalSummary};
+use crate::recipe_converter::{CleanedRecipe, convert_ingredients_to_grams, ContainerSizes, ConversionOptions, ToTasteDefaults, DEFAULT_CONVERSION_PARSE_RETRIES};
+use crate::recipe_parser::{grams_per_unit, is_known_unit, parse_quantity, ParsedRecipe, ParsedIngredient}; 
+use crate::recipe_aggregator::{
+    calculate_nutritional_profile, ensure_nutrition_computed, EnrichedRecipeOutput, NutritionalSummary, RecipeNutritionalProfile,
+};
+use crate::{log_verbose, log_warning};
 use crate::nutritional_matcher::NutritionalIndex;
+use crate::progress::{ProgressEvent, ProgressStage};
+use crate::prompts::{PromptKind, PromptTemplates};
 use crate::optim::targets::TargetNutritionalValues;
-use crate::optim::nutri_eval::calculate_mse; 
-use crate::api_connection::endpoints::{ChatCompletionRequest, ChatMessage, ResponseFormat, JsonSchemaDefinition, JsonSchema, JsonSchemaProperty, Provider};
+use crate::optim::nutri_eval::{calculate_mse_with_mode, MseMode, TargetTolerances};
+use crate::api_connection::endpoints::{ChatCompletionRequest, ChatMessage, ResponseFormat, ResponseFormatMode, JsonSchemaDefinition, JsonSchema, JsonSchemaProperty, StageGenParams};
+use crate::api_connection::client::{complete_untruncated, ChatClient, TRUNCATION_RETRIES};
+use crate::api_connection::usage::log_stage_usage;
+use crate::api_connection::json_extract::{extract_json_object, JsonMode};
 
 // --- Structs for LLM Interaction ---
 
@@ -47,14 +58,328 @@ pub struct LlmModificationResponse {
     pub overall_reasoning: String,
 }
 
+impl LlmRecipeModification {
+    /// One-line summary, e.g. "Replace 'butter' with 'olive oil' (80 g)".
+    pub fn describe(&self) -> String {
+        let name = |name: &Option<String>| name.as_deref().unwrap_or("?").to_string();
+        let amount = match (self.quantity_raw.as_deref(), self.unit_raw.as_deref().filter(|unit| !unit.is_empty())) {
+            (Some(quantity), Some(unit)) => format!(" ({} {})", quantity, unit),
+            (Some(quantity), None) => format!(" ({})", quantity),
+            _ => String::new(),
+        };
+        match self.operation {
+            LlmOperationType::ReplaceIngredient => format!(
+                "Replace '{}' with '{}'{}",
+                name(&self.original_ingredient_name),
+                name(&self.replacement_description.clone().or_else(|| self.new_ingredient_name.clone())),
+                amount
+            ),
+            LlmOperationType::AdjustQuantity => format!("Adjust '{}'{}", name(&self.original_ingredient_name), amount),
+            LlmOperationType::AddIngredient => format!("Add '{}'{}", name(&self.new_ingredient_name), amount),
+            LlmOperationType::RemoveIngredient => format!("Remove '{}'", name(&self.original_ingredient_name)),
+            LlmOperationType::NoChange => "No change".to_string(),
+        }
+    }
+}
+
+impl LlmModificationResponse {
+    /// The LLM's explanations: the overall reasoning, then one line per modification that gave one.
+    pub fn reasoning_notes(&self) -> Vec<String> {
+        let mut notes: Vec<String> = Some(self.overall_reasoning.trim())
+            .filter(|reasoning| !reasoning.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .collect();
+        for modification in &self.modifications {
+            let Some(reasoning) = modification.reasoning.as_deref().map(str::trim).filter(|r| !r.is_empty()) else {
+                continue;
+            };
+            let action = match modification.operation {
+                LlmOperationType::ReplaceIngredient => "Replaced",
+                LlmOperationType::AdjustQuantity => "Adjusted",
+                LlmOperationType::AddIngredient => "Added",
+                LlmOperationType::RemoveIngredient => "Removed",
+                LlmOperationType::NoChange => continue,
+            };
+            let target = modification.original_ingredient_name.as_deref()
+                .or(modification.new_ingredient_name.as_deref())
+                .or(modification.replacement_description.as_deref())
+                .unwrap_or("ingredient");
+            notes.push(format!("{} '{}': {}", action, target, reasoning));
+        }
+        notes
+    }
+}
+
 // --- Helper function to apply LLM modifications ---
 
-fn apply_modifications_to_recipe(
+/// True when the LLM gave no usable quantity: missing, blank, or a number that is not strictly positive.
+/// Textual quantities ("1/2", "a pinch") are left to the gram conversion step.
+fn is_nonsensical_quantity(quantity_raw: Option<&str>) -> bool {
+    match quantity_raw.map(str::trim) {
+        None | Some("") => true,
+        Some(raw) => raw.parse::<f32>().is_ok_and(|q| !q.is_finite() || q <= 0.0),
+    }
+}
+
+/// Rejects a quantity/unit pair the gram conversion couldn't use: the quantity must parse to a
+/// positive number and the unit must be a known one. `target` names the ingredient in the error.
+fn validate_quantity_and_unit(quantity: &str, unit: &str, target: &str) -> Result<()> {
+    if !parse_quantity(quantity).is_some_and(|value| value > 0.0) {
+        return Err(anyhow!("Quantity '{}' for '{}' is not a positive number", quantity, target));
+    }
+    if !is_known_unit(unit) {
+        return Err(anyhow!("Unit '{}' for '{}' is not a recognized unit", unit, target));
+    }
+    Ok(())
+}
+
+/// `quantity`/`unit` clamped to the limit's bounds for the ingredient, in grams when clamped.
+/// Units without a fixed weight (cups, pieces) can't be checked and are left as they are.
+fn clamp_quantity(
+    limit: &QuantityChangeLimit,
+    ingredient_name: &str,
+    current_grams: Option<f32>,
+    quantity: &str,
+    unit: &str,
+    progress_updater: &impl Fn(ProgressEvent),
+) -> (String, String) {
+    let unchanged = (quantity.to_string(), unit.to_string());
+    let Some((min_grams, max_grams)) = limit.bounds(ingredient_name, current_grams) else {
+        return unchanged;
+    };
+    let Some(grams) = parse_quantity(quantity).zip(grams_per_unit(unit)).map(|(quantity, per_unit)| quantity * per_unit) else {
+        progress_updater(format!("    Can't check the quantity limit for {} in '{}'; keeping {} {}.", ingredient_name, unit, quantity, unit).into());
+        return unchanged;
+    };
+    let clamped = grams.clamp(min_grams, max_grams);
+    if (clamped - grams).abs() < 0.05 {
+        return unchanged;
+    }
+    progress_updater(format!(
+        "    Clamped {} from {:.1} g to {:.1} g (limit ±{}% of {:.1}-{:.1} g).",
+        ingredient_name, grams, clamped, limit.max_change_pct, min_grams, max_grams
+    ).into());
+    (format!("{:.1}", clamped), "g".to_string())
+}
+
+/// Ingredient names as written by the LLM often differ from the recipe in case or surrounding whitespace.
+fn ingredient_names_match(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
+
+/// Minimum `name_similarity` for a fuzzy match to be accepted.
+const FUZZY_NAME_MATCH_THRESHOLD: f32 = 0.75;
+
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = previous_row[j] + usize::from(a_char != *b_char);
+            current_row.push(substitution.min(previous_row[j + 1] + 1).min(current_row[j] + 1));
+        }
+        previous_row = current_row;
+    }
+    previous_row[b_chars.len()]
+}
+
+/// Similarity in [0, 1] between two ingredient names: the better of the normalized Levenshtein similarity
+/// and a token-containment ratio ("flour" is contained in "all-purpose flour"), the latter capped
+/// slightly below an exact match.
+fn name_similarity(a: &str, b: &str) -> f32 {
+    let (a, b) = (a.trim().to_lowercase(), b.trim().to_lowercase());
+    if a == b {
+        return 1.0;
+    }
+    let max_len = a.chars().count().max(b.chars().count());
+    let edit_similarity = 1.0 - levenshtein_distance(&a, &b) as f32 / max_len as f32;
+
+    let tokens = |name: &str| -> HashSet<String> {
+        name.split(|c: char| !c.is_alphanumeric())
+            .filter(|token| !token.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let (a_tokens, b_tokens) = (tokens(&a), tokens(&b));
+    let smaller = a_tokens.len().min(b_tokens.len());
+    let token_similarity = if smaller == 0 {
+        0.0
+    } else {
+        0.9 * a_tokens.intersection(&b_tokens).count() as f32 / smaller as f32
+    };
+
+    edit_similarity.max(token_similarity)
+}
+
+/// Resolves a name given by the LLM to an ingredient of the recipe, tolerating slight variants.
+/// Returns the requested name unchanged when no ingredient is similar enough, so callers keep
+/// their not-found handling.
+fn resolve_ingredient_name(
+    ingredients: &[ParsedIngredient],
+    requested_name: &str,
+    progress_updater: &impl Fn(ProgressEvent),
+) -> String {
+    if ingredients.iter().any(|ing| ingredient_names_match(&ing.ingredient_name, requested_name)) {
+        return requested_name.to_string();
+    }
+
+    let mut best_match: Option<(f32, &ParsedIngredient)> = None;
+    for ing in ingredients {
+        let similarity = name_similarity(&ing.ingredient_name, requested_name);
+        // Ties keep the earliest ingredient so the choice is deterministic.
+        if similarity >= FUZZY_NAME_MATCH_THRESHOLD && best_match.is_none_or(|(best, _)| similarity > best) {
+            best_match = Some((similarity, ing));
+        }
+    }
+
+    match best_match {
+        Some((similarity, ing)) => {
+            progress_updater(format!(
+                "    Resolved ingredient '{}' to '{}' (similarity {:.2}).",
+                requested_name, ing.ingredient_name, similarity
+            ).into());
+            ing.ingredient_name.clone()
+        }
+        None => requested_name.to_string(),
+    }
+}
+
+/// True when an adjustment would leave the ingredient's quantity unchanged.
+fn is_same_quantity(ingredient: &ParsedIngredient, new_quantity: &str, new_unit: &str) -> bool {
+    if !ingredient.unit.trim().eq_ignore_ascii_case(new_unit.trim()) {
+        return false;
+    }
+    match (ingredient.quantity.trim().parse::<f32>(), new_quantity.trim().parse::<f32>()) {
+        (Ok(current), Ok(new)) => (current - new).abs() < 0.05,
+        _ => ingredient.quantity.trim() == new_quantity.trim(),
+    }
+}
+
+/// Bound on `AdjustQuantity` changes: the new grams stay within `max_change_pct` percent of the
+/// ingredient's grams in `original`, the recipe the optimization started from.
+#[derive(Debug, Clone, Copy)]
+pub struct QuantityChangeLimit<'a> {
+    pub max_change_pct: f32,
+    pub original: &'a CleanedRecipe,
+}
+
+impl QuantityChangeLimit<'_> {
+    /// Grams allowed for the ingredient: the original recipe's, or `current_grams` for an ingredient
+    /// the optimization added.
+    fn bounds(&self, ingredient_name: &str, current_grams: Option<f32>) -> Option<(f32, f32)> {
+        let reference = self.original.ingredients.iter()
+            .find(|ing| ingredient_names_match(&ing.ingredient_name, ingredient_name))
+            .map_or(current_grams, |ing| ing.quantity_grams)?;
+        let margin = reference * self.max_change_pct / 100.0;
+        Some(((reference - margin).max(0.0), reference + margin))
+    }
+}
+
+/// Rejects a modification of `ingredient_name` when an `optimizable` list is given and doesn't name it.
+fn ensure_optimizable(optimizable: &[String], ingredient_name: &str, operation: &str) -> Result<()> {
+    if optimizable.is_empty() || optimizable.iter().any(|allowed| ingredient_names_match(allowed, ingredient_name)) {
+        return Ok(());
+    }
+    Err(anyhow!("{} on '{}' is not allowed: only {} may be modified", operation, ingredient_name, optimizable.join(", ")))
+}
+
+/// Default `MassBand::tolerance_pct`.
+pub const DEFAULT_MASS_TOLERANCE_PCT: f32 = 10.0;
+
+/// Range the total recipe mass must stay in: `target_g`, give or take `tolerance_pct` percent of it.
+/// Candidates outside it are rejected, for recipes baked in a fixed pan or served in fixed portions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MassBand {
+    pub target_g: f32,
+    pub tolerance_pct: f32,
+}
+
+impl MassBand {
+    pub fn bounds(&self) -> (f32, f32) {
+        let margin = self.target_g * self.tolerance_pct / 100.0;
+        ((self.target_g - margin).max(0.0), self.target_g + margin)
+    }
+
+    /// Explains why a recipe of `total_mass_g` falls outside the band, if it does. An unknown mass
+    /// can't be shown to be inside it.
+    fn violation(&self, total_mass_g: Option<f32>) -> Option<String> {
+        let (min_g, max_g) = self.bounds();
+        match total_mass_g {
+            None => Some("total mass is unknown".to_string()),
+            Some(mass_g) if mass_g < min_g || mass_g > max_g => Some(format!(
+                "total mass of {:.0}g is outside {:.0}-{:.0}g", mass_g, min_g, max_g
+            )),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Default `Exploration::cooling_rate`.
+pub const DEFAULT_COOLING_RATE: f32 = 0.8;
+
+/// Simulated-annealing acceptance: a candidate worse than the current recipe by a fraction `w` of
+/// its MSE is still accepted with probability `exp(-w / T)`, so the search can leave a plateau
+/// the greedy loop is stuck on. `T` starts at `initial_temperature` and is multiplied by
+/// `cooling_rate` every iteration; the best recipe seen is what the optimization returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exploration {
+    pub initial_temperature: f32,
+    pub cooling_rate: f32,
+    /// Seed of the acceptance draws, so a run can be repeated.
+    pub seed: u64,
+}
+
+impl Exploration {
+    fn temperature(&self, iteration: u32) -> f32 {
+        self.initial_temperature * self.cooling_rate.powi(iteration as i32)
+    }
+
+    /// Probability of moving from a recipe of `current_mse` to a worse one of `candidate_mse`.
+    fn acceptance_probability(&self, current_mse: f32, candidate_mse: f32, iteration: u32) -> f32 {
+        let temperature = self.temperature(iteration);
+        if temperature <= 0.0 {
+            return 0.0;
+        }
+        let worsening = (candidate_mse - current_mse) / current_mse.max(f32::EPSILON);
+        (-worsening / temperature).exp()
+    }
+}
+
+/// SplitMix64: the exploration's draws need to be reproducible from a seed, not cryptographic.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    /// Uniform in [0, 1).
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Applies the LLM suggestions to the current recipe, returning a recipe to convert and match again.
+/// Kept ingredients come first, with their grams as quantity when known; added and replacement
+/// ingredients follow in suggestion order. Removing an ingredient that isn't there is a no-op, and a
+/// replacement whose original is missing is simply added. An unusable suggestion is an error.
+/// With `preserve_mass`, a replacement whose quantity is missing or nonsensical takes over the grams of the
+/// ingredient it replaces, and such an addition is rejected, so total recipe mass isn't changed arbitrarily.
+/// With a `quantity_limit`, adjusted quantities in mass units are clamped to its bounds.
+/// A non-empty `optimizable` list restricts the suggestions to those ingredients: removing, adjusting
+/// or replacing anything else, or adding an ingredient not on it, is an error.
+pub fn apply_modifications_to_recipe(
     current_recipe: &CleanedRecipe,
     llm_suggestions: &LlmModificationResponse,
-    progress_updater: &impl Fn(String),
+    preserve_mass: bool,
+    quantity_limit: Option<&QuantityChangeLimit>,
+    optimizable: &[String],
+    progress_updater: &impl Fn(ProgressEvent),
 ) -> Result<ParsedRecipe> {
-    progress_updater("Applying LLM suggestions to create a candidate recipe...".to_string());
+    progress_updater("Applying LLM suggestions to create a candidate recipe...".into());
     let mut candidate_ingredients: Vec<ParsedIngredient> = current_recipe.ingredients.iter().map(|ci| {
         let (quantity, unit) = ci.quantity_grams.map_or_else(
             || (ci.original_quantity.clone(), ci.original_unit.clone()),
@@ -67,53 +392,70 @@ fn apply_modifications_to_recipe(
             quantity,
             unit,
             preparation_notes: ci.preparation_notes.clone(),
+            section: ci.section.clone(),
+            optional: ci.optional,
         }
     }).collect();
 
     let mut new_ingredients_from_llm: Vec<ParsedIngredient> = Vec::new();
 
     for modification in &llm_suggestions.modifications {
-        progress_updater(format!("  Applying operation: {:?} for {:?}", modification.operation, modification.original_ingredient_name.as_deref().or(modification.replacement_description.as_deref())));
+        progress_updater(format!("  Applying operation: {:?} for {:?}", modification.operation, modification.original_ingredient_name.as_deref().or(modification.replacement_description.as_deref())).into());
         match modification.operation {
             LlmOperationType::RemoveIngredient => {
                 let original_name = modification.original_ingredient_name.as_ref()
                     .ok_or_else(|| anyhow!("'original_ingredient_name' missing for RemoveIngredient operation."))?;
-                candidate_ingredients.retain(|ing| &ing.ingredient_name != original_name);
-                progress_updater(format!("    Removed ingredient: {}", original_name));
+                let original_name = &resolve_ingredient_name(&candidate_ingredients, original_name, progress_updater);
+                ensure_optimizable(optimizable, original_name, "RemoveIngredient")?;
+                candidate_ingredients.retain(|ing| !ingredient_names_match(&ing.ingredient_name, original_name));
+                progress_updater(format!("    Removed ingredient: {}", original_name).into());
             }
             LlmOperationType::AdjustQuantity => {
                 let original_name = modification.original_ingredient_name.as_ref()
                     .ok_or_else(|| anyhow!("'original_ingredient_name' missing for AdjustQuantity operation."))?;
+                let original_name = &resolve_ingredient_name(&candidate_ingredients, original_name, progress_updater);
+                ensure_optimizable(optimizable, original_name, "AdjustQuantity")?;
                 let new_quantity = modification.quantity_raw.as_ref()
                     .ok_or_else(|| anyhow!("'quantity_raw' missing for AdjustQuantity on '{}'", original_name))?;
                 let new_unit = modification.unit_raw.as_ref()
                     .ok_or_else(|| anyhow!("'unit_raw' missing for AdjustQuantity on '{}'", original_name))?;
-                
-                let mut found = false;
-                for ing in candidate_ingredients.iter_mut() {
-                    if &ing.ingredient_name == original_name {
-                        ing.quantity = new_quantity.clone();
-                        ing.unit = new_unit.clone();
-                        ing.raw_text = format!("{} {} {}", new_quantity, new_unit, ing.ingredient_name); 
-                        if let Some(notes) = &modification.preparation_notes {
-                            ing.preparation_notes = notes.clone();
-                        }
-                        found = true;
-                        progress_updater(format!("    Adjusted quantity for {}: to {} {}", original_name, new_quantity, new_unit));
-                        break;
-                    }
+                validate_quantity_and_unit(new_quantity, new_unit, original_name)?;
+                let current_grams = current_recipe.ingredients.iter()
+                    .find(|ing| ingredient_names_match(&ing.ingredient_name, original_name))
+                    .and_then(|ing| ing.quantity_grams);
+                let (new_quantity, new_unit) = &match quantity_limit {
+                    Some(limit) => clamp_quantity(limit, original_name, current_grams, new_quantity, new_unit, progress_updater),
+                    None => (new_quantity.clone(), new_unit.clone()),
+                };
+
+                // A not-found or no-op adjustment is an error so the optimizer skips the candidate
+                // instead of evaluating an unchanged recipe.
+                let ing = candidate_ingredients.iter_mut()
+                    .find(|ing| ingredient_names_match(&ing.ingredient_name, original_name))
+                    .ok_or_else(|| anyhow!("Ingredient '{}' not found for AdjustQuantity", original_name))?;
+                if is_same_quantity(ing, new_quantity, new_unit) {
+                    return Err(anyhow!("AdjustQuantity on '{}' keeps the same quantity ({} {})", original_name, new_quantity, new_unit));
                 }
-                if !found {
-                    progress_updater(format!("    Warning: Ingredient '{}' not found for AdjustQuantity.", original_name));
+                ing.quantity = new_quantity.clone();
+                ing.unit = new_unit.clone();
+                ing.raw_text = format!("{} {} {}", new_quantity, new_unit, ing.ingredient_name); 
+                if let Some(notes) = &modification.preparation_notes {
+                    ing.preparation_notes = notes.clone();
                 }
+                progress_updater(format!("    Adjusted quantity for {}: to {} {}", original_name, new_quantity, new_unit).into());
             }
             LlmOperationType::AddIngredient => {
                 let description = modification.replacement_description.as_ref()
                     .ok_or_else(|| anyhow!("'replacement_description' missing for AddIngredient operation."))?;
+                ensure_optimizable(optimizable, modification.new_ingredient_name.as_ref().unwrap_or(description), "AddIngredient")?;
+                if preserve_mass && is_nonsensical_quantity(modification.quantity_raw.as_deref()) {
+                    return Err(anyhow!("Invalid quantity {:?} for AddIngredient of '{}' in mass-preserving mode", modification.quantity_raw, description));
+                }
                 let quantity = modification.quantity_raw.as_ref()
                     .ok_or_else(|| anyhow!("'quantity_raw' missing for AddIngredient of '{}'", description))?;
                 let unit = modification.unit_raw.as_ref()
                     .ok_or_else(|| anyhow!("'unit_raw' missing for AddIngredient of '{}'", description))?;
+                validate_quantity_and_unit(quantity, unit, description)?;
                 
                 let new_parsed_ingredient = ParsedIngredient {
                     raw_text: format!("{} {} {}", quantity, unit, description), 
@@ -121,26 +463,47 @@ fn apply_modifications_to_recipe(
                     quantity: quantity.clone(),
                     unit: unit.clone(),
                     preparation_notes: modification.preparation_notes.clone().unwrap_or_default(),
+                    section: None,
+                    optional: false,
                 };
                 new_ingredients_from_llm.push(new_parsed_ingredient.clone());
-                progress_updater(format!("    Added ingredient: {} {} {}", quantity, unit, description));
+                progress_updater(format!("    Added ingredient: {} {} {}", quantity, unit, description).into());
             }
             LlmOperationType::ReplaceIngredient => {
                 let original_name = modification.original_ingredient_name.as_ref()
                     .ok_or_else(|| anyhow!("'original_ingredient_name' missing for ReplaceIngredient operation."))?;
+                let original_name = &resolve_ingredient_name(&candidate_ingredients, original_name, progress_updater);
+                ensure_optimizable(optimizable, original_name, "ReplaceIngredient")?;
                 let replacement_desc = modification.replacement_description.as_ref()
                     .ok_or_else(|| anyhow!("'replacement_description' missing for ReplaceIngredient of '{}'", original_name))?;
-                let quantity = modification.quantity_raw.as_ref()
-                    .ok_or_else(|| anyhow!("'quantity_raw' missing for ReplaceIngredient of '{}'", original_name))?;
-                let unit = modification.unit_raw.as_ref()
-                    .ok_or_else(|| anyhow!("'unit_raw' missing for ReplaceIngredient of '{}'", original_name))?;
+                let replaced_grams = current_recipe.ingredients.iter()
+                    .find(|ing| ingredient_names_match(&ing.ingredient_name, original_name))
+                    .and_then(|ing| ing.quantity_grams);
+                let (quantity, unit) = match replaced_grams {
+                    Some(grams) if preserve_mass && is_nonsensical_quantity(modification.quantity_raw.as_deref()) => {
+                        progress_updater(format!("    (Replace) Keeping the {:.1} g of '{}' for the replacement to preserve mass.", grams, original_name).into());
+                        (format!("{:.1}", grams), "g".to_string())
+                    }
+                    _ => {
+                        let quantity = modification.quantity_raw.as_ref()
+                            .ok_or_else(|| anyhow!("'quantity_raw' missing for ReplaceIngredient of '{}'", original_name))?;
+                        let unit = modification.unit_raw.as_ref()
+                            .ok_or_else(|| anyhow!("'unit_raw' missing for ReplaceIngredient of '{}'", original_name))?;
+                        validate_quantity_and_unit(quantity, unit, replacement_desc)?;
+                        (quantity.clone(), unit.clone())
+                    }
+                };
 
-                let original_exists = candidate_ingredients.iter().any(|ing| &ing.ingredient_name == original_name);
+                // The replacement takes over the replaced ingredient's group.
+                let original_section = candidate_ingredients.iter()
+                    .find(|ing| ingredient_names_match(&ing.ingredient_name, original_name))
+                    .map(|ing| ing.section.clone());
+                let original_exists = original_section.is_some();
                 if original_exists {
-                    candidate_ingredients.retain(|ing| &ing.ingredient_name != original_name);
-                    progress_updater(format!("    (Replace) Removed ingredient: {}", original_name));
+                    candidate_ingredients.retain(|ing| !ingredient_names_match(&ing.ingredient_name, original_name));
+                    progress_updater(format!("    (Replace) Removed ingredient: {}", original_name).into());
                 } else {
-                     progress_updater(format!("    Warning: Original ingredient '{}' for replacement not found.", original_name));
+                     progress_updater(ProgressEvent::Warning { text: format!("    Warning: Original ingredient '{}' for replacement not found.", original_name) });
                 }
                 
                 let new_parsed_ingredient = ParsedIngredient {
@@ -149,12 +512,14 @@ fn apply_modifications_to_recipe(
                     quantity: quantity.clone(),
                     unit: unit.clone(),
                     preparation_notes: modification.preparation_notes.clone().unwrap_or_default(),
+                    section: original_section.flatten(),
+                    optional: false,
                 };
                 new_ingredients_from_llm.push(new_parsed_ingredient.clone());
-                progress_updater(format!("    (Replace) Added ingredient: {} {} {}", quantity, unit, replacement_desc));
+                progress_updater(format!("    (Replace) Added ingredient: {} {} {}", quantity, unit, replacement_desc).into());
             }
             LlmOperationType::NoChange => {
-                progress_updater("    NoChange operation encountered within apply_modifications. This is unexpected here.".to_string());
+                progress_updater("    NoChange operation encountered within apply_modifications. This is unexpected here.".into());
             }
         }
     }
@@ -165,56 +530,87 @@ fn apply_modifications_to_recipe(
         recipe_title: current_recipe.recipe_title.clone(), 
         ingredients: candidate_ingredients,
         instructions: current_recipe.instructions.clone(), 
+        servings: current_recipe.servings,
+        total_time_minutes: current_recipe.total_time_minutes,
+        heuristically_parsed: false,
     })
 }
 
-// --- Main Optimization Function ---
+// --- Candidate guards ---
 
-pub async fn optimize_recipe(
-    initial_cleaned_recipe: &CleanedRecipe,
-    initial_nutritional_profile: &RecipeNutritionalProfile,
-    target_nutrition_per_100g: &TargetNutritionalValues,
-    max_iterations: u32,
-    nutritional_index: &NutritionalIndex,
-    api_key_env_var: &str,
-    progress_updater: impl Fn(String) + Send + Sync + Clone + 'static,
-) -> Result<CleanedRecipe> {
-    progress_updater(format!("Starting recipe optimization. Max iterations: {}", max_iterations));
-    progress_updater(format!("Initial recipe title: {}", initial_cleaned_recipe.recipe_title));
-    progress_updater(format!("Target nutrition (per 100g): {:?}", target_nutrition_per_100g));
+/// Largest tolerated drop, between the current best and a candidate, in the fraction of mass or of
+/// ingredients that has nutrition data.
+const MAX_COVERAGE_DROP: f32 = 0.1;
 
-    let mut current_best_recipe = initial_cleaned_recipe.clone();
-    let mut current_best_profile = initial_nutritional_profile.clone();
-    let mut current_best_mse = calculate_mse(&current_best_profile.per_100g, target_nutrition_per_100g);
-    progress_updater(format!("Initial MSE: {:.4}", current_best_mse));
+/// How much of a recipe contributes to its nutritional profile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NutritionCoverage {
+    /// Share of the converted mass coming from ingredients with nutrition data.
+    mass_fraction: f32,
+    /// Share of the ingredients with nutrition data.
+    ingredient_fraction: f32,
+}
 
-    for i in 0..max_iterations {
-        progress_updater(format!("\n--- Optimization Iteration {}/{} ---", i + 1, max_iterations));
+fn nutrition_coverage(recipe: &CleanedRecipe) -> NutritionCoverage {
+    let mut total_mass_g = 0.0;
+    let mut enriched_mass_g = 0.0;
+    let mut enriched_count = 0;
+    for ingredient in &recipe.ingredients {
+        let grams = ingredient.quantity_grams.filter(|grams| *grams > 0.0).unwrap_or(0.0);
+        total_mass_g += grams;
+        if ingredient.nutritional_info.is_some() && grams > 0.0 {
+            enriched_mass_g += grams;
+            enriched_count += 1;
+        }
+    }
+    let fraction = |part: f32, whole: f32| if whole > 0.0 { part / whole } else { 0.0 };
+    NutritionCoverage {
+        mass_fraction: fraction(enriched_mass_g, total_mass_g),
+        ingredient_fraction: fraction(enriched_count as f32, recipe.ingredients.len() as f32),
+    }
+}
 
-        // 1. Construct Prompt for LLM
-        let system_prompt = format!(
-            "/no_thinking
+/// Explains why a candidate lost too much nutrition data compared to the current best, if it did.
+/// Such a candidate could otherwise "improve" its MSE just by dropping nutrients from the profile.
+fn coverage_loss(current: NutritionCoverage, candidate: NutritionCoverage) -> Option<String> {
+    if current.mass_fraction - candidate.mass_fraction > MAX_COVERAGE_DROP {
+        return Some(format!(
+            "mass with nutrition data dropped from {:.0}% to {:.0}%",
+            current.mass_fraction * 100.0, candidate.mass_fraction * 100.0
+        ));
+    }
+    if current.ingredient_fraction - candidate.ingredient_fraction > MAX_COVERAGE_DROP {
+        return Some(format!(
+            "ingredients with nutrition data dropped from {:.0}% to {:.0}%",
+            current.ingredient_fraction * 100.0, candidate.ingredient_fraction * 100.0
+        ));
+    }
+    None
+}
+
+/// Built-in system prompt of the optimizer; see `PromptKind::OptimizerSystem`.
+pub const OPTIMIZER_SYSTEM_PROMPT: &str = "/no_thinking
 You are a recipe optimization assistant. Your goal is to modify the given recipe to meet specific nutritional targets while maintaining or improving palatability and culinary coherence.
 Output your suggested modifications as a JSON object.
 The JSON object must be the only content in your response. Do not include any explanatory text, comments, or markdown formatting (like ```json) before or after the JSON object.
-Your response must start with {{{{ and end with }}}}.
+Your response must start with {{ and end with }}.
 
 The JSON object MUST adhere to the 'recipe_modification_suggestions' schema provided to you.
 The 'modifications' array MUST contain **EXACTLY ONE** modification object.
 Example of the required structure:
-{{{{
+{{
   \"modifications\": [
-    {{ \"operation\": \"replace_ingredient\", \"original_ingredient_name\": \"example original\", \"replacement_description\": \"example replacement\", \"quantity_raw\": \"100\", \"unit_raw\": \"g\", \"reasoning\": \"This single change is most impactful.\" }}
+    { \"operation\": \"replace_ingredient\", \"original_ingredient_name\": \"example original\", \"replacement_description\": \"example replacement\", \"quantity_raw\": \"100\", \"unit_raw\": \"g\", \"reasoning\": \"This single change is most impactful.\" }
   ],
   \"overall_reasoning\": \"This is the overall explanation for why this single change helps meet the target.\"
-}}}}
+}}
 Do NOT nest this structure inside any other keys.
 The 'overall_reasoning' field MUST be a string at the top level.
 
 **CRITICAL RULE: You MUST suggest EXACTLY ONE modification in the 'modifications' array.**
 This single modification should be the one you believe will have the most positive impact on reducing the MSE towards the target nutritional profile, while being culinarily sensible.
 
-Current MSE (Mean Squared Error) from target: {:.4} (lower is better). Aim to reduce this with your single suggested change.
+Current MSE (Mean Squared Error) from target: {mse} (lower is better). Aim to reduce this with your single suggested change.
 **Strategy Guidance for your SINGLE modification:**
 - **Highest Impact:** Choose the single change (replace, adjust, add, or remove an ingredient) that you predict will best improve the nutritional profile towards the targets.
 - **Culinary Sense:** The change MUST make sense for the recipe type.
@@ -229,77 +625,315 @@ Consider the following operations for your **SINGLE** modification:
 - 'no_change': Use this if no single beneficial change can be identified.
 
 When suggesting quantities and units for your single modification:
-- For 'quantity_raw', provide a string that can be parsed as a number or a common textual quantity.
-- For 'unit_raw', provide a common unit.
+- For 'quantity_raw', provide a positive number (e.g. '80', '1.5' or '1/2'), not words like 'half'.
+- For 'unit_raw', provide a common unit (e.g. 'g', 'ml', 'cup', 'tbsp', 'piece'); never leave it empty.
 
 The 'Current Recipe Ingredients' list below shows ingredients with their quantities primarily in grams (g).
 Focus on macronutrient targets (protein, carbohydrates, fat). Kcal is derived.
 The 'original_ingredient_name' for any modification MUST EXACTLY MATCH one of the ingredient names from the 'Current Recipe Ingredients' list.
-",
-        current_best_mse 
-        );
-
-        let current_ingredients_text = current_best_recipe.ingredients.iter()
-            .map(|ing| {
-                let quantity_display = ing.quantity_grams.map_or_else( 
-                    || ing.raw_text.clone(), 
-                    |q_g| format!("{:.1} g", q_g) 
-                );
-                format!("- {} (Current Quantity: {}, Original Text: '{}')", 
-                    ing.ingredient_name, 
-                    quantity_display,
-                    ing.raw_text 
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        let opt_f32_to_str = |val: Option<f32>| val.map_or_else(|| "N/A".to_string(), |v| format!("{:.1}", v));
+";
 
-        let user_prompt_content = format!(
-"Current Recipe Title: {}
+/// Built-in user prompt of the optimizer; see `PromptKind::OptimizerUser`.
+pub const OPTIMIZER_USER_PROMPT: &str = "Current Recipe Title: {recipe_title}
 
 Current Recipe Ingredients:
-{}
+{ingredients}
 
 Current Nutritional Profile (per 100g):
-- Kcal: {}
-- Protein: {} g
-- Carbohydrates: {} g
-- Fat: {} g
-- Sugars: {} g (for reference)
-- Saturated Fat: {} g (for reference)
-- Salt: {} g (for reference)
+- Kcal: {kcal}
+- Protein: {protein_g} g
+- Carbohydrates: {carbohydrate_g} g
+- Fat: {fat_g} g
+- Sugars: {sugars_g} g (for reference)
+- Saturated Fat: {fa_saturated_g} g (for reference)
+- Salt: {salt_g} g (for reference)
+- Fiber: {fiber_g} g (for reference)
+- Cholesterol: {cholesterol_mg} mg (for reference)
 
 Target Nutritional Profile (per 100g):
-- Kcal: {} (estimate, nutriments are more important)
-- Protein: {} g
-- Carbohydrates: {} g
-- Fat: {} g
+- Kcal: {target_kcal} (estimate, nutriments are more important)
+- Protein: {target_protein_g} g
+- Carbohydrates: {target_carbohydrate_g} g
+- Fat: {target_fat_g} g
 
 Please suggest **EXACTLY ONE** modification to the recipe to bring its nutritional profile closer to the target values, aiming to reduce the MSE, following the strategy guidance for a single change.
 Return your suggestion in the specified JSON format (modifications array must have only one item).
-",
-            current_best_recipe.recipe_title,
-            current_ingredients_text,
-            opt_f32_to_str(current_best_profile.per_100g.kcal),
-            opt_f32_to_str(current_best_profile.per_100g.protein_g),
-            opt_f32_to_str(current_best_profile.per_100g.carbohydrate_g),
-            opt_f32_to_str(current_best_profile.per_100g.fat_g),
-            opt_f32_to_str(current_best_profile.per_100g.sugars_g),
-            opt_f32_to_str(current_best_profile.per_100g.fa_saturated_g),
-            opt_f32_to_str(current_best_profile.per_100g.salt_g),
-            opt_f32_to_str(target_nutrition_per_100g.kcal),
-            opt_f32_to_str(target_nutrition_per_100g.protein_g),
-            opt_f32_to_str(target_nutrition_per_100g.carbohydrate_g),
-            opt_f32_to_str(target_nutrition_per_100g.fat_g),
+{optimizable}";
+
+/// A nutrient value for the prompts, with one decimal, or "N/A" when unknown.
+fn opt_f32_to_str(val: Option<f32>) -> String {
+    val.map_or_else(|| "N/A".to_string(), |v| format!("{:.1}", v))
+}
+
+/// The system and user prompts asking for one modification of `recipe`, whose per-100g profile
+/// is `profile`, towards `target`, changing only the `optimizable` ingredients when there are any.
+pub fn build_optimizer_prompts(
+    recipe: &CleanedRecipe,
+    profile: &RecipeNutritionalProfile,
+    target: &TargetNutritionalValues,
+    mse: f32,
+    optimizable: &[String],
+    prompts: &PromptTemplates,
+) -> (String, String) {
+    let system_prompt = prompts.render(PromptKind::OptimizerSystem, &[("mse", &format!("{:.4}", mse))]);
+
+    let current_ingredients_text = recipe.ingredients.iter()
+        .map(|ing| {
+            let quantity_display = ing.quantity_grams.map_or_else( 
+                || ing.raw_text.clone(), 
+                |q_g| format!("{:.1} g", q_g) 
+            );
+            format!("- {} (Current Quantity: {}, Original Text: '{}')", 
+                ing.ingredient_name, 
+                quantity_display,
+                ing.raw_text 
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let optimizable_text = if optimizable.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\nOnly these ingredients may be modified, removed, replaced or added; leave every other ingredient as it is: {}\n",
+            optimizable.join(", ")
+        )
+    };
+
+    let current = &profile.per_100g;
+    let user_prompt_content = prompts.render(PromptKind::OptimizerUser, &[
+        ("recipe_title", &recipe.recipe_title),
+        ("ingredients", &current_ingredients_text),
+        ("kcal", &opt_f32_to_str(current.kcal)),
+        ("protein_g", &opt_f32_to_str(current.protein_g)),
+        ("carbohydrate_g", &opt_f32_to_str(current.carbohydrate_g)),
+        ("fat_g", &opt_f32_to_str(current.fat_g)),
+        ("sugars_g", &opt_f32_to_str(current.sugars_g)),
+        ("fa_saturated_g", &opt_f32_to_str(current.fa_saturated_g)),
+        ("salt_g", &opt_f32_to_str(current.salt_g)),
+        ("fiber_g", &opt_f32_to_str(current.fiber_g)),
+        ("cholesterol_mg", &opt_f32_to_str(current.cholesterol_mg)),
+        ("target_kcal", &opt_f32_to_str(target.kcal)),
+        ("target_protein_g", &opt_f32_to_str(target.protein_g)),
+        ("target_carbohydrate_g", &opt_f32_to_str(target.carbohydrate_g)),
+        ("target_fat_g", &opt_f32_to_str(target.fat_g)),
+        ("optimizable", &optimizable_text),
+    ]);
+
+    (system_prompt, user_prompt_content)
+}
+
+// --- Main Optimization Function ---
+
+/// File the optimizer saves its best recipe so far to. Clones share the count of saves, so a
+/// caller keeping one knows whether anything was saved when it stops the optimization.
+/// A checkpoint lists the allergens found with the default rules and has no cost estimate.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    path: PathBuf,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Self {
+        Checkpoint { path, writes: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How many times the best recipe has been saved.
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Writes `recipe` as an enriched output; see `EnrichedRecipeOutput::save`.
+    fn write(&self, recipe: &CleanedRecipe, profile: &RecipeNutritionalProfile, notes: &[String]) -> Result<()> {
+        EnrichedRecipeOutput::new(recipe, profile)
+            .with_optimization_notes(notes.to_vec())
+            .save(&self.path)?;
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Settings controlling the optimization loop.
+#[derive(Debug, Clone)]
+pub struct OptimizerOptions {
+    pub max_iterations: u32,
+    /// See `apply_modifications_to_recipe`: keep total recipe mass stable across replacements.
+    pub preserve_mass: bool,
+    /// Reprompts allowed when a candidate's gram conversion isn't valid JSON.
+    pub conversion_parse_retries: u32,
+    /// Grams assumed for the candidates' seasonings measured "to taste".
+    pub to_taste_defaults: ToTasteDefaults,
+    /// Grams per container for the candidates' cans, packages, sticks and eggs.
+    pub container_sizes: ContainerSizes,
+    /// Score candidates with `calculate_mse_strict`, so targeted nutrients without a value are penalized.
+    pub strict_mse: bool,
+    /// How nutrient errors are scaled in the MSE.
+    pub mse_mode: MseMode,
+    /// Sampling settings; the optimizer uses the `optimizer` and `conversion` stages.
+    pub gen_params: StageGenParams,
+    /// Largest change, in percent of the initial grams, allowed by an `AdjustQuantity`; see `QuantityChangeLimit`.
+    pub max_quantity_change_pct: Option<f32>,
+    /// Range the total recipe mass of every accepted candidate must stay in.
+    pub mass_band: Option<MassBand>,
+    /// How the candidates' gram conversion answers are read.
+    pub json_mode: JsonMode,
+    /// How the optimizer and candidate conversion requests ask for their answer's schema.
+    pub response_format: ResponseFormatMode,
+    /// Templates of the optimizer and candidate conversion prompts.
+    pub prompts: PromptTemplates,
+    /// Only collect the LLM's suggestions: nothing is applied, converted or evaluated, and the
+    /// recipe comes back unchanged.
+    pub suggest_only: bool,
+    /// Where the best recipe so far is written after every accepted iteration, so an interrupted
+    /// run keeps its progress.
+    pub checkpoint: Option<Checkpoint>,
+    /// Stop as soon as the best recipe has every targeted nutrient within its band, even if its
+    /// MSE could still improve.
+    pub stop_within: Option<TargetTolerances>,
+    /// Also accept some worsening candidates; without it only improvements are kept.
+    pub exploration: Option<Exploration>,
+    /// Names of the only ingredients the optimizer may change; empty allows every ingredient.
+    pub optimizable: Vec<String>,
+    /// Replace a candidate's kcal with the Atwater estimate when the two are more than this many
+    /// percent apart, so candidates are scored with the same kcal as a reconciled initial profile.
+    pub reconcile_kcal_pct: Option<f32>,
+}
+
+impl Default for OptimizerOptions {
+    fn default() -> Self {
+        OptimizerOptions {
+            max_iterations: 10,
+            preserve_mass: false,
+            conversion_parse_retries: DEFAULT_CONVERSION_PARSE_RETRIES,
+            to_taste_defaults: ToTasteDefaults::default(),
+            container_sizes: ContainerSizes::default(),
+            strict_mse: false,
+            mse_mode: MseMode::default(),
+            gen_params: StageGenParams::default(),
+            max_quantity_change_pct: None,
+            mass_band: None,
+            json_mode: JsonMode::default(),
+            response_format: ResponseFormatMode::default(),
+            prompts: PromptTemplates::default(),
+            suggest_only: false,
+            checkpoint: None,
+            stop_within: None,
+            exploration: None,
+            optimizable: Vec::new(),
+            reconcile_kcal_pct: None,
+        }
+    }
+}
+
+/// Best recipe found by `optimize_recipe`, with the reasoning behind each change it kept.
+#[derive(Debug, Clone)]
+pub struct OptimizationResult {
+    pub recipe: CleanedRecipe,
+    /// `LlmModificationResponse::reasoning_notes` of every accepted iteration, oldest first.
+    pub notes: Vec<String>,
+    /// With `OptimizerOptions::suggest_only`, the suggestion of every iteration, oldest first.
+    pub suggestions: Vec<LlmModificationResponse>,
+}
+
+pub async fn optimize_recipe(
+    initial_cleaned_recipe: &CleanedRecipe,
+    initial_nutritional_profile: &RecipeNutritionalProfile,
+    target_nutrition_per_100g: &TargetNutritionalValues,
+    options: &OptimizerOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: impl Fn(ProgressEvent) + Send + Sync + Clone + 'static,
+) -> Result<OptimizationResult> {
+    // With no nutrition every MSE is 0 and the loop would have nothing to improve.
+    ensure_nutrition_computed(initial_cleaned_recipe, initial_nutritional_profile)?;
+    let max_iterations = options.max_iterations;
+    progress_updater(format!("Starting recipe optimization. Max iterations: {}", max_iterations).into());
+    progress_updater(format!("Initial recipe title: {}", initial_cleaned_recipe.recipe_title).into());
+    progress_updater(format!("Target nutrition (per 100g): {:?}", target_nutrition_per_100g).into());
+
+    let quantity_limit = options.max_quantity_change_pct
+        .map(|max_change_pct| QuantityChangeLimit { max_change_pct, original: initial_cleaned_recipe });
+    let conversion_options = ConversionOptions {
+        parse_retries: options.conversion_parse_retries,
+        gen_params: options.gen_params.conversion,
+        json_mode: options.json_mode,
+        response_format: options.response_format,
+        prompts: options.prompts.clone(),
+        to_taste_defaults: options.to_taste_defaults.clone(),
+        container_sizes: options.container_sizes.clone(),
+    };
+    if let Some(reason) = options.mass_band.and_then(|band| band.violation(initial_nutritional_profile.total_calculated_mass_g)) {
+        progress_updater(ProgressEvent::Warning { text: format!("[WARNING] The initial recipe is outside the target mass: {}. Only candidates within it can be accepted.", reason) });
+    }
+    for name in &options.optimizable {
+        if !initial_cleaned_recipe.ingredients.iter().any(|ing| ingredient_names_match(&ing.ingredient_name, name)) {
+            progress_updater(ProgressEvent::Warning { text: format!("[WARNING] Optimizable ingredient '{}' is not in the recipe; only an addition can use it.", name) });
+        }
+    }
+    let mut current_best_recipe = initial_cleaned_recipe.clone();
+    let mut current_best_profile = initial_nutritional_profile.clone();
+    let mse = |current: &NutritionalSummary, target: &TargetNutritionalValues| {
+        calculate_mse_with_mode(current, target, options.mse_mode, options.strict_mse)
+    };
+    let mut current_best_mse = mse(&current_best_profile.per_100g, target_nutrition_per_100g);
+    let mut notes = Vec::new();
+    // The recipe the next suggestion builds on: the best one, unless exploration accepted a worse one.
+    let mut current_recipe = current_best_recipe.clone();
+    let mut current_profile = current_best_profile.clone();
+    let mut current_mse = current_best_mse;
+    let mut current_notes: Vec<String> = Vec::new();
+    let mut rng = options.exploration.map(|exploration| SplitMix64(exploration.seed));
+    let mut suggestions: Vec<LlmModificationResponse> = Vec::new();
+    progress_updater(format!("Initial MSE: {:.4}", current_best_mse).into());
+    if let Some(exploration) = options.exploration {
+        progress_updater(format!(
+            "Exploring with a starting temperature of {} (seed {}); the best recipe seen is kept.",
+            exploration.initial_temperature, exploration.seed
+        ).into());
+    }
+    let targets_met = |profile: &RecipeNutritionalProfile| {
+        let met = options.stop_within.as_ref()
+            .is_some_and(|tolerances| tolerances.all_met(&profile.per_100g, target_nutrition_per_100g));
+        if met {
+            progress_updater("Every targeted nutrient is within its tolerance; ending optimization.".into());
+        }
+        met
+    };
+
+    for i in 0..max_iterations {
+        if !options.suggest_only && targets_met(&current_best_profile) {
+            break;
+        }
+        progress_updater(ProgressEvent::Step {
+            stage: ProgressStage::Optimization,
+            index: i + 1,
+            total: max_iterations,
+            label: String::new(),
+        });
+
+        // 1. Construct Prompt for LLM
+        let (system_prompt, mut user_prompt_content) = build_optimizer_prompts(
+            &current_recipe, &current_profile, target_nutrition_per_100g, current_mse, &options.optimizable, &options.prompts,
         );
-        
-        progress_updater(format!("System Prompt (Iteration {}):\n{}", i + 1, system_prompt));
-        progress_updater(format!("User Prompt (Iteration {}):\n{}", i + 1, user_prompt_content));
+
+        // The recipe doesn't change between suggest-only iterations, so ask for something new each time.
+        if !suggestions.is_empty() {
+            user_prompt_content.push_str("\nThese modifications were already suggested; suggest a different one:\n");
+            for modification in suggestions.iter().flat_map(|suggestion| &suggestion.modifications) {
+                user_prompt_content.push_str(&format!("- {}\n", modification.describe()));
+            }
+        }
+
+        log_verbose!("System Prompt (Iteration {}):\n{}", i + 1, system_prompt);
+        log_verbose!("User Prompt (Iteration {}):\n{}", i + 1, user_prompt_content);
 
         // 2. Call LLM
-        let provider = Provider::openrouter(api_key_env_var);
         let llm_schema = get_llm_modification_schema_single_item(); // Use a schema that expects a single item
 
         let request = ChatCompletionRequest {
@@ -308,28 +942,27 @@ Return your suggestion in the specified JSON format (modifications array must ha
                 ChatMessage { role: "system".to_string(), content: system_prompt },
                 ChatMessage { role: "user".to_string(), content: user_prompt_content },
             ],
-            response_format: Some(ResponseFormat {
-                format_type: "json_object".to_string(), 
-                json_schema: Some(llm_schema),
-            }),
-            temperature: Some(0.1), // Lowered temperature further
-            max_tokens: Some(1024), // Reduced max_tokens
+            response_format: Some(ResponseFormat::for_schema(llm_schema, options.response_format)),
+            temperature: Some(options.gen_params.optimizer.temperature),
+            max_tokens: Some(options.gen_params.optimizer.max_tokens),
         };
 
-        progress_updater(format!("Sending request to LLM (Iteration {})...", i + 1));
+        progress_updater(format!("Sending request to LLM (Iteration {})...", i + 1).into());
         
-        let llm_response_str = match provider.call_chat_completion(request).await {
+        let llm_response_str = match complete_untruncated(client, request, TRUNCATION_RETRIES).await {
             Ok(response) => {
+                log_stage_usage("optimization", response.usage.as_ref());
                 if let Some(choice) = response.choices.first() {
-                    progress_updater(format!("LLM Response (Iteration {}):\n{}", i + 1, choice.message.content));
-                    choice.message.content.clone()
+                    log_verbose!("LLM Response (Iteration {}):\n{}", i + 1, choice.message.content);
+                    let raw_content = choice.message.content.trim();
+                    extract_json_object(raw_content).unwrap_or(raw_content).to_string()
                 } else {
                     return Err(anyhow!("LLM returned no choices in response."));
                 }
             }
             Err(e) => {
-                progress_updater(format!("LLM call failed (Iteration {}): {}", i + 1, e));
-                eprintln!("LLM call failed: {}. Using mock 'no_change' response.", e);
+                progress_updater(ProgressEvent::Warning { text: format!("LLM call failed (Iteration {}): {}", i + 1, e) });
+                log_warning!("LLM call failed: {}. Using mock 'no_change' response.", e);
                  r#"{
                     "modifications": [ { "operation": "no_change", "reasoning": "LLM call failed, attempting graceful exit." } ],
                     "overall_reasoning": "LLM call failed during optimization."
@@ -341,11 +974,11 @@ Return your suggestion in the specified JSON format (modifications array must ha
             Ok(mut suggestion) => {
                 // Ensure only one modification is processed, even if LLM violates prompt
                 if suggestion.modifications.len() > 1 {
-                    progress_updater(format!("Warning: LLM returned {} modifications, but prompt asked for 1. Taking only the first.", suggestion.modifications.len()));
+                    progress_updater(ProgressEvent::Warning { text: format!("Warning: LLM returned {} modifications, but prompt asked for 1. Taking only the first.", suggestion.modifications.len()) });
                     suggestion.modifications.truncate(1);
                 }
                 if suggestion.modifications.is_empty() && !llm_response_str.contains("no_change") { // If it's empty but wasn't a deliberate no_change
-                     progress_updater(format!("LLM returned empty modifications array. Interpreting as 'no_change'. Content: {}", llm_response_str));
+                     progress_updater(format!("LLM returned empty modifications array. Interpreting as 'no_change'. Content: {}", llm_response_str).into());
                      suggestion.modifications.push(LlmRecipeModification {
                         operation: LlmOperationType::NoChange,
                         reasoning: Some("LLM returned empty modifications, interpreted as no change.".to_string()),
@@ -355,7 +988,7 @@ Return your suggestion in the specified JSON format (modifications array must ha
                 suggestion
             }
             Err(e) => {
-                progress_updater(format!("Failed to parse LLM suggestion (Iteration {}): {}. Content: '{}'", i + 1, e, llm_response_str));
+                progress_updater(ProgressEvent::Warning { text: format!("Failed to parse LLM suggestion (Iteration {}): {}. Content: '{}'", i + 1, e, llm_response_str) });
                 // Fallback to no_change if parsing fails completely
                 LlmModificationResponse {
                     modifications: vec![LlmRecipeModification {
@@ -375,69 +1008,125 @@ Return your suggestion in the specified JSON format (modifications array must ha
                     llm_suggestion.overall_reasoning.as_str(),
                     |s| s.as_str()
                 )
-            ));
+            ).into());
             break;
         }
+
+        if options.suggest_only {
+            for modification in &llm_suggestion.modifications {
+                progress_updater(format!("Suggestion {}: {}", i + 1, modification.describe()).into());
+            }
+            suggestions.push(llm_suggestion);
+            continue;
+        }
         
-        let candidate_parsed_recipe = match apply_modifications_to_recipe(&current_best_recipe, &llm_suggestion, &progress_updater) {
+        let candidate_parsed_recipe = match apply_modifications_to_recipe(&current_recipe, &llm_suggestion, options.preserve_mass, quantity_limit.as_ref(), &options.optimizable, &progress_updater) {
             Ok(recipe) => recipe,
             Err(e) => {
-                progress_updater(format!("Error applying LLM modifications: {}. Skipping this iteration.", e));
+                progress_updater(ProgressEvent::Warning { text: format!("Error applying LLM modifications: {}. Skipping this iteration.", e) });
                 continue; 
             }
         };
         
-        progress_updater("Converting candidate recipe ingredients to grams...".to_string());
-        let mut candidate_cleaned_recipe = match convert_ingredients_to_grams(&candidate_parsed_recipe, api_key_env_var, progress_updater.clone()).await {
+        progress_updater("Converting candidate recipe ingredients to grams...".into());
+        let mut candidate_cleaned_recipe = match convert_ingredients_to_grams(&candidate_parsed_recipe, client, &conversion_options, progress_updater.clone()).await {
             Ok(recipe) => recipe,
             Err(e) => {
-                progress_updater(format!("Error converting candidate ingredients to grams: {}. Skipping this iteration.", e));
+                progress_updater(ProgressEvent::Warning { text: format!("Error converting candidate ingredients to grams: {}. Skipping this iteration.", e) });
                 continue;
             }
         };
 
-        progress_updater("Enriching candidate recipe with nutritional information...".to_string());
+        progress_updater("Enriching candidate recipe with nutritional information...".into());
         for ingredient in candidate_cleaned_recipe.ingredients.iter_mut() {
             if ingredient.quantity_grams.is_some() { 
-                match nutritional_index.find_and_calculate_nutrition(ingredient, api_key_env_var, &progress_updater).await {
+                match nutritional_index.find_and_calculate_nutrition(ingredient, client, &progress_updater, None).await {
                     Ok(Some(calculated_info)) => { 
                         ingredient.nutritional_info = Some(calculated_info); 
-                        progress_updater(format!("  -> Successfully enriched '{}'", ingredient.ingredient_name));
+                        progress_updater(format!("  -> Successfully enriched '{}'", ingredient.ingredient_name).into());
                     }
                     Ok(None) => {
-                        progress_updater(format!("  -> Could not find nutritional info for '{}'", ingredient.ingredient_name));
+                        progress_updater(format!("  -> Could not find nutritional info for '{}'", ingredient.ingredient_name).into());
                     }
                     Err(e) => {
-                        progress_updater(format!("  -> Error enriching '{}': {}", ingredient.ingredient_name, e));
+                        progress_updater(ProgressEvent::Warning { text: format!("  -> Error enriching '{}': {}", ingredient.ingredient_name, e) });
                     }
                 }
             }
         }
 
-        let candidate_profile = calculate_nutritional_profile(&candidate_cleaned_recipe);
+        let mut candidate_profile = calculate_nutritional_profile(&candidate_cleaned_recipe);
+        if let Some(tolerance_pct) = options.reconcile_kcal_pct {
+            candidate_profile.reconcile_kcal_beyond(tolerance_pct);
+        }
         progress_updater(format!("Candidate recipe nutritional profile (per 100g): Kcal: {}, P: {}, C: {}, F: {}",
             opt_f32_to_str(candidate_profile.per_100g.kcal),
             opt_f32_to_str(candidate_profile.per_100g.protein_g),
             opt_f32_to_str(candidate_profile.per_100g.carbohydrate_g),
             opt_f32_to_str(candidate_profile.per_100g.fat_g)
-        ));
+        ).into());
 
-        let candidate_mse = calculate_mse(&candidate_profile.per_100g, target_nutrition_per_100g);
-        progress_updater(format!("Candidate MSE: {:.4}", candidate_mse));
+        if let Some(reason) = coverage_loss(nutrition_coverage(&current_recipe), nutrition_coverage(&candidate_cleaned_recipe)) {
+            progress_updater(format!("Rejecting candidate: {}. Skipping this iteration.", reason).into());
+            continue;
+        }
 
-        if candidate_mse < current_best_mse {
-            progress_updater(format!("Found improved recipe. New MSE: {:.4} (was {:.4})", candidate_mse, current_best_mse));
-            current_best_recipe = candidate_cleaned_recipe;
-            current_best_profile = candidate_profile;
-            current_best_mse = candidate_mse;
-        } else {
-            progress_updater(format!("Candidate recipe did not improve MSE (Candidate: {:.4}, Best: {:.4}). Retaining previous best.", candidate_mse, current_best_mse));
+        if let Some(reason) = options.mass_band.and_then(|band| band.violation(candidate_profile.total_calculated_mass_g)) {
+            progress_updater(format!("Rejecting candidate: {}. Skipping this iteration.", reason).into());
+            continue;
+        }
+
+        let candidate_mse = mse(&candidate_profile.per_100g, target_nutrition_per_100g);
+        progress_updater(format!("Candidate MSE: {:.4}", candidate_mse).into());
+
+        let improved = candidate_mse < current_best_mse;
+        progress_updater(ProgressEvent::IterationComplete {
+            iteration: i + 1,
+            candidate_mse,
+            best_mse: current_best_mse,
+            improved,
+        });
+        let accepted = candidate_mse < current_mse || match (options.exploration, rng.as_mut()) {
+            (Some(exploration), Some(rng)) => {
+                let probability = exploration.acceptance_probability(current_mse, candidate_mse, i);
+                let explored = rng.next_f32() < probability;
+                if explored {
+                    progress_updater(format!(
+                        "Exploring: accepting a worse candidate (MSE {:.4} vs {:.4}, temperature {:.3}, probability {:.2}).",
+                        candidate_mse, current_mse, exploration.temperature(i), probability
+                    ).into());
+                }
+                explored
+            }
+            _ => false,
+        };
+        if accepted {
+            current_recipe = candidate_cleaned_recipe;
+            current_profile = candidate_profile;
+            current_mse = candidate_mse;
+            current_notes.extend(llm_suggestion.reasoning_notes());
+        }
+        if improved {
+            current_best_recipe = current_recipe.clone();
+            current_best_profile = current_profile.clone();
+            current_best_mse = current_mse;
+            notes = current_notes.clone();
+            if let Some(checkpoint) = &options.checkpoint {
+                let mut profile = current_best_profile.clone();
+                if let Some(servings) = initial_nutritional_profile.servings {
+                    profile.apply_servings(servings);
+                }
+                match checkpoint.write(&current_best_recipe, &profile, &notes) {
+                    Ok(()) => progress_updater(format!("Saved the best recipe so far to '{}'", checkpoint.path().display()).into()),
+                    Err(e) => progress_updater(ProgressEvent::Warning { text: format!("[WARNING] Failed to save the best recipe so far: {:#}", e) }),
+                }
+            }
         }
     }
 
-    progress_updater(format!("\nOptimization finished. Best recipe found: {} with MSE: {:.4}", current_best_recipe.recipe_title, current_best_mse));
+    progress_updater(format!("\nOptimization finished. Best recipe found: {} with MSE: {:.4}", current_best_recipe.recipe_title, current_best_mse).into());
     
-    Ok(current_best_recipe)
+    Ok(OptimizationResult { recipe: current_best_recipe, notes, suggestions })
 }
 
 // Schema for a single modification item in the array
@@ -450,7 +1139,7 @@ fn get_llm_modification_schema_single_item() -> JsonSchemaDefinition {
         "no_change".to_string(),
     ];
 
-    let mut modification_properties = HashMap::new();
+    let mut modification_properties = BTreeMap::new();
     modification_properties.insert(
         "operation".to_string(),
         JsonSchemaProperty {
@@ -531,7 +1220,7 @@ fn get_llm_modification_schema_single_item() -> JsonSchemaDefinition {
         additional_properties: Some(true), 
     };
 
-    let mut response_properties = HashMap::new();
+    let mut response_properties = BTreeMap::new();
     response_properties.insert(
         "modifications".to_string(),
         JsonSchemaProperty {
@@ -573,7 +1262,7 @@ fn get_llm_modification_schema() -> JsonSchemaDefinition {
         "no_change".to_string(),
     ];
 
-    let mut modification_properties = HashMap::new();
+    let mut modification_properties = BTreeMap::new();
     modification_properties.insert(
         "operation".to_string(),
         JsonSchemaProperty {
@@ -654,7 +1343,7 @@ fn get_llm_modification_schema() -> JsonSchemaDefinition {
         additional_properties: Some(true), 
     };
 
-    let mut response_properties = HashMap::new();
+    let mut response_properties = BTreeMap::new();
     response_properties.insert(
         "modifications".to_string(),
         JsonSchemaProperty {
@@ -700,3 +1389,591 @@ impl Default for LlmRecipeModification {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nutritional_matcher::test_support::{cleaned_ingredient, food_item, index_of};
+    use crate::recipe_converter::{CalculatedNutritionalInfo, FoodItem};
+
+    fn recipe_with_butter() -> CleanedRecipe {
+        CleanedRecipe {
+            recipe_title: "Shortbread".to_string(),
+            ingredients: vec![cleaned_ingredient("butter", 100.0)],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_suggest_only_leaves_the_recipe_unchanged() -> Result<()> {
+        use crate::api_connection::client::MockChatClient;
+
+        let mut recipe = recipe_with_butter();
+        recipe.ingredients[0].nutritional_info = Some(CalculatedNutritionalInfo {
+            source_ciqual_name: "Butter".to_string(),
+            kcal: Some(717.0),
+            fat_g: Some(81.0),
+            ..Default::default()
+        });
+        let profile = calculate_nutritional_profile(&recipe);
+        let targets = TargetNutritionalValues { fat_g: Some(40.0), ..TargetNutritionalValues::from(&profile.per_100g) };
+        // Never queried: suggestions are neither converted nor matched.
+        let butter = FoodItem { kcal_per_100g: Some(717.0), fat_g_per_100g: Some(81.0), ..food_item("Butter", 0) };
+        let index = index_of(vec![butter], std::collections::HashMap::from([("Butter".to_string(), vec![1.0])]))?;
+        let client = MockChatClient::new([
+            r#"{"modifications": [{"operation": "replace_ingredient", "original_ingredient_name": "butter", "replacement_description": "olive oil", "quantity_raw": "80", "unit_raw": "g", "reasoning": "Less saturated fat."}], "overall_reasoning": "Swap the fat."}"#,
+            r#"{"modifications": [{"operation": "adjust_quantity", "original_ingredient_name": "butter", "quantity_raw": "60", "unit_raw": "g"}], "overall_reasoning": "Use less."}"#,
+            r#"{"modifications": [{"operation": "no_change"}], "overall_reasoning": "Nothing else."}"#,
+        ]);
+        let options = OptimizerOptions { max_iterations: 5, suggest_only: true, ..Default::default() };
+
+        let result = optimize_recipe(&recipe, &profile, &targets, &options, &index, &client, |_| {}).await?;
+        assert_eq!(result.recipe.ingredients[0].quantity_grams, Some(100.0));
+        assert!(result.notes.is_empty());
+        let described: Vec<String> = result.suggestions.iter()
+            .flat_map(|suggestion| suggestion.modifications.iter().map(LlmRecipeModification::describe))
+            .collect();
+        assert_eq!(described, ["Replace 'butter' with 'olive oil' (80 g)", "Adjust 'butter' (60 g)"]);
+
+        let requests = client.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(!requests[0].messages[1].content.contains("already suggested"));
+        assert!(requests[2].messages[1].content.contains("- Adjust 'butter' (60 g)"));
+        let response_format = serde_json::to_value(&requests[0].response_format).unwrap();
+        assert_eq!(response_format["type"], "json_schema");
+        assert_eq!(response_format["json_schema"]["strict"], true);
+        Ok(())
+    }
+
+    fn single(modification: LlmRecipeModification) -> LlmModificationResponse {
+        LlmModificationResponse { modifications: vec![modification], overall_reasoning: String::new() }
+    }
+
+    #[test]
+    fn test_is_nonsensical_quantity() {
+        assert!(is_nonsensical_quantity(None));
+        assert!(is_nonsensical_quantity(Some("  ")));
+        assert!(is_nonsensical_quantity(Some("0")));
+        assert!(is_nonsensical_quantity(Some("-50")));
+        assert!(!is_nonsensical_quantity(Some("80")));
+        assert!(!is_nonsensical_quantity(Some("1/2")));
+    }
+
+    #[test]
+    fn test_replace_preserves_mass_only_when_enabled() {
+        let suggestion = single(LlmRecipeModification {
+            operation: LlmOperationType::ReplaceIngredient,
+            original_ingredient_name: Some("butter".to_string()),
+            replacement_description: Some("greek yogurt".to_string()),
+            quantity_raw: Some("0".to_string()),
+            unit_raw: Some("cup".to_string()),
+            ..Default::default()
+        });
+
+        let preserved = apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, true, None, &[], &|_| {}).unwrap();
+        assert_eq!(preserved.ingredients.len(), 1);
+        assert_eq!(preserved.ingredients[0].ingredient_name, "greek yogurt");
+        assert_eq!((preserved.ingredients[0].quantity.as_str(), preserved.ingredients[0].unit.as_str()), ("100.0", "g"));
+
+        // Without mass preservation the unusable quantity rejects the modification.
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, false, None, &[], &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_add_rejects_nonsensical_quantity_when_preserving_mass() {
+        let suggestion = single(LlmRecipeModification {
+            operation: LlmOperationType::AddIngredient,
+            replacement_description: Some("oat flour".to_string()),
+            quantity_raw: Some("-20".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, true, None, &[], &|_| {}).is_err());
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, false, None, &[], &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_modifications_require_positive_quantity_and_known_unit() {
+        let add = |quantity: &str, unit: &str| single(LlmRecipeModification {
+            operation: LlmOperationType::AddIngredient,
+            replacement_description: Some("oat flour".to_string()),
+            quantity_raw: Some(quantity.to_string()),
+            unit_raw: Some(unit.to_string()),
+            ..Default::default()
+        });
+
+        let fractional = apply_modifications_to_recipe(&recipe_with_butter(), &add("1 1/2", "tbsp"), false, None, &[], &|_| {}).unwrap();
+        assert_eq!(fractional.ingredients[1].raw_text, "1 1/2 tbsp oat flour");
+
+        let textual = apply_modifications_to_recipe(&recipe_with_butter(), &add("half", "cup"), false, None, &[], &|_| {});
+        assert!(textual.unwrap_err().to_string().contains("not a positive number"));
+        let no_unit = apply_modifications_to_recipe(&recipe_with_butter(), &add("20", ""), false, None, &[], &|_| {});
+        assert!(no_unit.unwrap_err().to_string().contains("not a recognized unit"));
+    }
+
+    #[test]
+    fn test_modifications_are_limited_to_optimizable_ingredients() {
+        let adjust = single(LlmRecipeModification {
+            operation: LlmOperationType::AdjustQuantity,
+            original_ingredient_name: Some("butter".to_string()),
+            quantity_raw: Some("80".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+        let add = single(LlmRecipeModification {
+            operation: LlmOperationType::AddIngredient,
+            replacement_description: Some("oat flour".to_string()),
+            quantity_raw: Some("20".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+
+        let allowed = ["Butter".to_string()];
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &adjust, false, None, &allowed, &|_| {}).is_ok());
+        let rejected = apply_modifications_to_recipe(&recipe_with_butter(), &add, false, None, &allowed, &|_| {});
+        assert!(rejected.unwrap_err().to_string().contains("only Butter may be modified"));
+
+        let oils = ["olive oil".to_string()];
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &adjust, false, None, &oils, &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_adjust_quantity_matches_names_loosely() {
+        let suggestion = single(LlmRecipeModification {
+            operation: LlmOperationType::AdjustQuantity,
+            original_ingredient_name: Some("  Butter ".to_string()),
+            quantity_raw: Some("80".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+
+        let adjusted = apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, false, None, &[], &|_| {}).unwrap();
+        assert_eq!(adjusted.ingredients[0].quantity, "80");
+    }
+
+    #[test]
+    fn test_adjust_quantity_rejects_missing_and_no_op() {
+        let missing = single(LlmRecipeModification {
+            operation: LlmOperationType::AdjustQuantity,
+            original_ingredient_name: Some("sugar".to_string()),
+            quantity_raw: Some("80".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &missing, false, None, &[], &|_| {}).is_err());
+
+        let no_op = single(LlmRecipeModification {
+            operation: LlmOperationType::AdjustQuantity,
+            original_ingredient_name: Some("butter".to_string()),
+            quantity_raw: Some("100".to_string()),
+            unit_raw: Some("G".to_string()),
+            ..Default::default()
+        });
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &no_op, false, None, &[], &|_| {}).is_err());
+    }
+
+    fn modification(operation: LlmOperationType, original: Option<&str>, replacement: Option<&str>) -> LlmRecipeModification {
+        LlmRecipeModification {
+            operation,
+            original_ingredient_name: original.map(str::to_string),
+            replacement_description: replacement.map(str::to_string),
+            quantity_raw: Some("30".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn names(recipe: &ParsedRecipe) -> Vec<&str> {
+        recipe.ingredients.iter().map(|ing| ing.ingredient_name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_reasoning_notes() {
+        let response = LlmModificationResponse {
+            modifications: vec![
+                LlmRecipeModification {
+                    reasoning: Some("Less saturated fat.".to_string()),
+                    ..modification(LlmOperationType::ReplaceIngredient, Some("butter"), Some("olive oil"))
+                },
+                LlmRecipeModification {
+                    reasoning: Some("  ".to_string()),
+                    ..modification(LlmOperationType::RemoveIngredient, Some("salt"), None)
+                },
+                LlmRecipeModification {
+                    new_ingredient_name: Some("oat bran".to_string()),
+                    reasoning: Some("Adds fiber.".to_string()),
+                    ..modification(LlmOperationType::AddIngredient, None, Some("oat bran"))
+                },
+            ],
+            overall_reasoning: " Lighter fats and more fiber. ".to_string(),
+        };
+        assert_eq!(response.reasoning_notes(), vec![
+            "Lighter fats and more fiber.",
+            "Replaced 'butter': Less saturated fat.",
+            "Added 'oat bran': Adds fiber.",
+        ]);
+        assert!(single(modification(LlmOperationType::AdjustQuantity, Some("sugar"), None)).reasoning_notes().is_empty());
+    }
+
+    #[test]
+    fn test_remove_nonexistent_ingredient_is_a_no_op() {
+        let suggestion = single(modification(LlmOperationType::RemoveIngredient, Some("saffron"), None));
+        let candidate = apply_modifications_to_recipe(&recipe_with_butter(), &suggestion, false, None, &[], &|_| {}).unwrap();
+        assert_eq!(names(&candidate), vec!["butter"]);
+        assert_eq!((candidate.ingredients[0].quantity.as_str(), candidate.ingredients[0].unit.as_str()), ("100.0", "g"));
+
+        let unnamed = single(modification(LlmOperationType::RemoveIngredient, None, None));
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &unnamed, false, None, &[], &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_replace_missing_original_adds_the_replacement() {
+        let mut recipe = recipe_with_butter();
+        recipe.ingredients[0].section = Some("Dough".to_string());
+        let suggestion = single(modification(LlmOperationType::ReplaceIngredient, Some("saffron"), Some("turmeric")));
+        let candidate = apply_modifications_to_recipe(&recipe, &suggestion, true, None, &[], &|_| {}).unwrap();
+        assert_eq!(names(&candidate), vec!["butter", "turmeric"]);
+        // Neither the replaced grams nor the group of an ingredient that isn't there carry over.
+        assert_eq!(candidate.ingredients[1].quantity, "30");
+        assert_eq!(candidate.ingredients[1].section, None);
+        assert_eq!(candidate.ingredients[0].section.as_deref(), Some("Dough"));
+    }
+
+    #[test]
+    fn test_add_uses_new_ingredient_name_when_given() {
+        let described = single(modification(LlmOperationType::AddIngredient, None, Some("rolled oats, toasted")));
+        let candidate = apply_modifications_to_recipe(&recipe_with_butter(), &described, false, None, &[], &|_| {}).unwrap();
+        assert_eq!(names(&candidate), vec!["butter", "rolled oats, toasted"]);
+        assert_eq!(candidate.ingredients[1].raw_text, "30 g rolled oats, toasted");
+
+        let mut named = modification(LlmOperationType::AddIngredient, None, Some("rolled oats, toasted"));
+        named.new_ingredient_name = Some("oats".to_string());
+        named.preparation_notes = Some("toasted".to_string());
+        let candidate = apply_modifications_to_recipe(&recipe_with_butter(), &single(named), false, None, &[], &|_| {}).unwrap();
+        assert_eq!(names(&candidate), vec!["butter", "oats"]);
+        assert_eq!(candidate.ingredients[1].raw_text, "30 g rolled oats, toasted");
+        assert_eq!(candidate.ingredients[1].preparation_notes, "toasted");
+
+        let undescribed = single(modification(LlmOperationType::AddIngredient, None, None));
+        assert!(apply_modifications_to_recipe(&recipe_with_butter(), &undescribed, false, None, &[], &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_adjust_quantity_updates_quantity_and_notes() {
+        let mut adjust = modification(LlmOperationType::AdjustQuantity, Some("butter"), None);
+        adjust.quantity_raw = Some("2".to_string());
+        adjust.unit_raw = Some("tbsp".to_string());
+        adjust.preparation_notes = Some("softened".to_string());
+        let candidate = apply_modifications_to_recipe(&recipe_with_butter(), &single(adjust), false, None, &[], &|_| {}).unwrap();
+        let butter = &candidate.ingredients[0];
+        assert_eq!((butter.quantity.as_str(), butter.unit.as_str()), ("2", "tbsp"));
+        assert_eq!(butter.raw_text, "2 tbsp butter");
+        assert_eq!(butter.preparation_notes, "softened");
+    }
+
+    #[test]
+    fn test_new_ingredients_are_appended_in_suggestion_order() {
+        let mut recipe = recipe_with_butter();
+        let mut flour = recipe.ingredients[0].clone();
+        flour.ingredient_name = "flour".to_string();
+        let mut sugar = recipe.ingredients[0].clone();
+        sugar.ingredient_name = "sugar".to_string();
+        recipe.ingredients.extend([flour, sugar]);
+
+        let suggestions = LlmModificationResponse {
+            modifications: vec![
+                modification(LlmOperationType::AddIngredient, None, Some("oats")),
+                modification(LlmOperationType::ReplaceIngredient, Some("butter"), Some("margarine")),
+                modification(LlmOperationType::RemoveIngredient, Some("sugar"), None),
+                modification(LlmOperationType::AddIngredient, None, Some("raisins")),
+            ],
+            overall_reasoning: String::new(),
+        };
+        let candidate = apply_modifications_to_recipe(&recipe, &suggestions, false, None, &[], &|_| {}).unwrap();
+        // The replacement doesn't take the replaced ingredient's place in the list.
+        assert_eq!(names(&candidate), vec!["flour", "oats", "margarine", "raisins"]);
+    }
+
+    #[test]
+    fn test_adjust_quantity_is_clamped_to_the_limit() {
+        let original = recipe_with_butter();
+        let limit = QuantityChangeLimit { max_change_pct: 25.0, original: &original };
+        let adjust = |quantity: &str, unit: &str| {
+            let mut adjust = modification(LlmOperationType::AdjustQuantity, Some("butter"), None);
+            adjust.quantity_raw = Some(quantity.to_string());
+            adjust.unit_raw = Some(unit.to_string());
+            single(adjust)
+        };
+        let quantity_of = |candidate: ParsedRecipe| (candidate.ingredients[0].quantity.clone(), candidate.ingredients[0].unit.clone());
+
+        let tripled = apply_modifications_to_recipe(&original, &adjust("300", "g"), false, Some(&limit), &[], &|_| {}).unwrap();
+        assert_eq!(quantity_of(tripled), ("125.0".to_string(), "g".to_string()));
+        let in_kg = apply_modifications_to_recipe(&original, &adjust("0.01", "kg"), false, Some(&limit), &[], &|_| {}).unwrap();
+        assert_eq!(quantity_of(in_kg), ("75.0".to_string(), "g".to_string()));
+        let within = apply_modifications_to_recipe(&original, &adjust("90", "g"), false, Some(&limit), &[], &|_| {}).unwrap();
+        assert_eq!(quantity_of(within), ("90".to_string(), "g".to_string()));
+        // Volumes have no known weight and are left to the gram conversion.
+        let in_cups = apply_modifications_to_recipe(&original, &adjust("2", "cups"), false, Some(&limit), &[], &|_| {}).unwrap();
+        assert_eq!(quantity_of(in_cups), ("2".to_string(), "cups".to_string()));
+
+        // The bounds come from the original recipe, so repeated adjustments can't drift past them.
+        let mut current = original.clone();
+        current.ingredients[0].quantity_grams = Some(120.0);
+        let drifted = apply_modifications_to_recipe(&current, &adjust("150", "g"), false, Some(&limit), &[], &|_| {}).unwrap();
+        assert_eq!(quantity_of(drifted), ("125.0".to_string(), "g".to_string()));
+        // Clamped back onto the current quantity, the adjustment is a no-op.
+        current.ingredients[0].quantity_grams = Some(125.0);
+        assert!(apply_modifications_to_recipe(&current, &adjust("150", "g"), false, Some(&limit), &[], &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_name_similarity() {
+        assert_eq!(name_similarity(" Butter", "butter"), 1.0);
+        assert!(name_similarity("all-purpose flour", "flour") >= FUZZY_NAME_MATCH_THRESHOLD);
+        assert!(name_similarity("buter", "butter") >= FUZZY_NAME_MATCH_THRESHOLD);
+        assert!(name_similarity("sugar", "salt") < FUZZY_NAME_MATCH_THRESHOLD);
+    }
+
+    #[test]
+    fn test_replace_resolves_fuzzy_ingredient_name() {
+        let mut recipe = recipe_with_butter();
+        recipe.ingredients[0].ingredient_name = "unsalted butter".to_string();
+        let suggestion = single(LlmRecipeModification {
+            operation: LlmOperationType::ReplaceIngredient,
+            original_ingredient_name: Some("butter".to_string()),
+            replacement_description: Some("margarine".to_string()),
+            quantity_raw: Some("90".to_string()),
+            unit_raw: Some("g".to_string()),
+            ..Default::default()
+        });
+
+        let replaced = apply_modifications_to_recipe(&recipe, &suggestion, false, None, &[], &|_| {}).unwrap();
+        let names: Vec<&str> = replaced.ingredients.iter().map(|ing| ing.ingredient_name.as_str()).collect();
+        assert_eq!(names, vec!["margarine"]);
+    }
+
+    #[test]
+    fn test_coverage_loss_rejects_candidates_losing_nutrition() {
+        let mut current = recipe_with_butter();
+        current.ingredients[0].nutritional_info = Some(CalculatedNutritionalInfo::default());
+        let mut sugar = current.ingredients[0].clone();
+        sugar.ingredient_name = "sugar".to_string();
+        sugar.quantity_grams = Some(50.0);
+        current.ingredients.push(sugar);
+        let full = nutrition_coverage(&current);
+        assert_eq!(full, NutritionCoverage { mass_fraction: 1.0, ingredient_fraction: 1.0 });
+
+        // Replacing sugar with something unmatched loses a third of the mass.
+        let mut unmatched = current.clone();
+        unmatched.ingredients[1].nutritional_info = None;
+        let degraded = nutrition_coverage(&unmatched);
+        assert!((degraded.mass_fraction - 100.0 / 150.0).abs() < 1e-6);
+        assert!(coverage_loss(full, degraded).unwrap().contains("mass"));
+
+        // Removing an ingredient keeps full coverage.
+        let mut removed = current.clone();
+        removed.ingredients.pop();
+        assert_eq!(coverage_loss(full, nutrition_coverage(&removed)), None);
+    }
+
+    #[test]
+    fn test_mass_band_rejects_masses_outside_tolerance() {
+        let band = MassBand { target_g: 1000.0, tolerance_pct: 10.0 };
+        assert_eq!(band.bounds(), (900.0, 1100.0));
+        assert_eq!(band.violation(Some(900.0)), None);
+        assert_eq!(band.violation(Some(1050.0)), None);
+        assert_eq!(band.violation(Some(1200.0)).unwrap(), "total mass of 1200g is outside 900-1100g");
+        assert!(band.violation(Some(850.0)).is_some());
+        assert!(band.violation(None).is_some());
+        assert_eq!(MassBand { target_g: 100.0, tolerance_pct: 150.0 }.bounds(), (0.0, 250.0));
+    }
+
+    #[test]
+    fn test_modification_schema_serialization_is_stable() {
+        for build in [get_llm_modification_schema, get_llm_modification_schema_single_item] {
+            let first = serde_json::to_string(&build()).unwrap();
+            assert!((0..8).all(|_| serde_json::to_string(&build()).unwrap() == first));
+
+            let schema = build().schema;
+            let keys: Vec<&String> = schema.properties.as_ref().unwrap().keys().collect();
+            let mut sorted = keys.clone();
+            sorted.sort();
+            assert_eq!(keys, sorted);
+        }
+    }
+
+    /// Compares `actual` with `src/optim/snapshots/<name>`; run with `UPDATE_SNAPSHOTS=1` to
+    /// rewrite the snapshot after an intended prompt change.
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/optim/snapshots").join(name);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Missing snapshot {:?} ({}); run with UPDATE_SNAPSHOTS=1", path, e));
+        assert!(expected == actual, "Prompt differs from snapshot {:?}:\n{}", path, actual);
+    }
+
+    #[test]
+    fn test_optimizer_prompts_match_snapshots() {
+        let mut recipe = recipe_with_butter();
+        recipe.ingredients[0].nutritional_info = Some(CalculatedNutritionalInfo {
+            source_ciqual_name: "Butter, unsalted".to_string(),
+            kcal: Some(745.0),
+            protein_g: Some(0.7),
+            carbohydrate_g: Some(0.6),
+            fat_g: Some(82.0),
+            ..Default::default()
+        });
+        let mut salt = recipe.ingredients[0].clone();
+        salt.raw_text = "a pinch of salt".to_string();
+        salt.ingredient_name = "salt".to_string();
+        salt.quantity_grams = None;
+        salt.nutritional_info = None;
+        recipe.ingredients.push(salt);
+        let profile = calculate_nutritional_profile(&recipe);
+        let mut target = TargetNutritionalValues::from(&profile.per_100g);
+        target.fat_g = Some(70.0);
+        target.protein_g = Some(5.0);
+
+        let (system_prompt, user_prompt) = build_optimizer_prompts(&recipe, &profile, &target, 1.2345, &[], &PromptTemplates::default());
+        assert_snapshot("optimizer_system_prompt.txt", &system_prompt);
+        assert_snapshot("optimizer_user_prompt.txt", &user_prompt);
+
+        let optimizable = ["salt".to_string()];
+        let (_, user_prompt) = build_optimizer_prompts(&recipe, &profile, &target, 1.2345, &optimizable, &PromptTemplates::default());
+        assert_snapshot("optimizer_user_prompt_optimizable.txt", &user_prompt);
+    }
+
+    /// Butter and water indexed with auto-accept, a recipe of both, and a fat target of 20 g per 100 g.
+    fn butter_and_water_fixture() -> Result<(NutritionalIndex, CleanedRecipe, RecipeNutritionalProfile, TargetNutritionalValues)> {
+        use crate::nutritional_matcher::AutoAccept;
+
+        let fat = |name: &str, row: usize, fat: f32| FoodItem {
+            kcal_per_100g: Some(fat * 9.0),
+            fat_g_per_100g: Some(fat),
+            ..food_item(name, row)
+        };
+        let embeddings = std::collections::HashMap::from([
+            ("Butter".to_string(), vec![1.0, 0.0]),
+            ("butter".to_string(), vec![1.0, 0.0]),
+            ("Water".to_string(), vec![0.0, 1.0]),
+            ("water".to_string(), vec![0.0, 1.0]),
+        ]);
+        let index = index_of(vec![fat("Butter", 0, 81.0), fat("Water", 1, 0.0)], embeddings)?
+            .with_auto_accept(Some(AutoAccept { min_similarity: 0.9, min_margin: 0.1 }));
+
+        let mut recipe = recipe_with_butter();
+        let mut water = recipe.ingredients[0].clone();
+        water.raw_text = "100 g water".to_string();
+        water.ingredient_name = "water".to_string();
+        recipe.ingredients.push(water);
+        for (ingredient, fat) in recipe.ingredients.iter_mut().zip([81.0, 0.0]) {
+            ingredient.nutritional_info = Some(CalculatedNutritionalInfo { fat_g: Some(fat), ..Default::default() });
+        }
+        let mut profile = calculate_nutritional_profile(&recipe);
+        profile.apply_servings(4);
+        let targets = TargetNutritionalValues { fat_g: Some(20.0), ..TargetNutritionalValues::from(&profile.per_100g) };
+        Ok((index, recipe, profile, targets))
+    }
+
+    /// An optimizer answer setting the butter to `grams`.
+    fn adjust_butter(grams: u32) -> String {
+        format!(
+            r#"{{"modifications": [{{"operation": "adjust_quantity", "original_ingredient_name": "butter", "quantity_raw": "{}", "unit_raw": "g", "reasoning": "Try {} g."}}], "overall_reasoning": "Use less butter."}}"#,
+            grams, grams
+        )
+    }
+
+    const NO_CHANGE: &str = r#"{"modifications": [{"operation": "no_change"}], "overall_reasoning": "Good enough."}"#;
+
+    #[tokio::test]
+    async fn test_accepted_iterations_are_checkpointed() -> Result<()> {
+        use crate::api_connection::client::MockChatClient;
+        use crate::recipe_aggregator::EnrichedRecipeOutput;
+
+        let (index, recipe, profile, targets) = butter_and_water_fixture()?;
+        let client = MockChatClient::new([adjust_butter(60), NO_CHANGE.to_string()]);
+        let dir = tempfile::tempdir()?;
+        let checkpoint_path = dir.path().join("recipe_optimized.json");
+        let checkpoint = Checkpoint::new(checkpoint_path.clone());
+        let options = OptimizerOptions { max_iterations: 3, checkpoint: Some(checkpoint.clone()), ..Default::default() };
+
+        let result = optimize_recipe(&recipe, &profile, &targets, &options, &index, &client, |_| {}).await?;
+        assert_eq!(result.recipe.ingredients[0].quantity_grams, Some(60.0));
+        assert_eq!(checkpoint.writes(), 1);
+
+        let checkpoint = EnrichedRecipeOutput::from_json(&std::fs::read_to_string(&checkpoint_path)?)?;
+        assert_eq!(checkpoint.ingredients[0].quantity_grams, Some(60.0));
+        assert_eq!(checkpoint.nutritional_profile.servings, Some(4));
+        assert_eq!(checkpoint.optimization_notes, result.notes);
+        assert!(!dir.path().join("recipe_optimized.json.tmp").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_within_tolerance_ends_early() -> Result<()> {
+        use crate::api_connection::client::MockChatClient;
+        use crate::optim::targets::OptimizableNutrient;
+        use crate::optim::nutri_eval::{Tolerance, ToleranceBands};
+
+        // Once fat is within 15g of its target, the loop ends without asking the LLM again.
+        let (index, recipe, profile, targets) = butter_and_water_fixture()?;
+        let client = MockChatClient::new([adjust_butter(60)]);
+        let stop_within = ToleranceBands::default()
+            .with_tolerance(OptimizableNutrient::Fat, Tolerance::Absolute(15.0))
+            .for_targets([OptimizableNutrient::Fat]);
+        let options = OptimizerOptions { max_iterations: 3, stop_within: Some(stop_within), ..Default::default() };
+        let result = optimize_recipe(&recipe, &profile, &targets, &options, &index, &client, |_| {}).await?;
+        assert_eq!(result.recipe.ingredients[0].quantity_grams, Some(60.0));
+        assert_eq!(client.requests().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exploration_builds_on_worse_candidates_but_returns_the_best() -> Result<()> {
+        use crate::api_connection::client::MockChatClient;
+
+        let (index, recipe, profile, targets) = butter_and_water_fixture()?;
+        let replies = [adjust_butter(30), adjust_butter(150), NO_CHANGE.to_string()];
+        let exploration = Exploration { initial_temperature: 1e6, cooling_rate: DEFAULT_COOLING_RATE, seed: 1 };
+        // Greedy, the third suggestion builds on the best recipe; exploring, on the worse one accepted last.
+        for (exploration, current_butter) in [(None, "30"), (Some(exploration), "150")] {
+            let client = MockChatClient::new(replies.clone());
+            let options = OptimizerOptions { max_iterations: 3, exploration, ..Default::default() };
+            let result = optimize_recipe(&recipe, &profile, &targets, &options, &index, &client, |_| {}).await?;
+            assert_eq!(result.recipe.ingredients[0].quantity_grams, Some(30.0));
+            assert!(result.notes.iter().any(|note| note.contains("Try 30 g")), "{:?}", result.notes);
+            assert!(!result.notes.iter().any(|note| note.contains("Try 150 g")), "{:?}", result.notes);
+            let last_prompt = &client.requests()[2].messages[1].content;
+            assert!(last_prompt.contains(&format!("{} g butter", current_butter)), "{}", last_prompt);
+        }
+
+        assert_eq!(exploration.acceptance_probability(1.0, 1.0, 0), 1.0);
+        let cold = Exploration { initial_temperature: 0.1, ..exploration };
+        assert!((cold.acceptance_probability(1.0, 1.1, 0) - (-1.0f32).exp()).abs() < 1e-4);
+        assert!(cold.acceptance_probability(1.0, 1.1, 5) < cold.acceptance_probability(1.0, 1.1, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_optimizable_ingredients_are_in_the_prompt() -> Result<()> {
+        use crate::api_connection::client::MockChatClient;
+
+        // Only water may change, so the butter adjustment is skipped and the recipe is kept.
+        let (index, recipe, profile, targets) = butter_and_water_fixture()?;
+        let client = MockChatClient::new([adjust_butter(60), NO_CHANGE.to_string()]);
+        let options = OptimizerOptions { max_iterations: 3, optimizable: vec!["water".to_string()], ..Default::default() };
+
+        let result = optimize_recipe(&recipe, &profile, &targets, &options, &index, &client, |_| {}).await?;
+        assert_eq!(result.recipe.ingredients[0].quantity_grams, Some(100.0));
+        let requests = client.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].messages[1].content.contains("Only these ingredients may be modified, removed, replaced or added; leave every other ingredient as it is: water"));
+        Ok(())
+    }
+}
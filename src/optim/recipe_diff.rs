@@ -0,0 +1,200 @@
+//! What changed between two versions of a recipe, such as before and after an optimization:
+//! ingredients added, removed or re-weighed, the net mass change and the nutrient changes.
+use std::fmt;
+
+use crate::recipe_aggregator::NutritionalSummary;
+use crate::recipe_converter::{CleanedIngredient, CleanedRecipe};
+
+/// Weight changes smaller than this are rounding noise from re-converting the same quantity.
+const MIN_GRAMS_CHANGE: f32 = 0.05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IngredientChange {
+    Added { name: String, grams: Option<f32> },
+    Removed { name: String, grams: Option<f32> },
+    Adjusted { name: String, before_grams: Option<f32>, after_grams: Option<f32> },
+}
+
+impl fmt::Display for IngredientChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let grams = |grams: &Option<f32>| grams.map_or_else(|| "? g".to_string(), |grams| format!("{:.1} g", grams));
+        match self {
+            IngredientChange::Added { name, grams: added } => write!(f, "+ {} ({})", name, grams(added)),
+            IngredientChange::Removed { name, grams: removed } => write!(f, "- {} ({})", name, grams(removed)),
+            IngredientChange::Adjusted { name, before_grams, after_grams } => {
+                write!(f, "~ {} ({} -> {})", name, grams(before_grams), grams(after_grams))
+            }
+        }
+    }
+}
+
+/// Ingredient changes between two versions of a recipe, with their total known mass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipeDiff {
+    /// Removed and adjusted ingredients in the original recipe's order, then added ones.
+    pub changes: Vec<IngredientChange>,
+    pub mass_before_g: f32,
+    pub mass_after_g: f32,
+}
+
+impl RecipeDiff {
+    pub fn mass_delta_g(&self) -> f32 {
+        self.mass_after_g - self.mass_before_g
+    }
+
+    /// Net mass change relative to the original mass, `None` when that is unknown.
+    pub fn mass_delta_percent(&self) -> Option<f32> {
+        (self.mass_before_g > 0.0).then(|| self.mass_delta_g() / self.mass_before_g * 100.0)
+    }
+}
+
+fn find_ingredient<'a>(recipe: &'a CleanedRecipe, name: &str) -> Option<&'a CleanedIngredient> {
+    let key = name.trim().to_lowercase();
+    recipe.ingredients.iter().find(|ingredient| ingredient.ingredient_name.trim().to_lowercase() == key)
+}
+
+/// Compares recipes ingredient by ingredient, matching names case-insensitively. Ingredients
+/// without grams count as unknown mass and are only reported when added or removed.
+pub fn diff_recipes(before: &CleanedRecipe, after: &CleanedRecipe) -> RecipeDiff {
+    let mut changes = Vec::new();
+    for ingredient in &before.ingredients {
+        match find_ingredient(after, &ingredient.ingredient_name) {
+            None => changes.push(IngredientChange::Removed {
+                name: ingredient.ingredient_name.clone(),
+                grams: ingredient.quantity_grams,
+            }),
+            Some(kept) => {
+                let changed = match (ingredient.quantity_grams, kept.quantity_grams) {
+                    (Some(before_grams), Some(after_grams)) => (after_grams - before_grams).abs() >= MIN_GRAMS_CHANGE,
+                    (before_grams, after_grams) => before_grams.is_some() != after_grams.is_some(),
+                };
+                if changed {
+                    changes.push(IngredientChange::Adjusted {
+                        name: ingredient.ingredient_name.clone(),
+                        before_grams: ingredient.quantity_grams,
+                        after_grams: kept.quantity_grams,
+                    });
+                }
+            }
+        }
+    }
+    for ingredient in &after.ingredients {
+        if find_ingredient(before, &ingredient.ingredient_name).is_none() {
+            changes.push(IngredientChange::Added {
+                name: ingredient.ingredient_name.clone(),
+                grams: ingredient.quantity_grams,
+            });
+        }
+    }
+
+    let mass = |recipe: &CleanedRecipe| recipe.ingredients.iter().filter_map(|ingredient| ingredient.quantity_grams).sum();
+    RecipeDiff { changes, mass_before_g: mass(before), mass_after_g: mass(after) }
+}
+
+/// A nutrient's value in two versions of a recipe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NutrientChange {
+    pub nutrient: &'static str,
+    pub unit: &'static str,
+    pub before: Option<f32>,
+    pub after: Option<f32>,
+}
+
+impl NutrientChange {
+    /// `after - before`, `None` unless both are known.
+    pub fn delta(&self) -> Option<f32> {
+        Some(self.after? - self.before?)
+    }
+
+    /// The change relative to the value before, `None` when that is unknown or 0.
+    pub fn delta_percent(&self) -> Option<f32> {
+        let before = self.before.filter(|before| *before != 0.0)?;
+        Some(self.delta()? / before * 100.0)
+    }
+}
+
+impl fmt::Display for NutrientChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |value: Option<f32>| value.map_or_else(|| "?".to_string(), |value| format!("{:.1}{}", value, self.unit));
+        write!(f, "{}: {} -> {}", self.nutrient, value(self.before), value(self.after))?;
+        if let Some(delta) = self.delta() {
+            write!(f, " ({:+.1}{}", delta, self.unit)?;
+            if let Some(percent) = self.delta_percent() {
+                write!(f, ", {:+.1}%", percent)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every nutrient known in at least one of the two summaries, in `NutritionalSummary` field order.
+pub fn diff_nutrition(before: &NutritionalSummary, after: &NutritionalSummary) -> Vec<NutrientChange> {
+    [
+        ("kcal", " kcal", before.kcal, after.kcal),
+        ("water", "g", before.water_g, after.water_g),
+        ("protein", "g", before.protein_g, after.protein_g),
+        ("carbohydrate", "g", before.carbohydrate_g, after.carbohydrate_g),
+        ("fat", "g", before.fat_g, after.fat_g),
+        ("sugars", "g", before.sugars_g, after.sugars_g),
+        ("saturated fat", "g", before.fa_saturated_g, after.fa_saturated_g),
+        ("salt", "g", before.salt_g, after.salt_g),
+        ("fiber", "g", before.fiber_g, after.fiber_g),
+        ("cholesterol", "mg", before.cholesterol_mg, after.cholesterol_mg),
+    ]
+    .into_iter()
+    .filter(|(_, _, before, after)| before.is_some() || after.is_some())
+    .map(|(nutrient, unit, before, after)| NutrientChange { nutrient, unit, before, after })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nutritional_matcher::test_support::cleaned_ingredient;
+
+    fn recipe(ingredients: &[(&str, Option<f32>)]) -> CleanedRecipe {
+        CleanedRecipe {
+            recipe_title: "Cake".to_string(),
+            ingredients: ingredients.iter()
+                .map(|&(name, grams)| CleanedIngredient { quantity_grams: grams, ..cleaned_ingredient(name, grams.unwrap_or(0.0)) })
+                .collect(),
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_recipes() {
+        let before = recipe(&[("butter", Some(100.0)), ("Sugar", Some(200.0)), ("flour", Some(250.0)), ("salt", None)]);
+        let after = recipe(&[("flour", Some(250.02)), ("sugar", Some(120.0)), ("salt", None), ("applesauce", Some(60.0))]);
+        let diff = diff_recipes(&before, &after);
+
+        assert_eq!(diff.changes, vec![
+            IngredientChange::Removed { name: "butter".to_string(), grams: Some(100.0) },
+            IngredientChange::Adjusted { name: "Sugar".to_string(), before_grams: Some(200.0), after_grams: Some(120.0) },
+            IngredientChange::Added { name: "applesauce".to_string(), grams: Some(60.0) },
+        ]);
+        assert!((diff.mass_delta_g() + 119.98).abs() < 1e-3);
+        assert!((diff.mass_delta_percent().unwrap() + 21.814).abs() < 1e-2);
+        assert_eq!(diff.changes[1].to_string(), "~ Sugar (200.0 g -> 120.0 g)");
+
+        let unchanged = diff_recipes(&before, &before);
+        assert!(unchanged.changes.is_empty());
+        assert_eq!(unchanged.mass_delta_g(), 0.0);
+    }
+
+    #[test]
+    fn test_diff_nutrition() {
+        let before = NutritionalSummary { kcal: Some(400.0), fat_g: Some(20.0), salt_g: Some(0.0), ..Default::default() };
+        let after = NutritionalSummary { kcal: Some(300.0), fat_g: None, salt_g: Some(0.5), fiber_g: Some(2.0), ..Default::default() };
+        let changes: Vec<String> = diff_nutrition(&before, &after).iter().map(ToString::to_string).collect();
+        assert_eq!(changes, vec![
+            "kcal: 400.0 kcal -> 300.0 kcal (-100.0 kcal, -25.0%)",
+            "fat: 20.0g -> ?",
+            "salt: 0.0g -> 0.5g (+0.5g)",
+            "fiber: ? -> 2.0g",
+        ]);
+    }
+}
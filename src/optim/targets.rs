@@ -1,6 +1,58 @@
-use crate::cli::OptimizableNutrient;
-use crate::recipe_aggregator::NutritionalSummary; // Using the per-100g or aggregated summary
+use crate::recipe_aggregator::{atwater_kcal, NutritionalSummary}; // Using the per-100g or aggregated summary
 use std::collections::HashMap;
+use std::str::FromStr;
+
+// Define an enum for the nutrients we can target for percentage change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptimizableNutrient {
+    Carb,
+    Fat,
+    Protein,
+    // Kcal is removed as a direct percentage target for --optimize.
+    // It will be an outcome of macronutrient changes.
+    // Add Sugars, Fiber etc. as needed in the future, listing them in `ALL` and `names`.
+}
+
+impl OptimizableNutrient {
+    /// Every nutrient `--optimize` accepts, as listed by `--list-nutrients`.
+    pub const ALL: [OptimizableNutrient; 3] = [OptimizableNutrient::Carb, OptimizableNutrient::Fat, OptimizableNutrient::Protein];
+
+    /// Names accepted for the nutrient (case-insensitive); the first one is its canonical name.
+    pub fn names(self) -> &'static [&'static str] {
+        match self {
+            OptimizableNutrient::Carb => &["carb", "carbohydrate", "carbohydrates"],
+            OptimizableNutrient::Fat => &["fat", "fats"],
+            OptimizableNutrient::Protein => &["protein", "proteins"],
+        }
+    }
+
+    /// The per-100g value the percentage change applies to.
+    pub fn description(self) -> &'static str {
+        match self {
+            OptimizableNutrient::Carb => "carbohydrates, g per 100 g",
+            OptimizableNutrient::Fat => "total fat, g per 100 g",
+            OptimizableNutrient::Protein => "protein, g per 100 g",
+        }
+    }
+}
+
+impl FromStr for OptimizableNutrient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_lowercase();
+        OptimizableNutrient::ALL.into_iter()
+            .find(|nutrient| nutrient.names().contains(&name.as_str()))
+            .ok_or_else(|| {
+                let supported: Vec<&str> = OptimizableNutrient::ALL.iter().map(|nutrient| nutrient.names()[0]).collect();
+                format!(
+                    "Unknown nutrient for --optimize: '{}'. Supported: {}. Run with --list-nutrients for their aliases.",
+                    s,
+                    supported.join(", ")
+                )
+            })
+    }
+}
 
 // This struct will hold the desired absolute nutrient values after percentage changes.
 // It mirrors NutritionalSummary for direct comparison.
@@ -14,9 +66,29 @@ pub struct TargetNutritionalValues {
     pub sugars_g: Option<f32>,
     pub fa_saturated_g: Option<f32>,
     pub salt_g: Option<f32>,
+    pub fiber_g: Option<f32>,
+    pub cholesterol_mg: Option<f32>,
     // Add other fields if NutritionalSummary has more
 }
 
+/// The summary's values as targets, before any change is applied.
+impl From<&NutritionalSummary> for TargetNutritionalValues {
+    fn from(summary: &NutritionalSummary) -> Self {
+        TargetNutritionalValues {
+            kcal: summary.kcal,
+            water_g: summary.water_g,
+            protein_g: summary.protein_g,
+            carbohydrate_g: summary.carbohydrate_g,
+            fat_g: summary.fat_g,
+            sugars_g: summary.sugars_g,
+            fa_saturated_g: summary.fa_saturated_g,
+            salt_g: summary.salt_g,
+            fiber_g: summary.fiber_g,
+            cholesterol_mg: summary.cholesterol_mg,
+        }
+    }
+}
+
 /// Calculates the target nutritional values based on an initial profile and percentage changes.
 ///
 /// # Arguments
@@ -29,17 +101,8 @@ pub fn calculate_target_nutrition(
     initial_profile_per_100g: &NutritionalSummary,
     optimization_goals: &HashMap<OptimizableNutrient, f32>,
 ) -> TargetNutritionalValues {
-    let mut target_values = TargetNutritionalValues {
-        // Initialize with initial values, then adjust based on goals
-        kcal: initial_profile_per_100g.kcal,
-        water_g: initial_profile_per_100g.water_g,
-        protein_g: initial_profile_per_100g.protein_g,
-        carbohydrate_g: initial_profile_per_100g.carbohydrate_g,
-        fat_g: initial_profile_per_100g.fat_g,
-        sugars_g: initial_profile_per_100g.sugars_g,
-        fa_saturated_g: initial_profile_per_100g.fa_saturated_g,
-        salt_g: initial_profile_per_100g.salt_g,
-    };
+    // Initialize with initial values, then adjust based on goals
+    let mut target_values = TargetNutritionalValues::from(initial_profile_per_100g);
 
     for (nutrient, percentage_change) in optimization_goals {
         let multiplier = 1.0 + (percentage_change / 100.0);
@@ -74,13 +137,7 @@ pub fn calculate_target_nutrition(
     // If a specific kcal target is desired *independently*, it would need a different CLI mechanism.
 
     // Recalculate kcal based on modified macros (optional, but good for consistency if macros are primary targets)
-    let mut new_kcal = 0.0;
-    let mut has_macros = false;
-    if let Some(p) = target_values.protein_g { new_kcal += p * 4.0; has_macros = true; }
-    if let Some(c) = target_values.carbohydrate_g { new_kcal += c * 4.0; has_macros = true; }
-    if let Some(f) = target_values.fat_g { new_kcal += f * 9.0; has_macros = true; }
-
-    if has_macros {
+    if let Some(new_kcal) = atwater_kcal(target_values.protein_g, target_values.carbohydrate_g, target_values.fat_g) {
         target_values.kcal = Some(new_kcal);
     }
     // If no macros were present in the initial profile, kcal remains as it was (possibly None).
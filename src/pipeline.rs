@@ -0,0 +1,747 @@
+//! The whole recipe pipeline as a library: parse, convert to grams, match nutrition, aggregate and
+//! optionally optimize. `process_recipe` runs every step; the step functions let callers cache or
+//! inspect intermediate results, as the CLI does with its enriched files.
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::api_connection::client::{ChatClient, WithModel};
+use crate::api_connection::endpoints::{GenParams, ResponseFormatMode, StageGenParams};
+use crate::api_connection::json_extract::JsonMode;
+use crate::{log_info, log_warning};
+use crate::nutritional_matcher::NutritionalIndex;
+use crate::optim::nutri_eval::{TargetCheck, ToleranceBands};
+use crate::optim::optimizer::{optimize_recipe, LlmModificationResponse, OptimizerOptions};
+use crate::optim::targets::{calculate_target_nutrition, OptimizableNutrient, TargetNutritionalValues};
+use crate::progress::ProgressEvent;
+use crate::prompts::PromptTemplates;
+use crate::recipe_aggregator::{
+    calculate_nutritional_profile, calculate_recipe_cost, check_kcal, default_allergen_rules, detect_allergens,
+    AllergenRule, DEFAULT_KCAL_TOLERANCE_PCT, EnrichedRecipeOutput, IngredientOrder, RecipeNutritionalProfile,
+    UnsupportedSchemaVersion,
+};
+use crate::recipe_converter::{convert_ingredients_to_grams, CleanedIngredient, CleanedRecipe, ContainerSizes, ConversionOptions, ToTasteDefaults, DEFAULT_CONVERSION_PARSE_RETRIES};
+use crate::recipe_parser::{
+    count_ingredient_lines, estimated_parse_tokens, parse_quantity, parse_recipe_text, parse_recipe_text_in_chunks, ParseLimits,
+    ParsedIngredient, ParsedRecipe,
+};
+
+/// Format of the recipe input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// Free-form recipe text, parsed by the LLM.
+    Text,
+    /// An already-structured `ParsedRecipe` serialized as JSON; skips the LLM parse step.
+    Json,
+}
+
+/// Settings for one pipeline run.
+#[derive(Debug, Clone)]
+pub struct PipelineOptions {
+    /// How the recipe input is to be read.
+    pub input_format: InputFormat,
+    /// Reprompts allowed when a gram conversion isn't valid JSON.
+    pub conversion_retries: u32,
+    /// Grams assumed for seasonings measured "to taste" with no amount.
+    pub to_taste_defaults: ToTasteDefaults,
+    /// Grams per can, package, stick or egg, used before asking the LLM.
+    pub container_sizes: ContainerSizes,
+    /// Overrides the yield parsed from the recipe for the per-serving values.
+    pub servings: Option<u32>,
+    /// Percentage change per nutrient. Empty means no optimization.
+    pub optimization_targets: HashMap<OptimizableNutrient, f32>,
+    /// Optimizer settings. `optimize` replaces its conversion retries, to-taste defaults, sampling
+    /// settings and JSON mode with the ones of these options, so they are only set once.
+    pub optimizer: OptimizerOptions,
+    pub allergen_rules: Vec<AllergenRule>,
+    /// Price per kg by ingredient name; adds a cost estimate to the output when set.
+    pub price_table: Option<HashMap<String, f32>>,
+    /// Sampling settings of the parse, conversion and optimizer requests; disambiguation is
+    /// configured on the `NutritionalIndex`.
+    pub gen_params: StageGenParams,
+    /// Order of the ingredients in the output; the pipeline and the cached enriched file always
+    /// keep the recipe order.
+    pub ingredient_order: IngredientOrder,
+    /// Whether parse and conversion answers may be wrapped in prose or markdown fences.
+    pub json_mode: JsonMode,
+    /// How requests ask for their answer's schema; disambiguation is configured on the `NutritionalIndex`.
+    pub response_format: ResponseFormatMode,
+    /// Templates of the parse, conversion and optimizer prompts; disambiguation is configured on
+    /// the `NutritionalIndex`.
+    pub prompts: PromptTemplates,
+    /// Chunking of the parser calls and the cap on the number of ingredients.
+    pub parse_limits: ParseLimits,
+    /// Largest gap, in percent, between the database kcal and the Atwater estimate from the
+    /// macros before `validate_kcal` warns.
+    pub kcal_tolerance_pct: f32,
+    /// Replace the database kcal with the Atwater estimate when they are too far apart.
+    pub reconcile_kcal: bool,
+    /// How close to its target an optimized nutrient must be to count as met.
+    pub tolerances: ToleranceBands,
+    /// End the optimization once every targeted nutrient is within its tolerance.
+    pub stop_within_tolerance: bool,
+    /// Model named in every LLM request instead of the built-in one; see `WithModel`.
+    pub model: Option<String>,
+    /// Enriched file `process_recipe` reuses instead of parsing, converting and matching the recipe
+    /// again, and writes after doing so. Optimized recipes aren't cached.
+    pub cache_path: Option<PathBuf>,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            input_format: InputFormat::Text,
+            conversion_retries: DEFAULT_CONVERSION_PARSE_RETRIES,
+            to_taste_defaults: ToTasteDefaults::default(),
+            container_sizes: ContainerSizes::default(),
+            servings: None,
+            optimization_targets: HashMap::new(),
+            optimizer: OptimizerOptions::default(),
+            allergen_rules: default_allergen_rules(),
+            price_table: None,
+            gen_params: StageGenParams::default(),
+            ingredient_order: IngredientOrder::default(),
+            json_mode: JsonMode::default(),
+            response_format: ResponseFormatMode::default(),
+            prompts: PromptTemplates::default(),
+            parse_limits: ParseLimits::default(),
+            kcal_tolerance_pct: DEFAULT_KCAL_TOLERANCE_PCT,
+            reconcile_kcal: false,
+            tolerances: ToleranceBands::default(),
+            stop_within_tolerance: false,
+            model: None,
+            cache_path: None,
+        }
+    }
+}
+
+/// An optimized recipe with its recomputed profile and the targets it was optimized towards.
+#[derive(Debug, Clone)]
+pub struct OptimizedRecipe {
+    pub recipe: CleanedRecipe,
+    pub profile: RecipeNutritionalProfile,
+    pub targets: TargetNutritionalValues,
+    /// Why the recipe changed, as explained by the optimizer; see `OptimizationResult`.
+    pub notes: Vec<String>,
+    /// Suggested modifications, when the optimizer only suggests; see `OptimizerOptions::suggest_only`.
+    pub suggestions: Vec<LlmModificationResponse>,
+    /// Whether each targeted nutrient of the optimized profile is within its tolerance.
+    pub target_checks: Vec<TargetCheck>,
+}
+
+/// What `process_recipe` produced: the enriched output and, when the optimizer only suggests,
+/// its suggested modifications.
+#[derive(Debug, Clone)]
+pub struct ProcessedRecipe {
+    pub output: EnrichedRecipeOutput,
+    pub suggestions: Vec<LlmModificationResponse>,
+}
+
+/// How `--scale` or `--scale-to-servings` resizes a recipe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaling {
+    /// Multiply every quantity by this factor.
+    Factor(f32),
+    /// Resize the recipe to yield this many servings.
+    Servings(u32),
+}
+
+impl Scaling {
+    /// The factor to multiply quantities by, for a recipe yielding `servings`.
+    pub fn factor(self, servings: Option<u32>) -> Result<f32> {
+        match self {
+            Scaling::Factor(factor) => Ok(factor),
+            Scaling::Servings(target) => {
+                let servings = servings
+                    .ok_or_else(|| anyhow!("The recipe doesn't state its servings; pass --servings to scale it to {} servings", target))?;
+                Ok(target as f32 / servings as f32)
+            }
+        }
+    }
+}
+
+/// A recipe resized by `scale_recipe`, with a note per ingredient whose quantity text wasn't scaled.
+#[derive(Debug, Clone)]
+pub struct ScaledRecipe {
+    pub recipe: CleanedRecipe,
+    pub notes: Vec<String>,
+}
+
+/// Reads the recipe input: structured JSON is loaded as is, text goes through the LLM parser, in
+/// chunks when `limits` asks for them. A parsed recipe with more ingredients than
+/// `limits.max_ingredients` is an error; text whose "Ingredients" list already looks longer only
+/// gets a warning before the LLM call, since the count of raw lines is a guess.
+pub async fn parse_recipe(
+    text: &str,
+    input_format: InputFormat,
+    gen_params: GenParams,
+    json_mode: JsonMode,
+    limits: ParseLimits,
+    prompts: &PromptTemplates,
+    client: &impl ChatClient,
+) -> Result<ParsedRecipe> {
+    let too_many_ingredients = |count: usize| anyhow!(
+        "The recipe has {} ingredients, more than --max-ingredients {}; raise the limit if that is intended",
+        count, limits.max_ingredients
+    );
+    let parsed_recipe = match input_format {
+        InputFormat::Json => {
+            log_info!("\nLoading structured JSON recipe (skipping LLM parse)...");
+            serde_json::from_str::<ParsedRecipe>(text)
+                .with_context(|| "Input is not a valid structured recipe JSON (expected recipe_title, ingredients, instructions)")?
+        }
+        InputFormat::Text => {
+            if let Some(count) = count_ingredient_lines(text).filter(|count| *count > limits.max_ingredients) {
+                log_warning!(
+                    "\n[WARNING] The recipe's ingredient list has {} lines, more than --max-ingredients {}; the parse will fail if they are all ingredients.",
+                    count, limits.max_ingredients
+                );
+            }
+            log_info!("\nSending recipe to parser...");
+            match limits.chunk_size {
+                Some(chunk_size) => parse_recipe_text_in_chunks(text, chunk_size, client, gen_params, json_mode, prompts).await,
+                None => {
+                    let estimated_tokens = estimated_parse_tokens(text);
+                    if estimated_tokens > gen_params.max_tokens {
+                        log_warning!(
+                            "\n[WARNING] The recipe is long: parsing it may take about {} tokens, more than the parser's {} max tokens. Pass --parse-chunk-size to parse its ingredients in several calls.",
+                            estimated_tokens, gen_params.max_tokens
+                        );
+                    }
+                    parse_recipe_text(text, client, gen_params, json_mode, prompts).await
+                }
+            }
+            .with_context(|| "Recipe parsing failed")?
+        }
+    };
+    if parsed_recipe.ingredients.len() > limits.max_ingredients {
+        return Err(too_many_ingredients(parsed_recipe.ingredients.len()));
+    }
+    if parsed_recipe.heuristically_parsed {
+        log_warning!("\n[WARNING] The recipe was parsed with the rule-based fallback parser; please double-check the ingredients.");
+    }
+    Ok(parsed_recipe)
+}
+
+/// Merges several recipes into one meal: each recipe's ingredients form a section named after
+/// it (nested as "Recipe / section" when it had sections of its own) and its instructions are
+/// prefixed with its title. The servings are kept only when every recipe yields the same number;
+/// the total time is the sum of the recipes' times when all are known, capped at `u32::MAX`.
+pub fn combine_recipes(recipes: &[ParsedRecipe]) -> ParsedRecipe {
+    let mut ingredients = Vec::new();
+    let mut instructions = Vec::new();
+    for recipe in recipes {
+        let title = recipe.recipe_title.trim();
+        ingredients.extend(recipe.ingredients.iter().map(|ingredient| ParsedIngredient {
+            section: Some(match &ingredient.section {
+                Some(section) => format!("{} / {}", title, section),
+                None => title.to_string(),
+            }),
+            ..ingredient.clone()
+        }));
+        instructions.extend(recipe.instructions.iter().map(|step| format!("{}: {}", title, step)));
+    }
+
+    let servings = recipes.first().and_then(|first| first.servings)
+        .filter(|servings| recipes.iter().all(|recipe| recipe.servings == Some(*servings)));
+    ParsedRecipe {
+        recipe_title: recipes.iter().map(|recipe| recipe.recipe_title.trim()).collect::<Vec<_>>().join(" + "),
+        ingredients,
+        instructions,
+        servings,
+        total_time_minutes: recipes.iter()
+            .try_fold(0u32, |total, recipe| Some(total.saturating_add(recipe.total_time_minutes?))),
+        heuristically_parsed: recipes.iter().any(|recipe| recipe.heuristically_parsed),
+    }
+}
+
+/// "2" as it reads best: whole numbers without decimals, others with at most two.
+fn format_quantity(value: f32) -> String {
+    let rounded = (value * 100.0).round() / 100.0;
+    if rounded.fract() == 0.0 {
+        format!("{}", rounded as i64)
+    } else {
+        format!("{:.2}", rounded).trim_end_matches('0').to_string()
+    }
+}
+
+/// `quantity` times `factor`, for a number or a range of numbers ("1-2"); `None` when it isn't numeric.
+fn scale_quantity_text(quantity: &str, factor: f32) -> Option<String> {
+    quantity.split('-')
+        .map(|bound| {
+            let bound = bound.trim();
+            let is_numeric = !bound.is_empty()
+                && bound.chars().all(|c| c.is_ascii_digit() || c.is_whitespace() || matches!(c, '/' | '.' | ',' | '¼' | '½' | '¾' | '⅓' | '⅔' | '⅛'));
+            parse_quantity(bound).filter(|_| is_numeric).map(|value| format_quantity(value * factor))
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|bounds| bounds.join("-"))
+}
+
+/// Multiplies every ingredient's grams, nutrition and numeric quantity by `factor`, leaving the
+/// per-100g values unchanged. Quantities without a number ("to taste") keep their text, with a
+/// note. The servings are scaled too when that gives a whole number, so larger batches keep their
+/// portion size; otherwise they are kept and each serving grows with the recipe.
+pub fn scale_recipe(recipe: &CleanedRecipe, factor: f32) -> ScaledRecipe {
+    let mut notes = Vec::new();
+    let ingredients = recipe.ingredients.iter()
+        .map(|ingredient| {
+            let original_quantity = scale_quantity_text(&ingredient.original_quantity, factor).unwrap_or_else(|| {
+                notes.push(format!(
+                    "'{}': quantity '{}' is not a number and was left as is; its grams were scaled.",
+                    ingredient.ingredient_name, ingredient.raw_text
+                ));
+                ingredient.original_quantity.clone()
+            });
+            CleanedIngredient {
+                original_quantity,
+                quantity_grams: ingredient.quantity_grams.map(|grams| grams * factor),
+                nutritional_info: ingredient.nutritional_info.as_ref().map(|info| info.scaled(factor)),
+                ..ingredient.clone()
+            }
+        })
+        .collect();
+    let servings = recipe.servings.map(|servings| {
+        let scaled = servings as f32 * factor;
+        if scaled >= 1.0 && (scaled - scaled.round()).abs() < 1e-3 {
+            return scaled.round() as u32;
+        }
+        notes.push(format!(
+            "{} servings times {} is not a whole number; the servings are kept and each is {} times as large.",
+            servings, format_quantity(factor), format_quantity(factor)
+        ));
+        servings
+    });
+    ScaledRecipe {
+        recipe: CleanedRecipe { ingredients, servings, ..recipe.clone() },
+        notes,
+    }
+}
+
+/// Attaches nutritional info to every ingredient the index can match, matching the whole recipe in
+/// one batch. Unmatched ingredients are reported through `progress_updater` and left without nutrition.
+pub async fn enrich_with_nutritional_info(
+    cleaned_recipe: &mut CleanedRecipe,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: impl Fn(ProgressEvent) + Send + Sync + 'static,
+) {
+    log_info!("\nEnriching recipe with nutritional information ({} ingredients)...", cleaned_recipe.ingredients.len());
+    let matches = match nutritional_index.find_and_calculate_nutrition_batch(&cleaned_recipe.ingredients, client, &progress_updater).await {
+        Ok(matches) => matches,
+        Err(e) => {
+            progress_updater(ProgressEvent::Warning { text: format!("   -> Error finding nutrition for the recipe ingredients: {}", e) });
+            return;
+        }
+    };
+
+    for (ingredient, nutritional_info) in cleaned_recipe.ingredients.iter_mut().zip(matches) {
+        match nutritional_info {
+            Some(nutritional_info) => {
+                progress_updater(format!(
+                    "   -> Successfully calculated nutrition for '{}' from Ciqual item: '{}'",
+                    ingredient.ingredient_name, nutritional_info.source_ciqual_name
+                ).into());
+                ingredient.nutritional_info = Some(nutritional_info);
+            }
+            None => {
+                progress_updater(format!(
+                    "   -> Could not find or calculate nutritional information for '{}'",
+                    ingredient.ingredient_name
+                ).into());
+            }
+        }
+    }
+    log_info!("Nutritional enrichment complete.");
+}
+
+/// Parse, gram conversion and nutrition matching: everything up to the aggregated profile.
+pub async fn prepare_recipe<F>(
+    text: &str,
+    options: &PipelineOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: F,
+) -> Result<CleanedRecipe>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    let parsed_recipe = parse_recipe(text, options.input_format, options.gen_params.parser, options.json_mode, options.parse_limits, &options.prompts, client).await?;
+    log_info!("\nSuccessfully parsed recipe.");
+    prepare_parsed_recipe(&parsed_recipe, options, nutritional_index, client, progress_updater).await
+}
+
+/// `prepare_recipe` for a recipe that is already structured: gram conversion and nutrition matching.
+pub async fn prepare_parsed_recipe<F>(
+    parsed_recipe: &ParsedRecipe,
+    options: &PipelineOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: F,
+) -> Result<CleanedRecipe>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    log_info!("\nConverting ingredients to grams...");
+    let conversion_options = ConversionOptions {
+        parse_retries: options.conversion_retries,
+        gen_params: options.gen_params.conversion,
+        json_mode: options.json_mode,
+        response_format: options.response_format,
+        prompts: options.prompts.clone(),
+        to_taste_defaults: options.to_taste_defaults.clone(),
+        container_sizes: options.container_sizes.clone(),
+    };
+    let mut cleaned_recipe = convert_ingredients_to_grams(parsed_recipe, client, &conversion_options, progress_updater).await
+        .with_context(|| "Ingredient conversion to grams failed")?;
+    log_info!("\nSuccessfully converted recipe ingredients to grams.");
+
+    enrich_with_nutritional_info(&mut cleaned_recipe, nutritional_index, client, progress_updater).await;
+    Ok(cleaned_recipe)
+}
+
+/// The recipe's nutritional profile, with per-serving values when `servings` or the recipe's own
+/// yield is known. `servings` takes precedence.
+pub fn profile_recipe(recipe: &CleanedRecipe, servings: Option<u32>) -> RecipeNutritionalProfile {
+    let mut profile = calculate_nutritional_profile(recipe);
+    if let Some(servings) = servings.or(recipe.servings) {
+        log_info!("Computing per-serving nutrition for {} servings.", servings);
+        profile.apply_servings(servings);
+    }
+    profile
+}
+
+/// Warns when the profile's kcal and the Atwater estimate from its macros are more than
+/// `options.kcal_tolerance_pct` apart, naming the ingredients whose match is inconsistent, and
+/// reconciles the kcal with `options.reconcile_kcal`.
+pub fn validate_kcal(recipe: &CleanedRecipe, profile: &mut RecipeNutritionalProfile, options: &PipelineOptions) {
+    let Some((discrepancy, suspects)) = check_kcal(recipe, profile, options.kcal_tolerance_pct) else {
+        return;
+    };
+    log_warning!("[WARNING] The recipe's kcal disagree with its macronutrients: {}.", discrepancy);
+    for (ingredient, discrepancy) in suspects {
+        let matched = ingredient.nutritional_info.as_ref().map_or("", |info| info.source_ciqual_name.as_str());
+        log_warning!("  - '{}' (matched to '{}'): {}", ingredient.ingredient_name, matched, discrepancy);
+    }
+    if options.reconcile_kcal {
+        log_info!("Using the kcal estimated from the macronutrients instead.");
+        profile.reconcile_kcal();
+    }
+}
+
+/// Optimizes the recipe towards `options.optimization_targets` and recomputes its profile,
+/// keeping the servings of the initial one.
+pub async fn optimize<F>(
+    recipe: &CleanedRecipe,
+    profile: &RecipeNutritionalProfile,
+    options: &PipelineOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: F,
+) -> Result<OptimizedRecipe>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    let targets = calculate_target_nutrition(&profile.per_100g, &options.optimization_targets);
+    log_info!("Target Nutritional Values (per 100g): {:#?}", targets);
+    let tolerances = options.tolerances.for_targets(options.optimization_targets.keys().copied());
+    let optimizer_options = OptimizerOptions {
+        conversion_parse_retries: options.conversion_retries,
+        to_taste_defaults: options.to_taste_defaults.clone(),
+        container_sizes: options.container_sizes.clone(),
+        gen_params: options.gen_params,
+        json_mode: options.json_mode,
+        response_format: options.response_format,
+        prompts: options.prompts.clone(),
+        stop_within: options.stop_within_tolerance.then(|| tolerances.clone()),
+        // Candidates are scored against the kcal definition `validate_kcal` gave the initial profile.
+        reconcile_kcal_pct: options.reconcile_kcal.then_some(options.kcal_tolerance_pct),
+        ..options.optimizer.clone()
+    };
+
+    let optimized = optimize_recipe(
+        recipe,
+        profile,
+        &targets,
+        &optimizer_options,
+        nutritional_index,
+        client,
+        progress_updater,
+    ).await?;
+    let mut optimized_profile = calculate_nutritional_profile(&optimized.recipe);
+    if let Some(servings) = profile.servings {
+        optimized_profile.apply_servings(servings);
+    }
+    validate_kcal(&optimized.recipe, &mut optimized_profile, options);
+    let target_checks = tolerances.check(&optimized_profile.per_100g, &targets);
+    Ok(OptimizedRecipe {
+        recipe: optimized.recipe,
+        profile: optimized_profile,
+        targets,
+        notes: optimized.notes,
+        suggestions: optimized.suggestions,
+        target_checks,
+    })
+}
+
+/// Assembles the output: recipe, nutrition, allergens and, with a price table, the cost estimate.
+/// The ingredients stay in recipe order, as a cached enriched file must keep them; outputs shown
+/// or saved for the user are reordered with `with_ingredient_order(options.ingredient_order)`.
+pub fn build_output(recipe: &CleanedRecipe, profile: &RecipeNutritionalProfile, options: &PipelineOptions) -> EnrichedRecipeOutput {
+    let mut output = EnrichedRecipeOutput::new(recipe, profile)
+        .with_allergens(detect_allergens(recipe, &options.allergen_rules));
+    if let Some(price_table) = &options.price_table {
+        let cost = calculate_recipe_cost(recipe, price_table, profile.servings);
+        if !cost.uncosted.is_empty() {
+            log_warning!("[WARNING] No price for: {}. They are left out of the cost estimate.", cost.uncosted.join(", "));
+        }
+        output = output.with_cost(cost);
+    }
+    output
+}
+
+/// The recipe and profile of the enriched file at `path`, or `None` when there is no usable one:
+/// an unreadable or corrupt file is a cache miss. A file written by a newer version is an
+/// `UnsupportedSchemaVersion` error instead, so it isn't overwritten.
+pub fn load_cached_recipe(path: &Path) -> Result<Option<(CleanedRecipe, RecipeNutritionalProfile)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    log_info!("Attempting to load existing enriched file: {:?}", path);
+    let loaded = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read existing enriched file {:?}", path))
+        .and_then(|content| EnrichedRecipeOutput::from_json(&content));
+    match loaded {
+        Ok(output) => {
+            log_info!("Successfully loaded and parsed existing enriched data.");
+            Ok(Some((output.to_cleaned_recipe(), output.nutritional_profile)))
+        }
+        Err(e) if e.is::<UnsupportedSchemaVersion>() => Err(e.context(format!("Incompatible cache version in {:?}", path))),
+        Err(e) => {
+            log_info!("Failed to parse existing enriched file ({:#}). Will re-process if needed.", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Runs the full pipeline on one recipe, naming `options.model` in its LLM requests when set.
+/// With optimization targets the optimized recipe is returned, and a failed optimization is an error;
+/// with `suggest_only` the recipe is left as is and the suggestions are returned alongside it.
+pub async fn process_recipe<F>(
+    text: &str,
+    options: &PipelineOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: F,
+) -> Result<ProcessedRecipe>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    match &options.model {
+        Some(model) => run_pipeline(text, options, nutritional_index, &WithModel::new(client, model), progress_updater).await,
+        None => run_pipeline(text, options, nutritional_index, client, progress_updater).await,
+    }
+}
+
+async fn run_pipeline<F>(
+    text: &str,
+    options: &PipelineOptions,
+    nutritional_index: &NutritionalIndex,
+    client: &impl ChatClient,
+    progress_updater: F,
+) -> Result<ProcessedRecipe>
+where
+    F: Fn(ProgressEvent) + Send + Sync + Copy + 'static,
+{
+    let cached = options.cache_path.as_deref().map(load_cached_recipe).transpose()?.flatten();
+    let fresh = cached.is_none();
+    let (mut recipe, mut profile) = match cached {
+        Some((recipe, mut profile)) => {
+            if let Some(servings) = options.servings.or(recipe.servings) {
+                profile.apply_servings(servings);
+            }
+            (recipe, profile)
+        }
+        None => {
+            let recipe = prepare_recipe(text, options, nutritional_index, client, progress_updater).await?;
+            let profile = profile_recipe(&recipe, options.servings);
+            (recipe, profile)
+        }
+    };
+    validate_kcal(&recipe, &mut profile, options);
+    if let Some(cache_path) = options.cache_path.as_deref().filter(|_| fresh) {
+        build_output(&recipe, &profile, options).save(cache_path)
+            .with_context(|| format!("Failed to write enriched recipe to {:?}", cache_path))?;
+    }
+    let mut notes = Vec::new();
+    let mut suggestions = Vec::new();
+
+    if !options.optimization_targets.is_empty() {
+        let optimized = optimize(&recipe, &profile, options, nutritional_index, client, progress_updater).await
+            .with_context(|| "Recipe optimization failed")?;
+        recipe = optimized.recipe;
+        profile = optimized.profile;
+        notes = optimized.notes;
+        suggestions = optimized.suggestions;
+    }
+
+    Ok(ProcessedRecipe {
+        output: build_output(&recipe, &profile, options)
+            .with_optimization_notes(notes)
+            .with_ingredient_order(options.ingredient_order),
+        suggestions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::client::MockChatClient;
+    use crate::nutritional_matcher::test_support::cleaned_ingredient;
+    use crate::recipe_converter::CalculatedNutritionalInfo;
+
+    #[tokio::test]
+    async fn test_parse_recipe_json_skips_the_llm() {
+        let client = MockChatClient::new(Vec::<String>::new());
+        let json = r#"{"recipe_title": "Toast", "ingredients": [], "instructions": ["Toast the bread."], "servings": 2}"#;
+        let parsed = parse_recipe(json, InputFormat::Json, GenParams::PARSER, JsonMode::Lenient, ParseLimits::default(), &PromptTemplates::default(), &client).await.unwrap();
+        assert_eq!(parsed.recipe_title, "Toast");
+        assert_eq!(parsed.servings, Some(2));
+        assert!(client.requests().is_empty());
+
+        assert!(parse_recipe("not json", InputFormat::Json, GenParams::PARSER, JsonMode::Lenient, ParseLimits::default(), &PromptTemplates::default(), &client).await.is_err());
+
+        let limits = ParseLimits { chunk_size: None, max_ingredients: 1 };
+        let two = r#"{"recipe_title": "Toast", "instructions": [], "ingredients": [
+            {"raw_text": "2 slices bread", "ingredient_name": "bread", "quantity": "2", "unit": "slices", "preparation_notes": ""},
+            {"raw_text": "10 g butter", "ingredient_name": "butter", "quantity": "10", "unit": "g", "preparation_notes": ""}]}"#;
+        let error = parse_recipe(two, InputFormat::Json, GenParams::PARSER, JsonMode::Lenient, limits, &PromptTemplates::default(), &client).await.unwrap_err();
+        assert!(error.to_string().contains("--max-ingredients 1"), "{}", error);
+        // Counting the lines under an ingredients heading only warns; the parsed list is what's capped.
+        let text = "Toast\nIngredients:\n2 slices bread\n10 g butter";
+        let client = MockChatClient::new([two]);
+        let error = parse_recipe(text, InputFormat::Text, GenParams::PARSER, JsonMode::Lenient, limits, &PromptTemplates::default(), &client).await.unwrap_err();
+        assert!(error.to_string().contains("--max-ingredients 1"), "{}", error);
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_profile_recipe_servings_override() {
+        let recipe = CleanedRecipe {
+            recipe_title: "Toast".to_string(),
+            ingredients: vec![],
+            instructions: vec![],
+            servings: Some(2),
+            total_time_minutes: None,
+        };
+        assert_eq!(profile_recipe(&recipe, None).servings, Some(2));
+        assert_eq!(profile_recipe(&recipe, Some(4)).servings, Some(4));
+    }
+
+    #[test]
+    fn test_scale_recipe() {
+        let ingredient = |name: &str, quantity: &str, grams: f32, fat: f32| CleanedIngredient {
+            raw_text: format!("{} {}", quantity, name),
+            original_quantity: quantity.to_string(),
+            original_unit: String::new(),
+            conversion_source: "Local".to_string(),
+            nutritional_info: Some(CalculatedNutritionalInfo { fat_g: Some(fat), ..Default::default() }),
+            ..cleaned_ingredient(name, grams)
+        };
+        let recipe = CleanedRecipe {
+            recipe_title: "Pastry".to_string(),
+            ingredients: vec![
+                ingredient("butter", "100", 100.0, 81.0),
+                ingredient("eggs", "1 1/2", 75.0, 7.5),
+                ingredient("lemons", "1-2", 100.0, 0.0),
+                ingredient("salt", "to taste", 1.0, 0.0),
+            ],
+            instructions: vec![],
+            servings: Some(4),
+            total_time_minutes: None,
+        };
+
+        let scaled = scale_recipe(&recipe, Scaling::Servings(6).factor(recipe.servings).unwrap());
+        let quantities: Vec<&str> = scaled.recipe.ingredients.iter().map(|i| i.original_quantity.as_str()).collect();
+        assert_eq!(quantities, vec!["150", "2.25", "1.5-3", "to taste"]);
+        assert_eq!(scaled.recipe.ingredients[0].quantity_grams, Some(150.0));
+        assert_eq!(scaled.recipe.ingredients[3].quantity_grams, Some(1.5));
+        assert_eq!(scaled.recipe.servings, Some(6));
+        assert_eq!(scaled.notes.len(), 1);
+        assert!(scaled.notes[0].contains("to taste"), "{:?}", scaled.notes);
+
+        let before = profile_recipe(&recipe, None);
+        let after = profile_recipe(&scaled.recipe, None);
+        assert_eq!(after.aggregated.fat_g, Some(132.75));
+        let close = |a: Option<f32>, b: Option<f32>| (a.unwrap() - b.unwrap()).abs() < 1e-3;
+        assert!(close(after.per_100g.fat_g, before.per_100g.fat_g));
+        assert!(close(after.per_serving.unwrap().fat_g, before.per_serving.unwrap().fat_g));
+
+        // 4 servings times 1.1 isn't whole: the servings are kept and grow.
+        let scaled = scale_recipe(&recipe, 1.1);
+        assert_eq!(scaled.recipe.servings, Some(4));
+        assert_eq!(scaled.notes.len(), 2);
+        assert!(Scaling::Servings(6).factor(None).is_err());
+    }
+
+    #[test]
+    fn test_combine_recipes_sections_by_source() {
+        let parse = |json: &str| serde_json::from_str::<ParsedRecipe>(json).unwrap();
+        let steak = parse(r#"{"recipe_title": "Steak", "servings": 2, "total_time_minutes": 20, "instructions": ["Sear."],
+            "ingredients": [{"raw_text": "400 g steak", "ingredient_name": "steak", "quantity": "400", "unit": "g", "preparation_notes": ""}]}"#);
+        let salad = parse(r#"{"recipe_title": "Salad ", "servings": 2, "total_time_minutes": 10, "instructions": ["Toss."],
+            "ingredients": [{"raw_text": "2 tbsp oil", "ingredient_name": "oil", "quantity": "2", "unit": "tbsp", "preparation_notes": "", "section": "dressing"}]}"#);
+
+        let meal = combine_recipes(&[steak.clone(), salad.clone()]);
+        assert_eq!(meal.recipe_title, "Steak + Salad");
+        let sections: Vec<Option<&str>> = meal.ingredients.iter().map(|i| i.section.as_deref()).collect();
+        assert_eq!(sections, vec![Some("Steak"), Some("Salad / dressing")]);
+        assert_eq!(meal.instructions, vec!["Steak: Sear.", "Salad: Toss."]);
+        assert_eq!(meal.servings, Some(2));
+        assert_eq!(meal.total_time_minutes, Some(30));
+
+        let big_salad = ParsedRecipe { servings: Some(4), total_time_minutes: None, ..salad };
+        let meal = combine_recipes(&[steak.clone(), big_salad]);
+        assert_eq!(meal.servings, None);
+        assert_eq!(meal.total_time_minutes, None);
+        let slow = ParsedRecipe { total_time_minutes: Some(u32::MAX), ..steak.clone() };
+        assert_eq!(combine_recipes(&[slow, steak]).total_time_minutes, Some(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_process_recipe_returns_suggestions_and_caches() -> Result<()> {
+        use crate::nutritional_matcher::test_support::{food_item, index_of};
+        use crate::nutritional_matcher::AutoAccept;
+        use crate::recipe_converter::FoodItem;
+
+        let butter = FoodItem { kcal_per_100g: Some(717.0), fat_g_per_100g: Some(81.0), ..food_item("Butter", 0) };
+        let embeddings = HashMap::from([("Butter".to_string(), vec![1.0]), ("butter".to_string(), vec![1.0])]);
+        let index = index_of(vec![butter], embeddings)?
+            .with_auto_accept(Some(AutoAccept { min_similarity: 0.9, min_margin: 0.0 }));
+        let dir = tempfile::tempdir()?;
+        let options = PipelineOptions {
+            input_format: InputFormat::Json,
+            optimization_targets: HashMap::from([(OptimizableNutrient::Fat, -50.0)]),
+            optimizer: OptimizerOptions { suggest_only: true, ..Default::default() },
+            model: Some("small-model".to_string()),
+            cache_path: Some(dir.path().join("butter.json")),
+            ..Default::default()
+        };
+        let recipe = r#"{"recipe_title": "Butter", "instructions": [],
+            "ingredients": [{"raw_text": "100 g butter", "ingredient_name": "butter", "quantity": "100", "unit": "g", "preparation_notes": ""}]}"#;
+        let client = MockChatClient::new([
+            r#"{"modifications": [{"operation": "adjust_quantity", "original_ingredient_name": "butter", "quantity_raw": "50", "unit_raw": "g"}], "overall_reasoning": "Use less."}"#,
+            r#"{"modifications": [{"operation": "no_change"}], "overall_reasoning": "Nothing else."}"#,
+        ]);
+
+        let processed = process_recipe(recipe, &options, &index, &client, |_| {}).await?;
+        assert_eq!(processed.suggestions.len(), 1);
+        assert_eq!(processed.output.nutritional_profile.aggregated.fat_g, Some(81.0));
+        assert_eq!(client.requests().len(), 2);
+        assert!(client.requests().iter().all(|request| request.model == "small-model"));
+        let cached = load_cached_recipe(options.cache_path.as_deref().unwrap())?.unwrap();
+        assert_eq!(cached.0.ingredients[0].quantity_grams, Some(100.0));
+        Ok(())
+    }
+}
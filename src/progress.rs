@@ -0,0 +1,306 @@
+//! Structured progress events emitted by the pipeline through the `progress_updater` callbacks.
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+/// Pipeline stage whose items are counted by `ProgressEvent::Step`.
+#[derive(Debug, Clone, Copy, serde::Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStage {
+    Conversion,
+    Matching,
+    Optimization,
+}
+
+impl ProgressStage {
+    fn verb(self) -> &'static str {
+        match self {
+            ProgressStage::Conversion => "Converting",
+            ProgressStage::Matching => "Matching",
+            ProgressStage::Optimization => "Optimizing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Free-form status line.
+    Message { text: String },
+    /// Something went wrong with one item or step, without stopping the run.
+    Warning { text: String },
+    /// Start of item `index` (1-based) of `total` in a stage with a known size.
+    Step {
+        stage: ProgressStage,
+        index: u32,
+        total: u32,
+        label: String,
+    },
+    IngredientConverted {
+        ingredient: String,
+        grams: Option<f32>,
+        notes: String,
+    },
+    MatchFound {
+        ingredient: String,
+        ciqual_name: String,
+    },
+    MatchNotFound { ingredient: String },
+    IterationComplete {
+        iteration: u32,
+        candidate_mse: f32,
+        best_mse: f32,
+        improved: bool,
+    },
+}
+
+impl ProgressEvent {
+    /// Serializes the event as a single JSON line (NDJSON).
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| {
+            format!("{{\"event\":\"message\",\"text\":\"Failed to serialize progress event: {}\"}}", e)
+        })
+    }
+}
+
+// Written by hand so `Step` and `Warning` events, which used to be plain messages, still reach the
+// `--progress-format json` stream as `message` events with their text, their own fields added.
+impl Serialize for ProgressEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            ProgressEvent::Message { text } => {
+                map.serialize_entry("event", "message")?;
+                map.serialize_entry("text", text)?;
+            }
+            ProgressEvent::Warning { text } => {
+                map.serialize_entry("event", "message")?;
+                map.serialize_entry("text", text)?;
+                map.serialize_entry("level", "warning")?;
+            }
+            ProgressEvent::Step { stage, index, total, label } => {
+                map.serialize_entry("event", "message")?;
+                map.serialize_entry("text", &self.to_string())?;
+                map.serialize_entry("stage", stage)?;
+                map.serialize_entry("index", index)?;
+                map.serialize_entry("total", total)?;
+                map.serialize_entry("label", label)?;
+            }
+            ProgressEvent::IngredientConverted { ingredient, grams, notes } => {
+                map.serialize_entry("event", "ingredient_converted")?;
+                map.serialize_entry("ingredient", ingredient)?;
+                map.serialize_entry("grams", grams)?;
+                map.serialize_entry("notes", notes)?;
+            }
+            ProgressEvent::MatchFound { ingredient, ciqual_name } => {
+                map.serialize_entry("event", "match_found")?;
+                map.serialize_entry("ingredient", ingredient)?;
+                map.serialize_entry("ciqual_name", ciqual_name)?;
+            }
+            ProgressEvent::MatchNotFound { ingredient } => {
+                map.serialize_entry("event", "match_not_found")?;
+                map.serialize_entry("ingredient", ingredient)?;
+            }
+            ProgressEvent::IterationComplete { iteration, candidate_mse, best_mse, improved } => {
+                map.serialize_entry("event", "iteration_complete")?;
+                map.serialize_entry("iteration", iteration)?;
+                map.serialize_entry("candidate_mse", candidate_mse)?;
+                map.serialize_entry("best_mse", best_mse)?;
+                map.serialize_entry("improved", improved)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl From<String> for ProgressEvent {
+    fn from(text: String) -> Self {
+        ProgressEvent::Message { text }
+    }
+}
+
+impl From<&str> for ProgressEvent {
+    fn from(text: &str) -> Self {
+        ProgressEvent::Message { text: text.to_string() }
+    }
+}
+
+// Text rendering used by the CLI; matches the historical free-form messages.
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::Message { text } | ProgressEvent::Warning { text } => write!(f, "{}", text),
+            ProgressEvent::Step { stage, index, total, label } => match stage {
+                ProgressStage::Conversion => write!(f, "Converting ingredient {}/{}: {}...", index, total, label),
+                ProgressStage::Matching => write!(f, "   -> Matching ingredient: '{}'", label),
+                ProgressStage::Optimization => write!(f, "\n--- Optimization Iteration {}/{} ---", index, total),
+            },
+            ProgressEvent::IngredientConverted { grams, notes, .. } => {
+                write!(f, " -> Converted: {:?} grams. Notes: {}", grams, notes)
+            }
+            ProgressEvent::MatchFound { ingredient, ciqual_name } => {
+                write!(f, "   -> Matched '{}' to Ciqual item: '{}'", ingredient, ciqual_name)
+            }
+            ProgressEvent::MatchNotFound { ingredient } => {
+                write!(f, "   -> No definitive match found for '{}' after LLM disambiguation.", ingredient)
+            }
+            ProgressEvent::IterationComplete { candidate_mse, best_mse, improved, .. } => {
+                if *improved {
+                    write!(f, "Found improved recipe. New MSE: {:.4} (was {:.4})", candidate_mse, best_mse)
+                } else {
+                    write!(f, "Candidate recipe did not improve MSE (Candidate: {:.4}, Best: {:.4}). Retaining previous best.", candidate_mse, best_mse)
+                }
+            }
+        }
+    }
+}
+
+const BAR_WIDTH: usize = 20;
+const DEFAULT_LINE_WIDTH: usize = 80;
+
+#[derive(Default)]
+struct BarState {
+    step: Option<(ProgressStage, u32, u32)>,
+    message: String,
+    drawn: bool,
+}
+
+static PROGRESS_BAR: OnceLock<ProgressBar> = OnceLock::new();
+
+/// Installs the process-wide progress bar and has the logging macros erase it before printing.
+pub fn install_progress_bar() -> &'static ProgressBar {
+    PROGRESS_BAR.get_or_init(|| {
+        crate::logging::set_before_print(clear_progress_bar);
+        ProgressBar::new()
+    })
+}
+
+/// Erases the installed progress bar, if it is on screen, so a regular line can be printed.
+/// The next event draws it again.
+pub fn clear_progress_bar() {
+    if let Some(bar) = PROGRESS_BAR.get() {
+        bar.clear();
+    }
+}
+
+/// One-line progress bar on stderr in place of the text progress lines. `Step` events move the
+/// bar; `Warning` events are printed above it and every other event becomes its status message.
+pub struct ProgressBar {
+    state: Mutex<BarState>,
+    line_width: usize,
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressBar {
+    /// A bar as wide as `$COLUMNS`, or 80 columns.
+    pub fn new() -> Self {
+        let line_width = std::env::var("COLUMNS").ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(DEFAULT_LINE_WIDTH);
+        ProgressBar { state: Mutex::new(BarState::default()), line_width }
+    }
+
+    pub fn update(&self, event: &ProgressEvent) {
+        let line = {
+            let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match event {
+                ProgressEvent::Step { stage, index, total, label } => {
+                    state.step = Some((*stage, *index, *total));
+                    state.message = label.clone();
+                }
+                ProgressEvent::Warning { text } => {
+                    eprintln!("\r\x1b[2K{}", text.trim());
+                }
+                other => state.message = other.to_string().split_whitespace().collect::<Vec<_>>().join(" "),
+            }
+            state.drawn = true;
+            self.render(&state)
+        };
+        eprint!("\r\x1b[2K{}\r", line);
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Erases the bar's line, leaving the cursor at its start.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if std::mem::take(&mut state.drawn) {
+            eprint!("\r\x1b[2K");
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    fn render(&self, state: &BarState) -> String {
+        let line = match state.step {
+            Some((stage, index, total)) => {
+                let filled = (index.min(total) as usize * BAR_WIDTH).checked_div(total as usize).unwrap_or(0);
+                format!(
+                    "{:<10} [{}{}] {}/{} {}",
+                    stage.verb(), "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), index, total, state.message
+                )
+            }
+            None => state.message.clone(),
+        };
+        // One column short of the width, so the line never wraps.
+        line.chars().take(self.line_width.saturating_sub(1)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_event_json_line() {
+        let event = ProgressEvent::MatchFound {
+            ingredient: "egg".to_string(),
+            ciqual_name: "Egg, raw".to_string(),
+        };
+        assert_eq!(
+            event.to_json_line(),
+            r#"{"event":"match_found","ingredient":"egg","ciqual_name":"Egg, raw"}"#
+        );
+        let message: ProgressEvent = "hello".into();
+        assert_eq!(message.to_json_line(), r#"{"event":"message","text":"hello"}"#);
+        let warning = ProgressEvent::Warning { text: "[WARNING] careful".to_string() };
+        assert_eq!(warning.to_json_line(), r#"{"event":"message","text":"[WARNING] careful","level":"warning"}"#);
+        let iteration = ProgressEvent::IterationComplete { iteration: 2, candidate_mse: 0.5, best_mse: 1.0, improved: true };
+        assert_eq!(
+            iteration.to_json_line(),
+            r#"{"event":"iteration_complete","iteration":2,"candidate_mse":0.5,"best_mse":1.0,"improved":true}"#
+        );
+    }
+
+    #[test]
+    fn test_step_text_matches_the_historical_lines() {
+        let step = |stage, index, label: &str| ProgressEvent::Step { stage, index, total: 4, label: label.to_string() };
+        assert_eq!(step(ProgressStage::Conversion, 2, "2 tbsp butter").to_string(), "Converting ingredient 2/4: 2 tbsp butter...");
+        assert_eq!(step(ProgressStage::Matching, 1, "butter").to_string(), "   -> Matching ingredient: 'butter'");
+        assert_eq!(step(ProgressStage::Optimization, 3, "").to_string(), "\n--- Optimization Iteration 3/4 ---");
+        // Steps used to be plain messages, so JSON consumers still get them as such.
+        assert_eq!(
+            step(ProgressStage::Matching, 1, "butter").to_json_line(),
+            r#"{"event":"message","text":"   -> Matching ingredient: 'butter'","stage":"matching","index":1,"total":4,"label":"butter"}"#
+        );
+    }
+
+    #[test]
+    fn test_progress_bar_line() {
+        let bar = ProgressBar { state: Mutex::new(BarState::default()), line_width: 60 };
+        let mut state = BarState { step: Some((ProgressStage::Conversion, 1, 4)), message: "100 g butter".to_string(), drawn: false };
+        assert_eq!(bar.render(&state), "Converting [#####---------------] 1/4 100 g butter");
+
+        state.step = Some((ProgressStage::Optimization, 10, 10));
+        state.message = "Candidate MSE: 0.1234, a status message far too long for the line".to_string();
+        let line = bar.render(&state);
+        assert!(line.starts_with("Optimizing [####################] 10/10 Candidate MSE"));
+        assert_eq!(line.chars().count(), 59);
+
+        state.step = Some((ProgressStage::Matching, 0, 0));
+        assert!(bar.render(&state).starts_with("Matching   [--------------------] 0/0"));
+    }
+}
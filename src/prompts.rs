@@ -0,0 +1,216 @@
+//! Prompt templates of the LLM stages. Each has a built-in default that a file of `--prompt-dir`
+//! can replace; templates name their values with `{placeholder}`s, filled in by the stage.
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::nutritional_matcher::{BATCH_DISAMBIGUATION_USER_PROMPT, DISAMBIGUATION_GUIDELINES, DISAMBIGUATION_USER_PROMPT};
+use crate::optim::optimizer::{OPTIMIZER_SYSTEM_PROMPT, OPTIMIZER_USER_PROMPT};
+use crate::recipe_converter::{CONVERSION_SYSTEM_PROMPT, CONVERSION_USER_PROMPT};
+use crate::recipe_parser::PARSER_SYSTEM_PROMPT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PromptKind {
+    ParserSystem,
+    ConverterSystem,
+    ConverterUser,
+    /// Matching guidelines opening the system prompt of both single and batched disambiguation;
+    /// the answer format that follows them is tied to the schema and stays built in.
+    MatcherGuidelines,
+    MatcherUser,
+    /// User prompt of batched disambiguation, the default matching path; the per-ingredient
+    /// blocks it lists stay built in.
+    MatcherBatchUser,
+    OptimizerSystem,
+    OptimizerUser,
+}
+
+impl PromptKind {
+    pub const ALL: [PromptKind; 8] = [
+        PromptKind::ParserSystem,
+        PromptKind::ConverterSystem,
+        PromptKind::ConverterUser,
+        PromptKind::MatcherGuidelines,
+        PromptKind::MatcherUser,
+        PromptKind::MatcherBatchUser,
+        PromptKind::OptimizerSystem,
+        PromptKind::OptimizerUser,
+    ];
+
+    /// Name of the template's file in the prompt directory.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            PromptKind::ParserSystem => "parser_system.txt",
+            PromptKind::ConverterSystem => "converter_system.txt",
+            PromptKind::ConverterUser => "converter_user.txt",
+            PromptKind::MatcherGuidelines => "matcher_guidelines.txt",
+            PromptKind::MatcherUser => "matcher_user.txt",
+            PromptKind::MatcherBatchUser => "matcher_batch_user.txt",
+            PromptKind::OptimizerSystem => "optimizer_system.txt",
+            PromptKind::OptimizerUser => "optimizer_user.txt",
+        }
+    }
+
+    /// The placeholders the stage fills in.
+    pub fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            PromptKind::ParserSystem | PromptKind::ConverterSystem | PromptKind::MatcherGuidelines => &[],
+            PromptKind::ConverterUser => &["ingredient_name", "quantity", "unit", "preparation_notes"],
+            PromptKind::MatcherUser => &["ingredient_name", "preparation_notes", "candidates", "candidate_count"],
+            PromptKind::MatcherBatchUser => &["ingredients", "count"],
+            PromptKind::OptimizerSystem => &["mse"],
+            PromptKind::OptimizerUser => &[
+                "recipe_title", "ingredients",
+                "kcal", "protein_g", "carbohydrate_g", "fat_g", "sugars_g", "fa_saturated_g", "salt_g", "fiber_g",
+                "cholesterol_mg", "target_kcal", "target_protein_g", "target_carbohydrate_g", "target_fat_g",
+                // The `--optimizable` instruction, empty when every ingredient may change.
+                "optimizable",
+            ],
+        }
+    }
+
+    pub fn default_template(self) -> &'static str {
+        match self {
+            PromptKind::ParserSystem => PARSER_SYSTEM_PROMPT,
+            PromptKind::ConverterSystem => CONVERSION_SYSTEM_PROMPT,
+            PromptKind::ConverterUser => CONVERSION_USER_PROMPT,
+            PromptKind::MatcherGuidelines => DISAMBIGUATION_GUIDELINES,
+            PromptKind::MatcherUser => DISAMBIGUATION_USER_PROMPT,
+            PromptKind::MatcherBatchUser => BATCH_DISAMBIGUATION_USER_PROMPT,
+            PromptKind::OptimizerSystem => OPTIMIZER_SYSTEM_PROMPT,
+            PromptKind::OptimizerUser => OPTIMIZER_USER_PROMPT,
+        }
+    }
+}
+
+/// `{name}` placeholders of `template`. Braces around anything but an identifier, such as the
+/// JSON examples of the prompts, are not placeholders.
+fn placeholders_in(template: &str) -> Vec<&str> {
+    template.match_indices('{')
+        .filter_map(|(start, _)| {
+            let rest = &template[start + 1..];
+            let name = &rest[..rest.find('}')?];
+            let is_identifier = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            is_identifier.then_some(name)
+        })
+        .collect()
+}
+
+/// Replaces every `{name}` of `template` with its value, in one pass so values are never
+/// themselves filled; other braces are left as they are.
+pub fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = after.find('}').and_then(|end| {
+            let (_, value) = values.iter().find(|(name, _)| *name == &after[..end])?;
+            Some((*value, end))
+        });
+        match placeholder {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// The templates in use: the built-in ones, with those found in a prompt directory replacing them.
+/// The stages get them through their options, as they get their `JsonMode`.
+#[derive(Debug, Clone, Default)]
+pub struct PromptTemplates {
+    overrides: HashMap<PromptKind, String>,
+}
+
+impl PromptTemplates {
+    /// Reads the templates of `dir`, named after `PromptKind::file_name`. Missing files keep the
+    /// built-in template; a placeholder the stage doesn't fill is an error.
+    pub fn load(dir: &Path) -> Result<Self> {
+        if !dir.is_dir() {
+            return Err(anyhow!("Prompt directory {:?} does not exist", dir));
+        }
+        let mut templates = PromptTemplates::default();
+        for kind in PromptKind::ALL {
+            let path = dir.join(kind.file_name());
+            if !path.exists() {
+                continue;
+            }
+            let template = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read prompt template {:?}", path))?;
+            templates = templates.with_template(kind, template)
+                .with_context(|| format!("Invalid prompt template {:?}", path))?;
+        }
+        Ok(templates)
+    }
+
+    /// Replaces the template of `kind`, checking its placeholders.
+    pub fn with_template(mut self, kind: PromptKind, template: String) -> Result<Self> {
+        if let Some(unknown) = placeholders_in(&template).into_iter().find(|name| !kind.placeholders().contains(name)) {
+            return Err(anyhow!(
+                "unknown placeholder {{{}}}; the available ones are: {}",
+                unknown,
+                kind.placeholders().iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", ")
+            ));
+        }
+        self.overrides.insert(kind, template);
+        Ok(self)
+    }
+
+    /// The stages whose template was replaced, in pipeline order.
+    pub fn overridden(&self) -> Vec<PromptKind> {
+        PromptKind::ALL.into_iter().filter(|kind| self.is_overridden(*kind)).collect()
+    }
+
+    pub fn is_overridden(&self, kind: PromptKind) -> bool {
+        self.overrides.contains_key(&kind)
+    }
+
+    pub fn template(&self, kind: PromptKind) -> &str {
+        self.overrides.get(&kind).map_or(kind.default_template(), String::as_str)
+    }
+
+    pub fn render(&self, kind: PromptKind, values: &[(&str, &str)]) -> String {
+        fill(self.template(kind), values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_templates_only_use_their_placeholders() {
+        for kind in PromptKind::ALL {
+            let mut used = placeholders_in(kind.default_template());
+            used.sort_unstable();
+            used.dedup();
+            let mut expected = kind.placeholders().to_vec();
+            expected.sort_unstable();
+            assert_eq!(used, expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_load_prompt_dir_overrides_and_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("converter_user.txt"), "How many grams is {quantity} {unit} of {ingredient_name}? {\"grams\": 1}").unwrap();
+        let templates = PromptTemplates::load(dir.path()).unwrap();
+        assert_eq!(templates.overridden(), vec![PromptKind::ConverterUser]);
+        let prompt = templates.render(PromptKind::ConverterUser, &[("ingredient_name", "rice"), ("quantity", "1"), ("unit", "cup")]);
+        assert_eq!(prompt, "How many grams is 1 cup of rice? {\"grams\": 1}");
+        assert_eq!(templates.template(PromptKind::ParserSystem), PromptKind::ParserSystem.default_template());
+
+        std::fs::write(dir.path().join("optimizer_system.txt"), "MSE: {error}").unwrap();
+        let error = format!("{:#}", PromptTemplates::load(dir.path()).unwrap_err());
+        assert!(error.contains("optimizer_system.txt") && error.contains("{error}") && error.contains("{mse}"), "{}", error);
+        assert!(PromptTemplates::load(&dir.path().join("missing")).is_err());
+    }
+}
@@ -1,6 +1,87 @@
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
-use crate::recipe_converter::{CleanedRecipe, CleanedIngredient, CalculatedNutritionalInfo};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ops::{Add, AddAssign, Mul};
+use std::path::Path;
+use crate::recipe_converter::{CalculatedNutritionalInfo, CleanedRecipe, CleanedIngredient};
+use crate::recipe_parser::normalize_ingredient_name;
+use crate::search::data_loader::CIQUAL_COLUMNS;
 
+/// Milligrams of sodium in a gram of salt: sodium is about 40% of salt by mass.
+pub const SODIUM_MG_PER_SALT_G: f32 = 400.0;
+
+/// Sodium in `salt_g` grams of salt.
+fn sodium_mg(salt_g: Option<f32>) -> Option<f32> {
+    salt_g.map(|salt_g| salt_g * SODIUM_MG_PER_SALT_G)
+}
+
+/// Atwater general factors, in kcal per gram.
+pub const ATWATER_PROTEIN_KCAL_PER_G: f32 = 4.0;
+pub const ATWATER_CARBOHYDRATE_KCAL_PER_G: f32 = 4.0;
+pub const ATWATER_FAT_KCAL_PER_G: f32 = 9.0;
+
+/// Default largest gap, in percent, between the database kcal and the Atwater estimate before
+/// `check_kcal` warns.
+pub const DEFAULT_KCAL_TOLERANCE_PCT: f32 = 15.0;
+
+/// Kcal estimated from the macronutrients with the Atwater factors. Missing macros count as 0;
+/// `None` when all three are missing.
+pub fn atwater_kcal(protein_g: Option<f32>, carbohydrate_g: Option<f32>, fat_g: Option<f32>) -> Option<f32> {
+    if protein_g.is_none() && carbohydrate_g.is_none() && fat_g.is_none() {
+        return None;
+    }
+    Some(
+        protein_g.unwrap_or(0.0) * ATWATER_PROTEIN_KCAL_PER_G
+            + carbohydrate_g.unwrap_or(0.0) * ATWATER_CARBOHYDRATE_KCAL_PER_G
+            + fat_g.unwrap_or(0.0) * ATWATER_FAT_KCAL_PER_G,
+    )
+}
+
+/// Database kcal that disagree with the Atwater estimate from the same macronutrients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KcalDiscrepancy {
+    pub reported_kcal: f32,
+    pub atwater_kcal: f32,
+}
+
+impl KcalDiscrepancy {
+    /// The gap in percent of the larger of the two values.
+    pub fn difference_pct(&self) -> f32 {
+        let larger = self.reported_kcal.abs().max(self.atwater_kcal.abs());
+        if larger > 0.0 { (self.reported_kcal - self.atwater_kcal).abs() / larger * 100.0 } else { 0.0 }
+    }
+}
+
+impl std::fmt::Display for KcalDiscrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{:.0} kcal reported vs {:.0} kcal from the macros ({:.0}% apart)",
+            self.reported_kcal, self.atwater_kcal, self.difference_pct()
+        )
+    }
+}
+
+/// Share of the kcal, in percent, coming from each macronutrient by its Atwater factor. A share is
+/// `None` when its macro is unknown; the rest of the kcal come from fiber, alcohol and rounding.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct EnergyBreakdown {
+    pub protein_pct: Option<f32>,
+    pub carbohydrate_pct: Option<f32>,
+    pub fat_pct: Option<f32>,
+}
+
+impl std::fmt::Display for EnergyBreakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pct = |share: Option<f32>| share.map_or("n/a".to_string(), |share| format!("{:.0}%", share));
+        write!(
+            f, "protein {}, carbohydrate {}, fat {}",
+            pct(self.protein_pct), pct(self.carbohydrate_pct), pct(self.fat_pct)
+        )
+    }
+}
+
+/// Serialized with a derived `sodium_mg` next to `salt_g`; see `NutritionalSummary::sodium_mg`.
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct NutritionalSummary { // Renamed for clarity, represents absolute values
     pub kcal: Option<f32>,
@@ -10,8 +91,150 @@ pub struct NutritionalSummary { // Renamed for clarity, represents absolute valu
     pub fat_g: Option<f32>,
     pub sugars_g: Option<f32>,
     pub fa_saturated_g: Option<f32>,
+    #[serde(flatten, with = "salt_with_sodium")]
     pub salt_g: Option<f32>,
-    // Add other fields if CiqualFoodItem/CalculatedNutritionalInfo has more
+    pub fiber_g: Option<f32>,
+    pub cholesterol_mg: Option<f32>,
+    // Add other fields if FoodItem/CalculatedNutritionalInfo has more
+}
+
+impl NutritionalSummary {
+    /// Sodium derived from `salt_g`, so the two never disagree.
+    pub fn sodium_mg(&self) -> Option<f32> {
+        sodium_mg(self.salt_g)
+    }
+
+    /// Kcal estimated from the macronutrients; see `atwater_kcal`.
+    pub fn atwater_kcal(&self) -> Option<f32> {
+        atwater_kcal(self.protein_g, self.carbohydrate_g, self.fat_g)
+    }
+
+    /// The kcal and the Atwater estimate when both are known and more than `tolerance_pct` apart.
+    pub fn kcal_discrepancy(&self, tolerance_pct: f32) -> Option<KcalDiscrepancy> {
+        let discrepancy = KcalDiscrepancy { reported_kcal: self.kcal?, atwater_kcal: self.atwater_kcal()? };
+        (discrepancy.difference_pct() > tolerance_pct).then_some(discrepancy)
+    }
+
+    /// Energy shares of the macros, out of `kcal` or, when that is unknown, out of the Atwater
+    /// estimate. `None` without any kcal to divide.
+    pub fn energy_breakdown(&self) -> Option<EnergyBreakdown> {
+        let total_kcal = self.kcal.or_else(|| self.atwater_kcal()).filter(|kcal| *kcal > 0.0)?;
+        let share = |grams: Option<f32>, kcal_per_g: f32| grams.map(|grams| grams * kcal_per_g / total_kcal * 100.0);
+        Some(EnergyBreakdown {
+            protein_pct: share(self.protein_g, ATWATER_PROTEIN_KCAL_PER_G),
+            carbohydrate_pct: share(self.carbohydrate_g, ATWATER_CARBOHYDRATE_KCAL_PER_G),
+            fat_pct: share(self.fat_g, ATWATER_FAT_KCAL_PER_G),
+        })
+    }
+
+    /// `self - other`, field by field. A field is known only when it is known on both sides.
+    pub fn diff(&self, other: &NutritionalSummary) -> NutritionalSummary {
+        self.zip_with(other, |a, b| Some(a? - b?))
+    }
+
+    /// `self` with `part` taken out of every field known on both sides; other fields are kept as they are.
+    fn without(&self, part: &NutritionalSummary) -> NutritionalSummary {
+        self.zip_with(part, |total, part| match (total, part) {
+            (Some(total), Some(part)) => Some(total - part),
+            (total, _) => total,
+        })
+    }
+
+    /// Combines every field of `self` with the same field of `other`.
+    fn zip_with(&self, other: &NutritionalSummary, f: impl Fn(Option<f32>, Option<f32>) -> Option<f32>) -> NutritionalSummary {
+        NutritionalSummary {
+            kcal: f(self.kcal, other.kcal),
+            water_g: f(self.water_g, other.water_g),
+            protein_g: f(self.protein_g, other.protein_g),
+            carbohydrate_g: f(self.carbohydrate_g, other.carbohydrate_g),
+            fat_g: f(self.fat_g, other.fat_g),
+            sugars_g: f(self.sugars_g, other.sugars_g),
+            fa_saturated_g: f(self.fa_saturated_g, other.fa_saturated_g),
+            salt_g: f(self.salt_g, other.salt_g),
+            fiber_g: f(self.fiber_g, other.fiber_g),
+            cholesterol_mg: f(self.cholesterol_mg, other.cholesterol_mg),
+        }
+    }
+}
+
+/// Sums field by field. A field known on one side only keeps that value, as when aggregating
+/// ingredients where some lack a nutrient; it stays `None` only when unknown on both.
+impl Add for NutritionalSummary {
+    type Output = NutritionalSummary;
+
+    fn add(self, other: NutritionalSummary) -> NutritionalSummary {
+        self.zip_with(&other, |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        })
+    }
+}
+
+impl AddAssign for NutritionalSummary {
+    fn add_assign(&mut self, other: NutritionalSummary) {
+        *self = std::mem::take(self) + other;
+    }
+}
+
+/// Scales every known field, e.g. from a recipe's totals to its values per 100 g.
+impl Mul<f32> for &NutritionalSummary {
+    type Output = NutritionalSummary;
+
+    fn mul(self, factor: f32) -> NutritionalSummary {
+        self.zip_with(&NutritionalSummary::default(), |value, _| value.map(|value| value * factor))
+    }
+}
+
+impl Mul<f32> for NutritionalSummary {
+    type Output = NutritionalSummary;
+
+    fn mul(self, factor: f32) -> NutritionalSummary {
+        &self * factor
+    }
+}
+
+/// The nutrient values of one matched ingredient, without its source name.
+impl From<&CalculatedNutritionalInfo> for NutritionalSummary {
+    fn from(info: &CalculatedNutritionalInfo) -> Self {
+        NutritionalSummary {
+            kcal: info.kcal,
+            water_g: info.water_g,
+            protein_g: info.protein_g,
+            carbohydrate_g: info.carbohydrate_g,
+            fat_g: info.fat_g,
+            sugars_g: info.sugars_g,
+            fa_saturated_g: info.fa_saturated_g,
+            salt_g: info.salt_g,
+            fiber_g: info.fiber_g,
+            cholesterol_mg: info.cholesterol_mg,
+        }
+    }
+}
+
+/// `salt_g` followed by the `sodium_mg` derived from it; reading ignores `sodium_mg`, so a stale
+/// value in a file can't contradict the salt.
+mod salt_with_sodium {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct SaltWithSodium {
+        salt_g: Option<f32>,
+        sodium_mg: Option<f32>,
+    }
+
+    #[derive(Deserialize)]
+    struct Salt {
+        #[serde(default)]
+        salt_g: Option<f32>,
+    }
+
+    pub fn serialize<S: Serializer>(salt_g: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+        SaltWithSodium { salt_g: *salt_g, sodium_mg: super::sodium_mg(*salt_g) }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+        Ok(Salt::deserialize(deserializer)?.salt_g)
+    }
 }
 
 // This struct will hold both aggregated and per 100g normalized values
@@ -20,68 +243,1052 @@ pub struct RecipeNutritionalProfile {
     pub total_calculated_mass_g: Option<f32>,
     pub aggregated: NutritionalSummary,
     pub per_100g: NutritionalSummary, // Same fields, but values normalized per 100g
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub servings: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_serving: Option<NutritionalSummary>, // Aggregated values divided by `servings`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy_breakdown: Option<EnergyBreakdown>, // Same for the whole recipe, per 100g and per serving
 }
 
+impl RecipeNutritionalProfile {
+    /// Recomputes the per-serving view from the already aggregated values.
+    /// This is cheap and never touches the ingredients, so it can be applied to a cached profile.
+    pub fn apply_servings(&mut self, servings: u32) {
+        self.servings = Some(servings);
+        self.per_serving = Some(calculate_per_serving(&self.aggregated, servings));
+    }
+
+    /// Updates the profile in O(1) for a single ingredient going from `old` to `new`; `None` stands
+    /// for an ingredient that is added or removed. Equivalent to `calculate_nutritional_profile` on
+    /// the changed recipe, except that a value whose last contributor is removed stays at 0 instead
+    /// of becoming unknown; a full recompute settles that.
+    pub fn apply_ingredient_change(&mut self, old: Option<&CleanedIngredient>, new: Option<&CleanedIngredient>) {
+        let mut total_mass_g = self.total_calculated_mass_g.unwrap_or(0.0);
+        if let Some((grams, nutrition)) = old.and_then(nutrition_contribution) {
+            total_mass_g -= grams;
+            self.aggregated = self.aggregated.without(&nutrition);
+        }
+        if let Some((grams, nutrition)) = new.and_then(nutrition_contribution) {
+            total_mass_g += grams;
+            self.aggregated += nutrition;
+        }
+
+        // Rounding can leave a sliver of mass behind once every contributor is gone.
+        if total_mass_g > MASS_EPSILON_G {
+            self.total_calculated_mass_g = Some(total_mass_g);
+            self.per_100g = &self.aggregated * (100.0 / total_mass_g);
+        } else {
+            self.total_calculated_mass_g = None;
+            self.aggregated = NutritionalSummary::default();
+            self.per_100g = NutritionalSummary::default();
+        }
+        if let Some(servings) = self.servings {
+            self.apply_servings(servings);
+        }
+        self.refresh_energy_breakdown();
+    }
+
+    /// Recomputes the energy shares from the aggregated values, e.g. for a profile cached before they existed.
+    pub fn refresh_energy_breakdown(&mut self) {
+        self.energy_breakdown = self.aggregated.energy_breakdown();
+    }
+
+    /// Replaces the database kcal with the Atwater estimate wherever the macros are known, so the
+    /// kcal always agree with the macronutrients.
+    pub fn reconcile_kcal(&mut self) {
+        for summary in [&mut self.aggregated, &mut self.per_100g].into_iter().chain(self.per_serving.as_mut()) {
+            if let Some(kcal) = summary.atwater_kcal() {
+                summary.kcal = Some(kcal);
+            }
+        }
+        self.refresh_energy_breakdown();
+    }
+
+    /// `reconcile_kcal` when the aggregated kcal and Atwater estimate are more than `tolerance_pct`
+    /// apart; returns whether it did.
+    pub fn reconcile_kcal_beyond(&mut self, tolerance_pct: f32) -> bool {
+        let reconcile = self.aggregated.kcal_discrepancy(tolerance_pct).is_some();
+        if reconcile {
+            self.reconcile_kcal();
+        }
+        reconcile
+    }
+
+    /// False when no ingredient contributed any mass, so every per-100g value is missing.
+    pub fn has_nutrition(&self) -> bool {
+        self.total_calculated_mass_g.is_some_and(|mass| mass > 0.0)
+    }
+}
+
+/// The recipe's kcal discrepancy, if any, with every matched ingredient whose own values are
+/// inconsistent: those are the likely bad matches.
+pub fn check_kcal<'a>(
+    recipe: &'a CleanedRecipe,
+    profile: &RecipeNutritionalProfile,
+    tolerance_pct: f32,
+) -> Option<(KcalDiscrepancy, Vec<(&'a CleanedIngredient, KcalDiscrepancy)>)> {
+    let discrepancy = profile.aggregated.kcal_discrepancy(tolerance_pct)?;
+    let suspects = recipe.ingredients.iter()
+        .filter_map(|ingredient| {
+            let nutrition = NutritionalSummary::from(ingredient.nutritional_info.as_ref()?);
+            Some((ingredient, nutrition.kcal_discrepancy(tolerance_pct)?))
+        })
+        .collect();
+    Some((discrepancy, suspects))
+}
+
+/// Errors when the profile has no nutrition at all, explaining why from the recipe's ingredients.
+/// Targets and MSE computed from such a profile are meaningless, since every value is missing.
+pub fn ensure_nutrition_computed(recipe: &CleanedRecipe, profile: &RecipeNutritionalProfile) -> Result<()> {
+    if profile.has_nutrition() {
+        return Ok(());
+    }
+    let without_grams = recipe.ingredients.iter().filter(|ing| !ing.quantity_grams.is_some_and(|grams| grams > 0.0)).count();
+    let without_match = recipe.ingredients.iter().filter(|ing| ing.nutritional_info.is_none()).count();
+    Err(anyhow!(
+        "No nutrition could be computed for '{}': none of its {} ingredients has both a gram quantity and a nutrition match \
+         ({} without grams, {} without a match)",
+        recipe.recipe_title, recipe.ingredients.len(), without_grams, without_match
+    ))
+}
+
+
+/// Order of the ingredients in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum IngredientOrder {
+    /// As parsed from the recipe.
+    #[default]
+    Original,
+    /// Heaviest first, as ingredient lists on food labels are; ingredients without grams go last.
+    ByMass,
+    /// Alphabetical by ingredient name, ignoring case.
+    Alpha,
+}
+
+/// Stable sort of `ingredients` in `order`: ties keep their recipe order.
+pub fn sort_ingredients(ingredients: &mut [CleanedIngredient], order: IngredientOrder) {
+    match order {
+        IngredientOrder::Original => {}
+        IngredientOrder::ByMass => ingredients.sort_by(|a, b| match (a.quantity_grams, b.quantity_grams) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        }),
+        IngredientOrder::Alpha => ingredients.sort_by_cached_key(|ingredient| ingredient.ingredient_name.trim().to_lowercase()),
+    }
+}
+
+/// Version of the `EnrichedRecipeOutput` file format written by this build.
+/// 1: files from before versioning (no `schema_version` field). 2: adds `schema_version`.
+pub const ENRICHED_SCHEMA_VERSION: u32 = 2;
+
+/// Field of a saved enriched file holding the checksum of the rest of its content.
+const CHECKSUM_FIELD: &str = "checksum";
+
+/// FNV-1a hash of `bytes` as `fnv1a64:<hex>`. Stable across builds and platforms, unlike `std`'s hashers.
+fn checksum(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("fnv1a64:{:016x}", hash)
+}
+
+/// Writes `contents` to a temporary file next to `path`, flushed to disk, then renames it into
+/// place, so an interruption leaves either the old file or the new one, never a truncated one.
+pub fn write_file_atomically(path: &Path, contents: &str) -> Result<()> {
+    let file_name = path.file_name().ok_or_else(|| anyhow!("{:?} is not a file path", path))?;
+    let mut temporary_name = file_name.to_os_string();
+    temporary_name.push(".tmp");
+    let temporary_path = path.with_file_name(temporary_name);
+    let mut file = std::fs::File::create(&temporary_path)
+        .with_context(|| format!("Failed to create {:?}", temporary_path))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {:?}", temporary_path))?;
+    std::fs::rename(&temporary_path, path)
+        .with_context(|| format!("Failed to move {:?} into place at {:?}", temporary_path, path))
+}
+
+/// An enriched file written by a newer build, which this one can't read safely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedSchemaVersion {
+    pub found: u32,
+    pub supported: u32,
+}
+
+impl std::fmt::Display for UnsupportedSchemaVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Enriched file schema version {} is newer than the supported version {}", self.found, self.supported)
+    }
+}
+
+impl std::error::Error for UnsupportedSchemaVersion {}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EnrichedRecipeOutput {
+    pub schema_version: u32,
     pub recipe_title: String,
     pub ingredients: Vec<CleanedIngredient>,
     pub instructions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub servings: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_time_minutes: Option<u32>,
     pub nutritional_profile: RecipeNutritionalProfile, // Changed from aggregated_nutrition
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allergens: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<RecipeCost>,
+    /// Why the optimizer changed the recipe, for optimized outputs, or which quantities scaling
+    /// left untouched, for scaled ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub optimization_notes: Vec<String>,
 }
 
-// Function to perform the aggregation and normalization
-pub fn calculate_nutritional_profile(cleaned_recipe: &CleanedRecipe) -> RecipeNutritionalProfile {
-    let mut aggregated_nutrition = NutritionalSummary::default();
-    let mut total_mass_g = 0.0_f32;
+impl EnrichedRecipeOutput {
+    pub fn new(recipe: &CleanedRecipe, nutritional_profile: &RecipeNutritionalProfile) -> Self {
+        Self {
+            schema_version: ENRICHED_SCHEMA_VERSION,
+            recipe_title: recipe.recipe_title.clone(),
+            ingredients: recipe.ingredients.clone(),
+            instructions: recipe.instructions.clone(),
+            servings: recipe.servings,
+            total_time_minutes: recipe.total_time_minutes,
+            nutritional_profile: nutritional_profile.clone(),
+            allergens: detect_allergens(recipe, &default_allergen_rules()),
+            cost: None,
+            optimization_notes: Vec::new(),
+        }
+    }
 
-    for ingredient in &cleaned_recipe.ingredients {
-        if let (Some(grams), Some(nut_info)) = (ingredient.quantity_grams, &ingredient.nutritional_info) {
-            if grams > 0.0 {
-                total_mass_g += grams;
-                macro_rules! add_optional {
-                    ($field:ident) => {
-                        if let Some(value) = nut_info.$field {
-                            aggregated_nutrition.$field = Some(aggregated_nutrition.$field.unwrap_or(0.0) + value);
-                        }
-                    };
-                }
-                add_optional!(kcal);
-                add_optional!(water_g);
-                add_optional!(protein_g);
-                add_optional!(carbohydrate_g);
-                add_optional!(fat_g);
-                add_optional!(sugars_g);
-                add_optional!(fa_saturated_g);
-                add_optional!(salt_g);
+    /// Replaces the allergens detected with the default rules, e.g. by ones from a custom rule set.
+    pub fn with_allergens(mut self, allergens: Vec<String>) -> Self {
+        self.allergens = allergens;
+        self
+    }
+
+    pub fn with_cost(mut self, cost: RecipeCost) -> Self {
+        self.cost = Some(cost);
+        self
+    }
+
+    pub fn with_optimization_notes(mut self, notes: Vec<String>) -> Self {
+        self.optimization_notes = notes;
+        self
+    }
+
+    /// Reorders the output's ingredients; the recipe it was built from keeps its order.
+    pub fn with_ingredient_order(mut self, order: IngredientOrder) -> Self {
+        sort_ingredients(&mut self.ingredients, order);
+        self
+    }
+
+    /// The output as pretty JSON with a `checksum` of its content, which `from_json` verifies.
+    pub fn to_json(&self) -> Result<String> {
+        let mut value = serde_json::to_value(self)?;
+        let checksum = checksum(value.to_string().as_bytes());
+        if let Some(object) = value.as_object_mut() {
+            object.insert(CHECKSUM_FIELD.to_string(), checksum.into());
+        }
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
+    /// Saves the output to `path` with `to_json`, atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_file_atomically(path, &self.to_json()?)
+    }
+
+    /// Reads an enriched file, migrating older schema versions to the current one. A file from a
+    /// newer build is an `UnsupportedSchemaVersion` error; one whose content no longer matches its
+    /// checksum, e.g. after a partial write, is an error too. Files without a checksum are trusted.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut value: Value = serde_json::from_str(json).with_context(|| "Enriched file is not valid JSON")?;
+        if let Some(stored) = value.as_object_mut().and_then(|object| object.remove(CHECKSUM_FIELD)) {
+            let actual = checksum(value.to_string().as_bytes());
+            if stored.as_str() != Some(actual.as_str()) {
+                return Err(anyhow!("Enriched file checksum {} doesn't match its content ({})", stored, actual));
             }
         }
+        let version = match value.get("schema_version") {
+            None => 1,
+            Some(version) => version.as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or_else(|| anyhow!("Invalid schema_version {} in enriched file", version))?,
+        };
+        if version > ENRICHED_SCHEMA_VERSION {
+            return Err(UnsupportedSchemaVersion { found: version, supported: ENRICHED_SCHEMA_VERSION }.into());
+        }
+        if version < 2 {
+            value = migrate_enriched_v1(value)?;
+        }
+        serde_json::from_value(value)
+            .with_context(|| format!("Enriched file doesn't match schema version {}", ENRICHED_SCHEMA_VERSION))
     }
 
-    let mut per_100g_nutrition = NutritionalSummary::default();
-    if total_mass_g > 0.0 {
-        let scale_factor = 100.0 / total_mass_g;
-        macro_rules! normalize_optional {
-            ($field:ident) => {
-                if let Some(agg_value) = aggregated_nutrition.$field {
-                    per_100g_nutrition.$field = Some(agg_value * scale_factor);
-                }
-            };
+    /// The recipe part of the output, without the nutritional profile.
+    pub fn to_cleaned_recipe(&self) -> CleanedRecipe {
+        CleanedRecipe {
+            recipe_title: self.recipe_title.clone(),
+            ingredients: self.ingredients.clone(),
+            instructions: self.instructions.clone(),
+            servings: self.servings,
+            total_time_minutes: self.total_time_minutes,
         }
-        normalize_optional!(kcal);
-        normalize_optional!(water_g);
-        normalize_optional!(protein_g);
-        normalize_optional!(carbohydrate_g);
-        normalize_optional!(fat_g);
-        normalize_optional!(sugars_g);
-        normalize_optional!(fa_saturated_g);
-        normalize_optional!(salt_g);
     }
+}
+
+/// Version 1 to 2: stamps the version. The oldest version 1 files only stored the recipe totals
+/// under `aggregated_nutrition`; their profile is recomputed from the ingredients' nutrition.
+fn migrate_enriched_v1(mut value: Value) -> Result<Value> {
+    let object = value.as_object_mut().ok_or_else(|| anyhow!("Enriched file is not a JSON object"))?;
+    if object.remove("aggregated_nutrition").is_some() && !object.contains_key("nutritional_profile") {
+        let recipe = CleanedRecipe {
+            recipe_title: String::new(),
+            ingredients: serde_json::from_value(object.get("ingredients").cloned().unwrap_or_default())
+                .with_context(|| "Failed to read the ingredients of a version 1 enriched file")?,
+            instructions: Vec::new(),
+            servings: None,
+            total_time_minutes: None,
+        };
+        object.insert("nutritional_profile".to_string(), serde_json::to_value(calculate_nutritional_profile(&recipe))?);
+    }
+    object.insert("schema_version".to_string(), ENRICHED_SCHEMA_VERSION.into());
+    Ok(value)
+}
+
+/// Below this, a mass left by incremental updates is treated as no mass at all.
+const MASS_EPSILON_G: f32 = 1e-3;
+
+/// Mass and nutrition an ingredient adds to the recipe's profile: only non-optional ingredients
+/// with both a positive weight and nutritional info count.
+fn nutrition_contribution(ingredient: &CleanedIngredient) -> Option<(f32, NutritionalSummary)> {
+    if ingredient.optional {
+        return None;
+    }
+    let grams = ingredient.quantity_grams.filter(|grams| *grams > 0.0)?;
+    let nutrition = ingredient.nutritional_info.as_ref()?;
+    Some((grams, NutritionalSummary::from(nutrition)))
+}
+
+// Function to perform the aggregation and normalization
+pub fn calculate_nutritional_profile(cleaned_recipe: &CleanedRecipe) -> RecipeNutritionalProfile {
+    let mut aggregated_nutrition = NutritionalSummary::default();
+    let mut total_mass_g = 0.0_f32;
+
+    for (grams, nutrition) in cleaned_recipe.ingredients.iter().filter_map(nutrition_contribution) {
+        total_mass_g += grams;
+        aggregated_nutrition += nutrition;
+    }
+
+    let per_100g_nutrition = if total_mass_g > 0.0 {
+        &aggregated_nutrition * (100.0 / total_mass_g)
+    } else {
+        NutritionalSummary::default()
+    };
 
     RecipeNutritionalProfile {
         total_calculated_mass_g: if total_mass_g > 0.0 { Some(total_mass_g) } else { None },
+        energy_breakdown: aggregated_nutrition.energy_breakdown(),
         aggregated: aggregated_nutrition,
         per_100g: per_100g_nutrition,
+        servings: None,
+        per_serving: None,
+    }
+}
+
+// Divides aggregated values by the number of servings. A serving count of 0 yields an empty summary.
+pub fn calculate_per_serving(aggregated: &NutritionalSummary, servings: u32) -> NutritionalSummary {
+    if servings == 0 {
+        return NutritionalSummary::default();
+    }
+    aggregated * (1.0 / servings as f32)
+}
+
+/// Keywords identifying an allergen in ingredient names. `exclusions` lists names that contain
+/// a keyword without carrying the allergen (e.g. "peanut butter" for dairy).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AllergenRule {
+    pub allergen: String,
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub exclusions: Vec<String>,
+}
+
+fn allergen_rule(allergen: &str, keywords: &[&str], exclusions: &[&str]) -> AllergenRule {
+    AllergenRule {
+        allergen: allergen.to_string(),
+        keywords: keywords.iter().map(|k| k.to_string()).collect(),
+        exclusions: exclusions.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+/// Built-in rules for the most common allergens.
+pub fn default_allergen_rules() -> Vec<AllergenRule> {
+    vec![
+        allergen_rule("gluten", &["wheat", "flour", "bread", "breadcrumb", "pasta", "spaghetti", "noodle", "couscous", "semolina", "barley", "rye", "spelt", "bulgur", "seitan"],
+            &["rice flour", "corn flour", "cornflour", "almond flour", "coconut flour", "chickpea flour", "buckwheat flour", "rice noodle"]),
+        allergen_rule("dairy", &["milk", "butter", "cream", "cheese", "yogurt", "yoghurt", "creme fraiche", "crème fraîche", "ghee", "whey", "parmesan", "mozzarella", "ricotta", "mascarpone"],
+            &["peanut butter", "almond butter", "cocoa butter", "coconut milk", "coconut cream", "almond milk", "soy milk", "oat milk", "rice milk"]),
+        allergen_rule("egg", &["egg", "yolk", "mayonnaise", "meringue"], &[]),
+        allergen_rule("peanuts", &["peanut", "groundnut"], &[]),
+        allergen_rule("tree nuts", &["almond", "hazelnut", "walnut", "cashew", "pecan", "pistachio", "macadamia", "brazil nut", "pine nut"], &[]),
+        allergen_rule("soy", &["soy", "soya", "tofu", "tempeh", "edamame", "miso"], &[]),
+        allergen_rule("fish", &["fish", "salmon", "tuna", "cod", "anchovy", "sardine", "trout", "mackerel"], &[]),
+        allergen_rule("shellfish", &["shrimp", "prawn", "crab", "lobster", "crayfish", "mussel", "oyster", "clam", "scallop", "squid"], &[]),
+        allergen_rule("sesame", &["sesame", "tahini"], &[]),
+    ]
+}
+
+/// Custom rules replace the default rule of the same allergen and add new allergens.
+pub fn merge_allergen_rules(defaults: Vec<AllergenRule>, custom: Vec<AllergenRule>) -> Vec<AllergenRule> {
+    let mut merged: Vec<AllergenRule> = defaults.into_iter()
+        .filter(|rule| !custom.iter().any(|c| c.allergen.eq_ignore_ascii_case(&rule.allergen)))
+        .collect();
+    merged.extend(custom);
+    merged
+}
+
+/// True when `phrase` occurs in `text` as whole words, allowing a plural "s"/"es" ending.
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let is_boundary = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let rest = &text[start + phrase.len()..];
+        let rest = rest.strip_prefix("es").filter(|r| is_boundary(r.chars().next()))
+            .or_else(|| rest.strip_prefix('s'))
+            .unwrap_or(rest);
+        is_boundary(before) && is_boundary(rest.chars().next())
+    })
+}
+
+/// Allergens whose keywords appear in an ingredient name or its matched database item, sorted.
+pub fn detect_allergens(recipe: &CleanedRecipe, rules: &[AllergenRule]) -> Vec<String> {
+    let mut allergens: Vec<String> = Vec::new();
+    for ingredient in &recipe.ingredients {
+        let mut names = vec![ingredient.ingredient_name.to_lowercase()];
+        if let Some(info) = &ingredient.nutritional_info {
+            names.push(info.source_ciqual_name.to_lowercase());
+        }
+        for rule in rules {
+            let found = names.iter().any(|name| {
+                !rule.exclusions.iter().any(|e| contains_phrase(name, &e.to_lowercase()))
+                    && rule.keywords.iter().any(|k| contains_phrase(name, &k.to_lowercase()))
+            });
+            if found && !allergens.contains(&rule.allergen) {
+                allergens.push(rule.allergen.clone());
+            }
+        }
+    }
+    allergens.sort();
+    allergens
+}
+
+/// Estimated ingredient cost of a recipe, in the currency of the price table.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RecipeCost {
+    pub total: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_serving: Option<f32>,
+    /// Ingredients left out of the total: no price entry or no gram quantity.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uncosted: Vec<String>,
+}
+
+/// Reads a price table CSV with `ingredient` and `price_per_kg` columns.
+pub fn load_price_table(csv_path: &Path) -> Result<HashMap<String, f32>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).from_path(csv_path)
+        .with_context(|| format!("Failed to open price table {:?}", csv_path))?;
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim() == name)
+        .ok_or_else(|| anyhow::anyhow!("Column '{}' not found in price table {:?}", name, csv_path));
+    let (name_idx, price_idx) = (column("ingredient")?, column("price_per_kg")?);
+
+    let mut prices = HashMap::new();
+    for (row_index, result) in rdr.records().enumerate() {
+        let record = result.with_context(|| format!("Failed to read price table row {}", row_index))?;
+        let name = record.get(name_idx).unwrap_or_default();
+        let price = record.get(price_idx).unwrap_or_default().trim();
+        let price = price.parse::<f32>()
+            .with_context(|| format!("Invalid price '{}' for '{}' in price table", price, name))?;
+        prices.insert(normalize_ingredient_name(name), price);
+    }
+    Ok(prices)
+}
+
+/// Sums `quantity_grams` x price per kg over the ingredients found in `price_table` (keys normalized
+/// with `normalize_ingredient_name`, as produced by `load_price_table`).
+pub fn calculate_recipe_cost(recipe: &CleanedRecipe, price_table: &HashMap<String, f32>, servings: Option<u32>) -> RecipeCost {
+    let mut total = 0.0_f32;
+    let mut uncosted = Vec::new();
+    for ingredient in &recipe.ingredients {
+        let price_per_kg = price_table.get(&normalize_ingredient_name(&ingredient.ingredient_name));
+        match (ingredient.quantity_grams, price_per_kg) {
+            (Some(grams), Some(price_per_kg)) => total += grams / 1000.0 * price_per_kg,
+            _ => uncosted.push(ingredient.ingredient_name.clone()),
+        }
+    }
+    RecipeCost {
+        total,
+        per_serving: servings.filter(|&s| s > 0).map(|s| total / s as f32),
+        uncosted,
+    }
+}
+
+// --- CSV export ---
+
+/// Nutrient headers of the CSV export, in `NutritionalSummary` field order. They reuse the Ciqual
+/// column names without the "/100g" suffix, since most rows hold absolute amounts.
+fn csv_nutrient_headers() -> Vec<String> {
+    let columns = &CIQUAL_COLUMNS;
+    [
+        columns.kcal, columns.water, columns.protein, columns.carbohydrate, columns.fat,
+        columns.sugars, columns.fa_saturated, columns.salt, columns.fiber, columns.cholesterol,
+    ]
+    .iter()
+    .map(|column| column.header.replace("/100g", "").trim().to_string())
+    .flat_map(|header| {
+        // Sodium is derived from salt, so it has no column of its own in the database.
+        let is_salt = header == CIQUAL_COLUMNS.salt.header.replace("/100g", "").trim();
+        std::iter::once(header).chain(is_salt.then(|| "Sodium (mg)".to_string()))
+    })
+    .collect()
+}
+
+fn csv_number(value: Option<f32>) -> String {
+    value.map(|value| format!("{:.2}", value)).unwrap_or_default()
+}
+
+fn summary_values(summary: &NutritionalSummary) -> [Option<f32>; 11] {
+    [
+        summary.kcal, summary.water_g, summary.protein_g, summary.carbohydrate_g, summary.fat_g,
+        summary.sugars_g, summary.fa_saturated_g, summary.salt_g, summary.sodium_mg(), summary.fiber_g,
+        summary.cholesterol_mg,
+    ]
+}
+
+/// Writes the ingredient gram breakdown followed by the total, per-100g and per-serving profile
+/// as one CSV table. The `Row` column tells ingredient rows from profile rows.
+pub fn write_profile_csv(output: &EnrichedRecipeOutput, writer: impl std::io::Write) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    let mut header = vec!["Row".to_string(), "Name".to_string(), "Grams".to_string(), "Matched food".to_string()];
+    header.extend(csv_nutrient_headers());
+    wtr.write_record(&header)?;
+
+    for ingredient in &output.ingredients {
+        let info = ingredient.nutritional_info.as_ref();
+        let values = info.map_or([None; 11], |info| summary_values(&NutritionalSummary::from(info)));
+        let mut record = vec![
+            "ingredient".to_string(),
+            ingredient.ingredient_name.clone(),
+            csv_number(ingredient.quantity_grams),
+            info.map(|info| info.source_ciqual_name.clone()).unwrap_or_default(),
+        ];
+        record.extend(values.into_iter().map(csv_number));
+        wtr.write_record(&record)?;
+    }
+
+    let profile = &output.nutritional_profile;
+    let total_mass_g = profile.total_calculated_mass_g;
+    let mut summaries = vec![
+        ("total", total_mass_g, &profile.aggregated),
+        ("per 100g", Some(100.0), &profile.per_100g),
+    ];
+    if let (Some(servings), Some(per_serving)) = (profile.servings, &profile.per_serving) {
+        summaries.push(("per serving", total_mass_g.map(|mass| mass / servings.max(1) as f32), per_serving));
+    }
+    for (row, grams, summary) in summaries {
+        let mut record = vec![row.to_string(), output.recipe_title.clone(), csv_number(grams), String::new()];
+        record.extend(summary_values(summary).into_iter().map(csv_number));
+        wtr.write_record(&record)?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// "200 g" for an ingredient's grams, to one decimal below 10 g.
+fn format_grams(grams: f32) -> String {
+    if grams < 10.0 {
+        format!("{:.1} g", grams).replace(".0 g", " g")
+    } else {
+        format!("{:.0} g", grams)
+    }
+}
+
+/// One ingredient line: its quantity as written, its grams when the quantity isn't already in
+/// grams, and its preparation.
+fn recipe_text_ingredient_line(ingredient: &CleanedIngredient) -> String {
+    let mut line = [&ingredient.original_quantity, &ingredient.original_unit, &ingredient.ingredient_name]
+        .iter()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let in_grams = matches!(ingredient.original_unit.trim().to_lowercase().as_str(), "g" | "gram" | "grams");
+    if let Some(grams) = ingredient.quantity_grams.filter(|_| !in_grams) {
+        line.push_str(&format!(" ({})", format_grams(grams)));
+    }
+    if !ingredient.preparation_notes.trim().is_empty() {
+        line.push_str(&format!(", {}", ingredient.preparation_notes.trim()));
+    }
+    if ingredient.optional {
+        line.push_str(" (optional)");
+    }
+    line
+}
+
+/// The recipe as text to cook from: title, yield and time, the ingredients under their section
+/// headings, then the numbered instructions.
+pub fn format_recipe_text(output: &EnrichedRecipeOutput) -> String {
+    let mut text = format!("{}\n", output.recipe_title.trim());
+    let details: Vec<String> = [
+        output.servings.map(|servings| format!("Serves {}", servings)),
+        output.total_time_minutes.map(|minutes| format!("{} minutes", minutes)),
+    ].into_iter().flatten().collect();
+    if !details.is_empty() {
+        text.push_str(&format!("{}\n", details.join(", ")));
+    }
+
+    text.push_str("\nIngredients\n");
+    let mut section = None;
+    for ingredient in &output.ingredients {
+        if ingredient.section.is_some() && ingredient.section != section {
+            text.push_str(&format!("\n{}:\n", ingredient.section.as_deref().unwrap_or_default()));
+        }
+        section = ingredient.section.clone();
+        text.push_str(&format!("- {}\n", recipe_text_ingredient_line(ingredient)));
+    }
+
+    if !output.instructions.is_empty() {
+        text.push_str("\nInstructions\n");
+        for (i, step) in output.instructions.iter().enumerate() {
+            text.push_str(&format!("{}. {}\n", i + 1, step.trim()));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nutritional_matcher::test_support::cleaned_ingredient;
+
+    #[test]
+    fn test_apply_servings_divides_aggregated_values() {
+        let mut profile = RecipeNutritionalProfile {
+            total_calculated_mass_g: Some(400.0),
+            aggregated: NutritionalSummary {
+                kcal: Some(800.0),
+                protein_g: Some(40.0),
+                fat_g: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        profile.apply_servings(4);
+
+        assert_eq!(profile.servings, Some(4));
+        let per_serving = profile.per_serving.as_ref().unwrap();
+        assert_eq!(per_serving.kcal, Some(200.0));
+        assert_eq!(per_serving.protein_g, Some(10.0));
+        assert_eq!(per_serving.fat_g, None); // Missing values stay missing
+        assert_eq!(profile.aggregated.kcal, Some(800.0)); // Aggregated values are untouched
+    }
+
+    #[test]
+    fn test_enriched_output_round_trip_and_migration() {
+        let recipe = CleanedRecipe {
+            recipe_title: "Toast".to_string(),
+            ingredients: vec![CleanedIngredient {
+                nutritional_info: Some(CalculatedNutritionalInfo { kcal: Some(130.0), ..Default::default() }),
+                ..cleaned_ingredient("bread", 50.0)
+            }],
+            instructions: vec!["Toast the bread.".to_string()],
+            servings: None,
+            total_time_minutes: None,
+        };
+        let output = EnrichedRecipeOutput::new(&recipe, &calculate_nutritional_profile(&recipe));
+        let json = serde_json::to_string(&output).unwrap();
+        let loaded = EnrichedRecipeOutput::from_json(&json).unwrap();
+        assert_eq!(loaded.schema_version, ENRICHED_SCHEMA_VERSION);
+        assert_eq!(loaded.nutritional_profile.per_100g.kcal, Some(260.0));
+
+        // Version 1, with the profile but no version field.
+        let mut v1: Value = serde_json::from_str(&json).unwrap();
+        v1.as_object_mut().unwrap().remove("schema_version");
+        let migrated = EnrichedRecipeOutput::from_json(&v1.to_string()).unwrap();
+        assert_eq!(migrated.schema_version, ENRICHED_SCHEMA_VERSION);
+
+        // The oldest version 1 shape, with totals only: the profile is rebuilt from the ingredients.
+        let object = v1.as_object_mut().unwrap();
+        object.remove("nutritional_profile");
+        object.insert("aggregated_nutrition".to_string(), serde_json::json!({ "kcal": 130.0 }));
+        let migrated = EnrichedRecipeOutput::from_json(&v1.to_string()).unwrap();
+        assert_eq!(migrated.nutritional_profile.total_calculated_mass_g, Some(50.0));
+        assert_eq!(migrated.nutritional_profile.per_100g.kcal, Some(260.0));
+
+        // Saved files carry a checksum, and altered content is rejected.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("toast_enriched.json");
+        output.save(&path).unwrap();
+        assert!(!dir.path().join("toast_enriched.json.tmp").exists());
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("\"checksum\": \"fnv1a64:"));
+        assert_eq!(EnrichedRecipeOutput::from_json(&saved).unwrap().nutritional_profile.per_100g.kcal, Some(260.0));
+        let tampered = saved.replace("Toast the bread.", "Burn the bread.");
+        let error = EnrichedRecipeOutput::from_json(&tampered).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{}", error);
+
+        let mut newer: Value = serde_json::from_str(&json).unwrap();
+        newer["schema_version"] = (ENRICHED_SCHEMA_VERSION + 1).into();
+        let error = EnrichedRecipeOutput::from_json(&newer.to_string()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<UnsupportedSchemaVersion>(),
+            Some(&UnsupportedSchemaVersion { found: ENRICHED_SCHEMA_VERSION + 1, supported: ENRICHED_SCHEMA_VERSION })
+        );
+    }
+
+    #[test]
+    fn test_ensure_nutrition_computed() {
+        let mut recipe = CleanedRecipe {
+            recipe_title: "Soup".to_string(),
+            ingredients: vec![CleanedIngredient {
+                quantity_grams: None,
+                conversion_source: "API_Error".to_string(),
+                nutritional_info: Some(CalculatedNutritionalInfo::default()),
+                ..cleaned_ingredient("leek", 1.0)
+            }],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+        };
+        let profile = calculate_nutritional_profile(&recipe);
+        assert!(!profile.has_nutrition());
+        let error = ensure_nutrition_computed(&recipe, &profile).unwrap_err().to_string();
+        assert!(error.contains("1 without grams, 0 without a match"), "{}", error);
+
+        recipe.ingredients[0].quantity_grams = Some(80.0);
+        let profile = calculate_nutritional_profile(&recipe);
+        assert!(ensure_nutrition_computed(&recipe, &profile).is_ok());
+    }
+
+    #[test]
+    fn test_sort_ingredients() {
+        let mut recipe = recipe_with(&["salt", "Flour", "butter", "egg", "water"]);
+        let grams = [Some(2.0), Some(250.0), None, Some(50.0), Some(250.0)];
+        for (ingredient, grams) in recipe.ingredients.iter_mut().zip(grams) {
+            ingredient.quantity_grams = grams;
+        }
+        let names = |order: IngredientOrder| {
+            let mut ingredients = recipe.ingredients.clone();
+            sort_ingredients(&mut ingredients, order);
+            ingredients.into_iter().map(|ingredient| ingredient.ingredient_name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(IngredientOrder::Original), vec!["salt", "Flour", "butter", "egg", "water"]);
+        assert_eq!(names(IngredientOrder::ByMass), vec!["Flour", "water", "egg", "salt", "butter"]);
+        assert_eq!(names(IngredientOrder::Alpha), vec!["butter", "egg", "Flour", "salt", "water"]);
+    }
+
+    #[test]
+    fn test_sodium_is_derived_from_salt() {
+        let summary = NutritionalSummary { salt_g: Some(1.5), ..Default::default() };
+        assert_eq!(summary.sodium_mg(), Some(600.0));
+        assert_eq!(NutritionalSummary::default().sodium_mg(), None);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["sodium_mg"], 600.0);
+        let text = serde_json::to_string(&NutritionalSummary { fiber_g: Some(2.0), ..summary.clone() }).unwrap();
+        assert!(text.contains(r#""salt_g":1.5,"sodium_mg":600.0,"fiber_g":2.0"#), "{}", text);
+        assert_eq!(serde_json::to_value(NutritionalSummary::default()).unwrap()["sodium_mg"], Value::Null);
+        // A stale sodium value in a file can't contradict the salt it is read with.
+        let mut edited = json.clone();
+        edited["sodium_mg"] = 1.0.into();
+        let reloaded: NutritionalSummary = serde_json::from_value(edited).unwrap();
+        assert_eq!(reloaded.sodium_mg(), Some(600.0));
+    }
+
+    #[test]
+    fn test_calculate_per_serving_zero_servings() {
+        let aggregated = NutritionalSummary { kcal: Some(800.0), ..Default::default() };
+        assert_eq!(calculate_per_serving(&aggregated, 0).kcal, None);
+    }
+
+    fn recipe_with(names: &[&str]) -> CleanedRecipe {
+        CleanedRecipe {
+            recipe_title: "Test".to_string(),
+            ingredients: names.iter().map(|name| CleanedIngredient { quantity_grams: None, ..cleaned_ingredient(name, 1.0) }).collect(),
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+        }
+    }
+
+    #[test]
+    fn test_format_recipe_text() {
+        let mut recipe = recipe_with(&["butter", "flour", "salt", "parsley"]);
+        recipe.recipe_title = "Shortbread".to_string();
+        recipe.servings = Some(8);
+        recipe.total_time_minutes = Some(40);
+        recipe.instructions = vec!["Rub the butter into the flour.".to_string(), " Bake. ".to_string()];
+        let units = [("100", "g", Some(100.0)), ("1", "cup", Some(125.0)), ("", "a pinch", Some(0.5)), ("", "", None)];
+        for (ingredient, (quantity, unit, grams)) in recipe.ingredients.iter_mut().zip(units) {
+            ingredient.original_quantity = quantity.to_string();
+            ingredient.original_unit = unit.to_string();
+            ingredient.quantity_grams = grams;
+        }
+        recipe.ingredients[0].preparation_notes = "softened".to_string();
+        recipe.ingredients[2].section = Some("Topping".to_string());
+        recipe.ingredients[3].section = Some("Topping".to_string());
+        recipe.ingredients[3].optional = true;
+        let output = EnrichedRecipeOutput::new(&recipe, &calculate_nutritional_profile(&recipe));
+
+        assert_eq!(format_recipe_text(&output), "Shortbread
+Serves 8, 40 minutes
+
+Ingredients
+- 100 g butter, softened
+- 1 cup flour (125 g)
+
+Topping:
+- a pinch salt (0.5 g)
+- parsley (optional)
+
+Instructions
+1. Rub the butter into the flour.
+2. Bake.
+");
+    }
+
+    #[test]
+    fn test_write_profile_csv() {
+        let mut recipe = recipe_with(&["flour", "salt"]);
+        recipe.servings = Some(2);
+        recipe.ingredients[0].quantity_grams = Some(200.0);
+        recipe.ingredients[0].nutritional_info = Some(CalculatedNutritionalInfo {
+            source_ciqual_name: "Wheat flour".to_string(),
+            kcal: Some(700.0),
+            protein_g: Some(20.0),
+            ..Default::default()
+        });
+        let mut profile = calculate_nutritional_profile(&recipe);
+        profile.apply_servings(2);
+        let output = EnrichedRecipeOutput::new(&recipe, &profile);
+
+        let mut buffer = Vec::new();
+        write_profile_csv(&output, &mut buffer).unwrap();
+        let csv = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("Row,Name,Grams,Matched food,kcal,Water (g),Protein (g),"));
+        assert!(lines[0].ends_with("Cholesterol (mg)"));
+        assert!(lines[1].starts_with("ingredient,flour,200.00,Wheat flour,700.00,,20.00,"));
+        assert!(lines[0].contains(",Salt (g),Sodium (mg),"), "{}", lines[0]);
+        assert_eq!(lines[2], "ingredient,salt,,,,,,,,,,,,,");
+        assert!(lines[3].starts_with("total,Test,200.00,,700.00,"));
+        assert!(lines[4].starts_with("per 100g,Test,100.00,,350.00,"));
+        assert!(lines[5].starts_with("per serving,Test,100.00,,350.00,"));
+    }
+
+    #[test]
+    fn test_detect_allergens_synonyms_and_exclusions() {
+        let recipe = recipe_with(&["All-purpose Flour", "2 Eggs", "peanut butter", "Eggplant", "soy sauce"]);
+        let allergens = detect_allergens(&recipe, &default_allergen_rules());
+        assert_eq!(allergens, vec!["egg", "gluten", "peanuts", "soy"]);
+
+        let dairy_free = recipe_with(&["coconut milk", "rice flour"]);
+        assert!(detect_allergens(&dairy_free, &default_allergen_rules()).is_empty());
+    }
+
+    #[test]
+    fn test_merge_allergen_rules_overrides_and_extends() {
+        let custom = vec![
+            allergen_rule("Egg", &["egg"], &["egg substitute"]),
+            allergen_rule("celery", &["celery", "celeriac"], &[]),
+        ];
+        let rules = merge_allergen_rules(default_allergen_rules(), custom);
+        assert_eq!(rules.iter().filter(|r| r.allergen.eq_ignore_ascii_case("egg")).count(), 1);
+
+        let recipe = recipe_with(&["egg substitute", "celeriac"]);
+        assert_eq!(detect_allergens(&recipe, &rules), vec!["celery"]);
+    }
+
+    #[test]
+    fn test_calculate_recipe_cost() {
+        let mut recipe = recipe_with(&["Butter", "flour (sifted)", "salt"]);
+        recipe.ingredients[0].quantity_grams = Some(250.0);
+        recipe.ingredients[1].quantity_grams = Some(500.0);
+        let prices = HashMap::from([("butter".to_string(), 10.0), ("flour".to_string(), 1.2)]);
+
+        let cost = calculate_recipe_cost(&recipe, &prices, Some(4));
+        assert!((cost.total - 3.1).abs() < 1e-5);
+        assert!((cost.per_serving.unwrap() - 0.775).abs() < 1e-5);
+        assert_eq!(cost.uncosted, vec!["salt"]);
+    }
+
+    #[test]
+    fn test_nutritional_summary_arithmetic() {
+        let a = NutritionalSummary { kcal: Some(100.0), protein_g: Some(10.0), salt_g: Some(1.0), ..Default::default() };
+        let b = NutritionalSummary { kcal: Some(50.0), fat_g: Some(4.0), salt_g: None, ..Default::default() };
+
+        let sum = a.clone() + b.clone();
+        assert_eq!(sum.kcal, Some(150.0));
+        assert_eq!(sum.protein_g, Some(10.0));
+        assert_eq!(sum.fat_g, Some(4.0));
+        assert_eq!(sum.salt_g, Some(1.0));
+        assert_eq!(sum.fiber_g, None);
+
+        let scaled = &a * 0.5;
+        assert_eq!(scaled.kcal, Some(50.0));
+        assert_eq!(scaled.salt_g, Some(0.5));
+        assert_eq!(scaled.fat_g, None);
+
+        let diff = a.diff(&b);
+        assert_eq!(diff.kcal, Some(50.0));
+        // Known on one side only: the difference is unknown.
+        assert_eq!(diff.protein_g, None);
+        assert_eq!(diff.fat_g, None);
+    }
+
+    #[test]
+    fn test_incremental_profile_reconciles_with_full_recompute() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1605);
+        let mut random_ingredient = |name: &str| {
+            let mut ingredient = recipe_with(&[name]).ingredients.remove(0);
+            let grams: f32 = rng.gen_range(0.0..300.0);
+            ingredient.quantity_grams = rng.gen_bool(0.9).then_some(grams);
+            ingredient.nutritional_info = rng.gen_bool(0.8).then(|| CalculatedNutritionalInfo {
+                source_ciqual_name: name.to_string(),
+                kcal: Some(grams * rng.gen_range(0.0..9.0)),
+                protein_g: Some(grams * rng.gen_range(0.0..0.3)),
+                fat_g: rng.gen_bool(0.5).then(|| grams * rng.gen_range(0.0..0.8)),
+                ..Default::default()
+            });
+            ingredient
+        };
+
+        let mut recipe = recipe_with(&[]);
+        recipe.ingredients = (0..6).map(|i| random_ingredient(&format!("ingredient {}", i))).collect();
+        let mut profile = calculate_nutritional_profile(&recipe);
+        profile.apply_servings(3);
+
+        let close = |a: Option<f32>, b: Option<f32>| match (a, b) {
+            (Some(a), Some(b)) => (a - b).abs() <= 1e-2 * a.abs().max(1.0),
+            // A value whose contributors were all removed is 0 instead of unknown until a full recompute.
+            (Some(a), None) | (None, Some(a)) => a.abs() <= 1e-2,
+            (None, None) => true,
+        };
+        for step in 0..200 {
+            let idx = step % recipe.ingredients.len();
+            let new = random_ingredient(&format!("replacement {}", step));
+            profile.apply_ingredient_change(Some(&recipe.ingredients[idx]), Some(&new));
+            recipe.ingredients[idx] = new;
+
+            let mut expected = calculate_nutritional_profile(&recipe);
+            expected.apply_servings(3);
+            assert!(close(profile.total_calculated_mass_g, expected.total_calculated_mass_g), "mass at step {}", step);
+            for (actual, expected) in [
+                (&profile.per_100g, &expected.per_100g),
+                (&profile.aggregated, &expected.aggregated),
+                (profile.per_serving.as_ref().unwrap(), expected.per_serving.as_ref().unwrap()),
+            ] {
+                assert!(close(actual.kcal, expected.kcal), "kcal at step {}", step);
+                assert!(close(actual.protein_g, expected.protein_g), "protein at step {}", step);
+                assert!(close(actual.fat_g, expected.fat_g), "fat at step {}", step);
+            }
+        }
+
+        // Removing then adding back an ingredient round-trips.
+        let removed = recipe.ingredients[0].clone();
+        let before = profile.clone();
+        profile.apply_ingredient_change(Some(&removed), None);
+        profile.apply_ingredient_change(None, Some(&removed));
+        assert!(close(profile.per_100g.kcal, before.per_100g.kcal));
+    }
+
+    #[test]
+    fn test_optional_ingredients_are_left_out_of_the_profile() {
+        let mut recipe = recipe_with(&["rice", "cashews"]);
+        for (ingredient, kcal) in recipe.ingredients.iter_mut().zip([130.0, 550.0]) {
+            ingredient.quantity_grams = Some(100.0);
+            ingredient.nutritional_info = Some(CalculatedNutritionalInfo { kcal: Some(kcal), ..Default::default() });
+        }
+        recipe.ingredients[1].optional = true;
+
+        let profile = calculate_nutritional_profile(&recipe);
+        assert_eq!(profile.total_calculated_mass_g, Some(100.0));
+        assert_eq!(profile.per_100g.kcal, Some(130.0));
+    }
+
+    #[test]
+    fn test_check_kcal_flags_inconsistent_matches() {
+        let mut recipe = recipe_with(&["oats", "olive oil"]);
+        let values = [(389.0, 16.9, 66.3, 6.9), (120.0, 0.0, 0.0, 100.0)];
+        for (ingredient, (kcal, protein, carbs, fat)) in recipe.ingredients.iter_mut().zip(values) {
+            ingredient.quantity_grams = Some(100.0);
+            ingredient.nutritional_info = Some(CalculatedNutritionalInfo {
+                source_ciqual_name: ingredient.ingredient_name.clone(),
+                kcal: Some(kcal),
+                protein_g: Some(protein),
+                carbohydrate_g: Some(carbs),
+                fat_g: Some(fat),
+                ..Default::default()
+            });
+        }
+        let mut profile = calculate_nutritional_profile(&recipe);
+        profile.apply_servings(2);
+
+        let (discrepancy, suspects) = check_kcal(&recipe, &profile, DEFAULT_KCAL_TOLERANCE_PCT).unwrap();
+        assert_eq!(discrepancy.reported_kcal, 509.0);
+        assert!((discrepancy.atwater_kcal - 1294.9).abs() < 0.1);
+        let names: Vec<&str> = suspects.iter().map(|(ingredient, _)| ingredient.ingredient_name.as_str()).collect();
+        assert_eq!(names, vec!["olive oil"]);
+        assert!(check_kcal(&recipe, &profile, 70.0).is_none());
+        assert!(!profile.clone().reconcile_kcal_beyond(70.0));
+        assert!(profile.clone().reconcile_kcal_beyond(DEFAULT_KCAL_TOLERANCE_PCT));
+
+        profile.reconcile_kcal();
+        assert_eq!(profile.aggregated.kcal, profile.aggregated.atwater_kcal());
+        assert!((profile.per_100g.kcal.unwrap() - 647.45).abs() < 0.1);
+        assert_eq!(profile.per_serving.as_ref().unwrap().kcal, profile.per_100g.kcal);
+        assert!(check_kcal(&recipe, &profile, DEFAULT_KCAL_TOLERANCE_PCT).is_none());
+    }
+
+    #[test]
+    fn test_energy_breakdown_from_macros() {
+        let summary = NutritionalSummary {
+            kcal: Some(500.0),
+            protein_g: Some(25.0),
+            carbohydrate_g: Some(50.0),
+            ..Default::default()
+        };
+        let breakdown = summary.energy_breakdown().unwrap();
+        assert_eq!(breakdown, EnergyBreakdown { protein_pct: Some(20.0), carbohydrate_pct: Some(40.0), fat_pct: None });
+        assert_eq!(breakdown.to_string(), "protein 20%, carbohydrate 40%, fat n/a");
+
+        // Without kcal the Atwater estimate is the total, so the shares add up to 100%.
+        let breakdown = NutritionalSummary { kcal: None, fat_g: Some(10.0), ..summary.clone() }.energy_breakdown().unwrap();
+        let total = breakdown.protein_pct.unwrap() + breakdown.carbohydrate_pct.unwrap() + breakdown.fat_pct.unwrap();
+        assert!((total - 100.0).abs() < 1e-3);
+        assert!((breakdown.fat_pct.unwrap() - 90.0 / 390.0 * 100.0).abs() < 1e-3);
+        assert!(NutritionalSummary::default().energy_breakdown().is_none());
+        assert!(NutritionalSummary { kcal: Some(0.0), ..Default::default() }.energy_breakdown().is_none());
+
+        let mut recipe = recipe_with(&["oats"]);
+        recipe.ingredients[0].quantity_grams = Some(200.0);
+        recipe.ingredients[0].nutritional_info = Some(CalculatedNutritionalInfo {
+            source_ciqual_name: "oats".to_string(),
+            kcal: Some(400.0),
+            protein_g: Some(10.0),
+            ..Default::default()
+        });
+        let mut profile = calculate_nutritional_profile(&recipe);
+        assert_eq!(profile.energy_breakdown.unwrap().protein_pct, Some(10.0));
+        profile.apply_ingredient_change(recipe.ingredients.first(), None);
+        assert!(profile.energy_breakdown.is_none());
     }
 }
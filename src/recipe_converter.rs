@@ -1,13 +1,20 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use anyhow::Result;
 
-use crate::recipe_parser::{ParsedIngredient, ParsedRecipe}; // Assuming ParsedRecipe is in recipe_parser
+use crate::progress::{ProgressEvent, ProgressStage};
+use crate::recipe_parser::{
+    canonical_unit, grams_per_unit, millilitres_per_unit, normalize_ingredient_name, parse_quantity, ParsedIngredient,
+    ParsedRecipe,
+};
+use crate::api_connection::json_extract::JsonMode;
+use crate::prompts::{PromptKind, PromptTemplates};
 use crate::api_connection::endpoints::{
-    ChatCompletionRequest, ChatMessage, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
-    ResponseFormat, Provider,
+    ChatCompletionRequest, ChatMessage, GenParams, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
+    ResponseFormat, ResponseFormatMode,
 };
-use crate::api_connection::connection::ApiConnectionError;
+use crate::api_connection::client::{complete_untruncated, ChatClient, TRUNCATION_RETRIES};
+use crate::api_connection::usage::log_stage_usage;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CleanedIngredient {
@@ -17,13 +24,19 @@ pub struct CleanedIngredient {
     pub original_unit: String,
     pub preparation_notes: String,
     pub quantity_grams: Option<f32>,
-    pub conversion_source: String, // e.g., "LLM", "DatabaseLookup"
+    pub conversion_source: String, // e.g., "LLM", "Local", "DatabaseLookup"
     pub conversion_notes: Option<String>,
     pub nutritional_info: Option<CalculatedNutritionalInfo>, // Added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>, // Ingredient group carried over from the parsed recipe
+    /// Optional garnish or extra; left out of the recipe's per-100g nutrition.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
 }
 
+/// A food composition entry, normalized to per-100g values whatever database it was loaded from.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct CiqualFoodItem {
+pub struct FoodItem {
     pub name: String,
     pub original_row_index: usize, // To map back if needed, or for ANN ID
     pub kcal_per_100g: Option<f32>,
@@ -34,10 +47,12 @@ pub struct CiqualFoodItem {
     pub sugars_g_per_100g: Option<f32>,
     pub fa_saturated_g_per_100g: Option<f32>,
     pub salt_g_per_100g: Option<f32>,
-    // Add other fields if there are more nutritional columns from ciqual.csv
+    pub fiber_g_per_100g: Option<f32>,
+    pub cholesterol_mg_per_100g: Option<f32>,
+    // Add other fields if there are more nutritional columns in the source databases
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CalculatedNutritionalInfo {
     pub source_ciqual_name: String,
     pub kcal: Option<f32>,
@@ -48,7 +63,64 @@ pub struct CalculatedNutritionalInfo {
     pub sugars_g: Option<f32>,
     pub fa_saturated_g: Option<f32>,
     pub salt_g: Option<f32>,
-    // Mirror fields from CiqualFoodItem, but calculated for specific quantity
+    pub fiber_g: Option<f32>,
+    pub cholesterol_mg: Option<f32>,
+    // Mirror fields from FoodItem, but calculated for specific quantity
+
+    /// How the food item was chosen; missing in files enriched before methods were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_method: Option<MatchMethod>,
+    /// Embedding similarity of the chosen item to the ingredient name, when it came from the ANN search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_similarity: Option<f32>,
+}
+
+impl CalculatedNutritionalInfo {
+    /// The nutrition of `factor` times the quantity, from the same food item.
+    pub fn scaled(&self, factor: f32) -> Self {
+        let scale = |value: Option<f32>| value.map(|v| v * factor);
+        CalculatedNutritionalInfo {
+            kcal: scale(self.kcal),
+            water_g: scale(self.water_g),
+            protein_g: scale(self.protein_g),
+            carbohydrate_g: scale(self.carbohydrate_g),
+            fat_g: scale(self.fat_g),
+            sugars_g: scale(self.sugars_g),
+            fa_saturated_g: scale(self.fa_saturated_g),
+            salt_g: scale(self.salt_g),
+            fiber_g: scale(self.fiber_g),
+            cholesterol_mg: scale(self.cholesterol_mg),
+            ..self.clone()
+        }
+    }
+}
+
+/// How the food item behind an ingredient's nutrition was chosen.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMethod {
+    /// From the `--overrides` file.
+    Override,
+    /// The top ANN candidate, clear enough to skip disambiguation.
+    AutoAccepted,
+    /// Picked among the ANN candidates by the LLM.
+    Llm,
+    /// Picked among the ANN candidates by the user.
+    Interactive,
+    /// From Open Food Facts, when the database had no match.
+    OnlineFallback,
+}
+
+impl std::fmt::Display for MatchMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MatchMethod::Override => "override",
+            MatchMethod::AutoAccepted => "auto-accepted",
+            MatchMethod::Llm => "LLM",
+            MatchMethod::Interactive => "interactive",
+            MatchMethod::OnlineFallback => "online fallback",
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -56,6 +128,10 @@ pub struct CleanedRecipe {
     pub recipe_title: String,
     pub ingredients: Vec<CleanedIngredient>,
     pub instructions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub servings: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_time_minutes: Option<u32>,
 }
 
 // Struct for Qwen's response for gram conversion
@@ -66,7 +142,7 @@ struct GramConversionResponse {
 }
 
 fn get_gram_conversion_json_schema() -> JsonSchemaDefinition {
-    let mut properties_map = HashMap::new();
+    let mut properties_map = BTreeMap::new();
     properties_map.insert(
         "grams".to_string(),
         JsonSchemaProperty {
@@ -98,149 +174,579 @@ fn get_gram_conversion_json_schema() -> JsonSchemaDefinition {
     }
 }
 
-pub async fn convert_ingredients_to_grams(
-    parsed_recipe: &ParsedRecipe,
-    api_key_env_var: &str,
-    progress_updater: impl Fn(String) + Send + Sync + 'static, 
-) -> Result<CleanedRecipe, anyhow::Error> {
-    let mut cleaned_ingredients: Vec<CleanedIngredient> = Vec::new();
-    let provider = Provider::openrouter(api_key_env_var);
+/// Grams for quantities in a mass unit, or a volume unit (ml, cl, dl, l, tbsp, tsp) taken at the
+/// density of water, with decimal commas accepted ("1,5 kg"). Other units are left to the LLM.
+fn local_gram_conversion(quantity: &str, unit: &str) -> Option<GramConversionResponse> {
+    let value = parse_quantity(quantity)?;
+    let canonical = canonical_unit(unit)?;
+    if let Some(factor) = grams_per_unit(canonical) {
+        return Some(GramConversionResponse {
+            grams: Some(value * factor),
+            notes: format!("Converted locally from {} {}.", value, canonical),
+        });
+    }
+    let millilitres = millilitres_per_unit(canonical)?;
+    Some(GramConversionResponse {
+        grams: Some(value * millilitres),
+        notes: format!("Converted locally from {} {}, assuming the density of water.", value, canonical),
+    })
+}
 
-    for (index, ingredient) in parsed_recipe.ingredients.iter().enumerate() {
-        progress_updater(format!(
-            "Converting ingredient {}/{}: {} {} {}...",
-            index + 1,
-            parsed_recipe.ingredients.len(),
-            ingredient.quantity,
-            ingredient.unit,
-            ingredient.ingredient_name
-        ));
-
-        let conversion_prompt = format!(
-            "/no_thinking
+/// Grams assumed for seasonings measured "to taste", keyed by a word of the ingredient name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToTasteDefaults {
+    grams: BTreeMap<String, f32>,
+}
+
+impl Default for ToTasteDefaults {
+    fn default() -> Self {
+        let mut grams = BTreeMap::new();
+        grams.insert("salt".to_string(), 1.0);
+        grams.insert("pepper".to_string(), 0.5);
+        for spice in ["cayenne", "chili", "cinnamon", "cumin", "nutmeg", "paprika", "spice"] {
+            grams.insert(spice.to_string(), 0.5);
+        }
+        Self { grams }
+    }
+}
+
+impl ToTasteDefaults {
+    /// Sets the grams assumed for `keyword`; zero or less removes it, so such ingredients go
+    /// through the usual conversion.
+    pub fn with_override(mut self, keyword: &str, grams: f32) -> Self {
+        let keyword = keyword.trim().to_lowercase();
+        if grams > 0.0 {
+            self.grams.insert(keyword, grams);
+        } else {
+            self.grams.remove(&keyword);
+        }
+        self
+    }
+
+    /// The default for `ingredient_name`, from the longest keyword among its normalized words
+    /// ("smoked paprika" matches "paprika", "black pepper" matches "pepper").
+    pub fn grams_for(&self, ingredient_name: &str) -> Option<(&str, f32)> {
+        let normalized = normalize_ingredient_name(ingredient_name);
+        self.grams.iter()
+            .filter(|(keyword, _)| !keyword.is_empty() && contains_words(&normalized, keyword))
+            .max_by_key(|(keyword, _)| keyword.len())
+            .map(|(keyword, grams)| (keyword.as_str(), *grams))
+    }
+}
+
+/// True when the words of `keyword` appear together, in order, among the words of `name`.
+fn contains_words(name: &str, keyword: &str) -> bool {
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let keyword_words: Vec<&str> = keyword.split_whitespace().collect();
+    words.windows(keyword_words.len()).any(|window| window == keyword_words.as_slice())
+}
+
+/// Whether `name` ends with the words of `keyword`, which are then its head noun ("large egg"
+/// but not "egg noodle nest").
+fn ends_with_words(name: &str, keyword: &str) -> bool {
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let keyword_words: Vec<&str> = keyword.split_whitespace().collect();
+    words.ends_with(&keyword_words)
+}
+
+/// One entry of the container-size table: the grams in one `container` of the ingredients whose
+/// name contains `ingredient`, or of any ingredient when it's left out. An empty container is a
+/// count with no unit ("2 eggs"), which only applies to names ending with `ingredient`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ContainerSize {
+    pub container: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ingredient: Option<String>,
+    pub grams: f32,
+}
+
+/// Grams in a can, package, stick or egg, so such quantities don't depend on the LLM's guess.
+/// Keyed by canonical container and ingredient keyword, "" standing for any ingredient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerSizes {
+    grams: BTreeMap<(String, String), f32>,
+}
+
+impl Default for ContainerSizes {
+    fn default() -> Self {
+        let mut sizes = ContainerSizes { grams: BTreeMap::new() };
+        for (container, ingredient, grams) in [
+            ("can", None, 400.0),
+            ("can", Some("tuna"), 140.0),
+            ("can", Some("tomato paste"), 170.0),
+            ("can", Some("condensed milk"), 397.0),
+            ("package", Some("yeast"), 7.0),
+            ("package", Some("gelatin"), 7.0),
+            ("package", Some("cream cheese"), 226.0),
+            ("stick", Some("butter"), 113.0),
+            ("", Some("egg"), 50.0),
+            ("", Some("egg yolk"), 17.0),
+            ("", Some("egg white"), 33.0),
+        ] {
+            sizes = sizes.with_size(&ContainerSize { container: container.to_string(), ingredient: ingredient.map(str::to_string), grams });
+        }
+        // Shelled weights of the standard egg sizes, written either as the unit ("2 large eggs"
+        // parsed as unit "large") or in the name.
+        for (size, grams) in [("small", 38.0), ("medium", 44.0), ("large", 50.0), ("extra large", 56.0), ("jumbo", 63.0)] {
+            sizes = sizes
+                .with_size(&ContainerSize { container: size.to_string(), ingredient: Some("egg".to_string()), grams })
+                .with_size(&ContainerSize { container: String::new(), ingredient: Some(format!("{} egg", size)), grams });
+        }
+        sizes
+    }
+}
+
+impl ContainerSizes {
+    /// Sets the grams of `size`, replacing the entry of the same container and ingredient; zero
+    /// or less removes it, so such quantities go back to the LLM.
+    pub fn with_size(mut self, size: &ContainerSize) -> Self {
+        let ingredient = size.ingredient.as_deref().map_or(String::new(), normalize_ingredient_name);
+        let key = (canonical_container(&size.container), ingredient);
+        if size.grams > 0.0 {
+            self.grams.insert(key, size.grams);
+        } else {
+            self.grams.remove(&key);
+        }
+        self
+    }
+
+    /// Grams in one `unit` of `ingredient_name`, from the entry of that container with the longest
+    /// ingredient keyword found in the name, with that keyword ("" when the entry fits any ingredient).
+    /// Without a unit the keyword must be the name's head noun: "2 eggs" is a count of eggs,
+    /// "2 egg noodle nests" is not.
+    pub fn grams_for(&self, unit: &str, ingredient_name: &str) -> Option<(&str, f32)> {
+        let container = canonical_container(unit);
+        let normalized = normalize_ingredient_name(ingredient_name);
+        let names_keyword = |keyword: &str| if container.is_empty() {
+            ends_with_words(&normalized, keyword)
+        } else {
+            contains_words(&normalized, keyword)
+        };
+        self.grams.iter()
+            .filter(|((entry_container, keyword), _)| {
+                *entry_container == container && (keyword.is_empty() || names_keyword(keyword))
+            })
+            .max_by_key(|((_, keyword), _)| keyword.len())
+            .map(|((_, keyword), grams)| (keyword.as_str(), *grams))
+    }
+}
+
+/// Singular, lowercase container name, with "tin" for a can and "pack", "packet" or "pkg" for a package.
+fn canonical_container(unit: &str) -> String {
+    let container = normalize_ingredient_name(unit.trim().trim_end_matches('.'));
+    match container.as_str() {
+        "tin" => "can".to_string(),
+        "pack" | "packet" | "pkg" => "package".to_string(),
+        _ => container,
+    }
+}
+
+/// Grams of a quantity counted in containers the table knows.
+fn container_conversion(ingredient: &ParsedIngredient, sizes: &ContainerSizes) -> Option<GramConversionResponse> {
+    let count = parse_quantity(&ingredient.quantity)?;
+    let (keyword, grams) = sizes.grams_for(&ingredient.unit, &ingredient.ingredient_name)?;
+    let container = match (canonical_container(&ingredient.unit), keyword) {
+        (unit, keyword) if unit.is_empty() => keyword.to_string(),
+        (unit, "") => unit,
+        (unit, keyword) => format!("{} of {}", unit, keyword),
+    };
+    Some(GramConversionResponse {
+        grams: Some(count * grams),
+        notes: format!("Converted from the container-size table: {} g per {}.", grams, container),
+    })
+}
+
+/// The assumed grams for a seasoning measured "to taste" with no amount of its own.
+fn to_taste_conversion(ingredient: &ParsedIngredient, defaults: &ToTasteDefaults) -> Option<GramConversionResponse> {
+    if !ingredient.is_to_taste() || parse_quantity(&ingredient.quantity).is_some() {
+        return None;
+    }
+    let (keyword, grams) = defaults.grams_for(&ingredient.ingredient_name)?;
+    Some(GramConversionResponse {
+        grams: Some(grams),
+        notes: format!("Measured to taste; assumed the default of {} g for '{}'.", grams, keyword),
+    })
+}
+
+/// Built-in system prompt of the gram conversions; see `PromptKind::ConverterSystem`.
+pub const CONVERSION_SYSTEM_PROMPT: &str = "You are an expert unit conversion assistant. Output JSON.";
+
+/// Built-in user prompt of the gram conversions; see `PromptKind::ConverterUser`.
+pub const CONVERSION_USER_PROMPT: &str = "/no_thinking
 You are a unit conversion assistant. Your task is to convert the given ingredient quantity to grams.
-Ingredient Name: \"{}\"
-Quantity: \"{}\"
-Unit: \"{}\"
-Preparation Notes: \"{}\"
+Ingredient Name: \"{ingredient_name}\"
+Quantity: \"{quantity}\"
+Unit: \"{unit}\"
+Preparation Notes: \"{preparation_notes}\"
 
 Consider common food densities and typical weights for items specified by count (e.g., '1 large egg').
 If the unit is already in grams (g), simply return that value.
 If a direct conversion is impossible, highly ambiguous, or the unit is not a measure of mass/volume (e.g. 'to taste'), return null for grams and explain in notes.
-Respond ONLY with a JSON object strictly adhering to the provided schema: {{ \"grams\": float_or_null, \"notes\": \"string_explanation\" }}.",
-            ingredient.ingredient_name,
-            ingredient.quantity,
-            ingredient.unit,
-            ingredient.preparation_notes
-        );
+Respond ONLY with a JSON object strictly adhering to the provided schema: { \"grams\": float_or_null, \"notes\": \"string_explanation\" }.";
+
+/// Asks the LLM for the ingredient's grams. Parse failures are retried up to
+/// `options.parse_retries` times; the error is the conversion source and notes to record.
+async fn convert_with_llm(
+    ingredient: &ParsedIngredient,
+    client: &impl ChatClient,
+    options: &ConversionOptions,
+    progress_updater: &impl Fn(ProgressEvent),
+) -> Result<GramConversionResponse, (&'static str, String)> {
+    let ConversionOptions { parse_retries, gen_params, json_mode, response_format, prompts, .. } = options;
+    let conversion_prompt = prompts.render(PromptKind::ConverterUser, &[
+        ("ingredient_name", &ingredient.ingredient_name),
+        ("quantity", &ingredient.quantity),
+        ("unit", &ingredient.unit),
+        ("preparation_notes", &ingredient.preparation_notes),
+    ]);
 
+    let mut messages = vec![
+        ChatMessage {
+            role: "system".to_string(),
+            content: prompts.render(PromptKind::ConverterSystem, &[]),
+        },
+        ChatMessage {
+            role: "user".to_string(),
+            content: conversion_prompt,
+        },
+    ];
+
+    // Parse failures are retried up to `parse_retries` times, showing the model its malformed output.
+    let mut retries_left = *parse_retries;
+    loop {
         let request = ChatCompletionRequest {
             model: "qwen/qwen3-32b".to_string(),
-            messages: vec![
-                ChatMessage {
-                    role: "system".to_string(),
-                    content: "You are an expert unit conversion assistant. Output JSON.".to_string(), 
-                },
-                ChatMessage {
-                    role: "user".to_string(),
-                    content: conversion_prompt,
-                },
-            ],
-            response_format: Some(ResponseFormat {
-                format_type: "json_schema".to_string(),
-                json_schema: Some(get_gram_conversion_json_schema()),
-            }),
-            temperature: Some(0.0), 
-            max_tokens: Some(150),  
+            messages: messages.clone(),
+            response_format: Some(ResponseFormat::for_schema(get_gram_conversion_json_schema(), *response_format)),
+            temperature: Some(gen_params.temperature),
+            max_tokens: Some(gen_params.max_tokens),
         };
 
-        match provider.call_chat_completion(request).await {
-            Ok(response) => {
-                if let Some(choice) = response.choices.first() {
-                    let mut content_str = choice.message.content.trim().to_string();
-                    if content_str.starts_with("```json") && content_str.ends_with("```") {
-                        content_str = content_str.trim_start_matches("```json").trim_end_matches("```").trim().to_string();
-                    } else if content_str.starts_with("```") && content_str.ends_with("```") {
-                        content_str = content_str.trim_start_matches("```").trim_end_matches("```").trim().to_string();
-                    }
-
-                    match serde_json::from_str::<GramConversionResponse>(&content_str) {
-                        Ok(conv_response) => {
-                            progress_updater(format!(
-                                " -> Converted: {:?} grams. Notes: {}",
-                                conv_response.grams, conv_response.notes
-                            ));
-                            cleaned_ingredients.push(CleanedIngredient {
-                                raw_text: ingredient.raw_text.clone(),
-                                ingredient_name: ingredient.ingredient_name.clone(),
-                                original_quantity: ingredient.quantity.clone(),
-                                original_unit: ingredient.unit.clone(),
-                                preparation_notes: ingredient.preparation_notes.clone(),
-                                quantity_grams: conv_response.grams,
-                                conversion_source: "LLM".to_string(),
-                                conversion_notes: Some(conv_response.notes),
-                                nutritional_info: None, 
-                            });
-                        }
-                        Err(e) => {
-                            progress_updater(format!(
-                                " -> Failed to parse LLM conversion response for '{}': {}. Raw: {}",
-                                ingredient.ingredient_name, e, content_str
-                            ));
-                            cleaned_ingredients.push(CleanedIngredient {
-                                raw_text: ingredient.raw_text.clone(),
-                                ingredient_name: ingredient.ingredient_name.clone(),
-                                original_quantity: ingredient.quantity.clone(),
-                                original_unit: ingredient.unit.clone(),
-                                preparation_notes: ingredient.preparation_notes.clone(),
-                                quantity_grams: None,
-                                conversion_source: "LLM_Error".to_string(),
-                                conversion_notes: Some(format!("Failed to parse LLM response: {}. Raw: {}", e, content_str)),
-                                nutritional_info: None, 
-                            });
-                        }
-                    }
-                } else {
-                    progress_updater(format!(
-                        " -> No response choice from LLM for '{}'",
-                        ingredient.ingredient_name
-                    ));
-                     cleaned_ingredients.push(CleanedIngredient {
-                        raw_text: ingredient.raw_text.clone(),
-                        ingredient_name: ingredient.ingredient_name.clone(),
-                        original_quantity: ingredient.quantity.clone(),
-                        original_unit: ingredient.unit.clone(),
-                        preparation_notes: ingredient.preparation_notes.clone(),
-                        quantity_grams: None,
-                        conversion_source: "LLM_Error".to_string(),
-                        conversion_notes: Some("No response choice from LLM.".to_string()),
-                        nutritional_info: None, 
-                    });
-                }
-            }
+        let response = match complete_untruncated(client, request, TRUNCATION_RETRIES).await {
+            Ok(response) => response,
             Err(e) => {
-                progress_updater(format!(
+                progress_updater(ProgressEvent::Warning { text: format!(
                     " -> API call failed for '{}': {}",
                     ingredient.ingredient_name, e
-                ));
-                cleaned_ingredients.push(CleanedIngredient {
-                    raw_text: ingredient.raw_text.clone(),
-                    ingredient_name: ingredient.ingredient_name.clone(),
-                    original_quantity: ingredient.quantity.clone(),
-                    original_unit: ingredient.unit.clone(),
-                    preparation_notes: ingredient.preparation_notes.clone(),
-                    quantity_grams: None,
-                    conversion_source: "API_Error".to_string(),
-                    conversion_notes: Some(format!("API call failed: {}", e)),
-                    nutritional_info: None, 
+                ) });
+                break Err(("API_Error", format!("API call failed: {}", e)));
+            }
+        };
+        log_stage_usage("conversion", response.usage.as_ref());
+        let Some(choice) = response.choices.first() else {
+            progress_updater(format!(
+                " -> No response choice from LLM for '{}'",
+                ingredient.ingredient_name
+            ).into());
+            break Err(("LLM_Error", "No response choice from LLM.".to_string()));
+        };
+
+        let raw_content = choice.message.content.trim();
+        let content_str = json_mode.payload(raw_content);
+
+        match serde_json::from_str::<GramConversionResponse>(content_str) {
+            Ok(conv_response) => break Ok(conv_response),
+            Err(e) if retries_left > 0 => {
+                retries_left -= 1;
+                progress_updater(format!(
+                    " -> Invalid JSON for '{}' ({}). Retrying with a stricter prompt...",
+                    ingredient.ingredient_name, e
+                ).into());
+                messages.push(ChatMessage {
+                    role: "assistant".to_string(),
+                    content: choice.message.content.clone(),
+                });
+                messages.push(ChatMessage {
+                    role: "user".to_string(),
+                    content: format!(
+                        "Your previous response was not valid JSON ({}). Respond with ONLY the JSON object {{ \"grams\": float_or_null, \"notes\": \"string_explanation\" }}, with no markdown, comments or other text.",
+                        e
+                    ),
                 });
             }
+            Err(e) => {
+                progress_updater(ProgressEvent::Warning { text: format!(
+                    " -> Failed to parse LLM conversion response for '{}': {}. Raw: {}",
+                    ingredient.ingredient_name, e, content_str
+                ) });
+                break Err(("LLM_Error", format!("Failed to parse LLM response: {}. Raw: {}", e, content_str)));
+            }
         }
     }
+}
+
+/// Default number of reprompts when the model's gram conversion isn't valid JSON.
+pub const DEFAULT_CONVERSION_PARSE_RETRIES: u32 = 1;
+
+/// Settings of `convert_ingredients_to_grams`.
+#[derive(Debug, Clone)]
+pub struct ConversionOptions {
+    /// Reprompts allowed when a gram conversion answer isn't valid JSON.
+    pub parse_retries: u32,
+    pub gen_params: GenParams,
+    /// How the conversion answers are read.
+    pub json_mode: JsonMode,
+    /// How the conversion requests ask for their answer's schema.
+    pub response_format: ResponseFormatMode,
+    /// Templates of the conversion prompts.
+    pub prompts: PromptTemplates,
+    /// Grams assumed for seasonings measured "to taste".
+    pub to_taste_defaults: ToTasteDefaults,
+    /// Grams per container for cans, packages, sticks and eggs.
+    pub container_sizes: ContainerSizes,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        ConversionOptions {
+            parse_retries: DEFAULT_CONVERSION_PARSE_RETRIES,
+            gen_params: GenParams::CONVERSION,
+            json_mode: JsonMode::default(),
+            response_format: ResponseFormatMode::default(),
+            prompts: PromptTemplates::default(),
+            to_taste_defaults: ToTasteDefaults::default(),
+            container_sizes: ContainerSizes::default(),
+        }
+    }
+}
+
+pub async fn convert_ingredients_to_grams(
+    parsed_recipe: &ParsedRecipe,
+    client: &impl ChatClient,
+    options: &ConversionOptions,
+    progress_updater: impl Fn(ProgressEvent) + Send + Sync + 'static, 
+) -> Result<CleanedRecipe, anyhow::Error> {
+    let mut cleaned_ingredients: Vec<CleanedIngredient> = Vec::new();
+
+    for (index, ingredient) in parsed_recipe.ingredients.iter().enumerate() {
+        progress_updater(ProgressEvent::Step {
+            stage: ProgressStage::Conversion,
+            index: index as u32 + 1,
+            total: parsed_recipe.ingredients.len() as u32,
+            label: format!("{} {} {}", ingredient.quantity, ingredient.unit, ingredient.ingredient_name),
+        });
+
+        let conversion_result = match to_taste_conversion(ingredient, &options.to_taste_defaults) {
+            Some(default) => Ok((default, "Default")),
+            None => match local_gram_conversion(&ingredient.quantity, &ingredient.unit) {
+                Some(local) => Ok((local, "Local")),
+                None => match container_conversion(ingredient, &options.container_sizes) {
+                    Some(container) => Ok((container, "Container")),
+                    None => convert_with_llm(ingredient, client, options, &progress_updater).await
+                        .map(|conv_response| (conv_response, "LLM")),
+                },
+            },
+        };
+        let (quantity_grams, conversion_source, conversion_notes) = match conversion_result {
+            Ok((conv_response, source)) => {
+                progress_updater(ProgressEvent::IngredientConverted {
+                    ingredient: ingredient.ingredient_name.clone(),
+                    grams: conv_response.grams,
+                    notes: conv_response.notes.clone(),
+                });
+                (conv_response.grams, source, conv_response.notes)
+            }
+            Err((source, notes)) => (None, source, notes),
+        };
+        cleaned_ingredients.push(CleanedIngredient {
+            raw_text: ingredient.raw_text.clone(),
+            ingredient_name: ingredient.ingredient_name.clone(),
+            original_quantity: ingredient.quantity.clone(),
+            original_unit: ingredient.unit.clone(),
+            preparation_notes: ingredient.preparation_notes.clone(),
+            quantity_grams,
+            conversion_source: conversion_source.to_string(),
+            conversion_notes: Some(conversion_notes),
+            nutritional_info: None, 
+            section: ingredient.section.clone(),
+            optional: ingredient.is_optional(),
+        });
+    }
 
     Ok(CleanedRecipe {
         recipe_title: parsed_recipe.recipe_title.clone(),
         ingredients: cleaned_ingredients,
         instructions: parsed_recipe.instructions.clone(),
+        servings: parsed_recipe.servings,
+        total_time_minutes: parsed_recipe.total_time_minutes,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::client::MockChatClient;
+    use crate::recipe_parser::ParsedIngredient;
+
+    fn ingredient(name: &str, quantity: &str, unit: &str) -> ParsedIngredient {
+        ParsedIngredient {
+            raw_text: format!("{} {} {}", quantity, unit, name),
+            ingredient_name: name.to_string(),
+            quantity: quantity.to_string(),
+            unit: unit.to_string(),
+            preparation_notes: String::new(),
+            section: None,
+            optional: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_convert_ingredients_to_grams_with_mock_client() {
+        let parsed = ParsedRecipe {
+            recipe_title: "Pancakes".to_string(),
+            ingredients: vec![ingredient("flour", "1", "cup"), ingredient("onion", "1", "large"), ingredient("salt", "1", "pinch")],
+            instructions: vec!["Mix.".to_string()],
+            servings: Some(2),
+            total_time_minutes: None,
+            heuristically_parsed: false,
+        };
+        let client = MockChatClient::new([
+            "grams: 120",
+            r#"{"grams": 120.0, "notes": "1 cup of flour"}"#,
+            r#"<think>A large onion is about 150 g.</think>{"grams": 150, "notes": "large onion"}"#,
+        ]);
+
+        let cleaned = convert_ingredients_to_grams(&parsed, &client, &ConversionOptions::default(), |_| {}).await.unwrap();
+        assert_eq!(cleaned.servings, Some(2));
+        assert_eq!(cleaned.ingredients[0].quantity_grams, Some(120.0));
+        assert_eq!(cleaned.ingredients[1].quantity_grams, Some(150.0));
+        assert_eq!(cleaned.ingredients[1].conversion_source, "LLM");
+        // The mock has run out of responses by the third ingredient.
+        assert_eq!(cleaned.ingredients[2].quantity_grams, None);
+        assert_eq!(cleaned.ingredients[2].conversion_source, "API_Error");
+
+        // The retry shows the model its malformed answer before asking again.
+        let requests = client.requests();
+        assert_eq!(requests.len(), 4);
+        let retry = &requests[1].messages;
+        assert_eq!(retry.len(), 4);
+        assert_eq!(retry[2].role, "assistant");
+        assert_eq!(retry[2].content, "grams: 120");
+    }
+
+    #[tokio::test]
+    async fn test_mass_and_volume_units_convert_locally() {
+        let parsed = ParsedRecipe {
+            recipe_title: "Crêpes".to_string(),
+            ingredients: vec![
+                ingredient("flour", "0,25", "kg"),
+                ingredient("milk", "50", "cl"),
+                ingredient("sugar", "30", "gr"),
+                ingredient("oil", "2", "Tbsp"),
+                ingredient("parsley", "1", "bunch"),
+                ingredient("water", "1,000", "g"),
+            ],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+            heuristically_parsed: false,
+        };
+        let client = MockChatClient::new([
+            r#"{"grams": 25.0, "notes": "A bunch of parsley"}"#,
+            r#"{"grams": 1000.0, "notes": "1,000 g is a thousand grams"}"#,
+        ]);
+
+        let cleaned = convert_ingredients_to_grams(&parsed, &client, &ConversionOptions { parse_retries: 0, ..Default::default() }, |_| {}).await.unwrap();
+        let grams: Vec<Option<f32>> = cleaned.ingredients.iter().map(|ing| ing.quantity_grams).collect();
+        assert_eq!(grams, vec![Some(250.0), Some(500.0), Some(30.0), Some(30.0), Some(25.0), Some(1000.0)]);
+        let sources: Vec<&str> = cleaned.ingredients.iter().map(|ing| ing.conversion_source.as_str()).collect();
+        assert_eq!(sources, vec!["Local", "Local", "Local", "Local", "LLM", "LLM"]);
+        // Only the unit without a known size and the ambiguous comma needed the model.
+        assert_eq!(client.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_strict_json_conversion_keeps_the_raw_answer() {
+        let parsed = ParsedRecipe {
+            recipe_title: "Toast".to_string(),
+            ingredients: vec![ingredient("bread", "2", "slices")],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+            heuristically_parsed: false,
+        };
+        let fenced = "```json\n{\"grams\": 60, \"notes\": \"2 slices\"}\n```";
+        let cleaned = convert_ingredients_to_grams(&parsed, &MockChatClient::new([fenced]), &ConversionOptions { parse_retries: 0, ..Default::default() }, |_| {}).await.unwrap();
+        assert_eq!(cleaned.ingredients[0].quantity_grams, Some(60.0));
+
+        let cleaned = convert_ingredients_to_grams(&parsed, &MockChatClient::new([fenced]), &ConversionOptions { parse_retries: 0, json_mode: JsonMode::Strict, ..Default::default() }, |_| {}).await.unwrap();
+        assert_eq!(cleaned.ingredients[0].quantity_grams, None);
+        assert_eq!(cleaned.ingredients[0].conversion_source, "LLM_Error");
+        assert!(cleaned.ingredients[0].conversion_notes.as_deref().unwrap().contains(fenced));
+    }
+
+    #[tokio::test]
+    async fn test_to_taste_seasonings_get_default_grams() {
+        let mut salt = ingredient("sea salt", "", "");
+        salt.raw_text = "sea salt, to taste".to_string();
+        salt.preparation_notes = "to taste".to_string();
+        let mut pepper = ingredient("black pepper", "to taste", "");
+        pepper.raw_text = "black pepper to taste".to_string();
+        let mut capers = ingredient("capers", "1", "handful");
+        capers.raw_text = "1 handful capers (optional)".to_string();
+        let parsed = ParsedRecipe {
+            recipe_title: "Salad".to_string(),
+            ingredients: vec![salt, pepper, capers],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+            heuristically_parsed: false,
+        };
+        let client = MockChatClient::new([r#"{"grams": 9.0, "notes": "A handful of capers"}"#]);
+
+        let defaults = ToTasteDefaults::default().with_override("salt", 2.0);
+        let cleaned = convert_ingredients_to_grams(&parsed, &client, &ConversionOptions { parse_retries: 0, to_taste_defaults: defaults, ..Default::default() }, |_| {}).await.unwrap();
+        let grams: Vec<Option<f32>> = cleaned.ingredients.iter().map(|ing| ing.quantity_grams).collect();
+        assert_eq!(grams, vec![Some(2.0), Some(0.5), Some(9.0)]);
+        assert_eq!(cleaned.ingredients[0].conversion_source, "Default");
+        assert!(cleaned.ingredients[1].conversion_notes.as_deref().unwrap().contains("'pepper'"));
+        let optional: Vec<bool> = cleaned.ingredients.iter().map(|ing| ing.optional).collect();
+        assert_eq!(optional, vec![false, false, true]);
+        assert_eq!(client.requests().len(), 1);
+
+        // A disabled keyword goes back to the LLM.
+        let defaults = ToTasteDefaults::default().with_override("pepper", 0.0);
+        assert_eq!(defaults.grams_for("black pepper"), None);
+        assert_eq!(defaults.grams_for("Smoked Paprika"), Some(("paprika", 0.5)));
+    }
+
+    #[tokio::test]
+    async fn test_containers_convert_from_the_size_table() {
+        let parsed = ParsedRecipe {
+            recipe_title: "Shakshuka".to_string(),
+            ingredients: vec![
+                ingredient("diced tomatoes", "2", "cans"),
+                ingredient("tuna", "1", "tin"),
+                ingredient("butter", "1/2", "stick"),
+                ingredient("eggs", "4", "large"),
+                ingredient("medium eggs", "2", ""),
+                ingredient("feta", "1", "package"),
+            ],
+            instructions: vec![],
+            servings: None,
+            total_time_minutes: None,
+            heuristically_parsed: false,
+        };
+        let client = MockChatClient::new([r#"{"grams": 200.0, "notes": "1 package of feta"}"#]);
+
+        let sizes = ContainerSizes::default()
+            .with_size(&ContainerSize { container: "can".to_string(), ingredient: Some("tomatoes".to_string()), grams: 411.0 });
+        let cleaned = convert_ingredients_to_grams(&parsed, &client, &ConversionOptions { parse_retries: 0, container_sizes: sizes.clone(), ..Default::default() }, |_| {}).await.unwrap();
+        let grams: Vec<Option<f32>> = cleaned.ingredients.iter().map(|ing| ing.quantity_grams).collect();
+        assert_eq!(grams, vec![Some(822.0), Some(140.0), Some(56.5), Some(200.0), Some(88.0), Some(200.0)]);
+        let sources: Vec<&str> = cleaned.ingredients.iter().map(|ing| ing.conversion_source.as_str()).collect();
+        assert_eq!(sources, vec!["Container", "Container", "Container", "Container", "Container", "LLM"]);
+        assert_eq!(
+            cleaned.ingredients[0].conversion_notes.as_deref(),
+            Some("Converted from the container-size table: 411 g per can of tomato.")
+        );
+        // A package of an unknown ingredient still defers to the model.
+        assert_eq!(client.requests().len(), 1);
+
+        let sizes = sizes.with_size(&ContainerSize { container: "stick".to_string(), ingredient: Some("butter".to_string()), grams: 0.0 });
+        assert_eq!(sizes.grams_for("stick", "butter"), None);
+        assert_eq!(sizes.grams_for("Cans", "chickpeas"), Some(("", 400.0)));
+        assert_eq!(sizes.grams_for("", "egg yolks"), Some(("egg yolk", 17.0)));
+        assert_eq!(sizes.grams_for("", "egg noodle nests"), None);
+    }
+}
@@ -0,0 +1,336 @@
+//! Recipes from web pages: structured schema.org `Recipe` data when the page has it, readable
+//! page text for the LLM parser otherwise.
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use crate::recipe_parser::{parse_ingredient_line, ParsedRecipe};
+
+const USER_AGENT: &str = "Mozilla/5.0 (compatible; RecipeOptim/0.1)";
+const JSON_LD_SCRIPT_TYPE: &str = "application/ld+json";
+// Elements whose content is never readable text.
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style", "noscript", "svg", "template", "head"];
+// Elements that start a new line in the extracted text.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p", "div", "br", "li", "tr", "h1", "h2", "h3", "h4", "h5", "h6", "section", "article", "header",
+    "footer", "ul", "ol", "table", "blockquote",
+];
+
+/// What a recipe page yielded.
+#[derive(Debug, Clone)]
+pub enum WebRecipe {
+    /// A schema.org `Recipe` embedded as JSON-LD, already mapped to a recipe; no LLM parse needed.
+    Structured(ParsedRecipe),
+    /// The page's readable text, to go through the recipe parser.
+    Text(String),
+}
+
+/// Downloads a recipe page and extracts its recipe.
+pub async fn fetch_recipe(url: &str) -> Result<WebRecipe> {
+    let response = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()?
+        .get(url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to fetch recipe page {}", url))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow!("Fetching recipe page {} failed with HTTP {}", url, status));
+    }
+    let html = response.text().await
+        .with_context(|| format!("Failed to read recipe page {}", url))?;
+    recipe_from_html(&html).with_context(|| format!("No recipe found at {}", url))
+}
+
+/// Prefers JSON-LD recipe data and falls back to the page text. Pages without either are an error.
+pub fn recipe_from_html(html: &str) -> Result<WebRecipe> {
+    if let Some(recipe) = recipe_from_json_ld(html) {
+        return Ok(WebRecipe::Structured(recipe));
+    }
+    let text = html_to_text(html);
+    if text.is_empty() {
+        return Err(anyhow!("The page has no recipe data and no readable text"));
+    }
+    Ok(WebRecipe::Text(text))
+}
+
+/// The first schema.org `Recipe` with ingredients found in the page's JSON-LD blocks.
+pub fn recipe_from_json_ld(html: &str) -> Option<ParsedRecipe> {
+    json_ld_blocks(html)
+        .filter_map(|block| serde_json::from_str::<Value>(block.trim()).ok())
+        .find_map(|value| find_recipe_node(&value).and_then(parse_recipe_node))
+}
+
+/// Contents of the `<script type="application/ld+json">` elements.
+fn json_ld_blocks(html: &str) -> impl Iterator<Item = &str> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut blocks = Vec::new();
+    let mut position = 0;
+    while let Some(start) = lowercase[position..].find("<script").map(|offset| position + offset) {
+        let Some(tag_end) = lowercase[start..].find('>').map(|offset| start + offset + 1) else { break };
+        let Some(end) = lowercase[tag_end..].find("</script").map(|offset| tag_end + offset) else { break };
+        if lowercase[start..tag_end].contains(JSON_LD_SCRIPT_TYPE) {
+            blocks.push(&html[tag_end..end]);
+        }
+        position = end;
+    }
+    blocks.into_iter()
+}
+
+/// Searches nested objects, arrays and `@graph` lists for a node typed `Recipe`.
+fn find_recipe_node(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Array(items) => items.iter().find_map(find_recipe_node),
+        Value::Object(object) => {
+            let is_recipe = match object.get("@type") {
+                Some(Value::String(kind)) => kind == "Recipe",
+                Some(Value::Array(kinds)) => kinds.iter().any(|kind| kind == "Recipe"),
+                _ => false,
+            };
+            if is_recipe {
+                Some(value)
+            } else {
+                object.get("@graph").and_then(find_recipe_node)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn parse_recipe_node(node: &Value) -> Option<ParsedRecipe> {
+    let ingredients: Vec<_> = node.get("recipeIngredient")
+        .or_else(|| node.get("ingredients"))
+        .map(strings_of)
+        .unwrap_or_default()
+        .iter()
+        .map(|line| parse_ingredient_line(&decode_entities(line), None))
+        .filter(|ingredient| !ingredient.raw_text.is_empty())
+        .collect();
+    if ingredients.is_empty() {
+        return None;
+    }
+
+    let mut instructions = Vec::new();
+    if let Some(value) = node.get("recipeInstructions") {
+        collect_instructions(value, &mut instructions);
+    }
+
+    Some(ParsedRecipe {
+        recipe_title: node.get("name").and_then(Value::as_str).map(decode_entities).unwrap_or_default(),
+        ingredients,
+        instructions,
+        servings: node.get("recipeYield").and_then(servings_of),
+        total_time_minutes: node.get("totalTime")
+            .and_then(Value::as_str)
+            .and_then(iso8601_duration_minutes),
+        heuristically_parsed: false,
+    })
+}
+
+/// A string, or the strings of an array.
+fn strings_of(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(text) => vec![text.clone()],
+        Value::Array(items) => items.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Flattens plain strings, `HowToStep`s and `HowToSection`s into instruction lines.
+fn collect_instructions(value: &Value, instructions: &mut Vec<String>) {
+    match value {
+        Value::String(text) => instructions.extend(
+            html_to_text(text).lines().map(str::to_string),
+        ),
+        Value::Array(items) => items.iter().for_each(|item| collect_instructions(item, instructions)),
+        Value::Object(object) => {
+            if let Some(steps) = object.get("itemListElement") {
+                collect_instructions(steps, instructions);
+            } else if let Some(text) = object.get("text").or_else(|| object.get("name")) {
+                collect_instructions(text, instructions);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `recipeYield` is a number, a string such as "4 servings", or a list of those.
+fn servings_of(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(number) => number.as_u64().and_then(|servings| u32::try_from(servings).ok()),
+        Value::String(text) => text.split(|c: char| !c.is_ascii_digit())
+            .find(|digits| !digits.is_empty())
+            .and_then(|digits| digits.parse().ok()),
+        Value::Array(items) => items.iter().find_map(servings_of),
+        _ => None,
+    }
+    .filter(|&servings| servings > 0)
+}
+
+/// Minutes of an ISO 8601 duration such as "PT1H15M" or "P1DT2H". Seconds are dropped.
+fn iso8601_duration_minutes(duration: &str) -> Option<u32> {
+    let rest = duration.trim().strip_prefix('P')?;
+    let mut minutes = 0.0_f32;
+    let mut in_time = false;
+    let mut number = String::new();
+    for c in rest.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => number.push(c),
+            _ => {
+                let value: f32 = std::mem::take(&mut number).parse().ok()?;
+                minutes += match (c, in_time) {
+                    ('D', false) => value * 24.0 * 60.0,
+                    ('H', true) => value * 60.0,
+                    ('M', true) => value,
+                    ('S', true) => 0.0,
+                    _ => return None,
+                };
+            }
+        }
+    }
+    number.is_empty().then_some(minutes.round() as u32)
+}
+
+/// Readable text of an HTML document: scripts and styles dropped, tags removed, one line per block.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(tag_start) = rest.find('<') {
+        text.push_str(&rest[..tag_start]);
+        rest = &rest[tag_start..];
+        let Some(tag_end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[1..tag_end].trim().to_ascii_lowercase();
+        rest = &rest[tag_end + 1..];
+
+        let name: String = tag.trim_start_matches('/').chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+        if !tag.starts_with('/') && SKIPPED_ELEMENTS.contains(&name.as_str()) {
+            let closing = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(close) => &rest[close..],
+                None => "",
+            };
+        } else if BLOCK_ELEMENTS.contains(&name.as_str()) {
+            text.push('\n');
+        }
+    }
+    text.push_str(rest);
+
+    decode_entities(&text)
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decodes the named entities common in recipe pages and all numeric ones.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest.find(';')
+            .filter(|&semicolon| semicolon <= 10)
+            .and_then(|semicolon| Some((entity_char(&rest[1..semicolon])?, semicolon)));
+        match entity {
+            Some((c, semicolon)) => {
+                decoded.push(c);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn entity_char(entity: &str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "frac12" => '½',
+        "frac14" => '¼',
+        "frac34" => '¾',
+        "deg" => '°',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recipe_from_json_ld() {
+        let html = r#"<html><head>
+<script type="application/ld+json">{"@type": "WebSite", "name": "Food blog"}</script>
+<script type="application/ld+json">{"@context": "https://schema.org", "@graph": [
+  {"@type": "BreadcrumbList"},
+  {"@type": ["Recipe"], "name": "Banana Bread &amp; Walnuts",
+   "recipeYield": ["8", "1 loaf"], "totalTime": "PT1H15M",
+   "recipeIngredient": ["250 g flour", "3 ripe bananas, mashed", ""],
+   "recipeInstructions": [
+     {"@type": "HowToSection", "name": "Batter", "itemListElement": [
+       {"@type": "HowToStep", "text": "Mash the bananas."},
+       {"@type": "HowToStep", "text": "Fold in the flour."}]},
+     "Bake for 1 hour."]}
+]}</script></head><body>Ignored</body></html>"#;
+
+        let WebRecipe::Structured(recipe) = recipe_from_html(html).unwrap() else {
+            panic!("expected structured recipe data");
+        };
+        assert_eq!(recipe.recipe_title, "Banana Bread & Walnuts");
+        assert_eq!(recipe.servings, Some(8));
+        assert_eq!(recipe.total_time_minutes, Some(75));
+        assert_eq!(recipe.ingredients.len(), 2);
+        assert_eq!(recipe.ingredients[0].quantity, "250");
+        assert_eq!(recipe.ingredients[0].unit, "g");
+        assert_eq!(recipe.ingredients[1].ingredient_name, "ripe bananas");
+        assert_eq!(recipe.ingredients[1].preparation_notes, "mashed");
+        assert_eq!(recipe.instructions, vec!["Mash the bananas.", "Fold in the flour.", "Bake for 1 hour."]);
+        assert!(!recipe.heuristically_parsed);
+    }
+
+    #[test]
+    fn test_recipe_from_html_falls_back_to_text() {
+        let html = "<html><head><style>p { color: red; }</style></head><body>
+<script>var ingredients = [];</script>
+<h1>Crêpes</h1><ul><li>250&nbsp;g flour</li><li>2 eggs</li></ul><p>Whisk &amp; rest.<br>Cook.</p>
+</body></html>";
+        let WebRecipe::Text(text) = recipe_from_html(html).unwrap() else {
+            panic!("expected page text");
+        };
+        assert_eq!(text, "Crêpes\n250 g flour\n2 eggs\nWhisk & rest.\nCook.");
+
+        assert!(recipe_from_html("<html><script>only()</script></html>").is_err());
+    }
+
+    #[test]
+    fn test_iso8601_duration_minutes() {
+        assert_eq!(iso8601_duration_minutes("PT45M"), Some(45));
+        assert_eq!(iso8601_duration_minutes("P1DT2H"), Some(26 * 60));
+        assert_eq!(iso8601_duration_minutes("PT1H30M20S"), Some(90));
+        assert_eq!(iso8601_duration_minutes("45 minutes"), None);
+    }
+}
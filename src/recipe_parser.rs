@@ -1,11 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap; 
+use std::collections::BTreeMap; 
 use crate::api_connection::endpoints::{
-    ChatCompletionRequest, ChatMessage, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
-    Provider, // ResponseFormat no longer needed here for parse_recipe_text
+    ChatCompletionRequest, ChatMessage, GenParams, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
 };
+use crate::api_connection::client::{complete_untruncated, ChatClient, TRUNCATION_RETRIES};
+use crate::api_connection::usage::log_stage_usage;
 use crate::api_connection::connection::ApiConnectionError; 
+use crate::api_connection::json_extract::JsonMode;
 use anyhow::Result;
+use crate::{log_verbose, log_warning};
+use crate::prompts::{PromptKind, PromptTemplates};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ParsedIngredient {
@@ -14,6 +18,35 @@ pub struct ParsedIngredient {
     pub quantity: String,
     pub unit: String,
     pub preparation_notes: String,
+    /// Ingredient group from the source text (e.g. "sauce" for a "For the sauce:" header).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    /// The recipe marks the ingredient as optional; see `is_optional`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+/// True when `text` contains `phrase` (lowercase) as whole words.
+fn mentions(text: &str, phrase: &str) -> bool {
+    let text = text.to_lowercase();
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+impl ParsedIngredient {
+    /// Optional when flagged so, or when its text or notes say "optional".
+    pub fn is_optional(&self) -> bool {
+        self.optional || mentions(&self.raw_text, "optional") || mentions(&self.preparation_notes, "optional")
+    }
+
+    /// Measured "to taste" rather than by an amount ("salt, to taste").
+    pub fn is_to_taste(&self) -> bool {
+        [&self.quantity, &self.unit, &self.preparation_notes, &self.raw_text].iter()
+            .any(|text| mentions(text, "to taste"))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -22,6 +55,13 @@ pub struct ParsedRecipe {
     pub recipe_title: String,
     pub ingredients: Vec<ParsedIngredient>,
     pub instructions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub servings: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_time_minutes: Option<u32>,
+    /// Set when the recipe came from the rule-based fallback parser instead of the LLM.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub heuristically_parsed: bool,
 }
 
 // This function might become unused by parse_recipe_text if we fully remove schema enforcement.
@@ -43,7 +83,7 @@ fn get_recipe_json_schema() -> JsonSchemaDefinition {
         additional_properties: None,
     };
 
-    let mut recipe_properties_map = HashMap::new();
+    let mut recipe_properties_map = BTreeMap::new();
     recipe_properties_map.insert(
         "recipe_title".to_string(),
         JsonSchemaProperty {
@@ -57,10 +97,28 @@ fn get_recipe_json_schema() -> JsonSchemaDefinition {
         "ingredients".to_string(),
         JsonSchemaProperty {
             property_type: "array".to_string(),
-            description: Some("A list of ingredients. Each item in the array must be an object with the following string properties: 'raw_text', 'ingredient_name', 'quantity', 'unit', and 'preparation_notes'.".to_string()),
+            description: Some("A list of ingredients. Each item in the array must be an object with the following string properties: 'raw_text', 'ingredient_name', 'quantity', 'unit', and 'preparation_notes', plus an optional 'section' string naming the ingredient group and an optional 'optional' boolean.".to_string()),
             items: Some(Box::new(ingredient_item_schema)), 
             r#enum: None,
         },
+    );
+    recipe_properties_map.insert(
+        "servings".to_string(),
+        JsonSchemaProperty {
+            property_type: "integer".to_string(),
+            description: Some("Number of servings or portions the recipe yields, if stated.".to_string()),
+            r#enum: None,
+            items: None,
+        },
+    );
+    recipe_properties_map.insert(
+        "total_time_minutes".to_string(),
+        JsonSchemaProperty {
+            property_type: "integer".to_string(),
+            description: Some("Total preparation and cooking time in minutes, if stated.".to_string()),
+            r#enum: None,
+            items: None,
+        },
     );
      recipe_properties_map.insert(
         "instructions".to_string(),
@@ -88,15 +146,16 @@ fn get_recipe_json_schema() -> JsonSchemaDefinition {
     }
 }
 
-pub async fn parse_recipe_text(recipe_text: &str, api_key_env_var: &str) -> Result<ParsedRecipe, ApiConnectionError> {
-    let system_prompt = format!(
-        "/no_thinking
+/// Built-in system prompt of the recipe parser; see `PromptKind::ParserSystem`.
+pub const PARSER_SYSTEM_PROMPT: &str = "/no_thinking
 You are a recipe parsing assistant. Your task is to parse the given recipe text and extract its title, ingredients, and instructions.
 Return the output as a JSON object. The JSON object must be the only content in your response. Do not include any explanatory text, comments, or markdown formatting (like ```json) before or after the JSON object.
 The JSON object must have the following top-level properties:
 - \"recipe_title\": A string representing the title of the recipe.
 - \"ingredients\": An array of objects. Each object in this array represents a single ingredient.
 - \"instructions\": An array of strings, where each string is a distinct cooking instruction.
+- \"servings\": An integer with the number of servings or portions the recipe yields (e.g., 'Serves 4' -> 4), or null if not stated.
+- \"total_time_minutes\": An integer with the total time of the recipe in minutes (e.g., '1 hour 15 minutes' -> 75), or null if not stated.
 
 Each object in the \"ingredients\" array must have the following string properties:
 - \"raw_text\": The full, original text of the ingredient line.
@@ -104,13 +163,23 @@ Each object in the \"ingredients\" array must have the following string properti
 - \"quantity\": The amount specified (e.g., '2', '1/2', 'a pinch', '1-2').
 - \"unit\": The unit of measurement (e.g., 'cups', 'g', 'ml', 'large', 'clove', 'piece', or an empty string if unitless or descriptive like 'to taste').
 - \"preparation_notes\": Any additional notes on preparation or state (e.g., 'sifted', 'finely chopped', 'at room temperature', 'optional', or an empty string if none).
+- \"optional\": A boolean, true only when the recipe marks the ingredient as optional (e.g. '1 tbsp capers (optional)').
+- \"section\": The ingredient group this ingredient is listed under when the recipe groups its ingredients with headers such as 'For the sauce:' or 'Dough:' (e.g., 'sauce', 'dough'). Use null if the recipe has no such grouping.
 
-Ensure all specified fields are present in your JSON output. If a piece of information for an optional field (like 'preparation_notes' or 'unit' if not applicable) is not present in the recipe text, use an empty string for that field.
-Your response must start with {{ and end with }}.
-"
-    );
+Ensure all specified fields are present in your JSON output. If a piece of information for an optional field (like 'preparation_notes' or 'unit' if not applicable) is not present in the recipe text, use an empty string for that field (except 'section', which is null when absent, and 'optional', which is false).
+Your response must start with { and end with }.
+";
 
-    let provider = Provider::openrouter(api_key_env_var);
+/// Parses recipe text with the LLM. An answer that isn't a valid recipe falls back to
+/// `parse_recipe_heuristically`, except in `JsonMode::Strict` where it is an `InvalidJson` error.
+pub async fn parse_recipe_text(
+    recipe_text: &str,
+    client: &impl ChatClient,
+    gen_params: GenParams,
+    json_mode: JsonMode,
+    prompts: &PromptTemplates,
+) -> Result<ParsedRecipe, ApiConnectionError> {
+    let system_prompt = prompts.render(PromptKind::ParserSystem, &[]);
 
     let request = ChatCompletionRequest {
         model: "qwen/qwen3-32b".to_string(), 
@@ -125,44 +194,818 @@ Your response must start with {{ and end with }}.
             },
         ],
         response_format: None, // <<<< KEY CHANGE: No json_schema enforcement by the API
-        temperature: Some(0.05), 
-        max_tokens: Some(2048), 
+        temperature: Some(gen_params.temperature),
+        max_tokens: Some(gen_params.max_tokens),
     };
 
-    let response = provider.call_chat_completion(request).await?;
+    let response = complete_untruncated(client, request, TRUNCATION_RETRIES).await?;
+    log_stage_usage("parsing", response.usage.as_ref());
 
     if let Some(choice) = response.choices.first() {
-        let mut content_str = choice.message.content.trim().to_string(); 
-        println!("[DEBUG] Raw API Response Content:\n---\n{}\n---", content_str);
+        let raw_content = choice.message.content.trim();
+        log_verbose!("[DEBUG] Raw API Response Content:\n---\n{}\n---", raw_content);
 
-        // Attempt to strip markdown code fences if present
-        if content_str.starts_with("```json") && content_str.ends_with("```") {
-            content_str = content_str.trim_start_matches("```json").trim_end_matches("```").trim().to_string();
-            println!("[DEBUG] Content after stripping '```json...```':\n---\n{}\n---", content_str);
-        } else if content_str.starts_with("```") && content_str.ends_with("```") {
-            content_str = content_str.trim_start_matches("```").trim_end_matches("```").trim().to_string();
-            println!("[DEBUG] Content after stripping '```...```':\n---\n{}\n---", content_str);
-        }
+        // Unless strict, drop reasoning blocks, prose and markdown fences around the JSON object.
+        let content_str = json_mode.payload(raw_content).to_string();
+        log_verbose!("[DEBUG] Extracted JSON content:\n---\n{}\n---", content_str);
         
         if content_str.is_empty() {
-            eprintln!("[DEBUG] API response content is empty after stripping markdown.");
-            return Err(ApiConnectionError::ApiError {
-                status: reqwest::StatusCode::NO_CONTENT, 
-                error_body: "API returned empty content after stripping markdown.".to_string(),
-            });
+            log_verbose!("[DEBUG] API response content is empty.");
+            return Err(ApiConnectionError::api_error(reqwest::StatusCode::NO_CONTENT, "API returned empty content."));
         }
         
-        // The LLM might still not return perfect JSON, so this parsing can still fail.
-        serde_json::from_str(&content_str) 
-            .map_err(|e| {
-                eprintln!("[DEBUG] Failed to deserialize content. Error: {}. Content was:\n{}", e, content_str);
-                ApiConnectionError::SerializationError(e)
-            })
+        // The LLM might still not return perfect JSON; fall back to the rule-based parser so the run can continue.
+        match serde_json::from_str(&content_str) {
+            Ok(parsed_recipe) => Ok(parsed_recipe),
+            Err(e) if json_mode == JsonMode::Strict => {
+                Err(ApiConnectionError::InvalidJson { error: e.to_string(), content: content_str })
+            }
+            Err(e) => {
+                log_verbose!("[DEBUG] Failed to deserialize content. Error: {}. Content was:\n{}", e, content_str);
+                log_warning!("[WARNING] Falling back to heuristic recipe parsing.");
+                Ok(parse_recipe_heuristically(recipe_text))
+            }
+        }
     } else {
-        eprintln!("[DEBUG] No choices received from API response.");
-        Err(ApiConnectionError::ApiError { 
-            status: reqwest::StatusCode::INTERNAL_SERVER_ERROR, 
-            error_body: "No response choices received from API".to_string(),
+        log_verbose!("[DEBUG] No choices received from API response.");
+        Err(ApiConnectionError::api_error(reqwest::StatusCode::INTERNAL_SERVER_ERROR, "No response choices received from API"))
+    }
+}
+
+/// Default of `--max-ingredients`.
+pub const DEFAULT_MAX_INGREDIENTS: usize = 100;
+/// Rough number of characters per token of recipe text.
+const CHARS_PER_TOKEN: usize = 4;
+/// The parsed JSON repeats each ingredient line across several fields, so it runs about this many
+/// times longer than the text it comes from.
+const PARSE_OUTPUT_EXPANSION: usize = 3;
+
+/// How much recipe text goes to the parser at once, and how many ingredients a recipe may have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Ingredient lines parsed per LLM call; `None` parses the whole recipe in one call.
+    pub chunk_size: Option<usize>,
+    /// A recipe with more ingredients than this is an error rather than a recipe.
+    pub max_ingredients: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits { chunk_size: None, max_ingredients: DEFAULT_MAX_INGREDIENTS }
+    }
+}
+
+/// Rough number of tokens the parser's answer for `recipe_text` takes, to compare with its `max_tokens`.
+pub fn estimated_parse_tokens(recipe_text: &str) -> u32 {
+    (recipe_text.chars().count() * PARSE_OUTPUT_EXPANSION / CHARS_PER_TOKEN) as u32
+}
+
+/// The lines of a recipe around its ingredient list, which runs from an "Ingredients" heading to
+/// the next heading of another section; see `ends_ingredient_block`.
+struct IngredientBlock<'a> {
+    before: Vec<&'a str>,
+    heading: &'a str,
+    lines: Vec<&'a str>,
+    after: Vec<&'a str>,
+}
+
+impl<'a> IngredientBlock<'a> {
+    /// `None` when no ingredients heading delimits the list.
+    fn find(recipe_text: &'a str) -> Option<Self> {
+        let lines: Vec<&str> = recipe_text.lines().collect();
+        let start = lines.iter().position(|line| heading_of(line.trim()) == Some(HeuristicSection::Ingredients))?;
+        let end = lines[start + 1..].iter()
+            .position(|line| ends_ingredient_block(line.trim()))
+            .map_or(lines.len(), |offset| start + 1 + offset);
+        Some(IngredientBlock {
+            before: lines[..start].to_vec(),
+            heading: lines[start],
+            lines: lines[start + 1..end].iter().copied().filter(|line| !line.trim().is_empty()).collect(),
+            after: lines[end..].to_vec(),
         })
     }
+
+    fn is_ingredient(line: &str) -> bool {
+        section_header_of(line.trim()).is_none()
+    }
+
+    fn ingredient_count(&self) -> usize {
+        self.lines.iter().filter(|line| Self::is_ingredient(line)).count()
+    }
+
+    /// Recipe texts of at most `chunk_size` ingredients each. The first keeps everything around
+    /// the list; the others only the title, the heading and, when they continue a group, its header.
+    fn chunks(&self, chunk_size: usize) -> Vec<String> {
+        let title = self.before.iter().map(|line| line.trim()).find(|line| !line.is_empty()).unwrap_or_default();
+        let mut chunks: Vec<Vec<&str>> = Vec::new();
+        let mut group_header: Option<&str> = None;
+        let mut ingredients = 0;
+        for &line in &self.lines {
+            let chunk_is_full = ingredients % chunk_size == 0;
+            if !Self::is_ingredient(line) {
+                group_header = Some(line);
+                // A header at a chunk boundary opens the next chunk instead.
+                if !chunk_is_full {
+                    chunks.last_mut().expect("a chunk is open").push(line);
+                }
+                continue;
+            }
+            if chunk_is_full {
+                chunks.push(group_header.into_iter().collect());
+            }
+            chunks.last_mut().expect("a chunk is open").push(line);
+            ingredients += 1;
+        }
+
+        chunks.iter().enumerate()
+            .map(|(i, chunk)| {
+                let lines = chunk.join("\n");
+                if i == 0 {
+                    [self.before.join("\n"), self.heading.to_string(), lines, self.after.join("\n")].join("\n")
+                } else {
+                    format!("{}\n{}\n{}", title, self.heading, lines)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Number of ingredient lines under the recipe's "Ingredients" heading, if it has one.
+pub fn count_ingredient_lines(recipe_text: &str) -> Option<usize> {
+    IngredientBlock::find(recipe_text).map(|block| block.ingredient_count())
+}
+
+/// `parse_recipe_text`, sending at most `chunk_size` ingredient lines per call so long ingredient
+/// lists don't outgrow the parser's `max_tokens`. Title, instructions and metadata come from the
+/// first call; the ingredients of every call are joined in order. A recipe without an
+/// "Ingredients" heading, or short enough, is parsed in one call.
+pub async fn parse_recipe_text_in_chunks(
+    recipe_text: &str,
+    chunk_size: usize,
+    client: &impl ChatClient,
+    gen_params: GenParams,
+    json_mode: JsonMode,
+    prompts: &PromptTemplates,
+) -> Result<ParsedRecipe, ApiConnectionError> {
+    let chunks = match IngredientBlock::find(recipe_text) {
+        Some(block) if block.ingredient_count() > chunk_size => block.chunks(chunk_size.max(1)),
+        _ => return parse_recipe_text(recipe_text, client, gen_params, json_mode, prompts).await,
+    };
+    log_verbose!("[DEBUG] Parsing the recipe in {} chunks of up to {} ingredients.", chunks.len(), chunk_size);
+    let mut chunks = chunks.iter();
+    let first = chunks.next().expect("a recipe with ingredients has a first chunk");
+    let mut recipe = parse_recipe_text(first, client, gen_params, json_mode, prompts).await?;
+    for chunk in chunks {
+        let part = parse_recipe_text(chunk, client, gen_params, json_mode, prompts).await?;
+        recipe.ingredients.extend(part.ingredients);
+        recipe.heuristically_parsed |= part.heuristically_parsed;
+    }
+    Ok(recipe)
+}
+
+// --- Rule-based fallback parser ---
+
+const INGREDIENT_HEADINGS: &[&str] = &["ingredients", "ingredient"];
+const INSTRUCTION_HEADINGS: &[&str] = &["instructions", "directions", "method", "steps", "preparation"];
+const KNOWN_UNITS: &[&str] = &[
+    "g", "gram", "grams", "kg", "kilogram", "kilograms", "mg",
+    "ml", "millilitre", "milliliter", "millilitres", "milliliters", "cl", "dl", "l", "litre", "liter", "litres", "liters",
+    "cup", "cups", "tablespoon", "tablespoons", "tbsp", "tbs", "teaspoon", "teaspoons", "tsp",
+    "oz", "ounce", "ounces", "lb", "lbs", "pound", "pounds",
+    "pinch", "pinches", "dash", "clove", "cloves", "slice", "slices", "piece", "pieces",
+    "can", "cans", "package", "packages", "stick", "sticks", "bunch", "handful",
+    "large", "medium", "small",
+];
+
+/// Headings of page sections that can follow an ingredient list, besides the instructions.
+const OTHER_SECTION_HEADINGS: &[&str] = &["notes", "note", "nutrition", "tips", "comments", "reviews", "equipment", "storage", "variations"];
+const SERVINGS_PREFIXES: &[&str] = &["serves", "servings", "serving", "yield", "yields", "makes", "portions"];
+// A bare "Time" needs its colon, so instructions such as "Time to serve" aren't mistaken for metadata.
+const TIME_PREFIXES: &[&str] = &["total time", "time:", "ready in"];
+
+#[derive(PartialEq)]
+enum HeuristicSection {
+    Unknown,
+    Ingredients,
+    Instructions,
+}
+
+fn heading_of(line: &str) -> Option<HeuristicSection> {
+    let normalized = line.trim_start_matches('#').trim_end_matches(':').trim().to_lowercase();
+    if INGREDIENT_HEADINGS.contains(&normalized.as_str()) {
+        Some(HeuristicSection::Ingredients)
+    } else if INSTRUCTION_HEADINGS.contains(&normalized.as_str()) {
+        Some(HeuristicSection::Instructions)
+    } else {
+        None
+    }
+}
+
+/// Whether `line` heads a section other than the ingredients: a markdown heading, or a line whose
+/// first word names another section, as in "Instructions for the cake" or "Notes". Ingredient
+/// group headers such as "For the sauce:" don't end the list.
+fn ends_ingredient_block(line: &str) -> bool {
+    if line.starts_with('#') || heading_of(line) == Some(HeuristicSection::Instructions) {
+        return true;
+    }
+    line.split_whitespace().next()
+        .map(|word| word.trim_end_matches(':').to_lowercase())
+        .is_some_and(|word| INSTRUCTION_HEADINGS.contains(&word.as_str()) || OTHER_SECTION_HEADINGS.contains(&word.as_str()))
+}
+
+fn is_quantity_token(token: &str) -> bool {
+    let is_unicode_fraction = |c: char| matches!(c, '¼' | '½' | '¾' | '⅓' | '⅔' | '⅛');
+    !token.is_empty()
+        && token.chars().any(|c| c.is_ascii_digit() || is_unicode_fraction(c))
+        && token.chars().all(|c| c.is_ascii_digit() || is_unicode_fraction(c) || matches!(c, '/' | '.' | ',' | '-'))
+}
+
+fn unicode_fraction_value(c: char) -> Option<f32> {
+    match c {
+        '¼' => Some(0.25),
+        '½' => Some(0.5),
+        '¾' => Some(0.75),
+        '⅓' => Some(1.0 / 3.0),
+        '⅔' => Some(2.0 / 3.0),
+        '⅛' => Some(0.125),
+        _ => None,
+    }
+}
+
+/// Numeric value of a quantity such as "2", "1.5", "1,5", "1/2", "1 1/2", "½" or "1½".
+/// Ranges ("1-2") use the lower bound. Textual quantities ("half", "a pinch") give `None`, and so
+/// do ones like "1,000", where the comma may be a thousands separator or a decimal comma.
+pub fn parse_quantity(raw: &str) -> Option<f32> {
+    let lower_bound = raw.trim().split('-').next()?.trim();
+    if lower_bound.is_empty() {
+        return None;
+    }
+    let token_value = |token: &str| -> Option<f32> {
+        if let Some((numerator, denominator)) = token.split_once('/') {
+            let denominator: f32 = denominator.parse().ok()?;
+            return (denominator != 0.0).then_some(numerator.parse::<f32>().ok()? / denominator);
+        }
+        match token.chars().last().and_then(unicode_fraction_value) {
+            Some(fraction) => {
+                let whole = &token[..token.len() - token.chars().last()?.len_utf8()];
+                Some(if whole.is_empty() { fraction } else { whole.parse::<f32>().ok()? + fraction })
+            }
+            None => match token.split_once(',') {
+                Some((_, decimals)) if decimals.len() == 3 && decimals.bytes().all(|b| b.is_ascii_digit()) => None,
+                _ => token.replace(',', ".").parse().ok(),
+            },
+        }
+    };
+    let mut total = 0.0;
+    for token in lower_bound.split_whitespace() {
+        total += token_value(token)?;
+    }
+    total.is_finite().then_some(total)
+}
+
+/// True when `unit` (case-insensitive, trailing period allowed) is one of the units the parser knows.
+pub fn is_known_unit(unit: &str) -> bool {
+    let normalized = unit.trim().trim_end_matches('.').to_lowercase();
+    canonical_unit(&normalized).is_some() || KNOWN_UNITS.contains(&normalized.as_str())
+}
+
+/// Standard abbreviation of a mass or volume unit, whatever its spelling: "gr", "grammes" and "G"
+/// are all "g", "Tablespoons" is "tbsp", "litres" is "l". `None` for other units.
+pub fn canonical_unit(unit: &str) -> Option<&'static str> {
+    let canonical = match unit.trim().trim_end_matches('.').to_lowercase().as_str() {
+        "g" | "gr" | "grs" | "gram" | "grams" | "gramme" | "grammes" => "g",
+        "kg" | "kgs" | "kilo" | "kilos" | "kilogram" | "kilograms" | "kilogramme" | "kilogrammes" => "kg",
+        "mg" | "milligram" | "milligrams" | "milligramme" | "milligrammes" => "mg",
+        "oz" | "ounce" | "ounces" => "oz",
+        "lb" | "lbs" | "pound" | "pounds" => "lb",
+        "ml" | "millilitre" | "milliliter" | "millilitres" | "milliliters" => "ml",
+        "cl" | "centilitre" | "centiliter" | "centilitres" | "centiliters" => "cl",
+        "dl" | "décilitre" | "decilitre" | "deciliter" | "décilitres" | "decilitres" | "deciliters" => "dl",
+        "l" | "litre" | "liter" | "litres" | "liters" => "l",
+        "tbsp" | "tbs" | "tablespoon" | "tablespoons" => "tbsp",
+        "tsp" | "teaspoon" | "teaspoons" => "tsp",
+        _ => return None,
+    };
+    Some(canonical)
+}
+
+/// Grams in one `unit` for mass units (g, kg, mg, oz, lb and their spellings); `None` for volume
+/// or count units, whose weight depends on the ingredient.
+pub fn grams_per_unit(unit: &str) -> Option<f32> {
+    match canonical_unit(unit)? {
+        "g" => Some(1.0),
+        "kg" => Some(1000.0),
+        "mg" => Some(0.001),
+        "oz" => Some(28.3495),
+        "lb" => Some(453.592),
+        _ => None,
+    }
+}
+
+/// Millilitres in one `unit` for volume units (ml, cl, dl, l, tbsp, tsp and their spellings).
+pub fn millilitres_per_unit(unit: &str) -> Option<f32> {
+    match canonical_unit(unit)? {
+        "ml" => Some(1.0),
+        "cl" => Some(10.0),
+        "dl" => Some(100.0),
+        "l" => Some(1000.0),
+        "tbsp" => Some(15.0),
+        "tsp" => Some(5.0),
+        _ => None,
+    }
+}
+
+/// Brand names dropped by `normalize_ingredient_name`; words carrying ®, ™ or © are dropped too.
+const KNOWN_BRANDS: &[&str] = &[
+    "barilla", "heinz", "hellmann's", "knorr", "kraft", "maggi", "nestlé", "nestle", "philadelphia", "président", "president",
+];
+
+/// Words that end in "s" without being plurals.
+const INVARIANT_WORDS: &[&str] = &["asparagus", "citrus", "couscous", "hummus", "molasses", "watercress"];
+
+/// Words ending in "ie" whose plural would otherwise lose it: "cookies" is not "cooky".
+const IE_WORDS: &[&str] = &["brownie", "calorie", "cookie", "smoothie", "veggie"];
+
+/// Singular form of an English word, by suffix rules: "berries" is "berry", "tomatoes" is "tomato",
+/// "radishes" is "radish", "glasses" is "glass", "leeks" is "leek". Short and invariant words are kept.
+fn singularize(word: &str) -> String {
+    if word.chars().count() <= 3 || INVARIANT_WORDS.contains(&word) || word.ends_with("ss") || word.ends_with("us") || word.ends_with("is") {
+        return word.to_string();
+    }
+    if let Some(stem) = word.strip_suffix("ies") {
+        // A stem without a vowel ("pies", "ties") keeps its "ie", as do the listed words.
+        let ie_word = &word[..word.len() - 1];
+        return if !stem.contains(['a', 'e', 'i', 'o', 'u', 'y']) || IE_WORDS.contains(&ie_word) {
+            ie_word.to_string()
+        } else {
+            format!("{}y", stem)
+        };
+    }
+    for suffix in ["oes", "ches", "shes", "sses", "xes"] {
+        if word.ends_with(suffix) {
+            return word[..word.len() - 2].to_string();
+        }
+    }
+    word.strip_suffix('s').unwrap_or(word).to_string()
+}
+
+/// Canonical form of an ingredient name for embedding and matching: lowercased, without
+/// parenthesized or bracketed asides, brand names and trademark signs, with each word singular.
+/// "Leeks (washed)" and "leek" both give "leek". Display names are left as written.
+pub fn normalize_ingredient_name(name: &str) -> String {
+    let mut without_asides = String::with_capacity(name.len());
+    let mut depth = 0usize;
+    for c in name.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => without_asides.push(c),
+            _ => {}
+        }
+    }
+
+    let words: Vec<String> = without_asides.to_lowercase()
+        .split_whitespace()
+        .filter(|word| !word.contains(['®', '™', '©']))
+        .map(|word| word.trim_matches(|c: char| c == ',' || c == ';' || c == '.'))
+        .filter(|word| !word.is_empty() && !KNOWN_BRANDS.contains(word))
+        .map(singularize)
+        .collect();
+    if words.is_empty() {
+        // Nothing left once asides and brands are gone: fall back to the plain lowercased name.
+        return name.trim().to_lowercase();
+    }
+    words.join(" ")
+}
+
+fn strip_list_marker(line: &str) -> &str {
+    let trimmed = line.trim_start_matches(['-', '*', '•']).trim_start();
+    // Numbered steps such as "1." or "2)", but not decimal quantities such as "1.5 cups"
+    let digits_end = trimmed.find(|c: char| !c.is_ascii_digit()).unwrap_or(0);
+    if digits_end > 0 {
+        let rest = &trimmed[digits_end..];
+        if let Some(stripped) = rest.strip_prefix('.').or_else(|| rest.strip_prefix(')')) {
+            if stripped.is_empty() || stripped.starts_with(char::is_whitespace) {
+                return stripped.trim_start();
+            }
+        }
+    }
+    trimmed
+}
+
+/// Returns the text following one of the ASCII `prefixes` (case-insensitive), with any separator removed.
+fn strip_label<'a>(line: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| {
+        let head = line.get(..prefix.len()).filter(|head| head.eq_ignore_ascii_case(prefix))?;
+        let rest = &line[head.len()..];
+        if !rest.is_empty() && !prefix.ends_with(':') && !rest.starts_with([' ', ':', '-']) {
+            return None;
+        }
+        Some(rest.trim_start_matches([' ', ':', '-']))
+    })
+}
+
+/// Parses lines like "Serves 4" or "Yield: 6 pancakes".
+fn servings_of(line: &str) -> Option<u32> {
+    let rest = strip_label(line, SERVINGS_PREFIXES)?;
+    // Ranges such as "4-6" keep the lower bound.
+    rest.split_whitespace().next()?.split('-').next()?.parse().ok().filter(|servings| *servings > 0)
+}
+
+/// Parses lines like "Total time: 1 hour 15 minutes" or "Time: 45 min"; None when the total
+/// doesn't fit in a u32.
+fn total_time_of(line: &str) -> Option<u32> {
+    let rest = strip_label(line, TIME_PREFIXES)?;
+    let tokens: Vec<String> = rest.split_whitespace().map(str::to_lowercase).collect();
+    let mut minutes: u32 = 0;
+    let mut found = false;
+    for pair in tokens.windows(2) {
+        let Ok(value) = pair[0].parse::<u32>() else { continue };
+        if pair[1].starts_with('h') {
+            minutes = minutes.checked_add(value.checked_mul(60)?)?;
+            found = true;
+        } else if pair[1].starts_with("min") {
+            minutes = minutes.checked_add(value)?;
+            found = true;
+        }
+    }
+    found.then_some(minutes)
+}
+
+/// Recognizes ingredient group headers such as "For the sauce:" or "Dough:" and returns the group name.
+fn section_header_of(line: &str) -> Option<String> {
+    let header = line.strip_suffix(':')?.trim();
+    if header.is_empty() || header.split_whitespace().next().is_some_and(is_quantity_token) {
+        return None;
+    }
+    let lowercase = header.to_lowercase();
+    let name = ["for the ", "for "]
+        .iter()
+        .find_map(|prefix| lowercase.starts_with(prefix).then(|| &header[prefix.len()..]))
+        .unwrap_or(header);
+    Some(name.trim().to_string())
+}
+
+/// Splits an ingredient line into quantity, unit, name and preparation notes.
+pub(crate) fn parse_ingredient_line(line: &str, section: Option<String>) -> ParsedIngredient {
+    let raw_text = strip_list_marker(line).to_string();
+    let tokens: Vec<&str> = raw_text.split_whitespace().collect();
+
+    let quantity_len = tokens.iter().take_while(|t| is_quantity_token(t)).count();
+    let quantity = tokens[..quantity_len].join(" ");
+
+    let mut rest_start = quantity_len;
+    let mut unit = String::new();
+    if let Some(candidate) = tokens.get(rest_start) {
+        let normalized = candidate.trim_end_matches('.').to_lowercase();
+        if KNOWN_UNITS.contains(&normalized.as_str()) {
+            unit = candidate.trim_end_matches('.').to_string();
+            rest_start += 1;
+        }
+    }
+
+    let remainder = tokens[rest_start..].join(" ");
+    let remainder = remainder.strip_prefix("of ").unwrap_or(&remainder);
+    let (ingredient_name, preparation_notes) = match remainder.split_once(',') {
+        Some((name, notes)) => (name.trim().to_string(), notes.trim().to_string()),
+        None => (remainder.trim().to_string(), String::new()),
+    };
+
+    let optional = mentions(&raw_text, "optional");
+    ParsedIngredient {
+        raw_text,
+        ingredient_name,
+        quantity,
+        unit,
+        preparation_notes,
+        section,
+        optional,
+    }
+}
+
+/// Best-effort parser used when the LLM output can't be deserialized.
+///
+/// The first line is taken as the title. Lines under an "Ingredients"/"Instructions" style heading
+/// go to that section; outside of headings, lines starting with a quantity are treated as
+/// ingredients and everything else as instructions. Headers like "For the sauce:" start a new
+/// ingredient group, and "Serves 4" / "Total time: 45 minutes" lines fill in the metadata.
+pub fn parse_recipe_heuristically(recipe_text: &str) -> ParsedRecipe {
+    let mut lines = recipe_text.lines().map(str::trim).filter(|l| !l.is_empty()).peekable();
+
+    let recipe_title = match lines.peek() {
+        Some(first) if heading_of(first).is_none() => lines.next().unwrap_or_default().to_string(),
+        _ => String::new(),
+    };
+
+    let mut section = HeuristicSection::Unknown;
+    let mut ingredient_group: Option<String> = None;
+    let mut ingredients = Vec::new();
+    let mut instructions = Vec::new();
+    let mut servings = None;
+    let mut total_time_minutes = None;
+
+    for line in lines {
+        if let Some(heading) = heading_of(line) {
+            section = heading;
+            continue;
+        }
+        if let Some(value) = servings_of(line) {
+            servings = servings.or(Some(value));
+            continue;
+        }
+        if let Some(value) = total_time_of(line) {
+            total_time_minutes = total_time_minutes.or(Some(value));
+            continue;
+        }
+        if section != HeuristicSection::Instructions {
+            if let Some(group) = section_header_of(line) {
+                ingredient_group = Some(group);
+                section = HeuristicSection::Ingredients;
+                continue;
+            }
+        }
+        let starts_with_quantity = strip_list_marker(line)
+            .split_whitespace()
+            .next()
+            .is_some_and(is_quantity_token);
+        let is_ingredient = match section {
+            HeuristicSection::Ingredients => true,
+            HeuristicSection::Instructions => false,
+            HeuristicSection::Unknown => starts_with_quantity,
+        };
+        if is_ingredient {
+            ingredients.push(parse_ingredient_line(line, ingredient_group.clone()));
+        } else {
+            instructions.push(strip_list_marker(line).to_string());
+        }
+    }
+
+    ParsedRecipe {
+        recipe_title,
+        ingredients,
+        instructions,
+        servings,
+        total_time_minutes,
+        heuristically_parsed: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::client::MockChatClient;
+
+    #[test]
+    fn test_parse_recipe_heuristically_with_headings() {
+        let text = "Simple Pancakes
+
+Ingredients:
+1 cup all-purpose flour
+1/2 teaspoon salt
+1 large egg
+2 tablespoons unsalted butter, melted
+
+Instructions:
+1. Whisk together flour and salt.
+2. Brown on both sides and serve hot.
+";
+        let recipe = parse_recipe_heuristically(text);
+        assert!(recipe.heuristically_parsed);
+        assert_eq!(recipe.recipe_title, "Simple Pancakes");
+        assert_eq!(recipe.ingredients.len(), 4);
+        assert_eq!(recipe.instructions, vec!["Whisk together flour and salt.", "Brown on both sides and serve hot."]);
+
+        let salt = &recipe.ingredients[1];
+        assert_eq!(salt.quantity, "1/2");
+        assert_eq!(salt.unit, "teaspoon");
+        assert_eq!(salt.ingredient_name, "salt");
+
+        let butter = &recipe.ingredients[3];
+        assert_eq!(butter.ingredient_name, "unsalted butter");
+        assert_eq!(butter.preparation_notes, "melted");
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_without_headings() {
+        let text = "Toast\n- 2 slices bread\nsalt\nToast the bread.";
+        let recipe = parse_recipe_heuristically(text);
+        assert_eq!(recipe.recipe_title, "Toast");
+        assert_eq!(recipe.ingredients.len(), 1);
+        assert_eq!(recipe.ingredients[0].quantity, "2");
+        assert_eq!(recipe.ingredients[0].unit, "slices");
+        assert_eq!(recipe.ingredients[0].ingredient_name, "bread");
+        // Without a heading, unquantified lines can't be told apart from steps.
+        assert_eq!(recipe.instructions, vec!["salt", "Toast the bread."]);
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_decimal_quantities() {
+        let text = "Bread\nIngredients:\n1.5 cups flour\n2.25 kg potatoes\nInstructions:\n1. Mix.\n2) Bake.";
+        let recipe = parse_recipe_heuristically(text);
+        let quantities: Vec<&str> = recipe.ingredients.iter().map(|i| i.quantity.as_str()).collect();
+        assert_eq!(quantities, vec!["1.5", "2.25"]);
+        assert_eq!(recipe.ingredients[0].raw_text, "1.5 cups flour");
+        assert_eq!(recipe.ingredients[1].unit, "kg");
+        assert_eq!(recipe.instructions, vec!["Mix.", "Bake."]);
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_ingredient_sections() {
+        let text = "Pasta
+Ingredients:
+200 g spaghetti
+For the sauce:
+400 g tomatoes, crushed
+Topping:
+20 g parmesan
+Instructions:
+Cook everything.
+";
+        let recipe = parse_recipe_heuristically(text);
+        let sections: Vec<Option<&str>> = recipe.ingredients.iter().map(|i| i.section.as_deref()).collect();
+        assert_eq!(sections, vec![None, Some("sauce"), Some("Topping")]);
+        assert_eq!(recipe.instructions, vec!["Cook everything."]);
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_metadata() {
+        let text = "Stew\nServes 4-6\nTotal time: 1 hour 15 minutes\nIngredients:\n500 g beef\nInstructions:\nSimmer.";
+        let recipe = parse_recipe_heuristically(text);
+        assert_eq!(recipe.servings, Some(4));
+        assert_eq!(recipe.total_time_minutes, Some(75));
+        assert_eq!(recipe.ingredients.len(), 1);
+        assert_eq!(recipe.instructions, vec!["Simmer."]);
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_rejects_odd_metadata() {
+        let text = "Stew\nServes 0\nTime: 99999999 hours\nIngredients:\n500 g beef\nInstructions:\nTime to simmer for 10 minutes.";
+        let recipe = parse_recipe_heuristically(text);
+        assert_eq!(recipe.servings, None);
+        assert_eq!(recipe.total_time_minutes, None);
+        assert_eq!(recipe.instructions, vec!["Serves 0", "Time: 99999999 hours", "Time to simmer for 10 minutes."]);
+
+        assert_eq!(strip_label("Portions: 4", SERVINGS_PREFIXES), Some("4"));
+        assert_eq!(strip_label("Ölkuchen", SERVINGS_PREFIXES), None);
+        assert_eq!(servings_of("Makes 6 crêpes"), Some(6));
+    }
+
+    #[test]
+    fn test_parse_recipe_heuristically_optional_and_to_taste() {
+        let text = "Salad\nIngredients:\n200 g lettuce\n1 tbsp capers (optional)\nsalt, to taste\nInstructions:\nToss.";
+        let recipe = parse_recipe_heuristically(text);
+        let optional: Vec<bool> = recipe.ingredients.iter().map(|i| i.optional).collect();
+        assert_eq!(optional, vec![false, true, false]);
+        let to_taste: Vec<bool> = recipe.ingredients.iter().map(ParsedIngredient::is_to_taste).collect();
+        assert_eq!(to_taste, vec![false, false, true]);
+        // "optionally" is not the word "optional".
+        assert!(!mentions("optionally toasted", "optional"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_recipe_text_with_mock_client() {
+        let client = MockChatClient::new([
+            "<think>Two ingredients.</think>\n```json\n{\"recipe_title\": \"Toast\", \"servings\": 2, \"ingredients\": [{\"raw_text\": \"2 slices bread\", \"ingredient_name\": \"bread\", \"quantity\": \"2\", \"unit\": \"slices\", \"preparation_notes\": \"\", \"section\": null}], \"instructions\": [\"Toast the bread.\"]}\n```",
+            "Sorry, I cannot help with that.",
+        ]);
+
+        let recipe = parse_recipe_text("Toast\n2 slices bread\nToast the bread.", &client, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.unwrap();
+        assert!(!recipe.heuristically_parsed);
+        assert_eq!(recipe.recipe_title, "Toast");
+        assert_eq!(recipe.servings, Some(2));
+        assert_eq!(recipe.ingredients[0].ingredient_name, "bread");
+        let request = &client.requests()[0];
+        assert_eq!(request.messages[1].content, "Toast\n2 slices bread\nToast the bread.");
+
+        // Unusable output falls back to the rule-based parser.
+        let fallback = parse_recipe_text("Toast\n- 2 slices bread\nToast the bread.", &client, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.unwrap();
+        assert!(fallback.heuristically_parsed);
+        assert_eq!(fallback.ingredients[0].unit, "slices");
+
+        // No response at all is an error.
+        assert!(parse_recipe_text("Toast", &client, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.is_err());
+
+        // A cut-off answer is an explicit error rather than a heuristic parse of the input.
+        let truncated = MockChatClient::with_finish_reasons([("{\"recipe_title\": \"Toa", "length"), ("{\"recipe_title\": \"Toast\", \"ingr", "length")]);
+        let error = parse_recipe_text("Toast", &truncated, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.unwrap_err();
+        assert!(matches!(error, ApiConnectionError::Truncated { max_tokens: Some(4096) }));
+    }
+
+    #[tokio::test]
+    async fn test_parse_recipe_text_uses_the_given_templates() {
+        let client = MockChatClient::new([r#"{"recipe_title": "Toast", "ingredients": [], "instructions": []}"#]);
+        let prompts = PromptTemplates::default().with_template(PromptKind::ParserSystem, "Parse it.".to_string()).unwrap();
+        parse_recipe_text("Toast", &client, GenParams::PARSER, JsonMode::Lenient, &prompts).await.unwrap();
+        assert_eq!(client.requests()[0].messages[0].content, "Parse it.");
+    }
+
+    #[tokio::test]
+    async fn test_parse_recipe_text_in_chunks() {
+        let text = "Big Salad\nServes 8\n\nIngredients:\n1 lettuce\n2 tomatoes\nFor the dressing:\n3 tbsp oil\n1 tbsp vinegar\n\nInstructions:\nToss everything.";
+        assert_eq!(count_ingredient_lines(text), Some(4));
+        assert_eq!(count_ingredient_lines("Toast\n2 slices bread"), None);
+        let block = IngredientBlock::find(text).unwrap();
+        assert_eq!(block.chunks(3), vec![
+            "Big Salad\nServes 8\n\nIngredients:\n1 lettuce\n2 tomatoes\nFor the dressing:\n3 tbsp oil\nInstructions:\nToss everything.".to_string(),
+            "Big Salad\nIngredients:\nFor the dressing:\n1 tbsp vinegar".to_string(),
+        ]);
+        // A group header at a chunk boundary opens the next chunk only.
+        assert_eq!(block.chunks(2)[1], "Big Salad\nIngredients:\nFor the dressing:\n3 tbsp oil\n1 tbsp vinegar");
+
+        let ingredient = |name: &str| format!(
+            r#"{{"raw_text": "{0}", "ingredient_name": "{0}", "quantity": "1", "unit": "", "preparation_notes": ""}}"#, name
+        );
+        let client = MockChatClient::new([
+            format!(r#"{{"recipe_title": "Big Salad", "servings": 8, "ingredients": [{}, {}, {}], "instructions": ["Toss everything."]}}"#,
+                ingredient("lettuce"), ingredient("tomatoes"), ingredient("oil")),
+            format!(r#"{{"recipe_title": "Big Salad", "ingredients": [{}], "instructions": []}}"#, ingredient("vinegar")),
+        ]);
+        let recipe = parse_recipe_text_in_chunks(text, 3, &client, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.unwrap();
+        let names: Vec<&str> = recipe.ingredients.iter().map(|ingredient| ingredient.ingredient_name.as_str()).collect();
+        assert_eq!(names, vec!["lettuce", "tomatoes", "oil", "vinegar"]);
+        assert_eq!((recipe.servings, recipe.instructions.len()), (Some(8), 1));
+        assert_eq!(client.requests().len(), 2);
+
+        // Other page sections end the list, so their lines are neither counted nor chunked.
+        let page = "Cake\nIngredients\n200 g flour\n2 eggs\nInstructions for the cake\nBake.\nNotes\nKeeps 3 days.";
+        assert_eq!(count_ingredient_lines(page), Some(2));
+        let page = "Cake\n## Ingredients\n200 g flour\n## Nutrition\n350 kcal\nComments:\nLovely!";
+        assert_eq!(count_ingredient_lines(page), Some(1));
+
+        // Short enough for a single call.
+        let client = MockChatClient::new([r#"{"recipe_title": "Big Salad", "ingredients": [], "instructions": []}"#]);
+        parse_recipe_text_in_chunks(text, 10, &client, GenParams::PARSER, JsonMode::Lenient, &PromptTemplates::default()).await.unwrap();
+        assert_eq!(client.requests()[0].messages[1].content, text);
+    }
+
+    #[tokio::test]
+    async fn test_parse_recipe_text_strict_json() {
+        let recipe_json = "{\"recipe_title\": \"Toast\", \"ingredients\": [], \"instructions\": [\"Toast the bread.\"]}";
+        let fenced = format!("```json\n{}\n```", recipe_json);
+        let client = MockChatClient::new([recipe_json.to_string(), fenced.clone()]);
+
+        let recipe = parse_recipe_text("Toast", &client, GenParams::PARSER, JsonMode::Strict, &PromptTemplates::default()).await.unwrap();
+        assert_eq!(recipe.recipe_title, "Toast");
+
+        // Fences are not stripped, and the raw answer is reported instead of a heuristic parse.
+        let error = parse_recipe_text("Toast", &client, GenParams::PARSER, JsonMode::Strict, &PromptTemplates::default()).await.unwrap_err();
+        match error {
+            ApiConnectionError::InvalidJson { content, .. } => assert_eq!(content, fenced),
+            other => panic!("expected InvalidJson, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_quantity_and_units() {
+        assert_eq!(parse_quantity("2"), Some(2.0));
+        assert_eq!(parse_quantity(" 1,5 "), Some(1.5));
+        assert_eq!(parse_quantity("1,000"), None);
+        assert_eq!(parse_quantity("1,25"), Some(1.25));
+        assert_eq!(parse_quantity("1/2"), Some(0.5));
+        assert_eq!(parse_quantity("1 1/2"), Some(1.5));
+        assert_eq!(parse_quantity("½"), Some(0.5));
+        assert_eq!(parse_quantity("1¼"), Some(1.25));
+        assert_eq!(parse_quantity("1-2"), Some(1.0));
+        assert_eq!(parse_quantity("half"), None);
+        assert_eq!(parse_quantity("1/0"), None);
+        assert_eq!(parse_quantity("-20"), None);
+        assert_eq!(parse_quantity(""), None);
+
+        assert!(is_known_unit("Tbsp."));
+        assert!(is_known_unit("g"));
+        assert!(is_known_unit("gr"));
+        assert!(!is_known_unit(""));
+        assert!(!is_known_unit("bowl"));
+
+        assert_eq!(canonical_unit("Gr."), Some("g"));
+        assert_eq!(canonical_unit("Tablespoons"), Some("tbsp"));
+        assert_eq!(canonical_unit("cup"), None);
+        assert_eq!(grams_per_unit("grammes"), Some(1.0));
+        assert_eq!(grams_per_unit("KG"), Some(1000.0));
+        assert_eq!(grams_per_unit("ml"), None);
+        assert_eq!(millilitres_per_unit("cl"), Some(10.0));
+        assert_eq!(millilitres_per_unit("Tsp"), Some(5.0));
+        assert_eq!(millilitres_per_unit("g"), None);
+    }
+
+    #[test]
+    fn test_normalize_ingredient_name() {
+        assert_eq!(normalize_ingredient_name("Milk (whole)"), "milk");
+        assert_eq!(normalize_ingredient_name("  Whole   Milk "), "whole milk");
+        assert_eq!(normalize_ingredient_name("2% milk"), "2% milk");
+        assert_eq!(normalize_ingredient_name("Philadelphia® cream cheese"), "cream cheese");
+        assert_eq!(normalize_ingredient_name("Heinz ketchup"), "ketchup");
+        assert_eq!(normalize_ingredient_name("Cherry Tomatoes [ripe]"), "cherry tomato");
+        assert_eq!(normalize_ingredient_name("raspberries, fresh"), "raspberry fresh");
+        assert_eq!(normalize_ingredient_name("radishes"), "radish");
+        assert_eq!(normalize_ingredient_name("asparagus"), "asparagus");
+        assert_eq!(normalize_ingredient_name("peas"), "pea");
+        assert_eq!(normalize_ingredient_name("Chocolate Chip Cookies"), "chocolate chip cookie");
+        assert_eq!(normalize_ingredient_name("pies"), "pie");
+        assert_eq!(normalize_ingredient_name("brownies"), "brownie");
+        assert_eq!(normalize_ingredient_name("cherries"), "cherry");
+        assert_eq!(normalize_ingredient_name("glasses"), "glass");
+        assert_eq!(normalize_ingredient_name("Swiss chard"), "swiss chard");
+        assert_eq!(normalize_ingredient_name("(optional)"), "(optional)");
+    }
 }
@@ -1,38 +1,94 @@
 use anyhow::{Result, Context};
 use std::collections::HashMap; // For NanoDBData fields
-use crate::search::nano_vector_db::{NanoVectorDB, Data as NanoDBData, constants as NanoDBConstants};
-use crate::search::embedding_engine::EMBEDDING_DIMENSION; // To ensure consistency if needed, or use passed dimension
+use std::path::Path;
+use crate::{log_info, log_warning};
+use crate::search::nano_vector_db::{NanoVectorDB, Data as NanoDBData, DimensionMismatch, constants as NanoDBConstants};
 
-const DB_PATH: &str = "ann_engine_nanodb.json"; // Path for the NanoVectorDB file
+/// Where the NanoVectorDB file is kept unless a path is given.
+pub const DEFAULT_STORAGE_PATH: &str = "ann_engine_nanodb.json";
 
 // ANN_METRIC is not directly used by NanoVectorDB as it's fixed to cosine,
 // but we keep the constant here if other parts of the code might refer to it conceptually.
 // pub const ANN_METRIC: Metric = Metric::CosineSimilarity; // Hora specific, can be removed.
 
+/// A search hit: the item ID, its cosine similarity to the query and the metadata stored with it.
+#[derive(Debug, Clone)]
+pub struct AnnMatch {
+    pub id: String,
+    pub score: f32,
+    pub fields: HashMap<String, serde_json::Value>,
+}
+
 pub struct AnnEngine {
     db: NanoVectorDB,
     dimension: usize, // Store dimension for validation if needed, NanoDB also stores it
 }
 
 impl AnnEngine {
-    pub fn new(dimension: usize) -> Result<Self> {
-        let db = NanoVectorDB::new(dimension, DB_PATH)
-            .with_context(|| format!("Failed to initialize NanoVectorDB for AnnEngine at path: {}", DB_PATH))?;
+    /// Opens (or creates) the vector store at `storage_path`, creating missing parent directories.
+    /// A store built for another embedding dimension is an error naming the file to remove.
+    pub fn new(dimension: usize, storage_path: &Path) -> Result<Self> {
+        let db = Self::open_db(dimension, storage_path).map_err(|e| match e.downcast_ref::<DimensionMismatch>() {
+            Some(mismatch) => anyhow::anyhow!(
+                "The index at {:?} was built with {}-dimensional embeddings, but the embedding model produces {}. \
+                 Delete that file, or rebuild it with --rebuild-index.",
+                storage_path, mismatch.stored, mismatch.expected
+            ),
+            None => e.context(format!("Failed to initialize NanoVectorDB for AnnEngine at path: {:?}", storage_path)),
+        })?;
         Ok(Self { db, dimension })
     }
 
+    /// Like `new`, but a store built for another embedding dimension is deleted and recreated empty.
+    pub fn new_rebuilding_stale(dimension: usize, storage_path: &Path) -> Result<Self> {
+        match Self::open_db(dimension, storage_path) {
+            Ok(db) => Ok(Self { db, dimension }),
+            Err(e) if e.is::<DimensionMismatch>() => {
+                log_info!("Index at {:?} was built for another embedding dimension ({}); rebuilding it.", storage_path, e);
+                std::fs::remove_file(storage_path)
+                    .with_context(|| format!("Failed to remove stale index {:?}", storage_path))?;
+                Self::new(dimension, storage_path)
+            }
+            Err(e) => Err(e.context(format!("Failed to initialize NanoVectorDB for AnnEngine at path: {:?}", storage_path))),
+        }
+    }
+
+    fn open_db(dimension: usize, storage_path: &Path) -> Result<NanoVectorDB> {
+        if let Some(parent) = storage_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {:?} for the AnnEngine store", parent))?;
+        }
+        NanoVectorDB::new(dimension, storage_path)
+    }
+
+    /// An engine whose store lives only in memory and is never saved.
+    pub fn new_in_memory(dimension: usize) -> Self {
+        Self { db: NanoVectorDB::new_in_memory(dimension), dimension }
+    }
+
     pub fn add_items_batch(&mut self, embeddings: &[Vec<f32>], ids: &[String]) -> Result<()> {
-        if embeddings.len() != ids.len() {
+        self.add_items_batch_with_fields(embeddings, ids, vec![HashMap::new(); ids.len()])
+    }
+
+    /// Like `add_items_batch`, storing `fields[i]` as the metadata of item `ids[i]`.
+    pub fn add_items_batch_with_fields(
+        &mut self,
+        embeddings: &[Vec<f32>],
+        ids: &[String],
+        fields: Vec<HashMap<String, serde_json::Value>>,
+    ) -> Result<()> {
+        if embeddings.len() != ids.len() || fields.len() != ids.len() {
             return Err(anyhow::anyhow!(
-                "Embeddings and IDs count mismatch: {} vs {}",
+                "Embeddings, IDs and fields count mismatch: {} vs {} vs {}",
                 embeddings.len(),
-                ids.len()
+                ids.len(),
+                fields.len()
             ));
         }
 
         let mut nano_data_items: Vec<NanoDBData> = Vec::with_capacity(embeddings.len());
 
-        for (embedding, id_str) in embeddings.iter().zip(ids.iter()) {
+        for ((embedding, id_str), item_fields) in embeddings.iter().zip(ids.iter()).zip(fields) {
             if embedding.len() != self.dimension {
                 return Err(anyhow::anyhow!(
                     "Embedding dimension mismatch for item '{}'. Expected {}, got {}.",
@@ -41,13 +97,10 @@ impl AnnEngine {
                     embedding.len()
                 ));
             }
-            // The `ids` provided by NutritionalIndex are stringified usize indices ("0", "1", ...).
-            // These will be the `id` field in NanoDBData.
-            // We don't need an extra payload field if the ID itself is the index.
             let data_item = NanoDBData {
                 id: id_str.clone(),
                 vector: embedding.clone(),
-                fields: HashMap::new(), // No extra fields needed for now, ID is the key.
+                fields: item_fields,
             };
             nano_data_items.push(data_item);
         }
@@ -69,8 +122,24 @@ impl AnnEngine {
     }
 
     pub fn search(&self, query_embedding: &[f32], k: usize) -> Vec<String> {
+        self.search_with_scores(query_embedding, k)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Like `search`, but pairs each ID with its cosine similarity to the query (highest first).
+    pub fn search_with_scores(&self, query_embedding: &[f32], k: usize) -> Vec<(String, f32)> {
+        self.search_with_fields(query_embedding, k)
+            .into_iter()
+            .map(|ann_match| (ann_match.id, ann_match.score))
+            .collect()
+    }
+
+    /// Like `search_with_scores`, also returning the metadata stored with each item.
+    pub fn search_with_fields(&self, query_embedding: &[f32], k: usize) -> Vec<AnnMatch> {
         if query_embedding.len() != self.dimension {
-            eprintln!(
+            log_warning!(
                 "Search query embedding dimension mismatch. Expected {}, got {}.",
                 self.dimension,
                 query_embedding.len()
@@ -82,14 +151,18 @@ impl AnnEngine {
         
         search_results_maps
             .into_iter()
-            .filter_map(|result_map| {
-                match result_map.get(NanoDBConstants::F_ID) {
-                    Some(id_val) => id_val.as_str().map(String::from),
+            .filter_map(|mut result_map| {
+                let id = match result_map.remove(NanoDBConstants::F_ID) {
+                    Some(id_val) => id_val.as_str().map(String::from)?,
                     None => {
-                        eprintln!("Search result from NanoVectorDB missing ID field.");
-                        None
+                        log_warning!("Search result from NanoVectorDB missing ID field.");
+                        return None;
                     }
-                }
+                };
+                let score = result_map.remove(NanoDBConstants::F_METRICS)
+                    .and_then(|metric| metric.as_f64())
+                    .unwrap_or(0.0) as f32;
+                Some(AnnMatch { id, score, fields: result_map })
             })
             .collect()
     }
@@ -97,23 +170,12 @@ impl AnnEngine {
     pub fn item_count(&self) -> usize {
         self.db.len()
     }
-
-    // Helper to clean up the DB file, useful for tests
-    #[cfg(test)]
-    fn cleanup_db_file() -> Result<()> {
-        let path = std::path::Path::new(DB_PATH);
-        if path.exists() {
-            std::fs::remove_file(path)?;
-        }
-        Ok(())
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    // EMBEDDING_DIMENSION is already imported if this module is part of the crate
-    // use crate::search::embedding_engine::EMBEDDING_DIMENSION; 
+    use crate::search::embedding_engine::EMBEDDING_DIMENSION;
     use rand::Rng; // For generating dummy embeddings
 
     fn generate_dummy_embeddings(count: usize, dim: usize) -> (Vec<Vec<f32>>, Vec<String>) {
@@ -127,10 +189,8 @@ mod tests {
 
     #[test]
     fn test_ann_engine_new_add_search() -> Result<()> {
-        AnnEngine::cleanup_db_file()?; // Clean up before test
-
         let dim = EMBEDDING_DIMENSION; // Use the global const
-        let mut engine = AnnEngine::new(dim)?;
+        let mut engine = AnnEngine::new_in_memory(dim);
 
         let (embeddings, ids) = generate_dummy_embeddings(100, dim);
         engine.add_items_batch(&embeddings, &ids)?;
@@ -148,32 +208,64 @@ mod tests {
         // The closest item to embeddings[0] should be "0" (its own ID)
         assert_eq!(results[0], "0", "The first result should be the item itself");
 
-        AnnEngine::cleanup_db_file()?; // Clean up after test
+        let scored_results = engine.search_with_scores(&query_embedding, 5);
+        assert_eq!(scored_results.len(), results.len());
+        assert_eq!(scored_results[0].0, "0");
+        assert!(scored_results[0].1 > 0.99, "An item should be maximally similar to itself");
+        assert!(scored_results.windows(2).all(|pair| pair[0].1 >= pair[1].1), "Scores should be in descending order");
+
+        // Re-adding items with metadata attaches it to the existing entries.
+        let fields = (0..3)
+            .map(|i| HashMap::from([("original_row_index".to_string(), serde_json::json!(i * 10))]))
+            .collect();
+        engine.add_items_batch_with_fields(&embeddings[..3], &ids[..3], fields)?;
+        let matches = engine.search_with_fields(&embeddings[2], 1);
+        assert_eq!(matches[0].id, "2");
+        assert_eq!(matches[0].fields["original_row_index"], 20);
+        assert!(!matches[0].fields.contains_key(NanoDBConstants::F_ID));
         Ok(())
     }
 
     #[test]
     fn test_ann_engine_persistence() -> Result<()> {
-        AnnEngine::cleanup_db_file()?;
+        let dir = tempfile::tempdir()?;
+        // A missing cache directory is created on demand.
+        let storage_path = dir.path().join("cache").join("ann.json");
         let dim = EMBEDDING_DIMENSION;
 
         // Create engine, add items, it saves automatically
-        let mut engine1 = AnnEngine::new(dim)?;
+        let mut engine1 = AnnEngine::new(dim, &storage_path)?;
         let (embeddings, ids) = generate_dummy_embeddings(10, dim);
         engine1.add_items_batch(&embeddings, &ids)?;
         assert_eq!(engine1.item_count(), 10);
         
-        // Drop engine1, then create a new one (engine2) which should load from DB_PATH
+        // Drop engine1, then create a new one (engine2) which should load from storage_path
         drop(engine1);
-        let engine2 = AnnEngine::new(dim)?;
+        let engine2 = AnnEngine::new(dim, &storage_path)?;
         assert_eq!(engine2.item_count(), 10, "Engine2 should load 10 items from persisted DB");
 
         let query_embedding = embeddings[5].clone();
         let results = engine2.search(&query_embedding, 1);
         assert_eq!(results.len(), 1);
         assert_eq!(results[0], "5");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ann_engine_stale_dimension() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage_path = dir.path().join("ann.json");
+        let (embeddings, ids) = generate_dummy_embeddings(3, 4);
+        AnnEngine::new(4, &storage_path)?.add_items_batch(&embeddings, &ids)?;
+
+        let err = AnnEngine::new(8, &storage_path).err().expect("a dimension mismatch should fail");
+        let message = err.to_string();
+        assert!(message.contains("ann.json"), "{}", message);
+        assert!(message.contains("--rebuild-index"), "{}", message);
 
-        AnnEngine::cleanup_db_file()?;
+        // A store of the right dimension is kept, a stale one starts over empty.
+        assert_eq!(AnnEngine::new_rebuilding_stale(4, &storage_path)?.item_count(), 3);
+        assert_eq!(AnnEngine::new_rebuilding_stale(8, &storage_path)?.item_count(), 0);
         Ok(())
     }
 }
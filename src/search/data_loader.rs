@@ -1,46 +1,121 @@
 use anyhow::{Result, Context};
 use csv::ReaderBuilder;
 use std::path::Path;
-use crate::recipe_converter::CiqualFoodItem; // Assuming CiqualFoodItem is in recipe_converter
-
-// Define expected column headers
-const NAME_COL: &str = "Name";
-const KCAL_COL: &str = "kcal/100g";
-const WATER_COL: &str = "Water (g/100g)";
-const PROTEIN_COL: &str = "Protein (g/100g)";
-const CARB_COL: &str = "Carbohydrate (g/100g)";
-const FAT_COL: &str = "Fat (g/100g)";
-const SUGARS_COL: &str = "Sugars (g/100g)";
-const SAT_FAT_COL: &str = "FA saturated (g/100g)";
-const SALT_COL: &str = "Salt (g/100g)";
+use crate::log_warning;
+use crate::recipe_aggregator::SODIUM_MG_PER_SALT_G;
+use crate::recipe_converter::FoodItem;
+
+/// A nutrient column of a food composition CSV and the factor converting its values
+/// to the unit used by `FoodItem`.
+#[derive(Debug, Clone, Copy)]
+pub struct NutrientColumn {
+    pub header: &'static str,
+    pub factor: f32,
+}
+
+impl NutrientColumn {
+    const fn new(header: &'static str) -> Self {
+        NutrientColumn { header, factor: 1.0 }
+    }
+
+    const fn scaled(header: &'static str, factor: f32) -> Self {
+        NutrientColumn { header, factor }
+    }
+}
+
+/// Maps the columns of a food composition CSV export onto `FoodItem` fields.
+/// All values must be given per 100 g of food. Only the name, kcal and macro columns are
+/// required; any other missing column leaves its field `None` for every food and, except for
+/// fiber and cholesterol, logs a warning.
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnMapping {
+    /// Human-readable database name, used in error messages.
+    pub database: &'static str,
+    pub name: &'static str,
+    pub kcal: NutrientColumn,
+    pub water: NutrientColumn,
+    pub protein: NutrientColumn,
+    pub carbohydrate: NutrientColumn,
+    pub fat: NutrientColumn,
+    pub sugars: NutrientColumn,
+    pub fa_saturated: NutrientColumn,
+    pub salt: NutrientColumn,
+    // Optional columns: older exports don't carry these, so their absence is not even worth a warning.
+    pub fiber: NutrientColumn,
+    pub cholesterol: NutrientColumn,
+}
+
+/// Column layout of the ANSES Ciqual table.
+pub const CIQUAL_COLUMNS: ColumnMapping = ColumnMapping {
+    database: "Ciqual",
+    name: "Name",
+    kcal: NutrientColumn::new("kcal/100g"),
+    water: NutrientColumn::new("Water (g/100g)"),
+    protein: NutrientColumn::new("Protein (g/100g)"),
+    carbohydrate: NutrientColumn::new("Carbohydrate (g/100g)"),
+    fat: NutrientColumn::new("Fat (g/100g)"),
+    sugars: NutrientColumn::new("Sugars (g/100g)"),
+    fa_saturated: NutrientColumn::new("FA saturated (g/100g)"),
+    salt: NutrientColumn::new("Salt (g/100g)"),
+    fiber: NutrientColumn::new("Fibres (g/100g)"),
+    cholesterol: NutrientColumn::new("Cholesterol (mg/100g)"),
+};
+
+/// Column layout of a flattened USDA FoodData Central export (one row per food, nutrient names
+/// with their FDC unit as headers). USDA reports sodium rather than salt, so it is converted.
+pub const USDA_COLUMNS: ColumnMapping = ColumnMapping {
+    database: "USDA FoodData Central",
+    name: "description",
+    kcal: NutrientColumn::new("Energy (KCAL)"),
+    water: NutrientColumn::new("Water (G)"),
+    protein: NutrientColumn::new("Protein (G)"),
+    carbohydrate: NutrientColumn::new("Carbohydrate, by difference (G)"),
+    fat: NutrientColumn::new("Total lipid (fat) (G)"),
+    sugars: NutrientColumn::new("Sugars, total including NLEA (G)"),
+    fa_saturated: NutrientColumn::new("Fatty acids, total saturated (G)"),
+    salt: NutrientColumn::scaled("Sodium, Na (MG)", 1.0 / SODIUM_MG_PER_SALT_G),
+    fiber: NutrientColumn::new("Fiber, total dietary (G)"),
+    cholesterol: NutrientColumn::new("Cholesterol (MG)"),
+};
 
 fn parse_optional_f32(s: &str) -> Option<f32> {
     s.trim().parse::<f32>().ok()
 }
 
-pub fn load_ciqual_nutritional_data(csv_path: &Path) -> Result<Vec<CiqualFoodItem>> {
+pub fn load_nutritional_data(csv_path: &Path, mapping: &ColumnMapping) -> Result<Vec<FoodItem>> {
     if !csv_path.exists() {
-        return Err(anyhow::anyhow!("Ciqual CSV file not found at: {:?}", csv_path));
+        return Err(anyhow::anyhow!("{} CSV file not found at: {:?}", mapping.database, csv_path));
     }
 
     let file = std::fs::File::open(csv_path)
-        .with_context(|| format!("Failed to open Ciqual CSV file at {:?}", csv_path))?;
+        .with_context(|| format!("Failed to open {} CSV file at {:?}", mapping.database, csv_path))?;
     let mut rdr = ReaderBuilder::new().has_headers(true).from_reader(file);
 
     let headers = rdr.headers()?.clone();
-    
+    let find_column = |header: &str| headers.iter().position(|h| h == header);
+    let required_column = |header: &str| find_column(header).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", header));
+    let optional_column = |header: &str| {
+        let idx = find_column(header);
+        if idx.is_none() {
+            log_warning!("[WARNING] {} CSV has no '{}' column; that value will be missing for every food.", mapping.database, header);
+        }
+        idx
+    };
+
     // Get column indices
-    let name_idx = headers.iter().position(|h| h == NAME_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", NAME_COL))?;
-    let kcal_idx = headers.iter().position(|h| h == KCAL_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", KCAL_COL))?;
-    let water_idx = headers.iter().position(|h| h == WATER_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", WATER_COL))?;
-    let protein_idx = headers.iter().position(|h| h == PROTEIN_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", PROTEIN_COL))?;
-    let carb_idx = headers.iter().position(|h| h == CARB_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", CARB_COL))?;
-    let fat_idx = headers.iter().position(|h| h == FAT_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", FAT_COL))?;
-    let sugars_idx = headers.iter().position(|h| h == SUGARS_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", SUGARS_COL))?;
-    let sat_fat_idx = headers.iter().position(|h| h == SAT_FAT_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", SAT_FAT_COL))?;
-    let salt_idx = headers.iter().position(|h| h == SALT_COL).ok_or_else(|| anyhow::anyhow!("Column '{}' not found", SALT_COL))?;
-
-    let mut ciqual_data = Vec::new();
+    let name_idx = required_column(mapping.name)?;
+    let kcal_idx = required_column(mapping.kcal.header)?;
+    let water_idx = optional_column(mapping.water.header);
+    let protein_idx = required_column(mapping.protein.header)?;
+    let carb_idx = required_column(mapping.carbohydrate.header)?;
+    let fat_idx = required_column(mapping.fat.header)?;
+    let sugars_idx = optional_column(mapping.sugars.header);
+    let sat_fat_idx = optional_column(mapping.fa_saturated.header);
+    let salt_idx = optional_column(mapping.salt.header);
+    let fiber_idx = find_column(mapping.fiber.header);
+    let cholesterol_idx = find_column(mapping.cholesterol.header);
+
+    let mut food_data = Vec::new();
     for (row_index, result) in rdr.records().enumerate() {
         let record = result.with_context(|| format!("Failed to read record at row index {}", row_index))?;
         
@@ -51,26 +126,34 @@ pub fn load_ciqual_nutritional_data(csv_path: &Path) -> Result<Vec<CiqualFoodIte
             continue;
         }
 
-        let item = CiqualFoodItem {
+        let value = |idx: Option<usize>, column: &NutrientColumn| {
+            idx.and_then(|idx| record.get(idx))
+                .and_then(parse_optional_f32)
+                .map(|v| v * column.factor)
+        };
+
+        let item = FoodItem {
             name,
             original_row_index: row_index,
-            kcal_per_100g: record.get(kcal_idx).and_then(|s| parse_optional_f32(s)),
-            water_g_per_100g: record.get(water_idx).and_then(|s| parse_optional_f32(s)),
-            protein_g_per_100g: record.get(protein_idx).and_then(|s| parse_optional_f32(s)),
-            carbohydrate_g_per_100g: record.get(carb_idx).and_then(|s| parse_optional_f32(s)),
-            fat_g_per_100g: record.get(fat_idx).and_then(|s| parse_optional_f32(s)),
-            sugars_g_per_100g: record.get(sugars_idx).and_then(|s| parse_optional_f32(s)),
-            fa_saturated_g_per_100g: record.get(sat_fat_idx).and_then(|s| parse_optional_f32(s)),
-            salt_g_per_100g: record.get(salt_idx).and_then(|s| parse_optional_f32(s)),
+            kcal_per_100g: value(Some(kcal_idx), &mapping.kcal),
+            water_g_per_100g: value(water_idx, &mapping.water),
+            protein_g_per_100g: value(Some(protein_idx), &mapping.protein),
+            carbohydrate_g_per_100g: value(Some(carb_idx), &mapping.carbohydrate),
+            fat_g_per_100g: value(Some(fat_idx), &mapping.fat),
+            sugars_g_per_100g: value(sugars_idx, &mapping.sugars),
+            fa_saturated_g_per_100g: value(sat_fat_idx, &mapping.fa_saturated),
+            salt_g_per_100g: value(salt_idx, &mapping.salt),
+            fiber_g_per_100g: value(fiber_idx, &mapping.fiber),
+            cholesterol_mg_per_100g: value(cholesterol_idx, &mapping.cholesterol),
         };
-        ciqual_data.push(item);
+        food_data.push(item);
     }
 
-    if ciqual_data.is_empty() {
-        return Err(anyhow::anyhow!("No valid Ciqual data loaded from {:?}", csv_path));
+    if food_data.is_empty() {
+        return Err(anyhow::anyhow!("No valid {} data loaded from {:?}", mapping.database, csv_path));
     }
 
-    Ok(ciqual_data)
+    Ok(food_data)
 }
 
 
@@ -80,6 +163,18 @@ mod tests {
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    const NAME_COL: &str = CIQUAL_COLUMNS.name;
+    const KCAL_COL: &str = CIQUAL_COLUMNS.kcal.header;
+    const WATER_COL: &str = CIQUAL_COLUMNS.water.header;
+    const PROTEIN_COL: &str = CIQUAL_COLUMNS.protein.header;
+    const CARB_COL: &str = CIQUAL_COLUMNS.carbohydrate.header;
+    const FAT_COL: &str = CIQUAL_COLUMNS.fat.header;
+    const SUGARS_COL: &str = CIQUAL_COLUMNS.sugars.header;
+    const SAT_FAT_COL: &str = CIQUAL_COLUMNS.fa_saturated.header;
+    const SALT_COL: &str = CIQUAL_COLUMNS.salt.header;
+    const FIBER_COL: &str = CIQUAL_COLUMNS.fiber.header;
+    const CHOLESTEROL_COL: &str = CIQUAL_COLUMNS.cholesterol.header;
+
     fn create_test_csv_file() -> Result<NamedTempFile> {
         let mut file = NamedTempFile::new()?;
         writeln!(file, "{},{},{},{},{},{},{},{},{}", 
@@ -96,7 +191,7 @@ mod tests {
     #[test]
     fn test_load_ciqual_nutritional_data_success() -> Result<()> {
         let file = create_test_csv_file()?;
-        let data = load_ciqual_nutritional_data(file.path())?;
+        let data = load_nutritional_data(file.path(), &CIQUAL_COLUMNS)?;
         
         assert_eq!(data.len(), 4); // "Apple", "Banana", "Carrot", "InvalidNutrient" (empty name row skipped)
 
@@ -116,6 +211,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_load_ciqual_nutritional_data_optional_columns() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "{},{},{},{},{},{},{},{},{},{},{}",
+                 NAME_COL, KCAL_COL, WATER_COL, PROTEIN_COL, CARB_COL, FAT_COL, SUGARS_COL, SAT_FAT_COL, SALT_COL, FIBER_COL, CHOLESTEROL_COL)?;
+        writeln!(file, "Egg,145,76,12.5,0.3,10.3,0.3,2.6,0.35,0,398")?;
+        writeln!(file, "Lentils,116,69.6,9,16.3,0.4,0.5,0.1,0.01,7.9,")?; // Missing cholesterol
+        file.flush()?;
+
+        let data = load_nutritional_data(file.path(), &CIQUAL_COLUMNS)?;
+        let egg = data.iter().find(|item| item.name == "Egg").unwrap();
+        assert_eq!(egg.fiber_g_per_100g, Some(0.0));
+        assert_eq!(egg.cholesterol_mg_per_100g, Some(398.0));
+        let lentils = data.iter().find(|item| item.name == "Lentils").unwrap();
+        assert_eq!(lentils.fiber_g_per_100g, Some(7.9));
+        assert_eq!(lentils.cholesterol_mg_per_100g, None);
+
+        // The default test file has neither column, which must still load.
+        let legacy_file = create_test_csv_file()?;
+        let legacy_data = load_nutritional_data(legacy_file.path(), &CIQUAL_COLUMNS)?;
+        assert!(legacy_data.iter().all(|item| item.fiber_g_per_100g.is_none() && item.cholesterol_mg_per_100g.is_none()));
+        Ok(())
+    }
+
     #[test]
     fn test_load_ciqual_nutritional_data_missing_column() -> Result<()> {
         let mut file = NamedTempFile::new()?;
@@ -125,12 +244,31 @@ mod tests {
         writeln!(file, "Apple,85.6,0.3,13.8,0.2,10.4,0.0,0.0")?;
         file.flush()?;
 
-        let result = load_ciqual_nutritional_data(file.path());
+        let result = load_nutritional_data(file.path(), &CIQUAL_COLUMNS);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains(&format!("Column '{}' not found", KCAL_COL)));
         Ok(())
     }
 
+    #[test]
+    fn test_load_ciqual_nutritional_data_missing_optional_columns() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        // Only the name, kcal and macros: no water, sugars, saturated fat or salt.
+        writeln!(file, "{},{},{},{},{}", NAME_COL, KCAL_COL, PROTEIN_COL, CARB_COL, FAT_COL)?;
+        writeln!(file, "Apple,52,0.3,13.8,0.2")?;
+        file.flush()?;
+
+        let data = load_nutritional_data(file.path(), &CIQUAL_COLUMNS)?;
+        let apple = &data[0];
+        assert_eq!(apple.kcal_per_100g, Some(52.0));
+        assert_eq!(apple.fat_g_per_100g, Some(0.2));
+        assert_eq!(apple.water_g_per_100g, None);
+        assert_eq!(apple.sugars_g_per_100g, None);
+        assert_eq!(apple.fa_saturated_g_per_100g, None);
+        assert_eq!(apple.salt_g_per_100g, None);
+        Ok(())
+    }
+
     #[test]
     fn test_load_ciqual_nutritional_data_empty_file_with_headers() -> Result<()> {
         let mut file = NamedTempFile::new()?;
@@ -138,7 +276,7 @@ mod tests {
                  NAME_COL, KCAL_COL, WATER_COL, PROTEIN_COL, CARB_COL, FAT_COL, SUGARS_COL, SAT_FAT_COL, SALT_COL)?;
         file.flush()?;
 
-        let result = load_ciqual_nutritional_data(file.path());
+        let result = load_nutritional_data(file.path(), &CIQUAL_COLUMNS);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("No valid Ciqual data loaded"));
         Ok(())
@@ -147,8 +285,29 @@ mod tests {
     #[test]
     fn test_load_ciqual_nutritional_data_file_not_found() {
         let path = Path::new("this_file_does_not_exist.csv");
-        let result = load_ciqual_nutritional_data(path);
+        let result = load_nutritional_data(path, &CIQUAL_COLUMNS);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("Ciqual CSV file not found"));
     }
+
+    #[test]
+    fn test_load_usda_nutritional_data() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        let m = &USDA_COLUMNS;
+        writeln!(file, "fdc_id,\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\",\"{}\"",
+                 m.name, m.kcal.header, m.water.header, m.protein.header, m.carbohydrate.header, m.fat.header,
+                 m.sugars.header, m.fa_saturated.header, m.salt.header, m.fiber.header, m.cholesterol.header)?;
+        writeln!(file, "171287,\"Egg, whole, raw, fresh\",143,76.2,12.6,0.72,9.51,0.37,3.13,142,0,372")?;
+        file.flush()?;
+
+        let data = load_nutritional_data(file.path(), &USDA_COLUMNS)?;
+        assert_eq!(data.len(), 1);
+        let egg = &data[0];
+        assert_eq!(egg.name, "Egg, whole, raw, fresh");
+        assert_eq!(egg.kcal_per_100g, Some(143.0));
+        assert_eq!(egg.cholesterol_mg_per_100g, Some(372.0));
+        // 142 mg sodium -> 0.355 g salt
+        assert!((egg.salt_g_per_100g.unwrap() - 0.355).abs() < 1e-6);
+        Ok(())
+    }
 }
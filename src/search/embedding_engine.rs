@@ -1,41 +1,187 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use model2vec_rs::model::StaticModel;
+use std::collections::HashMap;
+use std::sync::RwLock;
 
-const EMBEDDING_MODEL_ID: &str = "minishlab/potion-base-32M";
+/// Hugging Face model (or local path) used unless another one is requested.
+pub const DEFAULT_EMBEDDING_MODEL_ID: &str = "minishlab/potion-base-32M";
+/// Environment variable holding the Hugging Face token for gated or private models.
+pub const HF_TOKEN_ENV_VAR: &str = "HF_TOKEN";
 
+/// Output dimension of the default model. The engine reports the loaded model's actual dimension.
 pub const EMBEDDING_DIMENSION: usize = 512; 
 
+// Text embedded once at load time to read the model's output dimension.
+const DIMENSION_PROBE_TEXT: &str = "dimension probe";
+
+/// Embeddings of previously seen texts. Shared behind `&self`, hence the lock.
+#[derive(Debug)]
+struct EmbeddingCache {
+    entries: RwLock<HashMap<String, Vec<f32>>>,
+    enabled: bool,
+}
+
+impl EmbeddingCache {
+    fn new(enabled: bool) -> Self {
+        Self { entries: RwLock::new(HashMap::new()), enabled }
+    }
+
+    fn get(&self, text: &str) -> Option<Vec<f32>> {
+        if !self.enabled {
+            return None;
+        }
+        self.entries.read().unwrap().get(text).cloned()
+    }
+
+    fn insert(&self, text: &str, embedding: &[f32]) {
+        if self.enabled {
+            self.entries.write().unwrap().insert(text.to_string(), embedding.to_vec());
+        }
+    }
+
+    fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+}
+
+/// Where embeddings come from: a loaded model, or a fixed table of known texts.
+enum EmbeddingSource {
+    Model(Box<StaticModel>),
+    Table(HashMap<String, Vec<f32>>),
+}
+
 pub struct EmbeddingEngine {
-    model: StaticModel,
+    source: EmbeddingSource,
+    model_id: String,
+    dimension: usize,
+    cache: EmbeddingCache,
 }
 
 impl EmbeddingEngine {
-    pub fn new() -> Result<Self> {
-        // TODO: Consider if hf_token, normalize_embeddings, or subfolder are needed.
-        // For now, using defaults as per the user's example.
-        let model = StaticModel::from_pretrained(EMBEDDING_MODEL_ID, None, None, None)?;
-        Ok(Self { model })
+    /// Loads `model_id` (a Hugging Face repo or local path, `DEFAULT_EMBEDDING_MODEL_ID` when `None`),
+    /// authenticating with `hf_token` if given.
+    pub fn new(model_id: Option<&str>, hf_token: Option<&str>) -> Result<Self> {
+        // TODO: Consider if normalize_embeddings or subfolder are needed.
+        let model_id = model_id.unwrap_or(DEFAULT_EMBEDDING_MODEL_ID);
+        let model = StaticModel::from_pretrained(model_id, hf_token, None, None)
+            .with_context(|| format!("Failed to load embedding model '{}'", model_id))?;
+        let dimension = model.encode(&[DIMENSION_PROBE_TEXT.to_string()])
+            .first()
+            .map(Vec::len)
+            .filter(|&dimension| dimension > 0)
+            .ok_or_else(|| anyhow::anyhow!("Embedding model '{}' returned no embedding for the dimension probe", model_id))?;
+        Ok(Self { source: EmbeddingSource::Model(Box::new(model)), model_id: model_id.to_string(), dimension, cache: EmbeddingCache::new(true) })
     }
 
+    /// An engine answering from precomputed embeddings instead of a model, so it needs no download.
+    /// Embedding a text missing from `embeddings` is an error. Meant for tests and fixtures.
+    pub fn from_embeddings(embeddings: HashMap<String, Vec<f32>>) -> Result<Self> {
+        let dimension = embeddings.values().next().map_or(0, Vec::len);
+        if dimension == 0 {
+            return Err(anyhow::anyhow!("Precomputed embeddings must be non-empty"));
+        }
+        if let Some((text, embedding)) = embeddings.iter().find(|(_, embedding)| embedding.len() != dimension) {
+            return Err(anyhow::anyhow!(
+                "Precomputed embedding for '{}' has dimension {}, expected {}", text, embedding.len(), dimension
+            ));
+        }
+        Ok(Self {
+            source: EmbeddingSource::Table(embeddings),
+            model_id: "precomputed".to_string(),
+            dimension,
+            cache: EmbeddingCache::new(true),
+        })
+    }
+
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// Fails when the loaded model doesn't produce `expected`-dimensional embeddings.
+    pub fn with_expected_dimension(self, expected: usize) -> Result<Self> {
+        if self.dimension != expected {
+            return Err(anyhow::anyhow!(
+                "Embedding model '{}' produces {}-dimensional embeddings, but {} were requested",
+                self.model_id, self.dimension, expected
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Enables or disables the `embed_one`/`embed_many` cache (enabled by default). Disabling drops cached entries.
+    pub fn with_cache(mut self, enabled: bool) -> Self {
+        self.cache = EmbeddingCache::new(enabled);
+        self
+    }
+
+    /// Forgets all cached embeddings.
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Number of texts whose embedding is cached.
+    pub fn cached_count(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Dimension of the loaded model's embeddings, measured when the engine was created.
     pub fn dimension(&self) -> usize {
-        // Ideally, model2vec_rs would expose a way to get this from the loaded model's config.
-        // For now, we hardcode it based on known model specs.
-        EMBEDDING_DIMENSION
+        self.dimension
     }
 
     pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
-        // Using default batch_size and max_length from model2vec-rs example.
-        // Consider making these configurable if needed.
-        let embeddings = self.model.encode(texts);
-        // model.encode returns Vec<Vec<f32>>, no explicit Result needed here unless it changes.
-        Ok(embeddings)
+        match &self.source {
+            // Using default batch_size and max_length from model2vec-rs example.
+            // Consider making these configurable if needed.
+            EmbeddingSource::Model(model) => Ok(model.encode(texts)),
+            EmbeddingSource::Table(embeddings) => texts.iter()
+                .map(|text| embeddings.get(text).cloned()
+                    .ok_or_else(|| anyhow::anyhow!("No precomputed embedding for '{}'", text)))
+                .collect(),
+        }
     }
 
+    /// Embeds a single text, reusing the cached embedding when the same text was embedded before.
     pub fn embed_one(&self, text: &str) -> Result<Vec<f32>> {
-        let embeddings = self.model.encode(&[text.to_string()]);
-        embeddings.into_iter().next().ok_or_else(|| {
+        if let Some(embedding) = self.cache.get(text) {
+            return Ok(embedding);
+        }
+        let embeddings = self.embed(&[text.to_string()])?;
+        let embedding = embeddings.into_iter().next().ok_or_else(|| {
             anyhow::anyhow!("Failed to generate embedding for single text: {}", text)
-        })
+        })?;
+        self.cache.insert(text, &embedding);
+        Ok(embedding)
+    }
+
+    /// Embeds several texts, reusing cached embeddings and encoding the rest in a single model call.
+    /// The result is aligned with `texts`.
+    pub fn embed_many(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let cached: Vec<Option<Vec<f32>>> = texts.iter().map(|text| self.cache.get(text)).collect();
+        let missing: Vec<String> = texts.iter().zip(&cached)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text.clone())
+            .collect();
+        let mut encoded = if missing.is_empty() { Vec::new() } else { self.embed(&missing)? }.into_iter();
+        if encoded.len() != missing.len() {
+            return Err(anyhow::anyhow!("Expected {} embeddings, got {}", missing.len(), encoded.len()));
+        }
+
+        texts.iter().zip(cached)
+            .map(|(text, embedding)| match embedding {
+                Some(embedding) => Ok(embedding),
+                None => {
+                    let embedding = encoded.next()
+                        .ok_or_else(|| anyhow::anyhow!("Failed to generate embedding for text: {}", text))?;
+                    self.cache.insert(text, &embedding);
+                    Ok(embedding)
+                }
+            })
+            .collect()
     }
 }
 
@@ -43,11 +189,45 @@ impl EmbeddingEngine {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_embedding_cache() {
+        let cache = EmbeddingCache::new(true);
+        assert_eq!(cache.get("butter"), None);
+        cache.insert("butter", &[0.1, 0.2]);
+        assert_eq!(cache.get("butter"), Some(vec![0.1, 0.2]));
+        assert_eq!(cache.get("Butter"), None, "Keys are the exact text");
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+
+        let disabled = EmbeddingCache::new(false);
+        disabled.insert("butter", &[0.1, 0.2]);
+        assert_eq!(disabled.get("butter"), None);
+        assert_eq!(disabled.len(), 0);
+    }
+
+    #[test]
+    fn test_precomputed_embeddings() -> Result<()> {
+        let engine = EmbeddingEngine::from_embeddings(HashMap::from([
+            ("butter".to_string(), vec![1.0, 0.0]),
+            ("salt".to_string(), vec![0.0, 1.0]),
+        ]))?;
+        assert_eq!(engine.dimension(), 2);
+        assert_eq!(engine.embed_many(&["salt".to_string(), "butter".to_string()])?, vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+        assert!(engine.embed_one("pepper").is_err());
+
+        let ragged = HashMap::from([("a".to_string(), vec![1.0]), ("b".to_string(), vec![1.0, 0.0])]);
+        assert!(EmbeddingEngine::from_embeddings(ragged).is_err());
+        assert!(EmbeddingEngine::from_embeddings(HashMap::new()).is_err());
+        Ok(())
+    }
+
     #[test]
     #[ignore] // This test downloads a model and might be slow/network-dependent
     fn test_embedding_engine_init_and_embed() -> Result<()> {
-        let engine = EmbeddingEngine::new()?;
+        let engine = EmbeddingEngine::new(None, None)?.with_expected_dimension(EMBEDDING_DIMENSION)?;
         assert_eq!(engine.dimension(), EMBEDDING_DIMENSION);
+        assert_eq!(engine.model_id(), DEFAULT_EMBEDDING_MODEL_ID);
 
         let sentences = vec![
             "Hello world".to_string(),
@@ -60,6 +240,11 @@ mod tests {
 
         let single_embedding = engine.embed_one("Test sentence")?;
         assert_eq!(single_embedding.len(), EMBEDDING_DIMENSION);
+        assert_eq!(engine.cached_count(), 1);
+        assert_eq!(engine.embed_one("Test sentence")?, single_embedding);
+        assert_eq!(engine.cached_count(), 1);
+
+        assert!(engine.with_expected_dimension(EMBEDDING_DIMENSION + 1).is_err());
         Ok(())
     }
 }
@@ -6,12 +6,13 @@
 
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine as _};
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::log_warning;
 
 /// Constants used for special field names
 pub mod constants {
@@ -23,6 +24,21 @@ pub mod constants {
 
 type Float = f32;
 
+/// The stored database was built for another embedding dimension, e.g. by a different model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DimensionMismatch {
+    pub stored: usize,
+    pub expected: usize,
+}
+
+impl std::fmt::Display for DimensionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Embedding dimension mismatch: DB has {}, expected {}", self.stored, self.expected)
+    }
+}
+
+impl std::error::Error for DimensionMismatch {}
+
 /// A single vector entry with metadata
 #[derive(Debug, Serialize, Deserialize, Clone)] // Added Clone
 pub struct Data {
@@ -77,7 +93,8 @@ pub struct NanoVectorDB {
     pub embedding_dim: usize,
     /// Distance metric used for similarity searches
     pub metric: String, // This is fixed to cosine in the implementation
-    storage_file: PathBuf,
+    storage_file: Option<PathBuf>, // None for an in-memory database
+
     storage: DataBase,
 }
 
@@ -114,6 +131,9 @@ impl Ord for ScoredIndex {
                 Ordering::Equal // Both NaN or both equal numbers
             }
         })
+        // Equal scores: the lower index ranks higher, so it is kept when the heap is full and
+        // comes first in the results. Without this, tied results come out in arbitrary order.
+        .then_with(|| self.index.cmp(&other.index))
     }
 }
 
@@ -121,17 +141,14 @@ type DataFilter = Box<dyn Fn(&Data) -> bool + Send + Sync>;
 
 impl NanoVectorDB {
     /// Creates a new NanoVectorDB instance
-    pub fn new(embedding_dim: usize, storage_file: &str) -> Result<Self> {
-        let storage_file = PathBuf::from(storage_file);
+    pub fn new(embedding_dim: usize, storage_file: impl AsRef<Path>) -> Result<Self> {
+        let storage_file = storage_file.as_ref().to_path_buf();
         let storage = if storage_file.exists() && storage_file.metadata()?.len() > 0 {
             let contents = fs::read_to_string(&storage_file)?;
             let db: DataBase = serde_json::from_str(&contents)?;
 
             if db.embedding_dim != embedding_dim {
-                anyhow::bail!(
-                    "Embedding dimension mismatch: DB has {}, expected {}",
-                    db.embedding_dim, embedding_dim
-                );
+                return Err(DimensionMismatch { stored: db.embedding_dim, expected: embedding_dim }.into());
             }
 
             let expected_len = db.data.len() * db.embedding_dim;
@@ -155,11 +172,26 @@ impl NanoVectorDB {
         Ok(Self {
             embedding_dim,
             metric: "cosine".to_string(), // Hardcoded as per implementation
-            storage_file,
+            storage_file: Some(storage_file),
             storage,
         })
     }
 
+    /// Creates an empty database that is never read from or written to disk; `save` is a no-op.
+    pub fn new_in_memory(embedding_dim: usize) -> Self {
+        Self {
+            embedding_dim,
+            metric: "cosine".to_string(),
+            storage_file: None,
+            storage: DataBase {
+                embedding_dim,
+                data: Vec::new(),
+                matrix: Vec::new(),
+                additional_data: HashMap::new(),
+            },
+        }
+    }
+
     /// Upserts vectors into the database
     pub fn upsert(&mut self, mut datas: Vec<Data>) -> Result<(Vec<String>, Vec<String>)> {
         let mut updates = Vec::new();
@@ -180,17 +212,19 @@ impl NanoVectorDB {
             // Use data_item.id directly as it's a String
             if let Some(&pos) = existing_ids_map.get(&data_item.id) {
                 // Update existing
-                let norm_vec = normalize(&data_item.vector); // Normalize input vector
+                let mut norm_vec = data_item.vector;
+                normalize_in_place(&mut norm_vec); // Normalize input vector, reusing its allocation
                 let start = pos * self.embedding_dim;
                 let end = start + self.embedding_dim;
                 if end <= self.storage.matrix.len() {
                      self.storage.matrix[start..end].copy_from_slice(&norm_vec);
+                     self.storage.data[pos].vector = norm_vec;
                      self.storage.data[pos].fields = data_item.fields; // Update fields too
                      updates.push(data_item.id);
                 } else {
                     // This case should ideally not happen if logic is correct
                     // Or it implies a corrupted state. For now, log and skip.
-                    eprintln!("Error: Matrix index out of bounds during update for ID: {}", data_item.id);
+                    log_warning!("Error: Matrix index out of bounds during update for ID: {}", data_item.id);
                 }
             } else {
                 // New item
@@ -198,20 +232,32 @@ impl NanoVectorDB {
             }
         }
         
+        // Normalize the new vectors where they are, then grow the matrix once for all of them
+        normalize_batch(new_data_to_add.iter_mut().map(|data_item| data_item.vector.as_mut_slice()));
+        self.storage.matrix.reserve(new_data_to_add.len() * self.embedding_dim);
+        self.storage.data.reserve(new_data_to_add.len());
         for data_item in new_data_to_add {
-            let norm_vec = normalize(&data_item.vector); // Normalize input vector
-            self.storage.matrix.extend_from_slice(&norm_vec);
-            self.storage.data.push(Data {
-                id: data_item.id.clone(),
-                vector: norm_vec, // Store normalized vector, though original code skips serializing it
-                fields: data_item.fields,
-            });
-            inserts.push(data_item.id);
+            self.storage.matrix.extend_from_slice(&data_item.vector);
+            inserts.push(data_item.id.clone());
+            // Store normalized vector, though original code skips serializing it
+            self.storage.data.push(data_item);
         }
 
         Ok((updates, inserts))
     }
 
+    /// Merges `fields` into the metadata of an existing entry, leaving its vector untouched.
+    /// Keys already present are overwritten, others are kept. Returns whether the ID was found.
+    pub fn update_fields(&mut self, id: &str, fields: HashMap<String, serde_json::Value>) -> Result<bool> {
+        match self.storage.data.iter_mut().find(|data| data.id == id) {
+            Some(data) => {
+                data.fields.extend(fields);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     /// Queries the database for similar vectors
     pub fn query(
         &self,
@@ -237,12 +283,12 @@ impl NanoVectorDB {
         let mut heap = BinaryHeap::with_capacity(top_k + 1);
 
         for (idx, data_item_ref) in self.storage.data.iter().enumerate() {
-            if filter.as_ref().map_or(true, |f| f(data_item_ref)) {
+            if filter.as_ref().is_none_or(|f| f(data_item_ref)) {
                 let vector_slice_start = idx * embedding_dim;
                 let vector_slice_end = vector_slice_start + embedding_dim;
                 if vector_slice_end > matrix.len() {
                     // Should not happen if DB is consistent
-                    eprintln!("Error: Matrix index out of bounds during query for internal index: {}", idx);
+                    log_warning!("Error: Matrix index out of bounds during query for internal index: {}", idx);
                     continue;
                 }
                 let vector_to_compare = &matrix[vector_slice_start..vector_slice_end];
@@ -278,6 +324,20 @@ impl NanoVectorDB {
             .collect()
     }
 
+    /// Like `query`, restricted to the entries whose `field` equals `value`.
+    /// Entries without that field never match.
+    pub fn query_filtered_by(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        field: &str,
+        value: impl Into<serde_json::Value>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        let field = field.to_string();
+        let value = value.into();
+        let filter: DataFilter = Box::new(move |data| data.fields.get(&field) == Some(&value));
+        self.query(query, top_k, None, Some(filter))
+    }
 
     /// Get vectors by their IDs
     pub fn get(&self, ids: &[String]) -> Vec<&Data> {
@@ -313,10 +373,13 @@ impl NanoVectorDB {
     }
 
 
-    /// Saves the database to disk
+    /// Saves the database to disk (does nothing for an in-memory database)
     pub fn save(&self) -> Result<()> {
+        let Some(storage_file) = &self.storage_file else {
+            return Ok(());
+        };
         let serialized = serde_json::to_string_pretty(&self.storage)?; // Use pretty for readability
-        fs::write(&self.storage_file, serialized)?;
+        fs::write(storage_file, serialized)?;
         Ok(())
     }
 
@@ -387,6 +450,23 @@ pub fn normalize(vector: &[Float]) -> Vec<Float> {
     vector.iter().map(|&x| x * inv_norm).collect()
 }
 
+/// Normalize a vector to unit length without allocating; a zero vector stays zero, as with `normalize`
+pub fn normalize_in_place(vector: &mut [Float]) {
+    let norm_sq: Float = vector.iter().map(|&x| x * x).sum();
+    if norm_sq == 0.0 {
+        return;
+    }
+    let inv_norm = 1.0 / norm_sq.sqrt();
+    vector.iter_mut().for_each(|x| *x *= inv_norm);
+}
+
+/// Normalize many vectors in place, e.g. every row of a matrix built for an upsert
+pub fn normalize_batch<'a>(vectors: impl IntoIterator<Item = &'a mut [Float]>) {
+    for vector in vectors {
+        normalize_in_place(vector);
+    }
+}
+
 /// Tests
 #[cfg(test)]
 mod tests {
@@ -428,12 +508,11 @@ mod tests {
         let path_str = temp_file.path().to_str().unwrap();
 
         // Create malformed database with mismatched matrix size
-         let mut data_for_db = Vec::new();
-         data_for_db.push(Data {
-             id: "entry1".to_string(),
-             vector: vec![1.0, 2.0], // This vector is not directly used for matrix construction in this test setup
-             fields: HashMap::new(),
-         });
+        let data_for_db = vec![Data {
+            id: "entry1".to_string(),
+            vector: vec![1.0, 2.0], // This vector is not directly used for matrix construction in this test setup
+            fields: HashMap::new(),
+        }];
         let corrupt_db_storage = DataBase {
             embedding_dim: 2, // Expects 2D vectors
             data: data_for_db,
@@ -456,12 +535,11 @@ mod tests {
         let temp_file = NamedTempFile::new().unwrap();
         let path_str = temp_file.path().to_str().unwrap();
 
-        let mut data_for_db = Vec::new();
-         data_for_db.push(Data {
-             id: "entry1".to_string(),
-             vector: vec![1.0, 2.0], 
-             fields: HashMap::new(),
-         });
+        let data_for_db = vec![Data {
+            id: "entry1".to_string(),
+            vector: vec![1.0, 2.0],
+            fields: HashMap::new(),
+        }];
         let db_storage_2d = DataBase { // DB stored with 2D embeddings
             embedding_dim: 2,
             data: data_for_db,
@@ -580,6 +658,83 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_query_filtered_by_field() -> Result<()> {
+        let mut db = NanoVectorDB::new_in_memory(3);
+        db.upsert(vec![
+            Data { id: "vec1".into(), vector: vec![1.0, 2.0, 3.0], fields: [("color".into(), serde_json::json!("red"))].into() },
+            Data { id: "vec2".into(), vector: vec![-4.0, 5.0, 6.0], fields: [("color".into(), serde_json::json!("blue"))].into() },
+            Data { id: "vec3".into(), vector: vec![1.1, 2.1, 3.1], fields: [("color".into(), serde_json::json!("blue"))].into() },
+            Data { id: "vec4".into(), vector: vec![1.0, 2.0, 3.0], fields: HashMap::new() },
+        ])?;
+
+        let blue: Vec<_> = db.query_filtered_by(&[1.0, 2.0, 3.0], 5, "color", "blue").iter()
+            .map(|result| result[constants::F_ID].clone())
+            .collect();
+        assert_eq!(blue, ["vec3", "vec2"]);
+        assert_eq!(db.query_filtered_by(&[1.0, 2.0, 3.0], 1, "color", "red")[0][constants::F_ID], "vec1");
+        assert!(db.query_filtered_by(&[1.0, 2.0, 3.0], 5, "color", "green").is_empty());
+        assert!(db.query_filtered_by(&[1.0, 2.0, 3.0], 5, "shape", "blue").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_ties_are_ordered_by_insertion() -> Result<()> {
+        let mut db = NanoVectorDB::new_in_memory(2);
+        let samples = ["a", "b", "c", "d"].iter()
+            .map(|id| Data { id: id.to_string(), vector: vec![1.0, 1.0], fields: HashMap::new() })
+            .collect();
+        db.upsert(samples)?;
+
+        for _ in 0..3 {
+            let ids: Vec<_> = db.query(&[1.0, 1.0], 4, None, None).iter()
+                .map(|result| result[constants::F_ID].clone())
+                .collect();
+            assert_eq!(ids, ["a", "b", "c", "d"]);
+        }
+        // Truncating to top_k keeps the earliest of the tied entries.
+        let top_two: Vec<_> = db.query(&[1.0, 1.0], 2, None, None).iter()
+            .map(|result| result[constants::F_ID].clone())
+            .collect();
+        assert_eq!(top_two, ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_fields() -> Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut db = NanoVectorDB::new(3, db_path)?;
+        db.upsert(vec![
+            Data { id: "v1".into(), vector: vec![1.0, 2.0, 3.0], fields: [("color".into(), serde_json::json!("red"))].into() },
+        ])?;
+        let matrix_before = db.storage.matrix.clone();
+
+        let found = db.update_fields("v1", [("row".into(), serde_json::json!(42))].into())?;
+        assert!(found);
+        assert_eq!(db.storage.matrix, matrix_before, "The vector must not be touched");
+
+        let results = db.query(&[1.0, 2.0, 3.0], 1, None, None);
+        assert_eq!(results[0]["color"], "red");
+        assert_eq!(results[0]["row"], 42);
+
+        assert!(!db.update_fields("missing", HashMap::new())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_never_touches_disk() -> Result<()> {
+        let mut db = NanoVectorDB::new_in_memory(3);
+        assert!(db.storage_file.is_none());
+        db.upsert(vec![
+            Data { id: "v1".into(), vector: vec![1.0, 0.0, 0.0], fields: HashMap::new() },
+        ])?;
+        db.save()?; // No-op
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.query(&[1.0, 0.0, 0.0], 1, None, None)[0][constants::F_ID], "v1");
+        Ok(())
+    }
+
     #[test]
     fn test_delete() -> Result<()> {
         let temp_file = NamedTempFile::new()?;
@@ -621,4 +776,17 @@ mod tests {
         assert!((normalized[0] - 0.6).abs() < 1e-6);
         assert!((normalized[1] - 0.8).abs() < 1e-6);
     }
+
+    #[test]
+    fn test_in_place_normalization_matches_normalize() {
+        let vectors = [vec![3.0, 4.0], vec![0.0, 0.0], vec![1e-20, -2e-20], vec![-0.3, 0.7]];
+        let mut matrix: Vec<Float> = vectors.concat();
+        normalize_batch(matrix.chunks_mut(2));
+        for (vector, row) in vectors.iter().zip(matrix.chunks(2)) {
+            assert_eq!(normalize(vector), row, "{:?}", vector);
+            let mut single = vector.clone();
+            normalize_in_place(&mut single);
+            assert_eq!(normalize(vector), single);
+        }
+    }
 }
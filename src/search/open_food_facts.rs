@@ -0,0 +1,145 @@
+//! Online nutrition lookup on Open Food Facts, used for ingredients the local database can't match.
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::recipe_converter::FoodItem;
+
+pub const OPEN_FOOD_FACTS_SEARCH_URL: &str = "https://world.openfoodfacts.org/cgi/search.pl";
+/// Prefix of the food item names built from Open Food Facts products, e.g. "OFF: Tahini".
+pub const OPEN_FOOD_FACTS_NAME_PREFIX: &str = "OFF: ";
+
+const USER_AGENT: &str = "RecipeOptim/0.1 (nutrition lookup)";
+// Products fetched per search; the first one with usable nutriments wins.
+const SEARCH_PAGE_SIZE: u32 = 5;
+
+/// Open Food Facts client with an in-memory cache of lookups, misses included.
+pub struct OpenFoodFacts {
+    http: reqwest::Client,
+    search_url: String,
+    cache: Mutex<HashMap<String, Option<FoodItem>>>,
+}
+
+impl OpenFoodFacts {
+    pub fn new() -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(std::time::Duration::from_secs(15))
+            .build()
+            .with_context(|| "Failed to build the Open Food Facts HTTP client")?;
+        Ok(Self { http, search_url: OPEN_FOOD_FACTS_SEARCH_URL.to_string(), cache: Mutex::new(HashMap::new()) })
+    }
+
+    /// Points the client at another search endpoint (a mirror or a local stub).
+    pub fn with_search_url(mut self, search_url: impl Into<String>) -> Self {
+        self.search_url = search_url.into();
+        self
+    }
+
+    /// Per-100g values of the best product for `ingredient_name`, or `None` when no product has
+    /// any. Failed requests are errors and are not cached, so a later lookup tries again.
+    pub async fn lookup(&self, ingredient_name: &str) -> Result<Option<FoodItem>> {
+        let key = ingredient_name.trim().to_lowercase();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            return Ok(cached.clone());
+        }
+
+        let page_size = SEARCH_PAGE_SIZE.to_string();
+        let response = self.http.get(&self.search_url)
+            .query(&[
+                ("search_terms", key.as_str()),
+                ("search_simple", "1"),
+                ("action", "process"),
+                ("json", "1"),
+                ("page_size", page_size.as_str()),
+                ("fields", "product_name,nutriments"),
+            ])
+            .send()
+            .await
+            .with_context(|| format!("Open Food Facts search for '{}' failed", ingredient_name))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("Open Food Facts search for '{}' failed with HTTP {}", ingredient_name, status));
+        }
+        let body: Value = response.json().await
+            .with_context(|| format!("Open Food Facts returned invalid JSON for '{}'", ingredient_name))?;
+
+        let item = food_item_from_search(&body);
+        self.cache.lock().unwrap().insert(key, item.clone());
+        Ok(item)
+    }
+}
+
+/// The first product of a search response with a name and at least one known nutriment, as a
+/// food item named `OFF: <product>`. Open Food Facts has no row index, so it is left at 0.
+pub fn food_item_from_search(body: &Value) -> Option<FoodItem> {
+    body.get("products")?.as_array()?.iter().find_map(|product| {
+        let name = product.get("product_name")?.as_str()?.trim();
+        let nutriments = product.get("nutriments")?;
+        let value = |key: &str| nutriment(nutriments, key);
+        let item = FoodItem {
+            name: format!("{}{}", OPEN_FOOD_FACTS_NAME_PREFIX, name),
+            original_row_index: 0,
+            kcal_per_100g: value("energy-kcal_100g"),
+            water_g_per_100g: None,
+            protein_g_per_100g: value("proteins_100g"),
+            carbohydrate_g_per_100g: value("carbohydrates_100g"),
+            fat_g_per_100g: value("fat_100g"),
+            sugars_g_per_100g: value("sugars_100g"),
+            fa_saturated_g_per_100g: value("saturated-fat_100g"),
+            salt_g_per_100g: value("salt_100g"),
+            fiber_g_per_100g: value("fiber_100g"),
+            // Open Food Facts reports cholesterol in grams.
+            cholesterol_mg_per_100g: value("cholesterol_100g").map(|grams| grams * 1000.0),
+        };
+        let has_nutriments = [item.kcal_per_100g, item.protein_g_per_100g, item.carbohydrate_g_per_100g, item.fat_g_per_100g]
+            .iter()
+            .any(Option::is_some);
+        (!name.is_empty() && has_nutriments).then_some(item)
+    })
+}
+
+// Nutriments are usually numbers but some products store them as strings.
+fn nutriment(nutriments: &Value, key: &str) -> Option<f32> {
+    match nutriments.get(key)? {
+        Value::Number(number) => number.as_f64().map(|v| v as f32),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_food_item_from_search() {
+        let body = json!({
+            "count": 3,
+            "products": [
+                { "product_name": "Unnamed nutriments", "nutriments": {} },
+                {
+                    "product_name": "Tahini ",
+                    "nutriments": {
+                        "energy-kcal_100g": 640,
+                        "proteins_100g": "24.5",
+                        "fat_100g": 56.1,
+                        "cholesterol_100g": 0.002
+                    }
+                },
+                { "product_name": "Other tahini", "nutriments": { "energy-kcal_100g": 600 } }
+            ]
+        });
+        let item = food_item_from_search(&body).unwrap();
+        assert_eq!(item.name, "OFF: Tahini");
+        assert_eq!(item.kcal_per_100g, Some(640.0));
+        assert_eq!(item.protein_g_per_100g, Some(24.5));
+        assert_eq!(item.carbohydrate_g_per_100g, None);
+        assert!((item.cholesterol_mg_per_100g.unwrap() - 2.0).abs() < 1e-4);
+
+        assert!(food_item_from_search(&json!({ "count": 0, "products": [] })).is_none());
+        assert!(food_item_from_search(&json!({ "error": "rate limited" })).is_none());
+    }
+}
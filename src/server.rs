@@ -0,0 +1,166 @@
+//! HTTP service around `process_recipe`, for a web frontend; built with the `server` feature.
+//! Every request shares one `NutritionalIndex` and one client, so LLM calls of concurrent
+//! requests stay under the client's request limit.
+use anyhow::{Context, Result};
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::api_connection::client::ChatClient;
+use crate::{log_info, log_verbose};
+use crate::nutritional_matcher::NutritionalIndex;
+use crate::optim::targets::OptimizableNutrient;
+use crate::optim::optimizer::LlmModificationResponse;
+use crate::pipeline::{process_recipe, InputFormat, PipelineOptions};
+use crate::progress::ProgressEvent;
+use crate::recipe_aggregator::EnrichedRecipeOutput;
+
+/// Body of `POST /analyze`. Settings left out keep the server's.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnalyzeRequest {
+    /// Recipe text, or a `ParsedRecipe` as JSON with `"format": "json"`.
+    pub recipe: String,
+    #[serde(default)]
+    pub format: Option<InputFormat>,
+    #[serde(default)]
+    pub servings: Option<u32>,
+    /// Percentage change per nutrient, e.g. `{"protein": 20, "fat": -10}`. Empty means no optimization.
+    #[serde(default)]
+    pub optimize: HashMap<String, f32>,
+}
+
+impl AnalyzeRequest {
+    /// `base` with this request's settings applied.
+    fn pipeline_options(&self, base: &PipelineOptions) -> Result<PipelineOptions, String> {
+        let mut options = base.clone();
+        if let Some(format) = self.format {
+            options.input_format = format;
+        }
+        if self.servings.is_some() {
+            options.servings = self.servings;
+        }
+        if !self.optimize.is_empty() {
+            options.optimization_targets = self.optimize.iter()
+                .map(|(nutrient, percentage)| Ok((OptimizableNutrient::from_str(nutrient)?, *percentage)))
+                .collect::<Result<_, String>>()?;
+        }
+        Ok(options)
+    }
+}
+
+/// Body of a successful `POST /analyze`: the enriched recipe, plus the optimizer's suggestions
+/// when the server only suggests.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalyzeResponse {
+    #[serde(flatten)]
+    pub output: EnrichedRecipeOutput,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<LlmModificationResponse>,
+}
+
+struct ServerState<C> {
+    index: NutritionalIndex,
+    client: C,
+    options: PipelineOptions,
+}
+
+type ErrorResponse = (StatusCode, Json<serde_json::Value>);
+
+fn error_response(status: StatusCode, message: String) -> ErrorResponse {
+    (status, Json(serde_json::json!({ "error": message })))
+}
+
+async fn analyze<C: ChatClient + Send + 'static>(
+    State(state): State<Arc<ServerState<C>>>,
+    Json(request): Json<AnalyzeRequest>,
+) -> Result<Json<AnalyzeResponse>, ErrorResponse> {
+    if request.recipe.trim().is_empty() {
+        return Err(error_response(StatusCode::BAD_REQUEST, "The recipe is empty".to_string()));
+    }
+    let options = request.pipeline_options(&state.options)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+    let progress_updater = |event: ProgressEvent| log_verbose!("{}", event);
+    process_recipe(&request.recipe, &options, &state.index, &state.client, progress_updater)
+        .await
+        .map(|processed| Json(AnalyzeResponse { output: processed.output, suggestions: processed.suggestions }))
+        .map_err(|e| error_response(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)))
+}
+
+/// The service's routes, answering with `options` unless a request overrides them.
+pub fn router<C: ChatClient + Send + 'static>(index: NutritionalIndex, client: C, options: PipelineOptions) -> Router {
+    Router::new()
+        .route("/analyze", post(analyze::<C>))
+        .with_state(Arc::new(ServerState { index, client, options }))
+}
+
+/// Serves `router` on `addr` until the process is stopped.
+pub async fn serve<C: ChatClient + Send + 'static>(
+    addr: SocketAddr,
+    index: NutritionalIndex,
+    client: C,
+    options: PipelineOptions,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    log_info!("Serving POST /analyze on http://{}", addr);
+    axum::serve(listener, router(index, client, options))
+        .await
+        .with_context(|| "HTTP server failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_connection::client::MockChatClient;
+    use crate::nutritional_matcher::test_support::{food_item, index_of};
+    use crate::nutritional_matcher::AutoAccept;
+    use crate::recipe_converter::FoodItem;
+
+    fn state() -> Arc<ServerState<MockChatClient>> {
+        let butter = FoodItem { kcal_per_100g: Some(717.0), fat_g_per_100g: Some(81.0), ..food_item("Butter", 0) };
+        let embeddings = HashMap::from([
+            ("Butter".to_string(), vec![1.0, 0.0]),
+            ("butter".to_string(), vec![1.0, 0.0]),
+        ]);
+        let index = index_of(vec![butter], embeddings)
+            .unwrap()
+            .with_auto_accept(Some(AutoAccept { min_similarity: 0.9, min_margin: 0.0 }));
+        let options = PipelineOptions { input_format: InputFormat::Text, ..Default::default() };
+        Arc::new(ServerState { index, client: MockChatClient::new(Vec::<String>::new()), options })
+    }
+
+    fn request(body: serde_json::Value) -> Json<AnalyzeRequest> {
+        Json(serde_json::from_value(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_analyze_returns_enriched_output() {
+        let recipe = r#"{"recipe_title": "Butter", "instructions": [],
+            "ingredients": [{"raw_text": "200 g butter", "ingredient_name": "butter", "quantity": "200", "unit": "g", "preparation_notes": ""}]}"#;
+        let Json(AnalyzeResponse { output, suggestions }) = analyze(State(state()), request(serde_json::json!({"recipe": recipe, "format": "json", "servings": 4})))
+            .await
+            .unwrap();
+        let profile = &output.nutritional_profile;
+        assert_eq!(profile.aggregated.kcal, Some(1434.0));
+        assert_eq!(profile.per_serving.as_ref().unwrap().kcal, Some(358.5));
+        assert!(suggestions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_analyze_rejects_bad_requests() {
+        let (status, _) = analyze(State(state()), request(serde_json::json!({"recipe": "  "}))).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, Json(body)) = analyze(State(state()), request(serde_json::json!({"recipe": "toast", "optimize": {"vitamins": 10}})))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("vitamins"));
+    }
+}
@@ -2,11 +2,11 @@ use recipe_optim::api_connection::{
     connection::ApiConnectionError,
     endpoints::{
         ChatCompletionRequest, ChatMessage, JsonSchema, JsonSchemaDefinition, JsonSchemaProperty,
-        ResponseFormat, OPENROUTER_MODELS, Provider
+        ResponseFormat, ResponseFormatMode, OPENROUTER_MODELS, Provider
     },
 };
 use dotenv::dotenv;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::env;
 
 const TEST_API_KEY_ENV_VAR: &str = "OPENROUTER_API_KEY";
@@ -81,6 +81,37 @@ async fn test_successful_non_structured_call() {
         .contains("paris"));
 }
 
+#[tokio::test]
+#[ignore]
+async fn test_successful_streaming_call() {
+    setup_test_environment();
+    if env::var(TEST_API_KEY_ENV_VAR).is_err() {
+        println!(
+            "Skipping test_successful_streaming_call: {} not set.",
+            TEST_API_KEY_ENV_VAR
+        );
+        return;
+    }
+
+    let provider = Provider::openrouter(TEST_API_KEY_ENV_VAR);
+    let request = ChatCompletionRequest {
+        model: get_cerebras_test_model(),
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: "What is the capital of France? Respond concisely.".to_string(),
+        }],
+        response_format: None,
+        temperature: Some(0.7),
+        max_tokens: Some(100),
+    };
+
+    let stream = provider.call_chat_completion_stream(request).await;
+    assert!(stream.is_ok(), "API call failed: {:?}", stream.err());
+    let content = stream.unwrap().collect_content().await;
+    assert!(content.is_ok(), "Stream failed: {:?}", content.err());
+    assert!(content.unwrap().to_lowercase().contains("paris"));
+}
+
 #[tokio::test]
 #[ignore]
 async fn test_successful_structured_call() {
@@ -94,7 +125,7 @@ async fn test_successful_structured_call() {
     }
     let provider = Provider::openrouter(TEST_API_KEY_ENV_VAR);
 
-    let mut properties_map = HashMap::new();
+    let mut properties_map = BTreeMap::new();
     properties_map.insert(
         "title".to_string(), 
         JsonSchemaProperty {
@@ -140,10 +171,7 @@ async fn test_successful_structured_call() {
                 content: "Give me details for the movie 'Inception'.".to_string(),
             },
         ],
-        response_format: Some(ResponseFormat {
-            format_type: "json_schema".to_string(),
-            json_schema: Some(schema_def),
-        }),
+        response_format: Some(ResponseFormat::for_schema(schema_def, ResponseFormatMode::JsonSchema)),
         temperature: Some(0.5),
         max_tokens: Some(300), 
     };